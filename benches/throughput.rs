@@ -0,0 +1,90 @@
+//! Throughput of the server with a single shard per keyspace (every command waits for the
+//! previous one, like a keyspace behind one lock), with the default number of shards, and
+//! with actors owning the shards (the actors engine), for 1, 8 and 64 concurrent clients.
+//! Run with `cargo bench`.
+//!
+//! Every client sends a SET then a GET of one of its own keys, and waits for the reply
+//! before sending the next command, for a few seconds.
+
+use std::time::{Duration, Instant};
+
+use tokio_mini_redis::{
+    client::RedisClient,
+    server::{Engine, RedisServer, ServerConfig, DEFAULT_SHARDS},
+};
+
+const CLIENTS: [usize; 3] = [1, 8, 64];
+const DURATION: Duration = Duration::from_secs(3);
+/// The keys of every client
+const KEYS: usize = 100;
+
+#[tokio::main]
+async fn main() {
+    println!(
+        "{:>8} {:>8} {:>8} {:>12}",
+        "engine", "shards", "clients", "commands/s"
+    );
+    let servers = [
+        (6380, Engine::Locks, 1),
+        (6381, Engine::Locks, DEFAULT_SHARDS),
+        (6382, Engine::Actors, DEFAULT_SHARDS),
+    ];
+    for (port, engine, shards) in servers {
+        let addr = format!("127.0.0.1:{}", port);
+        let server = RedisServer::with_config(ServerConfig {
+            shards,
+            engine,
+            ..ServerConfig::default()
+        });
+        let listening = addr.clone();
+        tokio::spawn(async move { server.listen(&listening).await });
+        for clients in CLIENTS.iter() {
+            let commands = run(&addr, *clients).await;
+            let per_second = commands as f64 / DURATION.as_secs_f64();
+            println!(
+                "{:>8} {:>8} {:>8} {:>12.0}",
+                engine, shards, clients, per_second
+            );
+        }
+    }
+}
+
+/// Runs the clients, returns the number of commands replied
+async fn run(addr: &str, clients: usize) -> usize {
+    let mut tasks = Vec::with_capacity(clients);
+    for client in 0..clients {
+        let mut connection = connect(addr).await;
+        tasks.push(tokio::spawn(async move {
+            let start = Instant::now();
+            let mut commands = 0;
+            for i in 0.. {
+                if start.elapsed() >= DURATION {
+                    break;
+                }
+                let key = format!("key:{}:{}", client, i % KEYS);
+                connection
+                    .set(key.clone(), "value")
+                    .await
+                    .expect("SET failed");
+                connection.get(&key).await.expect("GET failed");
+                commands += 2;
+            }
+            commands
+        }));
+    }
+    let mut commands = 0;
+    for task in tasks {
+        commands += task.await.expect("Client failed");
+    }
+    commands
+}
+
+/// Connects to the server, once it is listening
+async fn connect(addr: &str) -> RedisClient {
+    loop {
+        match RedisClient::connect(addr).await {
+            Ok(connection) => return connection,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
@@ -5,11 +5,21 @@ use tokio::{
     io::AsyncReadExt,
     sync::mpsc::{self, Sender},
 };
-use tokio_mini_redis::{client::RedisClient, resp::Type};
-use tokio_mini_redis::{commands::watch::WatchResult, Result};
+use tokio_mini_redis::{
+    client::RedisClient,
+    commands::Command,
+    resp::{Type, TypeConsumer},
+};
+use tokio_mini_redis::{
+    commands::{
+        pubsub::{Message, SubscriptionKind},
+        watch::{Watch, WatchResult},
+    },
+    Result,
+};
 
 use std::{
-    collections::LinkedList,
+    collections::{BTreeSet, LinkedList},
     error::Error,
     fmt::Display,
     io::{stdout, Write},
@@ -78,6 +88,7 @@ async fn main() -> Result<()> {
 fn print_type(t: Type) -> String {
     match t {
         Type::Error(message) => format!("Error: {}", message),
+        Type::BulkString(b) => String::from_utf8_lossy(&b).into(),
         _ => t.to_string(),
     }
 }
@@ -88,7 +99,7 @@ fn prompt() {
     stdout.flush().unwrap();
 }
 
-fn next<'a>(tokens: &'a mut Split<char>, field: &str) -> Result<String> {
+fn next(tokens: &mut Split<char>, field: &str) -> Result<String> {
     let r = tokens
         .next()
         .ok_or_else(|| CliError::ClientError(format!("{} cannot be empty", field)))?;
@@ -101,6 +112,7 @@ async fn send_command(
     sender: Sender<WatchResult>,
 ) -> Result<Type> {
     let mut tokens = command.split(' ');
+    let all_tokens = tokens.clone();
     if let Some(command) = tokens.next() {
         return match command.to_uppercase().as_ref() {
             "GET" => {
@@ -122,22 +134,101 @@ async fn send_command(
             }
             "PUSH" => {
                 let list_name = next(&mut tokens, "list_name")?;
-                let values: LinkedList<String> = tokens.into_iter().map(|v| v.into()).collect();
+                let values: LinkedList<String> = tokens.map(|v| v.into()).collect();
                 let t = client
                     .push(list_name, values)
                     .await
                     .map_err(|e| CliError::ServerError(e.to_string()))?;
                 Ok(t)
             }
-            // Watch is a special command, once in watch mode, you cannot send any more requests
-            "WATCH" => {
+            "PFADD" => {
                 let key = next(&mut tokens, "key")?;
-                let operation = next(&mut tokens, "operation")?;
-                let operation: u8 = operation.as_str().parse().map_err(|_| {
-                    CliError::ClientError(format!("Operation {} not a digit", operation))
-                })?;
+                let elements: LinkedList<Vec<u8>> = tokens.map(|v| v.into()).collect();
+                let t = client
+                    .pfadd(key, elements)
+                    .await
+                    .map_err(|e| CliError::ServerError(e.to_string()))?;
+                Ok(t)
+            }
+            "PFCOUNT" => {
+                let keys: LinkedList<String> = tokens.map(|v| v.into()).collect();
+                let t = client
+                    .pfcount(keys)
+                    .await
+                    .map_err(|e| CliError::ServerError(e.to_string()))?;
+                Ok(t)
+            }
+            "PFMERGE" => {
+                let destination = next(&mut tokens, "destination")?;
+                let sources: LinkedList<String> = tokens.map(|v| v.into()).collect();
+                let t = client
+                    .pfmerge(destination, sources)
+                    .await
+                    .map_err(|e| CliError::ServerError(e.to_string()))?;
+                Ok(t)
+            }
+            // The notifications of every watch are printed as they come, with the watch id
+            "WATCH" | "PWATCH" => {
+                let key = next(&mut tokens, "key")?;
+                let mut operations = BTreeSet::new();
+                let mut from = None;
+                let mut snapshot = false;
+                let first = next(&mut tokens, "operation")?;
+                let mut tokens = std::iter::once(first.as_str()).chain(tokens);
+                while let Some(token) = tokens.next() {
+                    if token.eq_ignore_ascii_case("FROM") {
+                        let offset = tokens.next().unwrap_or_default();
+                        from = Some(offset.parse().map_err(|_| {
+                            CliError::ClientError(format!("Offset {} not a number", offset))
+                        })?);
+                        continue;
+                    }
+                    if token.eq_ignore_ascii_case("SNAPSHOT") {
+                        snapshot = true;
+                        continue;
+                    }
+                    let operation: u8 = token.parse().map_err(|_| {
+                        CliError::ClientError(format!("Operation {} not a digit", token))
+                    })?;
+                    operations.insert(operation.into());
+                }
+                let watch = Watch {
+                    key,
+                    operations,
+                    pattern: command.eq_ignore_ascii_case("PWATCH"),
+                    from,
+                    snapshot,
+                };
+                let mut stream = client
+                    .watch_with(watch)
+                    .await
+                    .map_err(|e| CliError::ServerError(e.to_string()))?;
+                let id = stream.id();
+                tokio::spawn(async move {
+                    while let Some(result) = stream.next().await {
+                        if sender.send(result).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                Ok(Type::Integer(id as i64))
+            }
+            // Subscribe is special too, once subscribed, you cannot send any more requests
+            "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" => {
+                let channels: LinkedList<String> = tokens.map(|v| v.into()).collect();
+                let (sender, mut receiver) = mpsc::channel::<Message>(32);
+                tokio::spawn(async move {
+                    while let Some(m) = receiver.recv().await {
+                        println!("Message --> {:?}", m);
+                    }
+                });
+                let kind = match command.to_uppercase().as_ref() {
+                    "PSUBSCRIBE" => SubscriptionKind::Pattern,
+                    "SSUBSCRIBE" => SubscriptionKind::ShardChannel,
+                    _ => SubscriptionKind::Channel,
+                };
                 client
-                    .watch(key, operation.into(), sender)
+                    .subscribe(channels, kind, sender)
                     .await
                     .map_err(|e| CliError::ServerError(e.to_string()))?;
                 Ok(Type::Null)
@@ -149,13 +240,70 @@ async fn send_command(
                 GET - GET <key>
                 SET - SET <key> <value>
                 PUSH - PUSH <list name> <value1> <value2> ...
-                WATCH - WATCH <key> <1|2|3|4>
+                WATCH - WATCH <key> <1|2|3|4> [<1|2|3|4> ...] [FROM <offset> | SNAPSHOT]
+                UNWATCH - UNWATCH [<key>]
+                PWATCH - PWATCH <pattern> <1|2|3|4> [<1|2|3|4> ...] [FROM <offset> | SNAPSHOT]
+                PUNWATCH - PUNWATCH [<pattern>]
+                PFADD - PFADD <key> <element1> <element2> ...
+                PFCOUNT - PFCOUNT <key1> <key2> ...
+                PFMERGE - PFMERGE <destination> <source1> <source2> ...
+                SETBIT - SETBIT <key> <offset> <0|1>
+                GETBIT - GETBIT <key> <offset>
+                BITCOUNT - BITCOUNT <key> [<start> <end> [BYTE|BIT]]
+                BITPOS - BITPOS <key> <0|1> [<start> [<end> [BYTE|BIT]]]
+                BITOP - BITOP <AND|OR|XOR|NOT> <destination> <key1> <key2> ...
+                BITFIELD - BITFIELD <key> [GET <type> <offset>] [SET <type> <offset> <value>] [INCRBY <type> <offset> <increment>] [OVERFLOW <WRAP|SAT|FAIL>] ...
+                GEOADD - GEOADD <key> [NX|XX] [CH] <longitude> <latitude> <member> ...
+                GEODIST - GEODIST <key> <member1> <member2> [M|KM|FT|MI]
+                GEOPOS - GEOPOS <key> <member1> <member2> ...
+                GEOSEARCH - GEOSEARCH <key> <FROMMEMBER <member>|FROMLONLAT <longitude> <latitude>> <BYRADIUS <radius>|BYBOX <width> <height>> <M|KM|FT|MI> [ASC|DESC] [COUNT <count> [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+                KEYS - KEYS <pattern>
+                SCAN - SCAN <cursor> [MATCH <pattern>] [COUNT <count>] [TYPE <type>]
+                TYPE - TYPE <key>
+                RENAME - RENAME <key> <new_key>
+                RENAMENX - RENAMENX <key> <new_key>
+                COPY - COPY <source> <destination> [REPLACE]
+                RANDOMKEY - RANDOMKEY
+                DBSIZE - DBSIZE
+                FLUSHDB - FLUSHDB [ASYNC|SYNC]
+                FLUSHALL - FLUSHALL [ASYNC|SYNC]
+                SELECT - SELECT <index>
+                MOVE - MOVE <key> <db>
+                SWAPDB - SWAPDB <index1> <index2>
+                MULTI - MULTI
+                EXEC - EXEC
+                DISCARD - DISCARD
+                VERSION - VERSION <key>
+                CAS - CAS <key> <version> <value>
+                OWATCH - OWATCH <key1> <key2> ...
+                OUNWATCH - OUNWATCH
+                PING - PING [message]
+                INFO - INFO [section]
+                CLIENT - CLIENT <TRACKING <ON|OFF> [BCAST] [PREFIX <prefix> ...] [OPTIN|OPTOUT] [NOLOOP]|CACHING <YES|NO>>
+                CONFIG - CONFIG <GET <pattern>|SET notify-keyspace-events <flags>>
+                PUBLISH - PUBLISH <channel> <message>
+                SUBSCRIBE - SUBSCRIBE <channel1> <channel2> ...
+                PSUBSCRIBE - PSUBSCRIBE <pattern1> <pattern2> ...
+                SPUBLISH - SPUBLISH <shard channel> <message>
+                SSUBSCRIBE - SSUBSCRIBE <shard channel1> <shard channel2> ...
+                PUBSUB - PUBSUB <CHANNELS [pattern]|NUMSUB <channel1> ...|NUMPAT|SHARDCHANNELS [pattern]|SHARDNUMSUB <channel1> ...>
                 "#
                 .into(),
             )),
-            _ => Err(
-                CliError::ClientError(format!("Invalid command: <{}>, try HELP", command)).into(),
-            ),
+            // Any other command is parsed and sent as is
+            _ => {
+                let args = all_tokens
+                    .map(|t| Type::BulkString(t.as_bytes().to_vec()))
+                    .collect();
+                let command = Command::new(&mut TypeConsumer::new(Type::Array(args))).map_err(|e| {
+                    CliError::ClientError(format!("Invalid command: <{}> ({}), try HELP", command, e))
+                })?;
+                let t = client
+                    .execute(command)
+                    .await
+                    .map_err(|e| CliError::ServerError(e.to_string()))?;
+                Ok(t)
+            }
         };
     }
     Err("Invalid Command".into())
@@ -172,6 +320,10 @@ impl Error for CliError {}
 
 impl Display for CliError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{:?}", self))
+        match self {
+            CliError::ServerError(e) => write!(f, "ServerError: {}", e),
+            CliError::ClientError(e) => write!(f, "ClientError: {}", e),
+            CliError::Quit => f.write_str("Quit"),
+        }
     }
 }
@@ -1,11 +1,111 @@
 //! This is cli that runs the server. Under the hood it runs the server
 
-use tokio_mini_redis::server::RedisServer;
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tokio_mini_redis::server::{
+    parse_bytes, Engine, KeyspaceEvents, MaxMemoryPolicy, RedisServer, SaveRules, ServerConfig,
+    SlowWatchers, WatcherLimits,
+};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "mini-redis-server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "A Redis server")]
+struct Cli {
+    #[structopt(name = "databases", long = "--databases", default_value = "16")]
+    databases: usize,
+    /// The number of shards of every keyspace, a command only locks the shards of its keys
+    #[structopt(name = "shards", long = "--shards", default_value = "16")]
+    shards: usize,
+    /// How the commands are run: locks (by the connections) or actors (threads owning the shards)
+    #[structopt(name = "engine", long = "--engine", default_value = "locks")]
+    engine: Engine,
+    /// The number of recent changes kept by every shard of every database, for resuming watches
+    #[structopt(name = "change-log", long = "--change-log", default_value = "1024")]
+    change_log: usize,
+    /// A watcher is too slow as soon as this many notifications are queued (0 for no limit)
+    #[structopt(
+        name = "watch-limit-hard",
+        long = "--watch-limit-hard",
+        default_value = "8192"
+    )]
+    watch_limit_hard: usize,
+    /// A watcher is too slow once this many notifications stay queued for
+    /// --watch-limit-seconds (0 for no limit)
+    #[structopt(
+        name = "watch-limit-soft",
+        long = "--watch-limit-soft",
+        default_value = "1024"
+    )]
+    watch_limit_soft: usize,
+    #[structopt(
+        name = "watch-limit-seconds",
+        long = "--watch-limit-seconds",
+        default_value = "60"
+    )]
+    watch_limit_seconds: u64,
+    /// What happens to the watchers that are too slow: disconnect or coalesce
+    #[structopt(
+        name = "slow-watchers",
+        long = "--slow-watchers",
+        default_value = "disconnect"
+    )]
+    slow_watchers: SlowWatchers,
+    /// The keyspace notifications published over pub/sub, e.g. KEA (none by default)
+    #[structopt(
+        name = "notify-keyspace-events",
+        long = "--notify-keyspace-events",
+        default_value = ""
+    )]
+    notify_keyspace_events: KeyspaceEvents,
+    /// The memory the keys and values can use, e.g. 100mb (0 for no limit)
+    #[structopt(name = "maxmemory", long = "--maxmemory", default_value = "0", parse(try_from_str = parse_bytes))]
+    maxmemory: usize,
+    /// Which keys are evicted at the maxmemory: noeviction, allkeys-lru, allkeys-lfu,
+    /// volatile-lru, volatile-ttl or allkeys-random
+    #[structopt(
+        name = "maxmemory-policy",
+        long = "--maxmemory-policy",
+        default_value = "noeviction"
+    )]
+    maxmemory_policy: MaxMemoryPolicy,
+    /// The file the snapshots are saved to and loaded from at startup (empty for none)
+    #[structopt(
+        name = "dbfilename",
+        long = "--dbfilename",
+        default_value = "dump.rdb",
+        parse(from_os_str)
+    )]
+    dbfilename: PathBuf,
+    /// Save a snapshot after <seconds> if at least <changes> keys changed, as pairs of
+    /// numbers, e.g. "300 10 60 1000" (empty for no automatic snapshots)
+    #[structopt(
+        name = "save",
+        long = "--save",
+        default_value = "3600 1 300 100 60 10000"
+    )]
+    save: SaveRules,
+}
 
 #[tokio::main]
 async fn main() {
     // initialize the logger
     env_logger::init();
-    let server = RedisServer::new();
+    let cli = Cli::from_args();
+    let server = RedisServer::with_config(ServerConfig {
+        databases: cli.databases,
+        shards: cli.shards,
+        engine: cli.engine,
+        change_log: cli.change_log,
+        watcher_limits: WatcherLimits {
+            hard: cli.watch_limit_hard,
+            soft: cli.watch_limit_soft,
+            soft_seconds: cli.watch_limit_seconds,
+            policy: cli.slow_watchers,
+        },
+        keyspace_events: cli.notify_keyspace_events,
+        maxmemory: cli.maxmemory,
+        maxmemory_policy: cli.maxmemory_policy,
+        dbfilename: Some(cli.dbfilename).filter(|path| !path.as_os_str().is_empty()),
+        save: cli.save,
+    });
     server.listen("127.0.0.1:6000").await.expect("Error");
 }
@@ -1,23 +1,104 @@
 //! This is the client module.  
 //! This provides a simple [RedisClient] which supports the [super::commands::Command]
 
-use std::collections::LinkedList;
+use std::{
+    collections::{BTreeSet, HashMap, LinkedList},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
 
-use log::debug;
-use tokio::{net::TcpStream, sync::mpsc::Sender};
+use futures_core::Stream;
+use log::{debug, warn};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+};
 
 use crate::{
     commands::CommandCreationError,
-    commands::{get::Get, list::Push, set::Set, watch::Watch, watch::WatchResult, Command},
+    commands::{
+        bitmap::{BitCount, BitField, BitFieldOperation, BitOp, BitOperation, BitPos, BitRange},
+        bitmap::{GetBit, RangeUnit, SetBit},
+        cas::{Cas, OUnwatch, OWatch, Version},
+        client::{Client, Tracking, TrackingMode},
+        config::Config,
+        databases::{Move, Select, SwapDb},
+        geo::{DistanceUnit, GeoAdd, GeoDist, GeoMember, GeoPos, GeoSearch},
+        get::Get,
+        hyperloglog::{PfAdd, PfCount, PfMerge},
+        info::Info,
+        keys::{Copy, DbSize, Flush, KeyType, Keys, RandomKey, Rename, Scan},
+        list::Push,
+        ping::Ping,
+        pubsub::{Introspection, Message, Publish, Subscribe, SubscriptionKind},
+        set::Set,
+        transaction::{Discard, Exec, Multi},
+        watch::WatchResult,
+        watch::{Unwatch, Watch},
+        Command,
+    },
     connection::{Connection, ReadHalf, WriteHalf},
-    resp::Type,
+    resp::{Type, TypeConsumer},
 };
 use crate::{database::Operation, Result};
 
-/// A RedisClient
+/// A RedisClient. A task of its own reads the connection: the pushes of the watches go to
+/// their [WatchStream], the invalidations to the local cache, everything else is a reply.
 pub struct RedisClient {
-    read_half: ReadHalf,
     write_half: WriteHalf,
+    replies: UnboundedReceiver<Result<Type>>,
+    shared: Arc<Mutex<Shared>>,
+    /// A MULTI is open, the commands are queued (and replied with `QUEUED`) until EXEC
+    multi: bool,
+}
+
+/// What the client shares with the task reading its connection
+#[derive(Default)]
+struct Shared {
+    /// The streams of the watches, by id
+    streams: HashMap<u64, UnboundedSender<WatchResult>>,
+    /// The command waiting for its reply, which the reading task handles
+    pending: Option<Pending>,
+    /// The values read with GET, once the cache is enabled (see [RedisClient::enable_cache])
+    cache: Option<HashMap<String, Type>>,
+}
+
+/// A command whose reply is handled by the task reading the connection, before anything
+/// read after it
+enum Pending {
+    /// A WATCH, the reply is the id of the watch of the stream
+    Watch(UnboundedSender<WatchResult>),
+    /// A GET of the key, the value replied is cached
+    Get(String),
+}
+
+/// The notifications of a watch, see [RedisClient::watch].
+/// It ends after an UNWATCH of the watch, or when the connection is closed. Dropping it
+/// only drops the notifications, the server keeps watching until UNWATCH.
+pub struct WatchStream {
+    id: u64,
+    receiver: UnboundedReceiver<WatchResult>,
+}
+
+impl WatchStream {
+    /// The id of the watch, given by the server
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The next notification, None once the watch is over
+    pub async fn next(&mut self) -> Option<WatchResult> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for WatchStream {
+    type Item = WatchResult;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
 }
 
 impl RedisClient {
@@ -25,21 +106,85 @@ impl RedisClient {
     pub async fn connect(addr: &str) -> Result<Self> {
         let socket = TcpStream::connect(addr).await?;
         let (read_half, write_half) = Connection::new(socket).read_write_split();
+        let (sender, replies) = mpsc::unbounded_channel();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        tokio::spawn(read(read_half, sender, shared.clone()));
         Ok(RedisClient {
-            read_half,
             write_half,
+            replies,
+            shared,
+            multi: false,
         })
     }
 
-    /// Get command
+    /// Get command, from the local cache when it is enabled and the key is in it.
+    /// Inside a MULTI, the GET is always queued by the server, and nothing is cached.
     pub async fn get(&mut self, key: &str) -> Result<Type> {
+        if !self.multi {
+            let mut shared = self.shared.lock().expect("Lock failed");
+            if let Some(cache) = shared.cache.as_ref() {
+                if let Some(value) = cache.get(key) {
+                    debug!("Cached: {}", key);
+                    return Ok(value.clone());
+                }
+                shared.pending = Some(Pending::Get(key.into()));
+            }
+        }
         let get = Command::Get(Get { key: key.into() });
         debug!("{:?}", get);
         self.send(get.into()).await
     }
-    /// Set command
-    pub async fn set(&mut self, key: String, value: String) -> Result<Type> {
-        let set = Command::Set(Set { key, value });
+
+    /// Enables the local cache of the values read with GET. The server tracks the keys read
+    /// (CLIENT TRACKING ON) and tells the client when they change, they are then dropped
+    /// from the cache. The cache is emptied by SELECT, and disabled if the connection closes.
+    pub async fn enable_cache(&mut self) -> Result<Type> {
+        let reply = self
+            .client_tracking(Tracking {
+                on: true,
+                bcast: false,
+                prefixes: Vec::new(),
+                mode: TrackingMode::Default,
+                noloop: false,
+            })
+            .await?;
+        if let Type::Error(e) = reply {
+            return Err(e.into());
+        }
+        self.shared.lock().expect("Lock failed").cache = Some(HashMap::new());
+        Ok(reply)
+    }
+
+    /// Disables the local cache, and the tracking of the keys by the server
+    pub async fn disable_cache(&mut self) -> Result<Type> {
+        self.shared.lock().expect("Lock failed").cache = None;
+        self.client_tracking(Tracking {
+            on: false,
+            bcast: false,
+            prefixes: Vec::new(),
+            mode: TrackingMode::Default,
+            noloop: false,
+        })
+        .await
+    }
+
+    /// client tracking command, the invalidations are only used by the local cache
+    /// (see [RedisClient::enable_cache])
+    pub async fn client_tracking(&mut self, tracking: Tracking) -> Result<Type> {
+        self.execute(Command::Client(Client::Tracking(tracking)))
+            .await
+    }
+
+    /// client caching command, for the OPTIN and OPTOUT modes of CLIENT TRACKING
+    pub async fn client_caching(&mut self, yes: bool) -> Result<Type> {
+        self.execute(Command::Client(Client::Caching(yes))).await
+    }
+    /// Set command, the value is binary safe
+    pub async fn set(&mut self, key: String, value: impl Into<Vec<u8>>) -> Result<Type> {
+        let set = Command::Set(Set {
+            key,
+            value: value.into(),
+        });
         debug!("{:?}", set);
         self.send(set.into()).await
     }
@@ -51,31 +196,656 @@ impl RedisClient {
         self.send(push.into()).await
     }
 
-    /// push command
+    /// pfadd command
+    pub async fn pfadd(&mut self, key: String, elements: LinkedList<Vec<u8>>) -> Result<Type> {
+        let pfadd = Command::PfAdd(PfAdd { key, elements });
+        debug!("{:?}", pfadd);
+        self.send(pfadd.into()).await
+    }
+
+    /// pfcount command
+    pub async fn pfcount(&mut self, keys: LinkedList<String>) -> Result<Type> {
+        let pfcount = Command::PfCount(PfCount { keys });
+        debug!("{:?}", pfcount);
+        self.send(pfcount.into()).await
+    }
+
+    /// pfmerge command
+    pub async fn pfmerge(
+        &mut self,
+        destination: String,
+        sources: LinkedList<String>,
+    ) -> Result<Type> {
+        let pfmerge = Command::PfMerge(PfMerge {
+            destination,
+            sources,
+        });
+        debug!("{:?}", pfmerge);
+        self.send(pfmerge.into()).await
+    }
+
+    /// setbit command
+    pub async fn setbit(&mut self, key: String, offset: u64, value: u8) -> Result<Type> {
+        self.execute(Command::SetBit(SetBit { key, offset, value }))
+            .await
+    }
+
+    /// getbit command
+    pub async fn getbit(&mut self, key: String, offset: u64) -> Result<Type> {
+        self.execute(Command::GetBit(GetBit { key, offset })).await
+    }
+
+    /// bitcount command
+    pub async fn bitcount(&mut self, key: String, range: Option<BitRange>) -> Result<Type> {
+        self.execute(Command::BitCount(BitCount { key, range }))
+            .await
+    }
+
+    /// bitpos command
+    pub async fn bitpos(
+        &mut self,
+        key: String,
+        bit: u8,
+        start: Option<i64>,
+        end: Option<i64>,
+        unit: RangeUnit,
+    ) -> Result<Type> {
+        self.execute(Command::BitPos(BitPos {
+            key,
+            bit,
+            start,
+            end,
+            unit,
+        }))
+        .await
+    }
+
+    /// bitop command
+    pub async fn bitop(
+        &mut self,
+        operation: BitOperation,
+        destination: String,
+        keys: LinkedList<String>,
+    ) -> Result<Type> {
+        self.execute(Command::BitOp(BitOp {
+            operation,
+            destination,
+            keys,
+        }))
+        .await
+    }
+
+    /// bitfield command
+    pub async fn bitfield(
+        &mut self,
+        key: String,
+        operations: LinkedList<BitFieldOperation>,
+    ) -> Result<Type> {
+        self.execute(Command::BitField(BitField { key, operations }))
+            .await
+    }
+
+    /// geoadd command
+    pub async fn geoadd(&mut self, key: String, members: LinkedList<GeoMember>) -> Result<Type> {
+        self.execute(Command::GeoAdd(GeoAdd {
+            key,
+            condition: None,
+            changed: false,
+            members,
+        }))
+        .await
+    }
+
+    /// geodist command
+    pub async fn geodist(
+        &mut self,
+        key: String,
+        member1: String,
+        member2: String,
+        unit: DistanceUnit,
+    ) -> Result<Type> {
+        self.execute(Command::GeoDist(GeoDist {
+            key,
+            member1,
+            member2,
+            unit,
+        }))
+        .await
+    }
+
+    /// geopos command
+    pub async fn geopos(&mut self, key: String, members: LinkedList<String>) -> Result<Type> {
+        self.execute(Command::GeoPos(GeoPos { key, members })).await
+    }
+
+    /// geosearch command
+    pub async fn geosearch(&mut self, search: GeoSearch) -> Result<Type> {
+        self.execute(Command::GeoSearch(search)).await
+    }
+
+    /// keys command
+    pub async fn keys(&mut self, pattern: String) -> Result<Type> {
+        self.execute(Command::Keys(Keys { pattern })).await
+    }
+
+    /// scan command
+    pub async fn scan(&mut self, scan: Scan) -> Result<Type> {
+        self.execute(Command::Scan(scan)).await
+    }
+
+    /// type command
+    pub async fn key_type(&mut self, key: String) -> Result<Type> {
+        self.execute(Command::KeyType(KeyType { key })).await
+    }
+
+    /// rename command
+    pub async fn rename(&mut self, key: String, new_key: String) -> Result<Type> {
+        self.execute(Command::Rename(Rename {
+            key,
+            new_key,
+            only_if_new: false,
+        }))
+        .await
+    }
+
+    /// renamenx command
+    pub async fn renamenx(&mut self, key: String, new_key: String) -> Result<Type> {
+        self.execute(Command::Rename(Rename {
+            key,
+            new_key,
+            only_if_new: true,
+        }))
+        .await
+    }
+
+    /// copy command
+    pub async fn copy(
+        &mut self,
+        source: String,
+        destination: String,
+        replace: bool,
+    ) -> Result<Type> {
+        self.execute(Command::Copy(Copy {
+            source,
+            destination,
+            replace,
+        }))
+        .await
+    }
+
+    /// randomkey command
+    pub async fn randomkey(&mut self) -> Result<Type> {
+        self.execute(Command::RandomKey(RandomKey)).await
+    }
+
+    /// dbsize command
+    pub async fn dbsize(&mut self) -> Result<Type> {
+        self.execute(Command::DbSize(DbSize)).await
+    }
+
+    /// flushdb command
+    pub async fn flushdb(&mut self, asynchronous: bool) -> Result<Type> {
+        self.execute(Command::Flush(Flush {
+            all: false,
+            asynchronous,
+        }))
+        .await
+    }
+
+    /// flushall command
+    pub async fn flushall(&mut self, asynchronous: bool) -> Result<Type> {
+        self.execute(Command::Flush(Flush {
+            all: true,
+            asynchronous,
+        }))
+        .await
+    }
+
+    /// select command, the database is selected for the rest of the connection
+    pub async fn select(&mut self, index: i64) -> Result<Type> {
+        // The keys tracked were read in the other database
+        if let Some(cache) = self.shared.lock().expect("Lock failed").cache.as_mut() {
+            cache.clear();
+        }
+        self.execute(Command::Select(Select { index })).await
+    }
+
+    /// move command
+    pub async fn move_key(&mut self, key: String, db: i64) -> Result<Type> {
+        self.execute(Command::Move(Move { key, db })).await
+    }
+
+    /// swapdb command
+    pub async fn swapdb(&mut self, index1: i64, index2: i64) -> Result<Type> {
+        self.execute(Command::SwapDb(SwapDb { index1, index2 }))
+            .await
+    }
+
+    /// multi command, the commands that follow are queued (and replied with `QUEUED`)
+    pub async fn multi(&mut self) -> Result<Type> {
+        self.execute(Command::Multi(Multi)).await
+    }
+
+    /// exec command, replies with the replies of all the queued commands
+    pub async fn exec(&mut self) -> Result<Type> {
+        self.execute(Command::Exec(Exec)).await
+    }
+
+    /// discard command
+    pub async fn discard(&mut self) -> Result<Type> {
+        self.execute(Command::Discard(Discard)).await
+    }
+
+    /// version command
+    pub async fn version(&mut self, key: String) -> Result<Type> {
+        self.execute(Command::Version(Version { key })).await
+    }
+
+    /// cas command
+    pub async fn cas(&mut self, key: String, version: u64, value: Vec<u8>) -> Result<Type> {
+        self.execute(Command::Cas(Cas {
+            key,
+            version,
+            value,
+        }))
+        .await
+    }
+
+    /// owatch command
+    pub async fn owatch(&mut self, keys: LinkedList<String>) -> Result<Type> {
+        self.execute(Command::OWatch(OWatch { keys })).await
+    }
+
+    /// ounwatch command
+    pub async fn ounwatch(&mut self) -> Result<Type> {
+        self.execute(Command::OUnwatch(OUnwatch)).await
+    }
+
+    /// Runs an optimistic transaction, retrying until no other client got in the way.
+    /// The keys are watched (OWATCH) and read (GET), `commands` builds the commands to run
+    /// from their values, which are then run with MULTI/EXEC.
+    /// Returns the reply of the EXEC that succeeded.
+    pub async fn transaction<F>(
+        &mut self,
+        keys: LinkedList<String>,
+        mut commands: F,
+    ) -> Result<Type>
+    where
+        F: FnMut(Vec<Type>) -> Vec<Command>,
+    {
+        loop {
+            self.owatch(keys.clone()).await?;
+            let mut values = Vec::with_capacity(keys.len());
+            for key in &keys {
+                values.push(self.get(key).await?);
+            }
+            self.multi().await?;
+            for command in commands(values) {
+                self.execute(command).await?;
+            }
+            match self.exec().await? {
+                // A watched key changed, try again
+                Type::Null => debug!("Transaction on {:?} failed, retrying", keys),
+                reply => return Ok(reply),
+            }
+        }
+    }
+
+    /// Sends any [Command] and returns the reply
+    pub async fn execute(&mut self, command: Command) -> Result<Type> {
+        debug!("{:?}", command);
+        let multi = match command {
+            Command::Multi(_) => Some(true),
+            Command::Exec(_) | Command::Discard(_) => Some(false),
+            _ => None,
+        };
+        let reply = self.send(command.into()).await?;
+        match (multi, &reply) {
+            // A nested MULTI fails, the first one is still open
+            (Some(true), Type::Error(_)) => {}
+            (Some(multi), _) => self.multi = multi,
+            (None, _) => {}
+        }
+        Ok(reply)
+    }
+
+    /// watch command, the connection can still be used, and can have many watches
     pub async fn watch(
         &mut self,
         key: String,
-        operation: Operation,
-        watcher: Sender<WatchResult>,
-    ) -> Result<()> {
-        let watch = Command::Watch(Watch { key, operation });
+        operations: BTreeSet<Operation>,
+    ) -> Result<WatchStream> {
+        let watch = Watch {
+            key,
+            operations,
+            pattern: false,
+            from: None,
+            snapshot: false,
+        };
+        self.watch_with(watch).await
+    }
+
+    /// pwatch command, watches the keys matching a glob-style pattern
+    pub async fn pwatch(
+        &mut self,
+        pattern: String,
+        operations: BTreeSet<Operation>,
+    ) -> Result<WatchStream> {
+        let watch = Watch {
+            key: pattern,
+            operations,
+            pattern: true,
+            from: None,
+            snapshot: false,
+        };
+        self.watch_with(watch).await
+    }
+
+    /// Watches with all the options of [Watch]. With `from` set to the offset of the last
+    /// [WatchResult] received, a watcher that reconnects gets the changes it missed first.
+    /// Fails with a `RESYNC` error if they are not all kept by the server anymore.
+    pub async fn watch_with(&mut self, watch: Watch) -> Result<WatchStream> {
+        let watch = Command::Watch(watch);
         debug!("{:?}", watch);
-        self.write_half.send(watch.into()).await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        // Registered by the reader with the reply, before any notification is read
+        self.shared.lock().expect("Lock failed").pending = Some(Pending::Watch(sender));
+        match self.send(watch.into()).await? {
+            Type::Integer(id) => {
+                debug!("Watching: {}", id);
+                Ok(WatchStream {
+                    id: id as u64,
+                    receiver,
+                })
+            }
+            Type::Error(e) => Err(e.into()),
+            reply => Err(format!("Unexpected reply to WATCH: {:?}", reply).into()),
+        }
+    }
+
+    /// unwatch command, without a key every key watched by the connection is unwatched
+    pub async fn unwatch(&mut self, key: Option<String>) -> Result<Type> {
+        self.execute(Command::Unwatch(Unwatch {
+            key,
+            pattern: false,
+        }))
+        .await
+    }
+
+    /// punwatch command, without a pattern every pattern watched by the connection is unwatched
+    pub async fn punwatch(&mut self, pattern: Option<String>) -> Result<Type> {
+        self.execute(Command::Unwatch(Unwatch {
+            key: pattern,
+            pattern: true,
+        }))
+        .await
+    }
+
+    /// ping command
+    pub async fn ping(&mut self, message: Option<String>) -> Result<Type> {
+        self.execute(Command::Ping(Ping { message })).await
+    }
+
+    /// info command, the counters of the server (e.g. of the watchers) as `name:value` lines
+    pub async fn info(&mut self, section: Option<String>) -> Result<Type> {
+        self.execute(Command::Info(Info { section })).await
+    }
+
+    /// config get command, the parameters matching the pattern followed by their values
+    pub async fn config_get(&mut self, pattern: String) -> Result<Type> {
+        self.execute(Command::Config(Config::Get { pattern })).await
+    }
+
+    /// config set command, e.g. `notify-keyspace-events` to `KEA` for keyspace notifications
+    pub async fn config_set(&mut self, parameter: String, value: String) -> Result<Type> {
+        self.execute(Command::Config(Config::Set { parameter, value }))
+            .await
+    }
+
+    /// publish command, returns the number of subscribers that received the message
+    pub async fn publish(&mut self, channel: String, message: Vec<u8>) -> Result<Type> {
+        self.execute(Command::Publish(Publish {
+            channel,
+            message,
+            sharded: false,
+        }))
+        .await
+    }
+
+    /// spublish command, publishes to a shard channel
+    pub async fn spublish(&mut self, channel: String, message: Vec<u8>) -> Result<Type> {
+        self.execute(Command::Publish(Publish {
+            channel,
+            message,
+            sharded: true,
+        }))
+        .await
+    }
+
+    /// pubsub command
+    pub async fn pubsub(&mut self, introspection: Introspection) -> Result<Type> {
+        self.execute(Command::PubSub(introspection)).await
+    }
+
+    /// subscribe, psubscribe or ssubscribe command, depending on the kind
+    pub async fn subscribe(
+        &mut self,
+        channels: LinkedList<String>,
+        kind: SubscriptionKind,
+        subscriber: Sender<Message>,
+    ) -> Result<()> {
+        let subscribe = Command::Subscribe(Subscribe { channels, kind });
+        debug!("{:?}", subscribe);
+        self.write_half.send(subscribe.into()).await?;
         // Blocks from here
         loop {
-            let vv = self.read_half.recv().await?;
-            let watch_result =
-                std::result::Result::<WatchResult, CommandCreationError>::from(vv.unwrap())?;
-            debug!("Read: {:?}", watch_result);
-            watcher.send(watch_result).await?;
+            let t = self.reply().await?;
+            let message = Message::from(&mut TypeConsumer::new(t))?;
+            debug!("Read: {:?}", message);
+            // The confirmations of the subscriptions are skipped
+            if let Some(message) = message {
+                subscriber.send(message).await?;
+            }
         }
     }
 
     async fn send(&mut self, t: Type) -> Result<Type> {
         self.write_half.send(t).await?;
-        self.read_half
+        self.reply().await
+    }
+
+    async fn reply(&mut self) -> Result<Type> {
+        self.replies
             .recv()
-            .await?
-            .ok_or_else(|| "Connection closed".into())
+            .await
+            .unwrap_or_else(|| Err("Connection closed".into()))
+    }
+}
+
+/// Reads the connection until it is closed, or until the client is gone
+async fn read(
+    mut read_half: ReadHalf,
+    replies: UnboundedSender<Result<Type>>,
+    shared: Arc<Mutex<Shared>>,
+) {
+    loop {
+        let t = match read_half.recv().await {
+            Ok(Some(t)) => t,
+            Ok(None) => break,
+            Err(e) => {
+                let _ = replies.send(Err(e));
+                break;
+            }
+        };
+        let mut shared = shared.lock().expect("Lock failed");
+        match t {
+            Type::Push(values) => shared.push(values),
+            reply => {
+                // The stream of a WATCH is dropped on an error, only values of a GET are cached
+                match (shared.pending.take(), &reply) {
+                    (_, Type::Error(_)) => {}
+                    (Some(Pending::Watch(stream)), Type::Integer(id)) => {
+                        shared.streams.insert(*id as u64, stream);
+                    }
+                    (Some(Pending::Get(key)), value @ (Type::BulkString(_) | Type::Null)) => {
+                        if let Some(cache) = shared.cache.as_mut() {
+                            cache.insert(key, value.clone());
+                        }
+                    }
+                    _ => {}
+                }
+                if replies.send(Ok(reply)).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    // The streams end with the connection, nothing tells when the cached values change
+    let mut shared = shared.lock().expect("Lock failed");
+    shared.streams.clear();
+    shared.cache = None;
+}
+
+impl Shared {
+    /// Hands a push over to the stream of its watch, or to the cache for an invalidation
+    fn push(&mut self, values: LinkedList<Type>) {
+        let push = Type::Push(values.clone());
+        let mut type_consumer = TypeConsumer::new(Type::Array(values));
+        match type_consumer.next_string() {
+            Ok(Some(kind)) if kind == "watch" => {
+                match std::result::Result::<WatchResult, CommandCreationError>::from(push) {
+                    Ok(result) => {
+                        debug!("Read: {:?}", result);
+                        if let Some(stream) = self.streams.get(&result.watch) {
+                            // The stream may have been dropped, its notifications with it
+                            let _ = stream.send(result);
+                        }
+                    }
+                    Err(e) => warn!("Invalid watch notification: {:?}", e),
+                }
+            }
+            Ok(Some(kind)) if kind == "unwatch" => {
+                if let Ok(Some(id)) = type_consumer.next_integer() {
+                    self.streams.remove(&(id as u64));
+                }
+            }
+            Ok(Some(kind)) if kind == "invalidate" => {
+                if let (Some(cache), Ok(Some(Type::Array(keys)))) =
+                    (self.cache.as_mut(), type_consumer.next_type())
+                {
+                    for key in keys {
+                        if let Type::BulkString(key) = key {
+                            debug!("Invalidated: {}", String::from_utf8_lossy(&key));
+                            cache.remove(String::from_utf8_lossy(&key).as_ref());
+                        }
+                    }
+                }
+            }
+            _ => warn!("Unexpected push: {:?}", push),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mpsc, RedisClient, Shared};
+    use crate::{
+        commands::watch::WatchResult, database::Operation, resp::Type, server::RedisServer,
+    };
+
+    fn notification(watch: u64, key: &str) -> Type {
+        WatchResult {
+            watch,
+            key: key.into(),
+            operation: Operation::Addition,
+            before: None,
+            after: Type::BulkString("1".into()),
+            sequence: 1,
+            event: "set".into(),
+            offset: 1,
+        }
+        .into()
+    }
+
+    fn push(t: Type) -> std::collections::LinkedList<Type> {
+        match t {
+            Type::Push(values) => values,
+            t => panic!("{:?} is not a push", t),
+        }
+    }
+
+    #[tokio::test]
+    async fn pushes_go_to_the_stream_of_their_watch() {
+        let mut watches = Shared::default();
+        let (first, mut first_receiver) = mpsc::unbounded_channel();
+        let (second, mut second_receiver) = mpsc::unbounded_channel();
+        watches.streams.insert(1, first);
+        watches.streams.insert(2, second);
+        watches.push(push(notification(2, "b")));
+        watches.push(push(notification(1, "a")));
+        // Unknown watches are ignored
+        watches.push(push(notification(3, "c")));
+        let unwatch = vec![Type::SimpleString("unwatch".into()), Type::Integer(1)];
+        watches.push(unwatch.into_iter().collect());
+        assert_eq!(first_receiver.recv().await.unwrap().key, "a");
+        assert_eq!(second_receiver.recv().await.unwrap().key, "b");
+        // The stream of the removed watch is over, not the other one
+        assert!(first_receiver.recv().await.is_none());
+        assert_eq!(watches.streams.len(), 1);
+        assert!(watches.streams.contains_key(&2));
+    }
+
+    #[test]
+    fn invalidations_drop_the_cached_values() {
+        let mut shared = Shared::default();
+        let invalidate = |key: &str| {
+            let keys = Type::Array(vec![Type::BulkString(key.into())].into_iter().collect());
+            vec![Type::SimpleString("invalidate".into()), keys]
+                .into_iter()
+                .collect()
+        };
+        // Ignored without a cache
+        shared.push(invalidate("a"));
+        let value = Type::BulkString("1".into());
+        let cache = vec![("a".to_string(), value.clone()), ("b".into(), value)];
+        shared.cache = Some(cache.into_iter().collect());
+        shared.push(invalidate("a"));
+        let cache = shared.cache.unwrap();
+        assert!(!cache.contains_key("a"));
+        assert!(cache.contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn gets_inside_a_multi_are_queued_and_not_cached() {
+        let addr = "127.0.0.1:6390";
+        tokio::spawn(async move { RedisServer::default().listen(addr).await });
+        let mut client = loop {
+            match RedisClient::connect(addr).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        client.enable_cache().await.unwrap();
+        client.set("key".into(), "1").await.unwrap();
+        client.multi().await.unwrap();
+        let queued = Type::SimpleString("QUEUED".into());
+        assert_eq!(client.get("key").await.unwrap(), queued);
+        assert_eq!(client.get("key").await.unwrap(), queued);
+        let value = Type::BulkString("1".into());
+        let replies = vec![value.clone(), value.clone()].into_iter().collect();
+        assert_eq!(client.exec().await.unwrap(), Type::Array(replies));
+        assert!(client
+            .shared
+            .lock()
+            .unwrap()
+            .cache
+            .as_ref()
+            .unwrap()
+            .is_empty());
+        // Read from the server, then from the cache
+        assert_eq!(client.get("key").await.unwrap(), value);
+        assert_eq!(client.get("key").await.unwrap(), value);
+        let shared = client.shared.lock().unwrap();
+        assert_eq!(shared.cache.as_ref().unwrap().get("key"), Some(&value));
     }
 }
@@ -0,0 +1,634 @@
+//! Bitmap commands, operating on string values. See [SETBIT](https://redis.io/commands/setbit),
+//! [GETBIT](https://redis.io/commands/getbit), [BITCOUNT](https://redis.io/commands/bitcount),
+//! [BITPOS](https://redis.io/commands/bitpos), [BITOP](https://redis.io/commands/bitop) and
+//! [BITFIELD](https://redis.io/commands/bitfield) for official documentation
+
+use std::{collections::LinkedList, fmt::Display, str::FromStr};
+
+use crate::resp::{Type, TypeConsumer};
+
+use super::{as_command, extract_or_err, parse_or_err, parse_value, CommandCreationError};
+
+/// The largest bit offset, strings are limited to 512MB
+const MAX_BIT_OFFSET: u64 = (512 * 1024 * 1024 * 8) - 1;
+
+/// Sets or clears the bit at offset
+#[derive(Debug, PartialEq)]
+pub struct SetBit {
+    /// The key
+    pub key: String,
+    /// The offset of the bit
+    pub offset: u64,
+    /// The value of the bit (0 or 1)
+    pub value: u8,
+}
+
+impl SetBit {
+    /// Creates a SetBit type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let offset = bit_offset(type_consumer)?;
+        let value = bit(type_consumer)?;
+        Ok(SetBit { key, offset, value })
+    }
+}
+
+impl From<SetBit> for Type {
+    fn from(s: SetBit) -> Self {
+        as_command(
+            "SETBIT",
+            vec![s.key, s.offset.to_string(), s.value.to_string()],
+        )
+    }
+}
+
+/// Returns the bit at offset
+#[derive(Debug, PartialEq)]
+pub struct GetBit {
+    /// The key
+    pub key: String,
+    /// The offset of the bit
+    pub offset: u64,
+}
+
+impl GetBit {
+    /// Creates a GetBit type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let offset = bit_offset(type_consumer)?;
+        Ok(GetBit { key, offset })
+    }
+}
+
+impl From<GetBit> for Type {
+    fn from(g: GetBit) -> Self {
+        as_command("GETBIT", vec![g.key, g.offset.to_string()])
+    }
+}
+
+/// The unit of the indexes of a range
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RangeUnit {
+    /// The indexes are byte indexes (default)
+    Byte,
+    /// The indexes are bit indexes
+    Bit,
+}
+
+impl FromStr for RangeUnit {
+    type Err = CommandCreationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_ref() {
+            "BYTE" => Ok(RangeUnit::Byte),
+            "BIT" => Ok(RangeUnit::Bit),
+            _ => Err(syntax_error(s)),
+        }
+    }
+}
+
+impl Display for RangeUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RangeUnit::Byte => f.write_str("BYTE"),
+            RangeUnit::Bit => f.write_str("BIT"),
+        }
+    }
+}
+
+/// An inclusive range, negative indexes count from the end
+#[derive(Debug, PartialEq)]
+pub struct BitRange {
+    /// The start index
+    pub start: i64,
+    /// The end index (inclusive)
+    pub end: i64,
+    /// The unit of the indexes
+    pub unit: RangeUnit,
+}
+
+/// Counts the number of set bits
+#[derive(Debug, PartialEq)]
+pub struct BitCount {
+    /// The key
+    pub key: String,
+    /// The optional range
+    pub range: Option<BitRange>,
+}
+
+impl BitCount {
+    /// Creates a BitCount type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let range = match type_consumer.next_string()? {
+            Some(start) => {
+                let start = parse_value(&start, "start")?;
+                let end = parse_or_err(type_consumer.next_string(), "end")?;
+                let unit = optional_unit(type_consumer)?.unwrap_or(RangeUnit::Byte);
+                Some(BitRange { start, end, unit })
+            }
+            None => None,
+        };
+        Ok(BitCount { key, range })
+    }
+}
+
+impl From<BitCount> for Type {
+    fn from(b: BitCount) -> Self {
+        let mut args = vec![b.key];
+        if let Some(r) = b.range {
+            args.extend(vec![
+                r.start.to_string(),
+                r.end.to_string(),
+                r.unit.to_string(),
+            ]);
+        }
+        as_command("BITCOUNT", args)
+    }
+}
+
+/// Returns the position of the first bit set to 1 or 0
+#[derive(Debug, PartialEq)]
+pub struct BitPos {
+    /// The key
+    pub key: String,
+    /// The bit to look for
+    pub bit: u8,
+    /// The start index
+    pub start: Option<i64>,
+    /// The end index (inclusive)
+    pub end: Option<i64>,
+    /// The unit of the indexes
+    pub unit: RangeUnit,
+}
+
+impl BitPos {
+    /// Creates a BitPos type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let bit = bit(type_consumer)?;
+        let start = match type_consumer.next_string()? {
+            Some(start) => Some(parse_value(&start, "start")?),
+            None => None,
+        };
+        let end = match type_consumer.next_string()? {
+            Some(end) => Some(parse_value(&end, "end")?),
+            None => None,
+        };
+        let unit = optional_unit(type_consumer)?.unwrap_or(RangeUnit::Byte);
+        Ok(BitPos {
+            key,
+            bit,
+            start,
+            end,
+            unit,
+        })
+    }
+}
+
+impl From<BitPos> for Type {
+    fn from(b: BitPos) -> Self {
+        let mut args = vec![b.key, b.bit.to_string()];
+        args.extend(b.start.map(|s| s.to_string()));
+        if let Some(end) = b.end {
+            args.push(end.to_string());
+            args.push(b.unit.to_string());
+        }
+        as_command("BITPOS", args)
+    }
+}
+
+/// The operations supported by [BitOp]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitOperation {
+    /// Bitwise AND
+    And,
+    /// Bitwise OR
+    Or,
+    /// Bitwise XOR
+    Xor,
+    /// Bitwise NOT, takes a single source
+    Not,
+}
+
+impl FromStr for BitOperation {
+    type Err = CommandCreationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_ref() {
+            "AND" => Ok(BitOperation::And),
+            "OR" => Ok(BitOperation::Or),
+            "XOR" => Ok(BitOperation::Xor),
+            "NOT" => Ok(BitOperation::Not),
+            _ => Err(syntax_error(s)),
+        }
+    }
+}
+
+impl Display for BitOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+/// Performs a bitwise operation between the source keys and stores the result in destination
+#[derive(Debug, PartialEq)]
+pub struct BitOp {
+    /// The operation
+    pub operation: BitOperation,
+    /// The destination key
+    pub destination: String,
+    /// The source keys
+    pub keys: LinkedList<String>,
+}
+
+impl BitOp {
+    /// Creates a BitOp type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let operation: BitOperation =
+            extract_or_err(type_consumer.next_string(), "operation")?.parse()?;
+        let destination = extract_or_err(type_consumer.next_string(), "destination")?;
+        let mut keys = LinkedList::new();
+        keys.push_back(extract_or_err(type_consumer.next_string(), "key")?);
+        while let Some(key) = type_consumer.next_string()? {
+            keys.push_back(key);
+        }
+        if operation == BitOperation::Not && keys.len() != 1 {
+            return Err(CommandCreationError::InvalidArgument(
+                "BITOP NOT must be called with a single source key".into(),
+            ));
+        }
+        Ok(BitOp {
+            operation,
+            destination,
+            keys,
+        })
+    }
+}
+
+impl From<BitOp> for Type {
+    fn from(b: BitOp) -> Self {
+        let mut args = vec![b.operation.to_string(), b.destination];
+        args.extend(b.keys);
+        as_command("BITOP", args)
+    }
+}
+
+/// An integer type used by [BitField], e.g. `i8` or `u16`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IntegerType {
+    /// Whether the integer is signed
+    pub signed: bool,
+    /// The number of bits, up to 64 for signed and 63 for unsigned integers
+    pub bits: u8,
+}
+
+impl FromStr for IntegerType {
+    type Err = CommandCreationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            CommandCreationError::InvalidArgument(
+                "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".into(),
+            )
+        };
+        let signed = match s.chars().next() {
+            Some('i') | Some('I') => true,
+            Some('u') | Some('U') => false,
+            _ => return Err(invalid()),
+        };
+        let bits: u8 = s[1..].parse().map_err(|_| invalid())?;
+        match (signed, bits) {
+            (true, 1..=64) | (false, 1..=63) => Ok(IntegerType { signed, bits }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for IntegerType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", if self.signed { "i" } else { "u" }, self.bits)
+    }
+}
+
+/// The overflow policy of [BitField] `SET` and `INCRBY`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Overflow {
+    /// Wrap around (default)
+    Wrap,
+    /// Saturate to the minimum or maximum value
+    Sat,
+    /// Do not perform the operation and reply with Null
+    Fail,
+}
+
+impl FromStr for Overflow {
+    type Err = CommandCreationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_ref() {
+            "WRAP" => Ok(Overflow::Wrap),
+            "SAT" => Ok(Overflow::Sat),
+            "FAIL" => Ok(Overflow::Fail),
+            _ => Err(CommandCreationError::InvalidArgument(format!(
+                "Invalid OVERFLOW type specified: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+/// The sub commands of [BitField]. Offsets are bit offsets (`#N` offsets are already multiplied by the width)
+#[derive(Debug, PartialEq)]
+pub enum BitFieldOperation {
+    /// Returns the integer at offset
+    Get(IntegerType, u64),
+    /// Sets the integer at offset and returns the previous value
+    Set(IntegerType, u64, i64),
+    /// Increments the integer at offset and returns the new value
+    IncrBy(IntegerType, u64, i64),
+    /// Changes the overflow policy of the following operations
+    Overflow(Overflow),
+}
+
+/// Treats a string as an array of integers of arbitrary width
+#[derive(Debug, PartialEq)]
+pub struct BitField {
+    /// The key
+    pub key: String,
+    /// The operations, applied in order
+    pub operations: LinkedList<BitFieldOperation>,
+}
+
+impl BitField {
+    /// Creates a BitField type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let mut operations = LinkedList::new();
+        while let Some(operation) = type_consumer.next_string()? {
+            let operation = match operation.to_uppercase().as_ref() {
+                "GET" => {
+                    let (integer_type, offset) = type_and_offset(type_consumer)?;
+                    BitFieldOperation::Get(integer_type, offset)
+                }
+                "SET" => {
+                    let (integer_type, offset) = type_and_offset(type_consumer)?;
+                    let value = parse_or_err(type_consumer.next_string(), "value")?;
+                    BitFieldOperation::Set(integer_type, offset, value)
+                }
+                "INCRBY" => {
+                    let (integer_type, offset) = type_and_offset(type_consumer)?;
+                    let increment = parse_or_err(type_consumer.next_string(), "increment")?;
+                    BitFieldOperation::IncrBy(integer_type, offset, increment)
+                }
+                "OVERFLOW" => BitFieldOperation::Overflow(
+                    extract_or_err(type_consumer.next_string(), "overflow")?.parse()?,
+                ),
+                _ => return Err(syntax_error(&operation)),
+            };
+            operations.push_back(operation);
+        }
+        Ok(BitField { key, operations })
+    }
+}
+
+impl From<BitField> for Type {
+    fn from(b: BitField) -> Self {
+        let mut args = vec![b.key];
+        b.operations.into_iter().for_each(|o| match o {
+            BitFieldOperation::Get(t, offset) => {
+                args.extend(vec!["GET".into(), t.to_string(), offset.to_string()])
+            }
+            BitFieldOperation::Set(t, offset, value) => args.extend(vec![
+                "SET".into(),
+                t.to_string(),
+                offset.to_string(),
+                value.to_string(),
+            ]),
+            BitFieldOperation::IncrBy(t, offset, increment) => args.extend(vec![
+                "INCRBY".into(),
+                t.to_string(),
+                offset.to_string(),
+                increment.to_string(),
+            ]),
+            BitFieldOperation::Overflow(o) => args.extend(vec!["OVERFLOW".into(), o.to_string()]),
+        });
+        as_command("BITFIELD", args)
+    }
+}
+
+/* Utility methods */
+
+fn syntax_error(s: &str) -> CommandCreationError {
+    CommandCreationError::InvalidArgument(format!("syntax error near `{}`", s))
+}
+
+fn bit_offset(type_consumer: &mut TypeConsumer) -> Result<u64, CommandCreationError> {
+    let offset: u64 = parse_or_err(type_consumer.next_string(), "offset")?;
+    if offset > MAX_BIT_OFFSET {
+        return Err(CommandCreationError::InvalidArgument(
+            "bit offset is not an integer or out of range".into(),
+        ));
+    }
+    Ok(offset)
+}
+
+fn bit(type_consumer: &mut TypeConsumer) -> Result<u8, CommandCreationError> {
+    match parse_or_err(type_consumer.next_string(), "bit")? {
+        b @ 0..=1 => Ok(b),
+        _ => Err(CommandCreationError::InvalidArgument(
+            "The bit argument must be 1 or 0".into(),
+        )),
+    }
+}
+
+fn optional_unit(
+    type_consumer: &mut TypeConsumer,
+) -> Result<Option<RangeUnit>, CommandCreationError> {
+    match type_consumer.next_string()? {
+        Some(unit) => Ok(Some(unit.parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Parses the type and the offset, `#N` offsets are multiplied by the width of the type
+fn type_and_offset(
+    type_consumer: &mut TypeConsumer,
+) -> Result<(IntegerType, u64), CommandCreationError> {
+    let integer_type: IntegerType = extract_or_err(type_consumer.next_string(), "type")?.parse()?;
+    let offset = extract_or_err(type_consumer.next_string(), "offset")?;
+    let offset = match offset.strip_prefix('#') {
+        Some(index) => parse_value::<u64>(index, "offset")?.checked_mul(integer_type.bits as u64),
+        None => Some(parse_value::<u64>(&offset, "offset")?),
+    };
+    // The last bit of the integer must be in range too
+    offset
+        .filter(|offset| {
+            offset
+                .checked_add(integer_type.bits as u64 - 1)
+                .is_some_and(|last| last <= MAX_BIT_OFFSET)
+        })
+        .map(|offset| (integer_type, offset))
+        .ok_or_else(|| {
+            CommandCreationError::InvalidArgument(
+                "bit offset is not an integer or out of range".into(),
+            )
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn array(values: &[&str]) -> TypeConsumer {
+        TypeConsumer::new(Type::Array(
+            values
+                .iter()
+                .map(|v| Type::BulkString(v.as_bytes().to_vec()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn from_works() {
+        assert_eq!(
+            SetBit::from(&mut array(&["bits", "7", "1"])),
+            Ok(SetBit {
+                key: "bits".into(),
+                offset: 7,
+                value: 1
+            })
+        );
+        assert!(SetBit::from(&mut array(&["bits", "7", "2"])).is_err());
+        assert!(SetBit::from(&mut array(&["bits", "-1", "1"])).is_err());
+        assert!(SetBit::from(&mut array(&["bits", "4294967296", "1"])).is_err());
+        assert_eq!(
+            BitCount::from(&mut array(&["bits", "1", "-1", "bit"])),
+            Ok(BitCount {
+                key: "bits".into(),
+                range: Some(BitRange {
+                    start: 1,
+                    end: -1,
+                    unit: RangeUnit::Bit
+                })
+            })
+        );
+        assert_eq!(
+            BitCount::from(&mut array(&["bits", "1"])),
+            Err(CommandCreationError::MissingField("end".into()))
+        );
+        assert_eq!(
+            BitPos::from(&mut array(&["bits", "0", "2"])),
+            Ok(BitPos {
+                key: "bits".into(),
+                bit: 0,
+                start: Some(2),
+                end: None,
+                unit: RangeUnit::Byte
+            })
+        );
+        assert!(BitOp::from(&mut array(&["NOT", "dest", "a", "b"])).is_err());
+        assert_eq!(
+            BitOp::from(&mut array(&["xor", "dest", "a", "b"])),
+            Ok(BitOp {
+                operation: BitOperation::Xor,
+                destination: "dest".into(),
+                keys: vec!["a".into(), "b".into()].into_iter().collect()
+            })
+        );
+    }
+
+    #[test]
+    fn bitfield_from_works() {
+        let bitfield = BitField::from(&mut array(&[
+            "bits", "GET", "u4", "0", "OVERFLOW", "SAT", "SET", "i8", "#2", "-3", "INCRBY", "i64",
+            "3", "100",
+        ]));
+        assert_eq!(
+            bitfield,
+            Ok(BitField {
+                key: "bits".into(),
+                operations: vec![
+                    BitFieldOperation::Get(
+                        IntegerType {
+                            signed: false,
+                            bits: 4
+                        },
+                        0
+                    ),
+                    BitFieldOperation::Overflow(Overflow::Sat),
+                    BitFieldOperation::Set(
+                        IntegerType {
+                            signed: true,
+                            bits: 8
+                        },
+                        16,
+                        -3
+                    ),
+                    BitFieldOperation::IncrBy(
+                        IntegerType {
+                            signed: true,
+                            bits: 64
+                        },
+                        3,
+                        100
+                    ),
+                ]
+                .into_iter()
+                .collect()
+            })
+        );
+        assert!(BitField::from(&mut array(&["bits", "GET", "u64", "0"])).is_err());
+        assert!(BitField::from(&mut array(&["bits", "GET", "i65", "0"])).is_err());
+        assert!(BitField::from(&mut array(&["bits", "OVERFLOW", "MAYBE"])).is_err());
+        assert!(BitField::from(&mut array(&["bits", "DEL", "i8", "0"])).is_err());
+        // Offsets that overflow are out of range
+        for offset in ["#18446744073709551615", "18446744073709551615"] {
+            assert_eq!(
+                BitField::from(&mut array(&["bits", "GET", "i8", offset])),
+                Err(CommandCreationError::InvalidArgument(
+                    "bit offset is not an integer or out of range".into()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn into_works() {
+        let t: Type = BitField {
+            key: "bits".into(),
+            operations: vec![
+                BitFieldOperation::Overflow(Overflow::Fail),
+                BitFieldOperation::IncrBy(
+                    IntegerType {
+                        signed: false,
+                        bits: 2,
+                    },
+                    102,
+                    1,
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        }
+        .into();
+        let mut tc = TypeConsumer::new(t);
+        assert_eq!(tc.next_string(), Ok(Some("BITFIELD".into())));
+        assert_eq!(BitField::from(&mut tc).unwrap().operations.len(), 2);
+        let t: Type = BitOp {
+            operation: BitOperation::And,
+            destination: "dest".into(),
+            keys: vec!["a".into()].into_iter().collect(),
+        }
+        .into();
+        let mut tc = TypeConsumer::new(t);
+        assert_eq!(tc.next_string(), Ok(Some("BITOP".into())));
+        assert_eq!(tc.next_string(), Ok(Some("AND".into())));
+    }
+}
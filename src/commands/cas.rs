@@ -0,0 +1,161 @@
+//! Optimistic locking commands, every change to a key bumps its version.
+//!
+//! * `VERSION key` returns the version of a key
+//! * `CAS key version value` sets the value only if the key is still at that version
+//! * `OWATCH key [key ...]` makes the next EXEC fail (with a Null reply) if any of the keys changed,
+//!   like the WATCH command of Redis (WATCH is already used for the change notifications here)
+//! * `OUNWATCH` forgets the keys watched with OWATCH
+
+use std::collections::LinkedList;
+
+use crate::resp::{Type, TypeConsumer};
+
+use super::{as_command, extract_or_err, parse_or_err, CommandCreationError};
+
+/// Holds the key for the [Version command](super::Command::Version)
+#[derive(Debug, PartialEq)]
+pub struct Version {
+    /// The key to get the version of
+    pub key: String,
+}
+
+impl Version {
+    /// Creates a Version type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        Ok(Version { key })
+    }
+}
+
+impl From<Version> for Type {
+    fn from(v: Version) -> Self {
+        as_command("VERSION", vec![v.key])
+    }
+}
+
+/// Holds the arguments for the [Cas command](super::Command::Cas)
+#[derive(Debug, PartialEq)]
+pub struct Cas {
+    /// The key to set
+    pub key: String,
+    /// The version the key is expected to be at
+    pub version: u64,
+    /// The value to set, binary safe
+    pub value: Vec<u8>,
+}
+
+impl Cas {
+    /// Creates a Cas type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let version = parse_or_err(type_consumer.next_string(), "version")?;
+        let value = extract_or_err(type_consumer.next_bytes(), "value")?;
+        Ok(Cas {
+            key,
+            version,
+            value,
+        })
+    }
+}
+
+impl From<Cas> for Type {
+    fn from(c: Cas) -> Self {
+        let mut ll = LinkedList::new();
+        ll.push_back(Type::BulkString(b"CAS".to_vec()));
+        ll.push_back(Type::BulkString(c.key.into_bytes()));
+        ll.push_back(Type::BulkString(c.version.to_string().into_bytes()));
+        ll.push_back(Type::BulkString(c.value));
+        Type::Array(ll)
+    }
+}
+
+/// Holds the keys for the [OWatch command](super::Command::OWatch)
+#[derive(Debug, PartialEq)]
+pub struct OWatch {
+    /// The keys that must not change until EXEC
+    pub keys: LinkedList<String>,
+}
+
+impl OWatch {
+    /// Creates an OWatch type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let mut keys = LinkedList::new();
+        keys.push_back(extract_or_err(type_consumer.next_string(), "key")?);
+        while let Some(key) = type_consumer.next_string()? {
+            keys.push_back(key)
+        }
+        Ok(OWatch { keys })
+    }
+}
+
+impl From<OWatch> for Type {
+    fn from(o: OWatch) -> Self {
+        as_command("OWATCH", o.keys.into_iter().collect())
+    }
+}
+
+/// The [OUnwatch command](super::Command::OUnwatch), has no arguments
+#[derive(Debug, PartialEq)]
+pub struct OUnwatch;
+
+impl From<OUnwatch> for Type {
+    fn from(_: OUnwatch) -> Self {
+        as_command("OUNWATCH", vec![])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cas, OWatch};
+    use crate::commands::{as_command, CommandCreationError};
+    use crate::resp::{Type, TypeConsumer};
+
+    fn consumer(args: Vec<&str>) -> TypeConsumer {
+        TypeConsumer::new(Type::Array(
+            args.into_iter()
+                .map(|a| Type::BulkString(a.into()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn from_works() {
+        assert_eq!(
+            Cas::from(&mut consumer(vec!["key", "42", "value"])),
+            Ok(Cas {
+                key: "key".into(),
+                version: 42,
+                value: b"value".to_vec()
+            })
+        );
+        assert_eq!(
+            Cas::from(&mut consumer(vec!["key", "-1", "value"])),
+            Err(CommandCreationError::InvalidArgument(
+                "version is not valid: -1".into()
+            ))
+        );
+        assert_eq!(
+            OWatch::from(&mut consumer(vec![])),
+            Err(CommandCreationError::MissingField("key".into()))
+        );
+    }
+
+    #[test]
+    fn into_works() {
+        let t: Type = OWatch {
+            keys: vec!["a".to_string(), "b".to_string()].into_iter().collect(),
+        }
+        .into();
+        assert_eq!(t, as_command("OWATCH", vec!["a".into(), "b".into()]));
+        let t: Type = Cas {
+            key: "a".into(),
+            version: 7,
+            value: b"v".to_vec(),
+        }
+        .into();
+        assert_eq!(
+            t,
+            as_command("CAS", vec!["a".into(), "7".into(), "v".into()])
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Client command. See [CLIENT TRACKING](https://redis.io/commands/client-tracking) and
+//! [CLIENT CACHING](https://redis.io/commands/client-caching) for official documentation
+
+use super::{as_command, extract_or_err, CommandCreationError};
+use crate::resp::{Type, TypeConsumer};
+
+/// The [Client command](super::Command::Client), the subcommands for client side caching
+#[derive(Debug, PartialEq)]
+pub enum Client {
+    /// CLIENT TRACKING, the connection is told when the keys it read change
+    Tracking(Tracking),
+    /// CLIENT CACHING YES|NO, whether the keys read by the next command are tracked
+    /// (see [TrackingMode])
+    Caching(bool),
+}
+
+/// Which of the keys read by a connection are tracked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackingMode {
+    /// All of them
+    Default,
+    /// Only those of the commands right after a CLIENT CACHING YES
+    OptIn,
+    /// All of them but those of the commands right after a CLIENT CACHING NO
+    OptOut,
+}
+
+/// Holds the options of CLIENT TRACKING
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tracking {
+    /// ON or OFF
+    pub on: bool,
+    /// The connection is told about the changes of all the keys with the prefixes (or of
+    /// all the keys without a prefix), whether it read them or not
+    pub bcast: bool,
+    /// The prefixes of BCAST
+    pub prefixes: Vec<String>,
+    /// Which keys read are tracked, when not in BCAST
+    pub mode: TrackingMode,
+    /// The connection is not told about the keys it changed itself
+    pub noloop: bool,
+}
+
+impl Client {
+    /// Returns an instance of [super::client::Client]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let subcommand = extract_or_err(type_consumer.next_string(), "subcommand")?;
+        match subcommand.to_uppercase().as_ref() {
+            "TRACKING" => Ok(Client::Tracking(Tracking::from(type_consumer)?)),
+            "CACHING" => {
+                let yes = extract_or_err(type_consumer.next_string(), "YES or NO")?;
+                match yes.to_uppercase().as_ref() {
+                    "YES" => Ok(Client::Caching(true)),
+                    "NO" => Ok(Client::Caching(false)),
+                    _ => Err(syntax_error(&yes)),
+                }
+            }
+            _ => Err(syntax_error(&subcommand)),
+        }
+    }
+}
+
+impl Tracking {
+    fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let on = extract_or_err(type_consumer.next_string(), "ON or OFF")?;
+        let on = match on.to_uppercase().as_ref() {
+            "ON" => true,
+            "OFF" => false,
+            _ => return Err(syntax_error(&on)),
+        };
+        let mut tracking = Tracking {
+            on,
+            bcast: false,
+            prefixes: Vec::new(),
+            mode: TrackingMode::Default,
+            noloop: false,
+        };
+        while let Some(token) = type_consumer.next_string()? {
+            let mode = match token.to_uppercase().as_ref() {
+                "BCAST" => {
+                    tracking.bcast = true;
+                    continue;
+                }
+                "PREFIX" => {
+                    let prefix = extract_or_err(type_consumer.next_string(), "prefix")?;
+                    tracking.prefixes.push(prefix);
+                    continue;
+                }
+                "NOLOOP" => {
+                    tracking.noloop = true;
+                    continue;
+                }
+                "OPTIN" => TrackingMode::OptIn,
+                "OPTOUT" => TrackingMode::OptOut,
+                _ => return Err(syntax_error(&token)),
+            };
+            if tracking.mode != TrackingMode::Default && tracking.mode != mode {
+                return Err(CommandCreationError::InvalidArgument(
+                    "You can't use both OPTIN and OPTOUT".into(),
+                ));
+            }
+            tracking.mode = mode;
+        }
+        if !tracking.prefixes.is_empty() && !tracking.bcast {
+            return Err(CommandCreationError::InvalidArgument(
+                "PREFIX option requires BCAST mode to be enabled".into(),
+            ));
+        }
+        if tracking.bcast && tracking.mode != TrackingMode::Default {
+            return Err(CommandCreationError::InvalidArgument(
+                "OPTIN and OPTOUT are not compatible with BCAST".into(),
+            ));
+        }
+        Ok(tracking)
+    }
+}
+
+fn syntax_error(s: &str) -> CommandCreationError {
+    CommandCreationError::InvalidArgument(format!("syntax error near `{}`", s))
+}
+
+impl From<Client> for Type {
+    fn from(c: Client) -> Self {
+        let args = match c {
+            Client::Tracking(t) => {
+                let mut args = vec!["TRACKING".to_string()];
+                args.push(if t.on { "ON" } else { "OFF" }.into());
+                if t.bcast {
+                    args.push("BCAST".into());
+                }
+                for prefix in t.prefixes {
+                    args.push("PREFIX".into());
+                    args.push(prefix);
+                }
+                match t.mode {
+                    TrackingMode::Default => {}
+                    TrackingMode::OptIn => args.push("OPTIN".into()),
+                    TrackingMode::OptOut => args.push("OPTOUT".into()),
+                }
+                if t.noloop {
+                    args.push("NOLOOP".into());
+                }
+                args
+            }
+            Client::Caching(yes) => vec!["CACHING".into(), if yes { "YES" } else { "NO" }.into()],
+        };
+        as_command("CLIENT", args)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Client, Tracking, TrackingMode};
+    use crate::commands::{as_command, CommandCreationError};
+    use crate::resp::{Type, TypeConsumer};
+
+    fn client(args: &[&str]) -> Result<Client, CommandCreationError> {
+        let t = as_command("CLIENT", args.iter().map(|a| a.to_string()).collect());
+        let mut tc = TypeConsumer::new(t);
+        tc.next_string().unwrap();
+        Client::from(&mut tc)
+    }
+
+    #[test]
+    fn from_and_into_work() {
+        let tracking = Tracking {
+            on: true,
+            bcast: true,
+            prefixes: vec!["user:".into(), "session:".into()],
+            mode: TrackingMode::Default,
+            noloop: true,
+        };
+        for c in [
+            Client::Tracking(tracking),
+            Client::Tracking(Tracking {
+                on: false,
+                bcast: false,
+                prefixes: Vec::new(),
+                mode: TrackingMode::OptIn,
+                noloop: false,
+            }),
+            Client::Caching(false),
+        ] {
+            let t: Type = c.into();
+            let mut tc = TypeConsumer::new(t.clone());
+            tc.next_string().unwrap();
+            let back: Type = Client::from(&mut tc).unwrap().into();
+            assert_eq!(back, t);
+        }
+    }
+
+    #[test]
+    fn invalid_options_are_refused() {
+        assert!(client(&["TRACKING", "ON", "PREFIX", "a"]).is_err());
+        assert!(client(&["TRACKING", "ON", "OPTIN", "OPTOUT"]).is_err());
+        assert!(client(&["TRACKING", "ON", "BCAST", "OPTOUT"]).is_err());
+        assert!(client(&["TRACKING", "MAYBE"]).is_err());
+        assert!(client(&["CACHING", "MAYBE"]).is_err());
+        assert!(client(&["TRACKING", "on", "optout", "noloop"]).is_ok());
+    }
+}
@@ -0,0 +1,93 @@
+//! Config command. See [CONFIG GET](https://redis.io/commands/config-get) and
+//! [CONFIG SET](https://redis.io/commands/config-set) for official documentation
+
+use super::{as_command, extract_or_err, CommandCreationError};
+use crate::resp::{Type, TypeConsumer};
+
+/// The parameters of the server that can be read and changed while it runs
+pub const PARAMETERS: [&str; 4] = [
+    "notify-keyspace-events",
+    "maxmemory",
+    "maxmemory-policy",
+    "save",
+];
+
+/// The [Config command](super::Command::Config)
+#[derive(Debug, PartialEq)]
+pub enum Config {
+    /// The parameters matching a glob-style pattern, with their values
+    Get {
+        /// The pattern of the parameters
+        pattern: String,
+    },
+    /// Changes the value of a parameter
+    Set {
+        /// The name of the parameter, e.g. `notify-keyspace-events`
+        parameter: String,
+        /// The new value
+        value: String,
+    },
+}
+
+impl Config {
+    /// Returns an instance of [super::config::Config]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let subcommand = extract_or_err(type_consumer.next_string(), "subcommand")?;
+        let config = match subcommand.to_uppercase().as_ref() {
+            "GET" => Config::Get {
+                pattern: extract_or_err(type_consumer.next_string(), "parameter")?,
+            },
+            "SET" => Config::Set {
+                parameter: extract_or_err(type_consumer.next_string(), "parameter")?,
+                value: extract_or_err(type_consumer.next_string(), "value")?,
+            },
+            _ => {
+                return Err(CommandCreationError::InvalidArgument(format!(
+                    "syntax error near `{}`",
+                    subcommand
+                )))
+            }
+        };
+        Ok(config)
+    }
+}
+
+impl From<Config> for Type {
+    fn from(c: Config) -> Self {
+        let args = match c {
+            Config::Get { pattern } => vec!["GET".into(), pattern],
+            Config::Set { parameter, value } => vec!["SET".into(), parameter, value],
+        };
+        as_command("CONFIG", args)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+    use crate::commands::as_command;
+    use crate::resp::{Type, TypeConsumer};
+
+    #[test]
+    fn from_and_into_work() {
+        let configs = vec![
+            Config::Get {
+                pattern: "notify-*".into(),
+            },
+            Config::Set {
+                parameter: "notify-keyspace-events".into(),
+                value: "KEA".into(),
+            },
+        ];
+        for config in configs {
+            let t: Type = config.into();
+            let mut tc = TypeConsumer::new(t.clone());
+            tc.next_string().unwrap();
+            let back: Type = Config::from(&mut tc).unwrap().into();
+            assert_eq!(back, t);
+        }
+        let mut tc = TypeConsumer::new(as_command("CONFIG", vec!["RESETSTAT".into()]));
+        tc.next_string().unwrap();
+        assert!(Config::from(&mut tc).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Commands to work with the numbered databases, see [SELECT](https://redis.io/commands/select),
+//! [MOVE](https://redis.io/commands/move) and [SWAPDB](https://redis.io/commands/swapdb)
+
+use crate::resp::{Type, TypeConsumer};
+
+use super::{as_command, extract_or_err, parse_or_err, CommandCreationError};
+
+/// Holds the index for the [Select command](super::Command::Select)
+#[derive(Debug, PartialEq)]
+pub struct Select {
+    /// The database to use for the rest of the connection
+    pub index: i64,
+}
+
+impl Select {
+    /// Creates a Select type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let index = parse_or_err(type_consumer.next_string(), "index")?;
+        Ok(Select { index })
+    }
+}
+
+impl From<Select> for Type {
+    fn from(s: Select) -> Self {
+        as_command("SELECT", vec![s.index.to_string()])
+    }
+}
+
+/// Holds the key and the target database for the [Move command](super::Command::Move)
+#[derive(Debug, PartialEq)]
+pub struct Move {
+    /// The key to move (from the selected database)
+    pub key: String,
+    /// The database to move the key to
+    pub db: i64,
+}
+
+impl Move {
+    /// Creates a Move type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let db = parse_or_err(type_consumer.next_string(), "db")?;
+        Ok(Move { key, db })
+    }
+}
+
+impl From<Move> for Type {
+    fn from(m: Move) -> Self {
+        as_command("MOVE", vec![m.key, m.db.to_string()])
+    }
+}
+
+/// Holds the two databases for the [SwapDb command](super::Command::SwapDb)
+#[derive(Debug, PartialEq)]
+pub struct SwapDb {
+    /// The first database
+    pub index1: i64,
+    /// The second database
+    pub index2: i64,
+}
+
+impl SwapDb {
+    /// Creates a SwapDb type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let index1 = parse_or_err(type_consumer.next_string(), "index1")?;
+        let index2 = parse_or_err(type_consumer.next_string(), "index2")?;
+        Ok(SwapDb { index1, index2 })
+    }
+}
+
+impl From<SwapDb> for Type {
+    fn from(s: SwapDb) -> Self {
+        as_command("SWAPDB", vec![s.index1.to_string(), s.index2.to_string()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Move, Select, SwapDb};
+    use crate::commands::{as_command, CommandCreationError};
+    use crate::resp::{Type, TypeConsumer};
+
+    fn consumer(args: Vec<&str>) -> TypeConsumer {
+        TypeConsumer::new(Type::Array(
+            args.into_iter()
+                .map(|a| Type::BulkString(a.into()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn from_works() {
+        assert_eq!(
+            Select::from(&mut consumer(vec!["3"])),
+            Ok(Select { index: 3 })
+        );
+        assert_eq!(
+            Select::from(&mut consumer(vec!["three"])),
+            Err(CommandCreationError::InvalidArgument(
+                "index is not valid: three".into()
+            ))
+        );
+        assert_eq!(
+            Move::from(&mut consumer(vec!["key", "1"])),
+            Ok(Move {
+                key: "key".into(),
+                db: 1
+            })
+        );
+        assert_eq!(
+            SwapDb::from(&mut consumer(vec!["0"])),
+            Err(CommandCreationError::MissingField("index2".into()))
+        );
+    }
+
+    #[test]
+    fn into_works() {
+        let t: Type = SwapDb {
+            index1: 0,
+            index2: 1,
+        }
+        .into();
+        assert_eq!(t, as_command("SWAPDB", vec!["0".into(), "1".into()]));
+    }
+}
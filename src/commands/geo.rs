@@ -0,0 +1,565 @@
+//! Geospatial commands. See [GEOADD](https://redis.io/commands/geoadd), [GEODIST](https://redis.io/commands/geodist),
+//! [GEOPOS](https://redis.io/commands/geopos) and [GEOSEARCH](https://redis.io/commands/geosearch) for official documentation
+
+use std::{collections::LinkedList, fmt::Display, str::FromStr};
+
+use crate::resp::{Type, TypeConsumer};
+
+use super::{as_command, extract_or_err, parse_or_err, parse_value, CommandCreationError};
+
+/// The units of distances
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DistanceUnit {
+    /// Meters
+    M,
+    /// Kilometers
+    Km,
+    /// Miles
+    Mi,
+    /// Feet
+    Ft,
+}
+
+impl DistanceUnit {
+    /// The number of meters in one unit
+    pub fn in_meters(&self) -> f64 {
+        match self {
+            DistanceUnit::M => 1.0,
+            DistanceUnit::Km => 1000.0,
+            DistanceUnit::Mi => 1609.34,
+            DistanceUnit::Ft => 0.3048,
+        }
+    }
+}
+
+impl FromStr for DistanceUnit {
+    type Err = CommandCreationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "m" => Ok(DistanceUnit::M),
+            "km" => Ok(DistanceUnit::Km),
+            "mi" => Ok(DistanceUnit::Mi),
+            "ft" => Ok(DistanceUnit::Ft),
+            _ => Err(CommandCreationError::InvalidArgument(
+                "unsupported unit provided. please use M, KM, FT, MI".into(),
+            )),
+        }
+    }
+}
+
+impl Display for DistanceUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// A member to add, with its position
+#[derive(Debug, PartialEq, Clone)]
+pub struct GeoMember {
+    /// The longitude
+    pub longitude: f64,
+    /// The latitude
+    pub latitude: f64,
+    /// The name of the member
+    pub member: String,
+}
+
+/// Only update existing members (`XX`) or only add new ones (`NX`)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GeoAddCondition {
+    /// Only add new members
+    Nx,
+    /// Only update existing members
+    Xx,
+}
+
+/// Adds members with their positions
+#[derive(Debug, PartialEq)]
+pub struct GeoAdd {
+    /// The key
+    pub key: String,
+    /// The optional condition
+    pub condition: Option<GeoAddCondition>,
+    /// Reply with the number of changed members (`CH`), instead of the added ones
+    pub changed: bool,
+    /// The members
+    pub members: LinkedList<GeoMember>,
+}
+
+impl GeoAdd {
+    /// Creates a GeoAdd type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let mut condition = None;
+        let mut changed = false;
+        let mut members = LinkedList::new();
+        while let Some(token) = type_consumer.next_string()? {
+            match token.to_uppercase().as_ref() {
+                "NX" if members.is_empty() => condition = Some(GeoAddCondition::Nx),
+                "XX" if members.is_empty() => condition = Some(GeoAddCondition::Xx),
+                "CH" if members.is_empty() => changed = true,
+                _ => {
+                    let longitude = parse_value(&token, "longitude")?;
+                    let latitude = parse_or_err(type_consumer.next_string(), "latitude")?;
+                    let member = extract_or_err(type_consumer.next_string(), "member")?;
+                    members.push_back(GeoMember {
+                        longitude,
+                        latitude,
+                        member,
+                    });
+                }
+            }
+        }
+        if members.is_empty() {
+            return Err(CommandCreationError::MissingField("member".into()));
+        }
+        Ok(GeoAdd {
+            key,
+            condition,
+            changed,
+            members,
+        })
+    }
+}
+
+impl From<GeoAdd> for Type {
+    fn from(g: GeoAdd) -> Self {
+        let mut args = vec![g.key];
+        match g.condition {
+            Some(GeoAddCondition::Nx) => args.push("NX".into()),
+            Some(GeoAddCondition::Xx) => args.push("XX".into()),
+            None => {}
+        }
+        if g.changed {
+            args.push("CH".into());
+        }
+        g.members.into_iter().for_each(|m| {
+            args.extend(vec![
+                m.longitude.to_string(),
+                m.latitude.to_string(),
+                m.member,
+            ])
+        });
+        as_command("GEOADD", args)
+    }
+}
+
+/// Returns the distance between two members
+#[derive(Debug, PartialEq)]
+pub struct GeoDist {
+    /// The key
+    pub key: String,
+    /// The first member
+    pub member1: String,
+    /// The second member
+    pub member2: String,
+    /// The unit of the reply (meters by default)
+    pub unit: DistanceUnit,
+}
+
+impl GeoDist {
+    /// Creates a GeoDist type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let member1 = extract_or_err(type_consumer.next_string(), "member1")?;
+        let member2 = extract_or_err(type_consumer.next_string(), "member2")?;
+        let unit = match type_consumer.next_string()? {
+            Some(unit) => unit.parse()?,
+            None => DistanceUnit::M,
+        };
+        Ok(GeoDist {
+            key,
+            member1,
+            member2,
+            unit,
+        })
+    }
+}
+
+impl From<GeoDist> for Type {
+    fn from(g: GeoDist) -> Self {
+        as_command(
+            "GEODIST",
+            vec![g.key, g.member1, g.member2, g.unit.to_string()],
+        )
+    }
+}
+
+/// Returns the positions of the members
+#[derive(Debug, PartialEq)]
+pub struct GeoPos {
+    /// The key
+    pub key: String,
+    /// The members
+    pub members: LinkedList<String>,
+}
+
+impl GeoPos {
+    /// Creates a GeoPos type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let mut members = LinkedList::new();
+        while let Some(member) = type_consumer.next_string()? {
+            members.push_back(member);
+        }
+        Ok(GeoPos { key, members })
+    }
+}
+
+impl From<GeoPos> for Type {
+    fn from(g: GeoPos) -> Self {
+        let mut args = vec![g.key];
+        args.extend(g.members);
+        as_command("GEOPOS", args)
+    }
+}
+
+/// The center of a [GeoSearch]
+#[derive(Debug, PartialEq, Clone)]
+pub enum GeoFrom {
+    /// The position of an existing member (`FROMMEMBER`)
+    Member(String),
+    /// A longitude and latitude (`FROMLONLAT`)
+    LonLat(f64, f64),
+}
+
+/// The area of a [GeoSearch]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GeoBy {
+    /// A circle (`BYRADIUS`)
+    Radius(f64, DistanceUnit),
+    /// An axis aligned rectangle, width and height (`BYBOX`)
+    Box(f64, f64, DistanceUnit),
+}
+
+impl GeoBy {
+    /// The unit of the area, distances are replied in this unit
+    pub fn unit(&self) -> DistanceUnit {
+        match self {
+            GeoBy::Radius(_, unit) => *unit,
+            GeoBy::Box(_, _, unit) => *unit,
+        }
+    }
+}
+
+/// The sort order of a [GeoSearch], by distance from the center
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SortOrder {
+    /// Nearest first
+    Asc,
+    /// Farthest first
+    Desc,
+}
+
+/// Returns the members within an area
+#[derive(Debug, PartialEq)]
+pub struct GeoSearch {
+    /// The key
+    pub key: String,
+    /// The center of the search
+    pub from: GeoFrom,
+    /// The area
+    pub by: GeoBy,
+    /// The optional sort order
+    pub order: Option<SortOrder>,
+    /// The maximum number of members and whether any matching members can be returned (`ANY`)
+    pub count: Option<(usize, bool)>,
+    /// Reply with the positions
+    pub with_coord: bool,
+    /// Reply with the distances
+    pub with_dist: bool,
+    /// Reply with the raw geohashes
+    pub with_hash: bool,
+}
+
+impl GeoSearch {
+    /// Creates a GeoSearch type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let mut from = None;
+        let mut by = None;
+        let mut order = None;
+        let mut count: Option<(usize, bool)> = None;
+        let (mut with_coord, mut with_dist, mut with_hash) = (false, false, false);
+        let exactly_one = |option: &str| {
+            CommandCreationError::InvalidArgument(format!(
+                "exactly one of {} can be specified for GEOSEARCH",
+                option
+            ))
+        };
+        while let Some(token) = type_consumer.next_string()? {
+            match token.to_uppercase().as_ref() {
+                "FROMMEMBER" if from.is_none() => {
+                    from = Some(GeoFrom::Member(extract_or_err(
+                        type_consumer.next_string(),
+                        "member",
+                    )?))
+                }
+                "FROMLONLAT" if from.is_none() => {
+                    let longitude = parse_or_err(type_consumer.next_string(), "longitude")?;
+                    let latitude = parse_or_err(type_consumer.next_string(), "latitude")?;
+                    from = Some(GeoFrom::LonLat(longitude, latitude))
+                }
+                "FROMMEMBER" | "FROMLONLAT" => return Err(exactly_one("FROMMEMBER or FROMLONLAT")),
+                "BYRADIUS" if by.is_none() => {
+                    let radius =
+                        non_negative(parse_or_err(type_consumer.next_string(), "radius")?)?;
+                    let unit = extract_or_err(type_consumer.next_string(), "unit")?.parse()?;
+                    by = Some(GeoBy::Radius(radius, unit))
+                }
+                "BYBOX" if by.is_none() => {
+                    let width = non_negative(parse_or_err(type_consumer.next_string(), "width")?)?;
+                    let height =
+                        non_negative(parse_or_err(type_consumer.next_string(), "height")?)?;
+                    let unit = extract_or_err(type_consumer.next_string(), "unit")?.parse()?;
+                    by = Some(GeoBy::Box(width, height, unit))
+                }
+                "BYRADIUS" | "BYBOX" => return Err(exactly_one("BYRADIUS or BYBOX")),
+                "ASC" => order = Some(SortOrder::Asc),
+                "DESC" => order = Some(SortOrder::Desc),
+                "COUNT" => {
+                    let n: i64 = parse_or_err(type_consumer.next_string(), "count")?;
+                    if n <= 0 {
+                        return Err(CommandCreationError::InvalidArgument(
+                            "COUNT must be > 0".into(),
+                        ));
+                    }
+                    count = Some((n as usize, false))
+                }
+                "ANY" => match count.as_mut() {
+                    Some(c) => c.1 = true,
+                    None => {
+                        return Err(CommandCreationError::InvalidArgument(
+                            "the ANY argument requires COUNT argument".into(),
+                        ))
+                    }
+                },
+                "WITHCOORD" => with_coord = true,
+                "WITHDIST" => with_dist = true,
+                "WITHHASH" => with_hash = true,
+                _ => {
+                    return Err(CommandCreationError::InvalidArgument(format!(
+                        "syntax error near `{}`",
+                        token
+                    )))
+                }
+            }
+        }
+        let from = from.ok_or_else(|| exactly_one("FROMMEMBER or FROMLONLAT"))?;
+        let by = by.ok_or_else(|| exactly_one("BYRADIUS or BYBOX"))?;
+        Ok(GeoSearch {
+            key,
+            from,
+            by,
+            order,
+            count,
+            with_coord,
+            with_dist,
+            with_hash,
+        })
+    }
+}
+
+impl From<GeoSearch> for Type {
+    fn from(g: GeoSearch) -> Self {
+        let mut args = vec![g.key];
+        match g.from {
+            GeoFrom::Member(m) => args.extend(vec!["FROMMEMBER".into(), m]),
+            GeoFrom::LonLat(longitude, latitude) => args.extend(vec![
+                "FROMLONLAT".into(),
+                longitude.to_string(),
+                latitude.to_string(),
+            ]),
+        }
+        match g.by {
+            GeoBy::Radius(radius, unit) => args.extend(vec![
+                "BYRADIUS".into(),
+                radius.to_string(),
+                unit.to_string(),
+            ]),
+            GeoBy::Box(width, height, unit) => args.extend(vec![
+                "BYBOX".into(),
+                width.to_string(),
+                height.to_string(),
+                unit.to_string(),
+            ]),
+        }
+        match g.order {
+            Some(SortOrder::Asc) => args.push("ASC".into()),
+            Some(SortOrder::Desc) => args.push("DESC".into()),
+            None => {}
+        }
+        if let Some((count, any)) = g.count {
+            args.extend(vec!["COUNT".into(), count.to_string()]);
+            if any {
+                args.push("ANY".into());
+            }
+        }
+        let flags = [
+            (g.with_coord, "WITHCOORD"),
+            (g.with_dist, "WITHDIST"),
+            (g.with_hash, "WITHHASH"),
+        ];
+        flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .for_each(|(_, flag)| args.push(flag.to_string()));
+        as_command("GEOSEARCH", args)
+    }
+}
+
+/* Utility methods */
+
+fn non_negative(v: f64) -> Result<f64, CommandCreationError> {
+    if v < 0.0 {
+        Err(CommandCreationError::InvalidArgument(
+            "radius cannot be negative".into(),
+        ))
+    } else {
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn array(values: &[&str]) -> TypeConsumer {
+        TypeConsumer::new(Type::Array(
+            values
+                .iter()
+                .map(|v| Type::BulkString(v.as_bytes().to_vec()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn geoadd_from_works() {
+        let geoadd = GeoAdd::from(&mut array(&[
+            "Sicily",
+            "NX",
+            "13.361389",
+            "38.115556",
+            "Palermo",
+            "15.087269",
+            "37.502669",
+            "Catania",
+        ]));
+        assert_eq!(
+            geoadd,
+            Ok(GeoAdd {
+                key: "Sicily".into(),
+                condition: Some(GeoAddCondition::Nx),
+                changed: false,
+                members: vec![
+                    GeoMember {
+                        longitude: 13.361389,
+                        latitude: 38.115556,
+                        member: "Palermo".into()
+                    },
+                    GeoMember {
+                        longitude: 15.087269,
+                        latitude: 37.502669,
+                        member: "Catania".into()
+                    }
+                ]
+                .into_iter()
+                .collect()
+            })
+        );
+        assert!(GeoAdd::from(&mut array(&["Sicily", "13.361389", "38.115556"])).is_err());
+        assert!(GeoAdd::from(&mut array(&["Sicily", "CH"])).is_err());
+    }
+
+    #[test]
+    fn geosearch_from_works() {
+        let geosearch = GeoSearch::from(&mut array(&[
+            "Sicily",
+            "FROMLONLAT",
+            "15",
+            "37",
+            "BYBOX",
+            "400",
+            "400",
+            "km",
+            "ASC",
+            "COUNT",
+            "1",
+            "ANY",
+            "WITHDIST",
+        ]));
+        assert_eq!(
+            geosearch,
+            Ok(GeoSearch {
+                key: "Sicily".into(),
+                from: GeoFrom::LonLat(15.0, 37.0),
+                by: GeoBy::Box(400.0, 400.0, DistanceUnit::Km),
+                order: Some(SortOrder::Asc),
+                count: Some((1, true)),
+                with_coord: false,
+                with_dist: true,
+                with_hash: false
+            })
+        );
+        let errors: Vec<&[&str]> = vec![
+            &["Sicily", "BYRADIUS", "10", "km"],
+            &["Sicily", "FROMMEMBER", "Palermo"],
+            &[
+                "Sicily",
+                "FROMMEMBER",
+                "a",
+                "FROMMEMBER",
+                "b",
+                "BYRADIUS",
+                "10",
+                "km",
+            ],
+            &["Sicily", "FROMMEMBER", "a", "BYRADIUS", "10", "parsec"],
+            &["Sicily", "FROMMEMBER", "a", "BYRADIUS", "10", "km", "ANY"],
+            &[
+                "Sicily",
+                "FROMMEMBER",
+                "a",
+                "BYRADIUS",
+                "10",
+                "km",
+                "COUNT",
+                "0",
+            ],
+        ];
+        errors
+            .into_iter()
+            .for_each(|e| assert!(GeoSearch::from(&mut array(e)).is_err(), "{:?}", e));
+    }
+
+    #[test]
+    fn into_works() {
+        let geosearch = GeoSearch {
+            key: "Sicily".into(),
+            from: GeoFrom::Member("Palermo".into()),
+            by: GeoBy::Radius(200.0, DistanceUnit::Mi),
+            order: None,
+            count: Some((2, false)),
+            with_coord: true,
+            with_dist: false,
+            with_hash: true,
+        };
+        let t: Type = geosearch.into();
+        let mut tc = TypeConsumer::new(t);
+        assert_eq!(tc.next_string(), Ok(Some("GEOSEARCH".into())));
+        assert_eq!(
+            GeoSearch::from(&mut tc),
+            Ok(GeoSearch {
+                key: "Sicily".into(),
+                from: GeoFrom::Member("Palermo".into()),
+                by: GeoBy::Radius(200.0, DistanceUnit::Mi),
+                order: None,
+                count: Some((2, false)),
+                with_coord: true,
+                with_dist: false,
+                with_hash: true,
+            })
+        );
+    }
+}
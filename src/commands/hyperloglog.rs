@@ -0,0 +1,163 @@
+//! HyperLogLog commands. See [PFADD](https://redis.io/commands/pfadd), [PFCOUNT](https://redis.io/commands/pfcount)
+//! and [PFMERGE](https://redis.io/commands/pfmerge) for official documentation
+
+use std::collections::LinkedList;
+
+use crate::resp::{Type, TypeConsumer};
+
+use super::{extract_or_err, CommandCreationError};
+
+/// Adds the elements to the HyperLogLog stored at key
+#[derive(Debug, PartialEq)]
+pub struct PfAdd {
+    /// The key of the HyperLogLog
+    pub key: String,
+    /// The elements to add
+    pub elements: LinkedList<Vec<u8>>,
+}
+
+impl PfAdd {
+    /// Creates a PfAdd type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let mut elements = LinkedList::new();
+        while let Some(element) = type_consumer.next_bytes()? {
+            elements.push_back(element)
+        }
+        Ok(PfAdd { key, elements })
+    }
+}
+
+impl From<PfAdd> for Type {
+    fn from(p: PfAdd) -> Self {
+        let mut ll = LinkedList::new();
+        ll.push_back(Type::BulkString(b"PFADD".to_vec()));
+        ll.push_back(Type::BulkString(p.key.into_bytes()));
+        p.elements
+            .into_iter()
+            .for_each(|e| ll.push_back(Type::BulkString(e)));
+        Type::Array(ll)
+    }
+}
+
+/// Returns the approximated cardinality of the union of the HyperLogLogs stored at the keys
+#[derive(Debug, PartialEq)]
+pub struct PfCount {
+    /// The keys of the HyperLogLogs
+    pub keys: LinkedList<String>,
+}
+
+impl PfCount {
+    /// Creates a PfCount type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let mut keys = LinkedList::new();
+        keys.push_back(extract_or_err(type_consumer.next_string(), "key")?);
+        while let Some(key) = type_consumer.next_string()? {
+            keys.push_back(key)
+        }
+        Ok(PfCount { keys })
+    }
+}
+
+impl From<PfCount> for Type {
+    fn from(p: PfCount) -> Self {
+        let mut ll = LinkedList::new();
+        ll.push_back(Type::BulkString(b"PFCOUNT".to_vec()));
+        p.keys
+            .into_iter()
+            .for_each(|k| ll.push_back(Type::BulkString(k.into_bytes())));
+        Type::Array(ll)
+    }
+}
+
+/// Merges the source HyperLogLogs into the destination
+#[derive(Debug, PartialEq)]
+pub struct PfMerge {
+    /// The destination key
+    pub destination: String,
+    /// The source keys
+    pub sources: LinkedList<String>,
+}
+
+impl PfMerge {
+    /// Creates a PfMerge type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let destination = extract_or_err(type_consumer.next_string(), "destination")?;
+        let mut sources = LinkedList::new();
+        while let Some(key) = type_consumer.next_string()? {
+            sources.push_back(key)
+        }
+        Ok(PfMerge {
+            destination,
+            sources,
+        })
+    }
+}
+
+impl From<PfMerge> for Type {
+    fn from(p: PfMerge) -> Self {
+        let mut ll = LinkedList::new();
+        ll.push_back(Type::BulkString(b"PFMERGE".to_vec()));
+        ll.push_back(Type::BulkString(p.destination.into_bytes()));
+        p.sources
+            .into_iter()
+            .for_each(|k| ll.push_back(Type::BulkString(k.into_bytes())));
+        Type::Array(ll)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PfAdd, PfCount, PfMerge};
+    use crate::commands::CommandCreationError;
+    use crate::resp::{Type, TypeConsumer};
+
+    fn array(values: &[&str]) -> Type {
+        Type::Array(
+            values
+                .iter()
+                .map(|v| Type::BulkString(v.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn from_works() {
+        let mut tc = TypeConsumer::new(array(&["hll", "a", "b"]));
+        assert_eq!(
+            PfAdd::from(&mut tc).unwrap(),
+            PfAdd {
+                key: "hll".into(),
+                elements: vec![b"a".to_vec(), b"b".to_vec()].into_iter().collect()
+            }
+        );
+        let mut tc = TypeConsumer::new(array(&[]));
+        assert_eq!(
+            PfCount::from(&mut tc),
+            Err(CommandCreationError::MissingField("key".into()))
+        );
+        let mut tc = TypeConsumer::new(array(&["dest", "a", "b"]));
+        assert_eq!(
+            PfMerge::from(&mut tc).unwrap(),
+            PfMerge {
+                destination: "dest".into(),
+                sources: vec!["a".into(), "b".into()].into_iter().collect()
+            }
+        );
+    }
+
+    #[test]
+    fn into_works() {
+        let t: Type = PfCount {
+            keys: vec!["a".into(), "b".into()].into_iter().collect(),
+        }
+        .into();
+        assert_eq!(t, array(&["PFCOUNT", "a", "b"]));
+        let t: Type = PfAdd {
+            key: "hll".into(),
+            elements: vec![b"a".to_vec()].into_iter().collect(),
+        }
+        .into();
+        assert_eq!(t, array(&["PFADD", "hll", "a"]));
+    }
+}
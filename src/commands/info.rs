@@ -0,0 +1,45 @@
+//! Info command. See [Info command](https://redis.io/commands/info) for official documentation
+
+use super::{as_command, CommandCreationError};
+use crate::resp::{Type, TypeConsumer};
+
+/// Holds the optional section of the [Info command](super::Command::Info)
+#[derive(Debug, PartialEq)]
+pub struct Info {
+    /// Only this section (e.g. `watchers`), all of them when there is none
+    pub section: Option<String>,
+}
+
+impl Info {
+    /// Returns an instance of [super::info::Info]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let section = type_consumer.next_string()?;
+        Ok(Info { section })
+    }
+}
+
+impl From<Info> for Type {
+    fn from(i: Info) -> Self {
+        as_command("INFO", i.section.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Info;
+    use crate::commands::as_command;
+    use crate::resp::{Type, TypeConsumer};
+
+    #[test]
+    fn from_and_into_work() {
+        for section in [None, Some("watchers".to_string())].iter().cloned() {
+            let t = as_command("INFO", section.clone().into_iter().collect());
+            let mut tc = TypeConsumer::new(t.clone());
+            tc.next_string().unwrap();
+            let info = Info::from(&mut tc).unwrap();
+            assert_eq!(info, Info { section });
+            let back: Type = info.into();
+            assert_eq!(back, t);
+        }
+    }
+}
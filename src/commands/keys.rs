@@ -0,0 +1,401 @@
+//! Keyspace commands, e.g. [KEYS](https://redis.io/commands/keys), [SCAN](https://redis.io/commands/scan)
+//! and [RENAME](https://redis.io/commands/rename)
+
+use crate::resp::{Type, TypeConsumer};
+
+use super::{as_command, extract_or_err, parse_or_err, CommandCreationError};
+
+/// The default number of keys a [Scan] looks at
+pub const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Holds the pattern for the [Keys command](super::Command::Keys)
+#[derive(Debug, PartialEq)]
+pub struct Keys {
+    /// The glob-style pattern the keys must match
+    pub pattern: String,
+}
+
+impl Keys {
+    /// Creates a Keys type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let pattern = extract_or_err(type_consumer.next_string(), "pattern")?;
+        Ok(Keys { pattern })
+    }
+}
+
+impl From<Keys> for Type {
+    fn from(k: Keys) -> Self {
+        as_command("KEYS", vec![k.pattern])
+    }
+}
+
+/// Holds the arguments for the [Scan command](super::Command::Scan)
+#[derive(Debug, PartialEq)]
+pub struct Scan {
+    /// Where to continue from, 0 starts a new iteration
+    pub cursor: u64,
+    /// Only reply with the keys matching this glob-style pattern
+    pub pattern: Option<String>,
+    /// The number of keys to look at
+    pub count: usize,
+    /// Only reply with the keys holding this type of value
+    pub value_type: Option<String>,
+}
+
+impl Scan {
+    /// Creates a Scan type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let cursor = parse_or_err(type_consumer.next_string(), "cursor")?;
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut value_type = None;
+        while let Some(token) = type_consumer.next_string()? {
+            match token.to_uppercase().as_ref() {
+                "MATCH" => pattern = Some(extract_or_err(type_consumer.next_string(), "pattern")?),
+                "COUNT" => {
+                    count = parse_or_err(type_consumer.next_string(), "count")?;
+                    if count == 0 {
+                        return Err(CommandCreationError::InvalidArgument(
+                            "COUNT must be > 0".into(),
+                        ));
+                    }
+                }
+                "TYPE" => value_type = Some(extract_or_err(type_consumer.next_string(), "type")?),
+                _ => {
+                    return Err(CommandCreationError::InvalidArgument(format!(
+                        "syntax error near `{}`",
+                        token
+                    )))
+                }
+            }
+        }
+        Ok(Scan {
+            cursor,
+            pattern,
+            count,
+            value_type,
+        })
+    }
+}
+
+impl From<Scan> for Type {
+    fn from(s: Scan) -> Self {
+        let mut args = vec![s.cursor.to_string()];
+        if let Some(pattern) = s.pattern {
+            args.extend(vec!["MATCH".into(), pattern]);
+        }
+        args.extend(vec!["COUNT".into(), s.count.to_string()]);
+        if let Some(value_type) = s.value_type {
+            args.extend(vec!["TYPE".into(), value_type]);
+        }
+        as_command("SCAN", args)
+    }
+}
+
+/// Holds the key for the [KeyType command](super::Command::KeyType)
+#[derive(Debug, PartialEq)]
+pub struct KeyType {
+    /// The key to look up
+    pub key: String,
+}
+
+impl KeyType {
+    /// Creates a KeyType type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        Ok(KeyType { key })
+    }
+}
+
+impl From<KeyType> for Type {
+    fn from(k: KeyType) -> Self {
+        as_command("TYPE", vec![k.key])
+    }
+}
+
+/// Holds the keys for the [Rename command](super::Command::Rename), used for both RENAME and RENAMENX
+#[derive(Debug, PartialEq)]
+pub struct Rename {
+    /// The key to rename
+    pub key: String,
+    /// The new name of the key
+    pub new_key: String,
+    /// Only rename if the new key does not exist (RENAMENX)
+    pub only_if_new: bool,
+}
+
+impl Rename {
+    /// Creates a Rename type from [TypeConsumer]
+    pub fn from(
+        type_consumer: &mut TypeConsumer,
+        only_if_new: bool,
+    ) -> Result<Self, CommandCreationError> {
+        let key = extract_or_err(type_consumer.next_string(), "key")?;
+        let new_key = extract_or_err(type_consumer.next_string(), "new_key")?;
+        Ok(Rename {
+            key,
+            new_key,
+            only_if_new,
+        })
+    }
+}
+
+impl From<Rename> for Type {
+    fn from(r: Rename) -> Self {
+        let name = if r.only_if_new { "RENAMENX" } else { "RENAME" };
+        as_command(name, vec![r.key, r.new_key])
+    }
+}
+
+/// Holds the arguments for the [Copy command](super::Command::Copy)
+#[derive(Debug, PartialEq)]
+pub struct Copy {
+    /// The key to copy
+    pub source: String,
+    /// The key to copy to
+    pub destination: String,
+    /// Overwrite the destination if it exists
+    pub replace: bool,
+}
+
+impl Copy {
+    /// Creates a Copy type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let source = extract_or_err(type_consumer.next_string(), "source")?;
+        let destination = extract_or_err(type_consumer.next_string(), "destination")?;
+        let mut replace = false;
+        while let Some(token) = type_consumer.next_string()? {
+            match token.to_uppercase().as_ref() {
+                "REPLACE" => replace = true,
+                _ => {
+                    return Err(CommandCreationError::InvalidArgument(format!(
+                        "syntax error near `{}`",
+                        token
+                    )))
+                }
+            }
+        }
+        Ok(Copy {
+            source,
+            destination,
+            replace,
+        })
+    }
+}
+
+impl From<Copy> for Type {
+    fn from(c: Copy) -> Self {
+        let mut args = vec![c.source, c.destination];
+        if c.replace {
+            args.push("REPLACE".into());
+        }
+        as_command("COPY", args)
+    }
+}
+
+/// The [RandomKey command](super::Command::RandomKey), has no arguments
+#[derive(Debug, PartialEq)]
+pub struct RandomKey;
+
+impl From<RandomKey> for Type {
+    fn from(_: RandomKey) -> Self {
+        as_command("RANDOMKEY", vec![])
+    }
+}
+
+/// The [DbSize command](super::Command::DbSize), has no arguments
+#[derive(Debug, PartialEq)]
+pub struct DbSize;
+
+impl From<DbSize> for Type {
+    fn from(_: DbSize) -> Self {
+        as_command("DBSIZE", vec![])
+    }
+}
+
+/// Holds the arguments for the [Flush command](super::Command::Flush), used for both FLUSHDB and FLUSHALL
+#[derive(Debug, PartialEq)]
+pub struct Flush {
+    /// Flush all the databases (FLUSHALL)
+    pub all: bool,
+    /// Drop the data on a background task
+    pub asynchronous: bool,
+}
+
+impl Flush {
+    /// Creates a Flush type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer, all: bool) -> Result<Self, CommandCreationError> {
+        let asynchronous = match type_consumer.next_string()? {
+            None => false,
+            Some(mode) => match mode.to_uppercase().as_ref() {
+                "ASYNC" => true,
+                "SYNC" => false,
+                _ => {
+                    return Err(CommandCreationError::InvalidArgument(format!(
+                        "syntax error near `{}`",
+                        mode
+                    )))
+                }
+            },
+        };
+        Ok(Flush { all, asynchronous })
+    }
+}
+
+impl From<Flush> for Type {
+    fn from(f: Flush) -> Self {
+        let name = if f.all { "FLUSHALL" } else { "FLUSHDB" };
+        let mode = if f.asynchronous { "ASYNC" } else { "SYNC" };
+        as_command(name, vec![mode.into()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Copy, Flush, Keys, Rename, Scan, DEFAULT_SCAN_COUNT};
+    use crate::commands::{as_command, CommandCreationError};
+    use crate::resp::{Type, TypeConsumer};
+
+    fn consumer(args: Vec<&str>) -> TypeConsumer {
+        TypeConsumer::new(Type::Array(
+            args.into_iter()
+                .map(|a| Type::BulkString(a.into()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn keys_from_works() {
+        let keys = Keys::from(&mut consumer(vec!["user:*"])).unwrap();
+        assert_eq!(
+            keys,
+            Keys {
+                pattern: "user:*".into()
+            }
+        );
+        assert_eq!(
+            Keys::from(&mut consumer(vec![])),
+            Err(CommandCreationError::MissingField("pattern".into()))
+        );
+    }
+
+    #[test]
+    fn scan_from_works() {
+        let scan = Scan::from(&mut consumer(vec!["0"])).unwrap();
+        assert_eq!(
+            scan,
+            Scan {
+                cursor: 0,
+                pattern: None,
+                count: DEFAULT_SCAN_COUNT,
+                value_type: None
+            }
+        );
+        let scan = Scan::from(&mut consumer(vec![
+            "42", "match", "user:*", "COUNT", "100", "TYPE", "list",
+        ]))
+        .unwrap();
+        assert_eq!(
+            scan,
+            Scan {
+                cursor: 42,
+                pattern: Some("user:*".into()),
+                count: 100,
+                value_type: Some("list".into())
+            }
+        );
+        assert_eq!(
+            Scan::from(&mut consumer(vec!["x"])),
+            Err(CommandCreationError::InvalidArgument(
+                "cursor is not valid: x".into()
+            ))
+        );
+        assert_eq!(
+            Scan::from(&mut consumer(vec!["0", "COUNT", "0"])),
+            Err(CommandCreationError::InvalidArgument(
+                "COUNT must be > 0".into()
+            ))
+        );
+        assert_eq!(
+            Scan::from(&mut consumer(vec!["0", "LIMIT", "1"])),
+            Err(CommandCreationError::InvalidArgument(
+                "syntax error near `LIMIT`".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn into_works() {
+        let t: Type = Scan {
+            cursor: 7,
+            pattern: Some("a*".into()),
+            count: 5,
+            value_type: None,
+        }
+        .into();
+        assert_eq!(
+            t,
+            as_command(
+                "SCAN",
+                vec![
+                    "7".into(),
+                    "MATCH".into(),
+                    "a*".into(),
+                    "COUNT".into(),
+                    "5".into()
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn rename_copy_and_flush_from_works() {
+        assert_eq!(
+            Rename::from(&mut consumer(vec!["a", "b"]), true),
+            Ok(Rename {
+                key: "a".into(),
+                new_key: "b".into(),
+                only_if_new: true
+            })
+        );
+        assert_eq!(
+            Rename::from(&mut consumer(vec!["a"]), false),
+            Err(CommandCreationError::MissingField("new_key".into()))
+        );
+        assert_eq!(
+            Copy::from(&mut consumer(vec!["a", "b", "replace"])),
+            Ok(Copy {
+                source: "a".into(),
+                destination: "b".into(),
+                replace: true
+            })
+        );
+        assert_eq!(
+            Flush::from(&mut consumer(vec!["async"]), false),
+            Ok(Flush {
+                all: false,
+                asynchronous: true
+            })
+        );
+        assert_eq!(
+            Flush::from(&mut consumer(vec![]), true),
+            Ok(Flush {
+                all: true,
+                asynchronous: false
+            })
+        );
+        assert_eq!(
+            Flush::from(&mut consumer(vec!["later"]), true),
+            Err(CommandCreationError::InvalidArgument(
+                "syntax error near `later`".into()
+            ))
+        );
+        let t: Type = Rename {
+            key: "a".into(),
+            new_key: "b".into(),
+            only_if_new: true,
+        }
+        .into();
+        assert_eq!(t, as_command("RENAMENX", vec!["a".into(), "b".into()]));
+    }
+}
@@ -1,13 +1,57 @@
 //! The commands module, lists all the supported commands
-use self::{get::Get, list::Push, set::Set, watch::Watch};
+use self::{
+    bitmap::{BitCount, BitField, BitOp, BitPos, GetBit, SetBit},
+    cas::{Cas, OUnwatch, OWatch, Version},
+    client::Client,
+    config::Config,
+    databases::{Move, Select, SwapDb},
+    geo::{GeoAdd, GeoDist, GeoPos, GeoSearch},
+    get::Get,
+    hyperloglog::{PfAdd, PfCount, PfMerge},
+    info::Info,
+    keys::{Copy, DbSize, Flush, KeyType, Keys, RandomKey, Rename, Scan},
+    list::Push,
+    ping::Ping,
+    pubsub::{Introspection, Publish, Subscribe, SubscriptionKind, Unsubscribe},
+    save::Save,
+    set::Set,
+    transaction::{Discard, Exec, Multi},
+    watch::{Unwatch, Watch},
+};
 use crate::resp::{Type, TypeConsumer, TypeConsumerError};
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, str::FromStr};
+/// The bitmap commands module
+pub mod bitmap;
+/// The optimistic locking commands module
+pub mod cas;
+/// The client command related data, for client side caching
+pub mod client;
+/// The config command related data
+pub mod config;
+/// The commands for the numbered databases
+pub mod databases;
+/// The geospatial commands module
+pub mod geo;
 /// The get command related data
 pub mod get;
+/// The HyperLogLog commands module
+pub mod hyperloglog;
+/// The info command related data
+pub mod info;
+/// The keyspace commands module
+pub mod keys;
 /// The list commands module
 pub mod list;
+/// The ping command related data
+pub mod ping;
+/// The pub/sub commands module
+pub mod pubsub;
+/// The snapshot commands module
+pub mod save;
 /// The set command related data
 pub mod set;
+/// The transaction commands module
+pub mod transaction;
 /// The watch commands module
 pub mod watch;
 
@@ -22,10 +66,109 @@ pub enum Command {
     /// Accepts a tuple of key (name of the list), list of elements
     /// Used to implement [Push](https://redis.io/commands/rpush)
     Push(Push),
-    /// A custom command to watch a particular key
-    /// Once in watch mode, the server will send any updates that happen for that key.
+    /// A custom command to watch a particular key, for some types of [Operation](crate::database::Operation)
+    /// Once in watch mode, the server will send the updates of those types that happen for that key.
+    /// PWATCH watches the keys matching a glob-style pattern instead.
     /// If the key does not exist, returns Error
     Watch(Watch),
+    /// Stops watching a key watched with [Command::Watch], or all of them without a key.
+    /// PUNWATCH does the same for patterns.
+    /// Replies with the number of subscriptions removed.
+    Unwatch(Unwatch),
+    /// Used to implement [PFADD](https://redis.io/commands/pfadd) command from Redis
+    PfAdd(PfAdd),
+    /// Used to implement [PFCOUNT](https://redis.io/commands/pfcount) command from Redis
+    PfCount(PfCount),
+    /// Used to implement [PFMERGE](https://redis.io/commands/pfmerge) command from Redis
+    PfMerge(PfMerge),
+    /// Used to implement [SETBIT](https://redis.io/commands/setbit) command from Redis
+    SetBit(SetBit),
+    /// Used to implement [GETBIT](https://redis.io/commands/getbit) command from Redis
+    GetBit(GetBit),
+    /// Used to implement [BITCOUNT](https://redis.io/commands/bitcount) command from Redis
+    BitCount(BitCount),
+    /// Used to implement [BITPOS](https://redis.io/commands/bitpos) command from Redis
+    BitPos(BitPos),
+    /// Used to implement [BITOP](https://redis.io/commands/bitop) command from Redis
+    BitOp(BitOp),
+    /// Used to implement [BITFIELD](https://redis.io/commands/bitfield) command from Redis
+    BitField(BitField),
+    /// Used to implement [GEOADD](https://redis.io/commands/geoadd) command from Redis
+    GeoAdd(GeoAdd),
+    /// Used to implement [GEODIST](https://redis.io/commands/geodist) command from Redis
+    GeoDist(GeoDist),
+    /// Used to implement [GEOPOS](https://redis.io/commands/geopos) command from Redis
+    GeoPos(GeoPos),
+    /// Used to implement [GEOSEARCH](https://redis.io/commands/geosearch) command from Redis
+    GeoSearch(GeoSearch),
+    /// Used to implement [KEYS](https://redis.io/commands/keys) command from Redis
+    Keys(Keys),
+    /// Used to implement [SCAN](https://redis.io/commands/scan) command from Redis
+    Scan(Scan),
+    /// Used to implement [TYPE](https://redis.io/commands/type) command from Redis
+    KeyType(KeyType),
+    /// Used to implement [RENAME](https://redis.io/commands/rename) and
+    /// [RENAMENX](https://redis.io/commands/renamenx) commands from Redis
+    Rename(Rename),
+    /// Used to implement [COPY](https://redis.io/commands/copy) command from Redis
+    Copy(Copy),
+    /// Used to implement [RANDOMKEY](https://redis.io/commands/randomkey) command from Redis
+    RandomKey(RandomKey),
+    /// Used to implement [DBSIZE](https://redis.io/commands/dbsize) command from Redis
+    DbSize(DbSize),
+    /// Used to implement [FLUSHDB](https://redis.io/commands/flushdb) and
+    /// [FLUSHALL](https://redis.io/commands/flushall) commands from Redis
+    Flush(Flush),
+    /// Used to implement [SELECT](https://redis.io/commands/select) command from Redis
+    Select(Select),
+    /// Used to implement [MOVE](https://redis.io/commands/move) command from Redis
+    Move(Move),
+    /// Used to implement [SWAPDB](https://redis.io/commands/swapdb) command from Redis
+    SwapDb(SwapDb),
+    /// Used to implement [MULTI](https://redis.io/commands/multi) command from Redis
+    Multi(Multi),
+    /// Used to implement [EXEC](https://redis.io/commands/exec) command from Redis
+    Exec(Exec),
+    /// Used to implement [DISCARD](https://redis.io/commands/discard) command from Redis
+    Discard(Discard),
+    /// Returns the version of a key, every change to a key bumps its version
+    Version(Version),
+    /// Sets the value of a key only if it is still at the expected version
+    Cas(Cas),
+    /// Makes the next EXEC fail if any of the keys changed,
+    /// like [WATCH](https://redis.io/commands/watch) in Redis
+    OWatch(OWatch),
+    /// Forgets the keys watched with [Command::OWatch],
+    /// like [UNWATCH](https://redis.io/commands/unwatch) in Redis
+    OUnwatch(OUnwatch),
+    /// Used to implement [PING](https://redis.io/commands/ping) command from Redis
+    Ping(Ping),
+    /// Used to implement [PUBLISH](https://redis.io/commands/publish) and
+    /// [SPUBLISH](https://redis.io/commands/spublish) commands from Redis
+    Publish(Publish),
+    /// Used to implement [SUBSCRIBE](https://redis.io/commands/subscribe),
+    /// [PSUBSCRIBE](https://redis.io/commands/psubscribe) and
+    /// [SSUBSCRIBE](https://redis.io/commands/ssubscribe) commands from Redis
+    Subscribe(Subscribe),
+    /// Used to implement [UNSUBSCRIBE](https://redis.io/commands/unsubscribe),
+    /// [PUNSUBSCRIBE](https://redis.io/commands/punsubscribe) and
+    /// [SUNSUBSCRIBE](https://redis.io/commands/sunsubscribe) commands from Redis
+    Unsubscribe(Unsubscribe),
+    /// Used to implement [PUBSUB](https://redis.io/commands/pubsub) command from Redis
+    PubSub(Introspection),
+    /// Used to implement [INFO](https://redis.io/commands/info) command from Redis,
+    /// with the counters of the watchers
+    Info(Info),
+    /// Used to implement [CONFIG GET](https://redis.io/commands/config-get) and
+    /// [CONFIG SET](https://redis.io/commands/config-set) commands from Redis,
+    /// for the parameters in [config::PARAMETERS]
+    Config(Config),
+    /// Used to implement [CLIENT TRACKING](https://redis.io/commands/client-tracking) and
+    /// [CLIENT CACHING](https://redis.io/commands/client-caching) commands from Redis
+    Client(Client),
+    /// Used to implement [SAVE](https://redis.io/commands/save) and
+    /// [BGSAVE](https://redis.io/commands/bgsave) commands from Redis
+    Save(Save),
 }
 
 impl From<Command> for Type {
@@ -35,6 +178,47 @@ impl From<Command> for Type {
             Command::Set(s) => s.into(),
             Command::Push(p) => p.into(),
             Command::Watch(w) => w.into(),
+            Command::Unwatch(u) => u.into(),
+            Command::PfAdd(p) => p.into(),
+            Command::PfCount(p) => p.into(),
+            Command::PfMerge(p) => p.into(),
+            Command::SetBit(s) => s.into(),
+            Command::GetBit(g) => g.into(),
+            Command::BitCount(b) => b.into(),
+            Command::BitPos(b) => b.into(),
+            Command::BitOp(b) => b.into(),
+            Command::BitField(b) => b.into(),
+            Command::GeoAdd(g) => g.into(),
+            Command::GeoDist(g) => g.into(),
+            Command::GeoPos(g) => g.into(),
+            Command::GeoSearch(g) => g.into(),
+            Command::Keys(k) => k.into(),
+            Command::Scan(s) => s.into(),
+            Command::KeyType(k) => k.into(),
+            Command::Rename(r) => r.into(),
+            Command::Copy(c) => c.into(),
+            Command::RandomKey(r) => r.into(),
+            Command::DbSize(d) => d.into(),
+            Command::Flush(f) => f.into(),
+            Command::Select(s) => s.into(),
+            Command::Move(m) => m.into(),
+            Command::SwapDb(s) => s.into(),
+            Command::Multi(m) => m.into(),
+            Command::Exec(e) => e.into(),
+            Command::Discard(d) => d.into(),
+            Command::Version(v) => v.into(),
+            Command::Cas(c) => c.into(),
+            Command::OWatch(o) => o.into(),
+            Command::OUnwatch(o) => o.into(),
+            Command::Ping(p) => p.into(),
+            Command::Publish(p) => p.into(),
+            Command::Subscribe(s) => s.into(),
+            Command::Unsubscribe(u) => u.into(),
+            Command::PubSub(p) => p.into(),
+            Command::Info(i) => i.into(),
+            Command::Config(c) => c.into(),
+            Command::Client(c) => c.into(),
+            Command::Save(s) => s.into(),
         }
     }
 }
@@ -49,6 +233,8 @@ pub enum CommandCreationError {
     MissingField(String),
     /// A command that is not supported
     UnSupportedCommand,
+    /// A field that is present but has an invalid value (e.g. an unknown option)
+    InvalidArgument(String),
 }
 
 /// Extracts the field or returns an error
@@ -63,6 +249,43 @@ pub(crate) fn extract_or_err<T>(
     }
 }
 
+/// The kind of a (un)subscription, from the name of the command (e.g. PSUBSCRIBE)
+fn subscription_kind(command: &str) -> SubscriptionKind {
+    match command.to_uppercase().as_ref() {
+        "PSUBSCRIBE" | "PUNSUBSCRIBE" => SubscriptionKind::Pattern,
+        "SSUBSCRIBE" | "SUNSUBSCRIBE" => SubscriptionKind::ShardChannel,
+        _ => SubscriptionKind::Channel,
+    }
+}
+
+/// Creates the [Type] for a command, the name followed by the arguments as bulk strings
+pub(crate) fn as_command(name: &str, args: Vec<String>) -> Type {
+    let mut ll = std::collections::LinkedList::new();
+    ll.push_back(Type::BulkString(name.as_bytes().to_vec()));
+    args.into_iter()
+        .for_each(|a| ll.push_back(Type::BulkString(a.into_bytes())));
+    Type::Array(ll)
+}
+
+/// Extracts the field and parses it, or returns an error
+pub(crate) fn parse_or_err<T: FromStr>(
+    input: Result<Option<String>, TypeConsumerError>,
+    field: &'static str,
+) -> Result<T, CommandCreationError> {
+    let value = extract_or_err(input, field)?;
+    parse_value(&value, field)
+}
+
+/// Parses the value of a field, or returns an error
+pub(crate) fn parse_value<T: FromStr>(
+    value: &str,
+    field: &'static str,
+) -> Result<T, CommandCreationError> {
+    value.parse().map_err(|_| {
+        CommandCreationError::InvalidArgument(format!("{} is not valid: {}", field, value))
+    })
+}
+
 impl Error for CommandCreationError {}
 
 impl Display for CommandCreationError {
@@ -81,14 +304,176 @@ impl Command {
     /// Creates a new instance of a [Command]
     pub fn new(type_consumer: &mut TypeConsumer) -> Result<Command, CommandCreationError> {
         let command = extract_or_err(type_consumer.next_string(), "Command")?;
-        match command.as_ref() {
+        match command.to_uppercase().as_ref() {
             "GET" => Ok(Command::Get(Get::from(type_consumer)?)),
             "SET" => Ok(Command::Set(Set::from(type_consumer)?)),
             "PUSH" => Ok(Command::Push(Push::from(type_consumer)?)),
-            "WATCH" => Ok(Command::Watch(Watch::from(type_consumer)?)),
+            "WATCH" => Ok(Command::Watch(Watch::from(type_consumer, false)?)),
+            "PWATCH" => Ok(Command::Watch(Watch::from(type_consumer, true)?)),
+            "UNWATCH" => Ok(Command::Unwatch(Unwatch::from(type_consumer, false)?)),
+            "PUNWATCH" => Ok(Command::Unwatch(Unwatch::from(type_consumer, true)?)),
+            "PFADD" => Ok(Command::PfAdd(PfAdd::from(type_consumer)?)),
+            "PFCOUNT" => Ok(Command::PfCount(PfCount::from(type_consumer)?)),
+            "PFMERGE" => Ok(Command::PfMerge(PfMerge::from(type_consumer)?)),
+            "SETBIT" => Ok(Command::SetBit(SetBit::from(type_consumer)?)),
+            "GETBIT" => Ok(Command::GetBit(GetBit::from(type_consumer)?)),
+            "BITCOUNT" => Ok(Command::BitCount(BitCount::from(type_consumer)?)),
+            "BITPOS" => Ok(Command::BitPos(BitPos::from(type_consumer)?)),
+            "BITOP" => Ok(Command::BitOp(BitOp::from(type_consumer)?)),
+            "BITFIELD" => Ok(Command::BitField(BitField::from(type_consumer)?)),
+            "GEOADD" => Ok(Command::GeoAdd(GeoAdd::from(type_consumer)?)),
+            "GEODIST" => Ok(Command::GeoDist(GeoDist::from(type_consumer)?)),
+            "GEOPOS" => Ok(Command::GeoPos(GeoPos::from(type_consumer)?)),
+            "GEOSEARCH" => Ok(Command::GeoSearch(GeoSearch::from(type_consumer)?)),
+            "KEYS" => Ok(Command::Keys(Keys::from(type_consumer)?)),
+            "SCAN" => Ok(Command::Scan(Scan::from(type_consumer)?)),
+            "TYPE" => Ok(Command::KeyType(KeyType::from(type_consumer)?)),
+            "RENAME" => Ok(Command::Rename(Rename::from(type_consumer, false)?)),
+            "RENAMENX" => Ok(Command::Rename(Rename::from(type_consumer, true)?)),
+            "COPY" => Ok(Command::Copy(Copy::from(type_consumer)?)),
+            "RANDOMKEY" => Ok(Command::RandomKey(RandomKey)),
+            "DBSIZE" => Ok(Command::DbSize(DbSize)),
+            "FLUSHDB" => Ok(Command::Flush(Flush::from(type_consumer, false)?)),
+            "FLUSHALL" => Ok(Command::Flush(Flush::from(type_consumer, true)?)),
+            "SELECT" => Ok(Command::Select(Select::from(type_consumer)?)),
+            "MOVE" => Ok(Command::Move(Move::from(type_consumer)?)),
+            "SWAPDB" => Ok(Command::SwapDb(SwapDb::from(type_consumer)?)),
+            "MULTI" => Ok(Command::Multi(Multi)),
+            "EXEC" => Ok(Command::Exec(Exec)),
+            "DISCARD" => Ok(Command::Discard(Discard)),
+            "VERSION" => Ok(Command::Version(Version::from(type_consumer)?)),
+            "CAS" => Ok(Command::Cas(Cas::from(type_consumer)?)),
+            "OWATCH" => Ok(Command::OWatch(OWatch::from(type_consumer)?)),
+            "OUNWATCH" => Ok(Command::OUnwatch(OUnwatch)),
+            "PING" => Ok(Command::Ping(Ping::from(type_consumer)?)),
+            "PUBLISH" => Ok(Command::Publish(Publish::from(type_consumer, false)?)),
+            "SPUBLISH" => Ok(Command::Publish(Publish::from(type_consumer, true)?)),
+            "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" => {
+                let kind = subscription_kind(&command);
+                Ok(Command::Subscribe(Subscribe::from(type_consumer, kind)?))
+            }
+            "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => {
+                let kind = subscription_kind(&command);
+                Ok(Command::Unsubscribe(Unsubscribe::from(
+                    type_consumer,
+                    kind,
+                )?))
+            }
+            "PUBSUB" => Ok(Command::PubSub(Introspection::from(type_consumer)?)),
+            "INFO" => Ok(Command::Info(Info::from(type_consumer)?)),
+            "CONFIG" => Ok(Command::Config(Config::from(type_consumer)?)),
+            "CLIENT" => Ok(Command::Client(Client::from(type_consumer)?)),
+            "SAVE" => Ok(Command::Save(Save::from(type_consumer, false)?)),
+            "BGSAVE" => Ok(Command::Save(Save::from(type_consumer, true)?)),
             _ => Err(CommandCreationError::UnSupportedCommand),
         }
     }
+
+    /// Returns true if the command can be queued in a transaction (after [Multi]).
+    /// The commands that need more than the keyspace of the selected database cannot.
+    pub fn allowed_in_transaction(&self) -> bool {
+        match self {
+            Command::Watch(_)
+            | Command::Unwatch(_)
+            | Command::Select(_)
+            | Command::Move(_)
+            | Command::SwapDb(_)
+            | Command::Multi(_)
+            | Command::Exec(_)
+            | Command::Discard(_)
+            | Command::OWatch(_)
+            | Command::OUnwatch(_)
+            | Command::Publish(_)
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PubSub(_)
+            | Command::Info(_)
+            | Command::Config(_)
+            | Command::Client(_)
+            | Command::Save(_) => false,
+            Command::Flush(f) => !f.all,
+            _ => true,
+        }
+    }
+
+    /// The keys read by the command, tracked for the connections with CLIENT TRACKING
+    pub fn read_keys(&self) -> Vec<String> {
+        match self {
+            Command::Get(Get { key })
+            | Command::GetBit(GetBit { key, .. })
+            | Command::BitCount(BitCount { key, .. })
+            | Command::BitPos(BitPos { key, .. })
+            | Command::GeoDist(GeoDist { key, .. })
+            | Command::GeoPos(GeoPos { key, .. })
+            | Command::GeoSearch(GeoSearch { key, .. })
+            | Command::KeyType(KeyType { key })
+            | Command::Version(Version { key }) => vec![key.clone()],
+            Command::PfCount(PfCount { keys }) => keys.iter().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The keys read or written by the command, None for the commands on the whole keyspace
+    /// (and for those not run against a keyspace). Only the shards of the keys are locked.
+    pub fn keys(&self) -> Option<Vec<String>> {
+        let keys = match self {
+            Command::Set(Set { key, .. })
+            | Command::PfAdd(PfAdd { key, .. })
+            | Command::SetBit(SetBit { key, .. })
+            | Command::BitField(BitField { key, .. })
+            | Command::GeoAdd(GeoAdd { key, .. })
+            | Command::Cas(Cas { key, .. }) => vec![key.clone()],
+            Command::Push(Push { list_name, .. }) => vec![list_name.clone()],
+            Command::PfMerge(PfMerge {
+                destination,
+                sources,
+            }) => std::iter::once(destination)
+                .chain(sources)
+                .cloned()
+                .collect(),
+            Command::BitOp(BitOp {
+                destination, keys, ..
+            }) => std::iter::once(destination).chain(keys).cloned().collect(),
+            Command::Rename(Rename { key, new_key, .. }) => vec![key.clone(), new_key.clone()],
+            Command::Copy(Copy {
+                source,
+                destination,
+                ..
+            }) => vec![source.clone(), destination.clone()],
+            Command::Ping(_) => Vec::new(),
+            c => match c.read_keys() {
+                keys if keys.is_empty() => return None,
+                keys => keys,
+            },
+        };
+        Some(keys)
+    }
+
+    /// Returns true if the command can use more memory, it is refused when the `maxmemory`
+    /// is reached and no key can be evicted
+    pub fn uses_memory(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(_)
+                | Command::Push(_)
+                | Command::PfAdd(_)
+                | Command::PfMerge(_)
+                | Command::SetBit(_)
+                | Command::BitOp(_)
+                | Command::BitField(_)
+                | Command::GeoAdd(_)
+                | Command::Copy(_)
+                | Command::Cas(_)
+        )
+    }
+
+    /// Returns true if the command can be sent by a connection that subscribed to a channel
+    pub fn allowed_when_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Ping(_) | Command::Subscribe(_) | Command::Unsubscribe(_)
+        )
+    }
 }
 
 #[cfg(test)]
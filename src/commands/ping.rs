@@ -0,0 +1,51 @@
+//! Ping command. See [Ping command](https://redis.io/commands/ping) for official documentation
+
+use super::{as_command, CommandCreationError};
+use crate::resp::{Type, TypeConsumer};
+
+/// Holds the optional message of the [Ping command](super::Command::Ping)
+#[derive(Debug, PartialEq)]
+pub struct Ping {
+    /// Replied as is, instead of PONG
+    pub message: Option<String>,
+}
+
+impl Ping {
+    /// Returns an instance of [super::ping::Ping]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let message = type_consumer.next_string()?;
+        Ok(Ping { message })
+    }
+}
+
+impl From<Ping> for Type {
+    fn from(p: Ping) -> Self {
+        as_command("PING", p.message.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Ping;
+    use crate::commands::as_command;
+    use crate::resp::{Type, TypeConsumer};
+
+    #[test]
+    fn from_and_into_work() {
+        let t = as_command("PING", vec!["hello".into()]);
+        let mut tc = TypeConsumer::new(t.clone());
+        tc.next_string().unwrap();
+        let ping = Ping::from(&mut tc).unwrap();
+        assert_eq!(
+            ping,
+            Ping {
+                message: Some("hello".into())
+            }
+        );
+        let back: Type = ping.into();
+        assert_eq!(back, t);
+        let mut tc = TypeConsumer::new(as_command("PING", vec![]));
+        tc.next_string().unwrap();
+        assert_eq!(Ping::from(&mut tc), Ok(Ping { message: None }));
+    }
+}
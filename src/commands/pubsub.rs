@@ -0,0 +1,397 @@
+//! The pub/sub commands, [PUBLISH](https://redis.io/commands/publish),
+//! [SUBSCRIBE](https://redis.io/commands/subscribe) and the like.
+//!
+//! Once a connection has subscribed to a channel, a pattern or a shard channel, it only accepts
+//! (P|S)SUBSCRIBE, (P|S)UNSUBSCRIBE and PING until it has unsubscribed from all of them.
+
+use std::collections::LinkedList;
+
+use crate::resp::{Type, TypeConsumer};
+
+use super::{as_command, extract_or_err, CommandCreationError};
+
+/// What a connection subscribes to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SubscriptionKind {
+    /// A channel (SUBSCRIBE)
+    Channel,
+    /// A glob-style pattern of channels (PSUBSCRIBE)
+    Pattern,
+    /// A shard channel (SSUBSCRIBE), see [SPUBLISH](https://redis.io/commands/spublish)
+    ShardChannel,
+}
+
+impl SubscriptionKind {
+    /// The prefix of the names of the commands of this kind, e.g. `P` for PSUBSCRIBE
+    pub fn prefix(self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "",
+            SubscriptionKind::Pattern => "P",
+            SubscriptionKind::ShardChannel => "S",
+        }
+    }
+}
+
+/// Holds the arguments for the [Publish command](super::Command::Publish),
+/// used for both PUBLISH and SPUBLISH
+#[derive(Debug, PartialEq)]
+pub struct Publish {
+    /// The channel to publish to
+    pub channel: String,
+    /// The message, binary safe
+    pub message: Vec<u8>,
+    /// The channel is a shard channel (SPUBLISH)
+    pub sharded: bool,
+}
+
+impl Publish {
+    /// Creates a Publish type from [TypeConsumer]
+    pub fn from(
+        type_consumer: &mut TypeConsumer,
+        sharded: bool,
+    ) -> Result<Self, CommandCreationError> {
+        let channel = extract_or_err(type_consumer.next_string(), "channel")?;
+        let message = extract_or_err(type_consumer.next_bytes(), "message")?;
+        Ok(Publish {
+            channel,
+            message,
+            sharded,
+        })
+    }
+}
+
+impl From<Publish> for Type {
+    fn from(p: Publish) -> Self {
+        let name: &[u8] = if p.sharded { b"SPUBLISH" } else { b"PUBLISH" };
+        let mut ll = LinkedList::new();
+        ll.push_back(Type::BulkString(name.to_vec()));
+        ll.push_back(Type::BulkString(p.channel.into_bytes()));
+        ll.push_back(Type::BulkString(p.message));
+        Type::Array(ll)
+    }
+}
+
+/// Holds the channels for the [Subscribe command](super::Command::Subscribe),
+/// used for SUBSCRIBE, PSUBSCRIBE and SSUBSCRIBE
+#[derive(Debug, PartialEq)]
+pub struct Subscribe {
+    /// The channels (or glob-style patterns) to subscribe to
+    pub channels: LinkedList<String>,
+    /// What the channels are
+    pub kind: SubscriptionKind,
+}
+
+impl Subscribe {
+    /// Creates a Subscribe type from [TypeConsumer]
+    pub fn from(
+        type_consumer: &mut TypeConsumer,
+        kind: SubscriptionKind,
+    ) -> Result<Self, CommandCreationError> {
+        let mut channels = LinkedList::new();
+        channels.push_back(extract_or_err(type_consumer.next_string(), "channel")?);
+        while let Some(channel) = type_consumer.next_string()? {
+            channels.push_back(channel)
+        }
+        Ok(Subscribe { channels, kind })
+    }
+}
+
+impl From<Subscribe> for Type {
+    fn from(s: Subscribe) -> Self {
+        let name = format!("{}SUBSCRIBE", s.kind.prefix());
+        as_command(&name, s.channels.into_iter().collect())
+    }
+}
+
+/// Holds the channels for the [Unsubscribe command](super::Command::Unsubscribe),
+/// used for UNSUBSCRIBE, PUNSUBSCRIBE and SUNSUBSCRIBE
+#[derive(Debug, PartialEq)]
+pub struct Unsubscribe {
+    /// The channels (or patterns) to unsubscribe from, all of them if empty
+    pub channels: LinkedList<String>,
+    /// What the channels are
+    pub kind: SubscriptionKind,
+}
+
+impl Unsubscribe {
+    /// Creates an Unsubscribe type from [TypeConsumer]
+    pub fn from(
+        type_consumer: &mut TypeConsumer,
+        kind: SubscriptionKind,
+    ) -> Result<Self, CommandCreationError> {
+        let mut channels = LinkedList::new();
+        while let Some(channel) = type_consumer.next_string()? {
+            channels.push_back(channel)
+        }
+        Ok(Unsubscribe { channels, kind })
+    }
+}
+
+impl From<Unsubscribe> for Type {
+    fn from(u: Unsubscribe) -> Self {
+        let name = format!("{}UNSUBSCRIBE", u.kind.prefix());
+        as_command(&name, u.channels.into_iter().collect())
+    }
+}
+
+/// The subcommands of the [PubSub command](super::Command::PubSub)
+#[derive(Debug, PartialEq)]
+pub enum Introspection {
+    /// `PUBSUB CHANNELS [pattern]` (or SHARDCHANNELS), the channels with subscribers
+    Channels {
+        /// Only the channels matching this glob-style pattern
+        pattern: Option<String>,
+        /// The shard channels
+        sharded: bool,
+    },
+    /// `PUBSUB NUMSUB [channel ...]` (or SHARDNUMSUB), the number of subscribers of channels
+    NumSub {
+        /// The channels to count the subscribers of
+        channels: LinkedList<String>,
+        /// The shard channels
+        sharded: bool,
+    },
+    /// `PUBSUB NUMPAT`, the number of patterns with subscribers
+    NumPat,
+}
+
+impl Introspection {
+    /// Creates an Introspection type from [TypeConsumer]
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+        let subcommand = extract_or_err(type_consumer.next_string(), "subcommand")?;
+        let (subcommand, sharded) = match subcommand.to_uppercase().as_ref() {
+            "SHARDCHANNELS" => ("CHANNELS".to_string(), true),
+            "SHARDNUMSUB" => ("NUMSUB".to_string(), true),
+            upper => (upper.to_string(), false),
+        };
+        let introspection = match subcommand.as_ref() {
+            "CHANNELS" => Introspection::Channels {
+                pattern: type_consumer.next_string()?,
+                sharded,
+            },
+            "NUMSUB" => {
+                let mut channels = LinkedList::new();
+                while let Some(channel) = type_consumer.next_string()? {
+                    channels.push_back(channel)
+                }
+                Introspection::NumSub { channels, sharded }
+            }
+            "NUMPAT" => Introspection::NumPat,
+            _ => {
+                return Err(CommandCreationError::InvalidArgument(format!(
+                    "syntax error near `{}`",
+                    subcommand
+                )))
+            }
+        };
+        Ok(introspection)
+    }
+}
+
+impl From<Introspection> for Type {
+    fn from(i: Introspection) -> Self {
+        let shard = |sharded| if sharded { "SHARD" } else { "" };
+        let args = match i {
+            Introspection::Channels { pattern, sharded } => {
+                let mut args = vec![format!("{}CHANNELS", shard(sharded))];
+                args.extend(pattern);
+                args
+            }
+            Introspection::NumSub { channels, sharded } => {
+                let mut args = vec![format!("{}NUMSUB", shard(sharded))];
+                args.extend(channels);
+                args
+            }
+            Introspection::NumPat => vec!["NUMPAT".into()],
+        };
+        as_command("PUBSUB", args)
+    }
+}
+
+/// A message received by a subscriber
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The pattern that matched the channel, for a PSUBSCRIBE
+    pub pattern: Option<String>,
+    /// The channel the message was published to
+    pub channel: String,
+    /// The message
+    pub payload: Vec<u8>,
+    /// The channel is a shard channel
+    pub sharded: bool,
+}
+
+impl Message {
+    /// Creates a Message from a [TypeConsumer] holding a message pushed by the server.
+    /// Returns None for the other pushes (e.g. the confirmation of a subscription).
+    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Option<Self>, CommandCreationError> {
+        let kind = extract_or_err(type_consumer.next_string(), "kind")?;
+        let (pattern, sharded) = match kind.as_ref() {
+            "message" => (None, false),
+            "smessage" => (None, true),
+            "pmessage" => (
+                Some(extract_or_err(type_consumer.next_string(), "pattern")?),
+                false,
+            ),
+            _ => return Ok(None),
+        };
+        let channel = extract_or_err(type_consumer.next_string(), "channel")?;
+        let payload = extract_or_err(type_consumer.next_bytes(), "payload")?;
+        Ok(Some(Message {
+            pattern,
+            channel,
+            payload,
+            sharded,
+        }))
+    }
+}
+
+impl From<Message> for Type {
+    fn from(m: Message) -> Self {
+        let mut ll = LinkedList::new();
+        match m.pattern {
+            Some(pattern) => {
+                ll.push_back(Type::BulkString(b"pmessage".to_vec()));
+                ll.push_back(Type::BulkString(pattern.into_bytes()));
+            }
+            None if m.sharded => ll.push_back(Type::BulkString(b"smessage".to_vec())),
+            None => ll.push_back(Type::BulkString(b"message".to_vec())),
+        }
+        ll.push_back(Type::BulkString(m.channel.into_bytes()));
+        ll.push_back(Type::BulkString(m.payload));
+        Type::Array(ll)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Introspection, Message, Publish, Subscribe, SubscriptionKind, Unsubscribe};
+    use crate::commands::{as_command, CommandCreationError};
+    use crate::resp::{Type, TypeConsumer};
+
+    fn consumer(args: Vec<&str>) -> TypeConsumer {
+        TypeConsumer::new(Type::Array(
+            args.into_iter()
+                .map(|a| Type::BulkString(a.into()))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn from_works() {
+        assert_eq!(
+            Publish::from(&mut consumer(vec!["news", "hello"]), true),
+            Ok(Publish {
+                channel: "news".into(),
+                message: b"hello".to_vec(),
+                sharded: true
+            })
+        );
+        assert_eq!(
+            Publish::from(&mut consumer(vec!["news"]), false),
+            Err(CommandCreationError::MissingField("message".into()))
+        );
+        assert_eq!(
+            Subscribe::from(
+                &mut consumer(vec!["news.*", "sport.*"]),
+                SubscriptionKind::Pattern
+            ),
+            Ok(Subscribe {
+                channels: vec!["news.*".to_string(), "sport.*".to_string()]
+                    .into_iter()
+                    .collect(),
+                kind: SubscriptionKind::Pattern
+            })
+        );
+        assert_eq!(
+            Subscribe::from(&mut consumer(vec![]), SubscriptionKind::Channel),
+            Err(CommandCreationError::MissingField("channel".into()))
+        );
+        // Unsubscribing from nothing means from everything
+        assert_eq!(
+            Unsubscribe::from(&mut consumer(vec![]), SubscriptionKind::ShardChannel),
+            Ok(Unsubscribe {
+                channels: Default::default(),
+                kind: SubscriptionKind::ShardChannel
+            })
+        );
+        assert_eq!(
+            Introspection::from(&mut consumer(vec!["channels", "news.*"])),
+            Ok(Introspection::Channels {
+                pattern: Some("news.*".into()),
+                sharded: false
+            })
+        );
+        assert_eq!(
+            Introspection::from(&mut consumer(vec!["SHARDNUMSUB", "a", "b"])),
+            Ok(Introspection::NumSub {
+                channels: vec!["a".to_string(), "b".to_string()].into_iter().collect(),
+                sharded: true
+            })
+        );
+        assert_eq!(
+            Introspection::from(&mut consumer(vec!["numpat"])),
+            Ok(Introspection::NumPat)
+        );
+        assert_eq!(
+            Introspection::from(&mut consumer(vec!["help"])),
+            Err(CommandCreationError::InvalidArgument(
+                "syntax error near `HELP`".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn into_works() {
+        let t: Type = Unsubscribe {
+            channels: vec!["a*".to_string()].into_iter().collect(),
+            kind: SubscriptionKind::Pattern,
+        }
+        .into();
+        assert_eq!(t, as_command("PUNSUBSCRIBE", vec!["a*".into()]));
+        let t: Type = Publish {
+            channel: "news".into(),
+            message: b"hello".to_vec(),
+            sharded: true,
+        }
+        .into();
+        assert_eq!(
+            t,
+            as_command("SPUBLISH", vec!["news".into(), "hello".into()])
+        );
+        let t: Type = Introspection::Channels {
+            pattern: None,
+            sharded: true,
+        }
+        .into();
+        assert_eq!(t, as_command("PUBSUB", vec!["SHARDCHANNELS".into()]));
+    }
+
+    #[test]
+    fn message_round_trips() {
+        let message = Message {
+            pattern: Some("news.*".into()),
+            channel: "news.today".into(),
+            payload: b"hello".to_vec(),
+            sharded: false,
+        };
+        let t: Type = message.clone().into();
+        assert_eq!(Message::from(&mut TypeConsumer::new(t)), Ok(Some(message)));
+        let t: Type = Message {
+            pattern: None,
+            channel: "news".into(),
+            payload: b"hello".to_vec(),
+            sharded: true,
+        }
+        .into();
+        assert_eq!(
+            t,
+            as_command("smessage", vec!["news".into(), "hello".into()])
+        );
+        // The confirmation of a subscription is not a message
+        assert_eq!(
+            Message::from(&mut consumer(vec!["subscribe", "news", "1"])),
+            Ok(None)
+        );
+    }
+}
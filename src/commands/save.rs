@@ -0,0 +1,58 @@
+//! Save commands. See [SAVE](https://redis.io/commands/save) and
+//! [BGSAVE](https://redis.io/commands/bgsave) for official documentation
+
+use super::{as_command, CommandCreationError};
+use crate::resp::{Type, TypeConsumer};
+
+/// Holds the arguments for the [Save command](super::Command::Save), used for both SAVE and BGSAVE
+#[derive(Debug, PartialEq)]
+pub struct Save {
+    /// Write the snapshot from a background task (BGSAVE), the reply does not wait for it
+    pub background: bool,
+}
+
+impl Save {
+    /// Creates a Save type from [TypeConsumer]
+    pub fn from(
+        type_consumer: &mut TypeConsumer,
+        background: bool,
+    ) -> Result<Self, CommandCreationError> {
+        match type_consumer.next_string()? {
+            None => Ok(Save { background }),
+            Some(argument) => Err(CommandCreationError::InvalidArgument(format!(
+                "syntax error near `{}`",
+                argument
+            ))),
+        }
+    }
+}
+
+impl From<Save> for Type {
+    fn from(s: Save) -> Self {
+        let name = if s.background { "BGSAVE" } else { "SAVE" };
+        as_command(name, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Save;
+    use crate::commands::as_command;
+    use crate::resp::{Type, TypeConsumer};
+
+    #[test]
+    fn from_and_into_work() {
+        for (name, background) in [("SAVE", false), ("BGSAVE", true)] {
+            let t = as_command(name, Vec::new());
+            let mut tc = TypeConsumer::new(t.clone());
+            tc.next_string().unwrap();
+            let save = Save::from(&mut tc, background).unwrap();
+            assert_eq!(save, Save { background });
+            let back: Type = save.into();
+            assert_eq!(back, t);
+        }
+        let mut tc = TypeConsumer::new(as_command("BGSAVE", vec!["SCHEDULE".into()]));
+        tc.next_string().unwrap();
+        assert!(Save::from(&mut tc, true).is_err());
+    }
+}
@@ -7,14 +7,14 @@ use std::collections::LinkedList;
 #[derive(Debug, PartialEq)]
 pub struct Set {
     pub(crate) key: String,
-    pub(crate) value: String,
+    pub(crate) value: Vec<u8>,
 }
 
 impl Set {
     /// Returns an instance of [super::get::Get]
     pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
         let key = extract_or_err(type_consumer.next_string(), "key")?;
-        let value = extract_or_err(type_consumer.next_string(), "value")?;
+        let value = extract_or_err(type_consumer.next_bytes(), "value")?;
         Ok(Set { key, value })
     }
 }
@@ -24,7 +24,7 @@ impl From<Set> for Type {
         let mut ll = LinkedList::new();
         ll.push_back(Type::BulkString(b"SET".to_vec()));
         ll.push_back(Type::BulkString(get.key.into_bytes()));
-        ll.push_back(Type::BulkString(get.value.into_bytes()));
+        ll.push_back(Type::BulkString(get.value));
         Type::Array(ll)
     }
 }
@@ -63,7 +63,7 @@ mod test {
                 .collect(),
         ));
         let set = Set::from(&mut tc);
-        assert_eq!(set, Err(CommandCreationError::MissingField("key".into())));
+        assert_eq!(set, Err(CommandCreationError::MissingField("value".into())));
     }
 
     #[test]
@@ -0,0 +1,36 @@
+//! Transaction commands, see [MULTI](https://redis.io/commands/multi),
+//! [EXEC](https://redis.io/commands/exec) and [DISCARD](https://redis.io/commands/discard)
+
+use crate::resp::Type;
+
+use super::as_command;
+
+/// The [Multi command](super::Command::Multi), starts queuing the commands
+#[derive(Debug, PartialEq)]
+pub struct Multi;
+
+impl From<Multi> for Type {
+    fn from(_: Multi) -> Self {
+        as_command("MULTI", vec![])
+    }
+}
+
+/// The [Exec command](super::Command::Exec), runs the queued commands
+#[derive(Debug, PartialEq)]
+pub struct Exec;
+
+impl From<Exec> for Type {
+    fn from(_: Exec) -> Self {
+        as_command("EXEC", vec![])
+    }
+}
+
+/// The [Discard command](super::Command::Discard), drops the queued commands
+#[derive(Debug, PartialEq)]
+pub struct Discard;
+
+impl From<Discard> for Type {
+    fn from(_: Discard) -> Self {
+        as_command("DISCARD", vec![])
+    }
+}
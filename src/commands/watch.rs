@@ -1,28 +1,50 @@
 //! The watch module.
 //! This module is responsible for watching on changes to a key
 
-use std::{collections::LinkedList, fmt::Display};
+use std::{
+    collections::{BTreeSet, LinkedList},
+    fmt::Display,
+};
 
 use crate::{
     database::Operation,
     resp::{Type, TypeConsumer},
 };
 
-use super::{extract_or_err, CommandCreationError};
+use super::{as_command, extract_or_err, parse_or_err, CommandCreationError};
 
 /// Defines the watch
 #[derive(Debug, PartialEq)]
 pub struct Watch {
-    /// the key to watch
+    /// the key to watch, or a glob-style pattern of the keys to watch (PWATCH)
     pub key: String,
-    /// the type of operation to watch
-    pub operation: Operation,
+    /// the types of operation to watch, [Operation::All] for any of them
+    pub operations: BTreeSet<Operation>,
+    /// true if `key` is a pattern
+    pub pattern: bool,
+    /// `FROM <offset>`, the changes after that offset are replayed before the live ones
+    pub from: Option<u64>,
+    /// `SNAPSHOT`, the current value of the key (or of every matching key) is sent first,
+    /// taken under the same lock as the subscription so that only later changes follow
+    pub snapshot: bool,
 }
 
-/// Represents the result of [Watch]
+/// Stops watching a key (or pattern for PUNWATCH), or every key (or pattern) watched
+/// by the connection when there is none
+#[derive(Debug, PartialEq)]
+pub struct Unwatch {
+    /// the key (or pattern) to stop watching, in the selected database
+    pub key: Option<String>,
+    /// true for PUNWATCH
+    pub pattern: bool,
+}
+
+/// Represents the result of [Watch], pushed as `watch` followed by the fields
 #[derive(Debug)]
 pub struct WatchResult {
-    /// Key
+    /// The id of the watch, the reply to its WATCH (a connection can have many watches)
+    pub watch: u64,
+    /// The key that changed, also for a pattern
     pub key: String,
     /// Operation
     pub operation: Operation,
@@ -30,22 +52,45 @@ pub struct WatchResult {
     pub before: Option<Type>,
     /// Value after
     pub after: Type,
+    /// The sequence number of the notification, for a connection they go up by one.
+    /// A consumer can detect missed notifications from a gap.
+    pub sequence: u64,
+    /// What made the change, after the command (e.g. `set`, `rpush` or `rename_from`).
+    /// For `rpush`, `after` is only the appended values and `before` is Null.
+    /// For `snapshot`, `after` is the current value, Null if the key does not exist.
+    pub event: String,
+    /// The offset of the change in the change log of its database, to resume from with
+    /// `WATCH ... FROM <offset>` after a reconnection
+    pub offset: u64,
 }
 
 impl From<WatchResult> for Type {
     fn from(w: WatchResult) -> Self {
         let mut message: LinkedList<Type> = LinkedList::new();
+        message.push_back(Type::SimpleString("watch".into()));
+        message.push_back(Type::Integer(w.watch as i64));
         message.push_back(Type::SimpleString(w.key));
         message.push_back(Type::Integer(w.operation as i64));
         message.push_back(w.before.unwrap_or(Type::Null));
         message.push_back(w.after);
-        Type::Array(message)
+        message.push_back(Type::Integer(w.sequence as i64));
+        message.push_back(Type::SimpleString(w.event));
+        message.push_back(Type::Integer(w.offset as i64));
+        Type::Push(message)
     }
 }
 
 impl From<Type> for Result<WatchResult, CommandCreationError> {
     fn from(w: Type) -> Self {
         let mut type_consumer = TypeConsumer::new(w);
+        let kind = extract_or_err(type_consumer.next_string(), "kind")?;
+        if kind != "watch" {
+            return Err(CommandCreationError::InvalidArgument(format!(
+                "{} is not a watch notification",
+                kind
+            )));
+        }
+        let watch = extract_or_err(type_consumer.next_integer(), "watch")?;
         let key = extract_or_err(type_consumer.next_string(), "key")?;
         let operation = extract_or_err(type_consumer.next_integer(), "operation")?;
         let operation: Operation = (operation as u8).into();
@@ -53,22 +98,81 @@ impl From<Type> for Result<WatchResult, CommandCreationError> {
             .next_type()
             .map_err(|t| CommandCreationError::InvalidFrame(t, "before"))?;
         let after = extract_or_err(type_consumer.next_type(), "after")?;
+        let sequence = extract_or_err(type_consumer.next_integer(), "sequence")?;
+        let event = extract_or_err(type_consumer.next_string(), "event")?;
+        let offset = extract_or_err(type_consumer.next_integer(), "offset")?;
         Ok(WatchResult {
+            watch: watch as u64,
             key,
             operation,
             before,
             after,
+            sequence: sequence as u64,
+            event,
+            offset: offset as u64,
         })
     }
 }
 
 impl Watch {
     /// Returns an instance of [super::watch::Watch]
-    pub fn from(type_consumer: &mut TypeConsumer) -> Result<Self, CommandCreationError> {
+    pub fn from(
+        type_consumer: &mut TypeConsumer,
+        pattern: bool,
+    ) -> Result<Self, CommandCreationError> {
         let key = extract_or_err(type_consumer.next_string(), "key")?;
         let operation = extract_or_err(type_consumer.next_integer(), "operation")?;
-        let operation: Operation = (operation as u8).into();
-        Ok(Watch { key, operation })
+        let mut operations = BTreeSet::new();
+        operations.insert((operation as u8).into());
+        let mut from = None;
+        let mut snapshot = false;
+        while let Some(token) = type_consumer.next_string()? {
+            match token.parse::<u8>() {
+                Ok(operation) => {
+                    operations.insert(operation.into());
+                }
+                Err(_) if token.eq_ignore_ascii_case("FROM") => {
+                    from = Some(parse_or_err(type_consumer.next_string(), "offset")?)
+                }
+                Err(_) if token.eq_ignore_ascii_case("SNAPSHOT") => snapshot = true,
+                Err(_) => {
+                    return Err(CommandCreationError::InvalidArgument(format!(
+                        "syntax error near `{}`",
+                        token
+                    )))
+                }
+            }
+        }
+        if snapshot && from.is_some() {
+            return Err(CommandCreationError::InvalidArgument(
+                "SNAPSHOT and FROM can not be combined".into(),
+            ));
+        }
+        Ok(Watch {
+            key,
+            operations,
+            pattern,
+            from,
+            snapshot,
+        })
+    }
+}
+
+impl Unwatch {
+    /// Returns an instance of [super::watch::Unwatch]
+    pub fn from(
+        type_consumer: &mut TypeConsumer,
+        pattern: bool,
+    ) -> Result<Self, CommandCreationError> {
+        let key = type_consumer.next_string()?;
+        Ok(Unwatch { key, pattern })
+    }
+}
+
+impl From<Unwatch> for Type {
+    fn from(u: Unwatch) -> Self {
+        let name = if u.pattern { "PUNWATCH" } else { "UNWATCH" };
+        as_command(name, u.key.into_iter().collect())
     }
 }
 
@@ -93,9 +197,80 @@ impl Display for Operation {
 impl From<Watch> for Type {
     fn from(watch: Watch) -> Self {
         let mut ll = LinkedList::new();
-        ll.push_back(Type::BulkString(b"WATCH".to_vec()));
+        let name: &[u8] = if watch.pattern { b"PWATCH" } else { b"WATCH" };
+        ll.push_back(Type::BulkString(name.to_vec()));
         ll.push_back(Type::BulkString(watch.key.into_bytes()));
-        ll.push_back(Type::Integer(watch.operation as i64));
+        watch
+            .operations
+            .into_iter()
+            .for_each(|o| ll.push_back(Type::Integer(o as i64)));
+        if let Some(from) = watch.from {
+            ll.push_back(Type::BulkString(b"FROM".to_vec()));
+            ll.push_back(Type::Integer(from as i64));
+        }
+        if watch.snapshot {
+            ll.push_back(Type::BulkString(b"SNAPSHOT".to_vec()));
+        }
         Type::Array(ll)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::{Unwatch, Watch};
+    use crate::{database::Operation, resp::TypeConsumer};
+
+    #[test]
+    fn from_and_into_work() {
+        let operations: BTreeSet<Operation> = vec![Operation::Removal, Operation::Addition]
+            .into_iter()
+            .collect();
+        let options = [(false, None, true), (true, Some(42), false)];
+        for (pattern, from, snapshot) in options.iter().cloned() {
+            let watch = Watch {
+                key: "key:*".into(),
+                operations: operations.clone(),
+                pattern,
+                from,
+                snapshot,
+            };
+            let mut tc = TypeConsumer::new(watch.into());
+            let name = tc.next_string().unwrap().unwrap();
+            assert_eq!(name == "PWATCH", pattern);
+            assert_eq!(
+                Watch::from(&mut tc, pattern).unwrap(),
+                Watch {
+                    key: "key:*".into(),
+                    operations: operations.clone(),
+                    pattern,
+                    from,
+                    snapshot
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn unwatch_from_and_into_work() {
+        for key in &[None, Some("key".to_string())] {
+            for pattern in [false, true].iter().cloned() {
+                let unwatch = Unwatch {
+                    key: key.clone(),
+                    pattern,
+                };
+                let mut tc = TypeConsumer::new(unwatch.into());
+                let name = tc.next_string().unwrap().unwrap();
+                assert_eq!(name == "PUNWATCH", pattern);
+                assert_eq!(
+                    Unwatch::from(&mut tc, pattern).unwrap(),
+                    Unwatch {
+                        key: key.clone(),
+                        pattern
+                    }
+                );
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! The connection module.
 //! This module encapsulates a connection and provides convenient (owned) read write accessors
 use crate::Result;
+use bytes::{Buf, BytesMut};
 use log::{debug, trace};
 use std::io::Cursor;
 use tcp::{OwnedReadHalf, OwnedWriteHalf};
@@ -9,7 +10,10 @@ use tokio::{
     net::{tcp, TcpStream},
 };
 
-use crate::{parse::Parse, resp::Type};
+use crate::{
+    parse::{Parse, ParseError},
+    resp::Type,
+};
 
 /// Defines a connection (Client-Server)
 /// Provides utility methods to write and read [Type]
@@ -21,23 +25,43 @@ pub struct Connection {
 pub struct ReadHalf {
     inner: OwnedReadHalf,
     parse: Parse,
+    /// Bytes that have been read but not parsed yet.
+    /// A frame can span multiple reads and a read can contain multiple frames.
+    buffer: BytesMut,
 }
 
 impl ReadHalf {
     /// Receives [Type]
     /// Attempts to wait for a value, returning an error if there is an error.
     pub async fn recv(&mut self) -> Result<Option<Type>> {
-        // Read 512 bytes at a time
-        let mut buf = [0; 512];
-        let n = self.inner.read(&mut buf).await?;
-        if n > 0 {
+        loop {
+            if let Some(t) = self.parse_buffered()? {
+                return Ok(Some(t));
+            }
+            let n = self.inner.read_buf(&mut self.buffer).await?;
+            if n == 0 {
+                return if self.buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err("Connection closed in the middle of a frame".into())
+                };
+            }
             debug!("Read {} bytes", n);
-            trace!("Read {} bytes, {:?}", n, std::str::from_utf8(&buf));
-            let mut cur = Cursor::new(&buf[..]);
-            let t = self.parse.parse_next(&mut cur)?;
-            Ok(Some(t))
-        } else {
-            Ok(None)
+            trace!("Read {} bytes, {:?}", n, std::str::from_utf8(&self.buffer));
+        }
+    }
+
+    /// Parses a [Type] from the buffered bytes, returns `None` if more bytes are needed
+    fn parse_buffered(&mut self) -> Result<Option<Type>> {
+        let mut cur = Cursor::new(&self.buffer[..]);
+        match self.parse.parse_next(&mut cur) {
+            Ok(t) => {
+                let consumed = cur.position() as usize;
+                self.buffer.advance(consumed);
+                Ok(Some(t))
+            }
+            Err(ParseError::Incomplete) | Err(ParseError::EndOfBytes) => Ok(None),
+            Err(e) => Err(e.into()),
         }
     }
 }
@@ -50,7 +74,8 @@ impl WriteHalf {
     /// Attempts to write this type, returning the number of bytes written (or error).
     pub async fn send(&mut self, t: Type) -> Result<usize> {
         let bytes = t.into_bytes();
-        let u = self.inner.write(&bytes).await?;
+        self.inner.write_all(&bytes).await?;
+        let u = bytes.len();
         debug!("Wrote {} bytes", u);
         trace!("Wrote {} bytes, {:?}", u, std::str::from_utf8(&bytes));
         Ok(u)
@@ -69,6 +94,7 @@ impl Connection {
             ReadHalf {
                 inner: r,
                 parse: Parse::new(),
+                buffer: BytesMut::with_capacity(4 * 1024),
             },
             WriteHalf { inner: w },
         )
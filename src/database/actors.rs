@@ -0,0 +1,419 @@
+//! The actor engine, every shard of a keyspace is owned by an actor that runs its commands.
+//!
+//! With the [Engine::Actors] engine, the shards are not behind locks: each one is owned by
+//! an actor, a thread of a pool shared by every database (shard `i` goes to the actor
+//! `i % actors`). A command whose keys are all in one shard is sent to the actor of the
+//! shard, and the connection waits for the reply. The commands of a shard run one after
+//! the other in the order they were received, so their changes (and the watch
+//! notifications) follow that order.
+//!
+//! The commands on several shards (e.g. a RENAME across shards, EXEC or KEYS) go through
+//! the actors too: the actors lend them their shards, in the order of the actors, and wait
+//! for them to be given back (see [Store::lock_all](super::Store::lock_all)). An actor runs
+//! nothing else meanwhile, so a command asks each actor once for all the shards it needs,
+//! in every database. The lender waits for the shards on a thread of its own, never on a
+//! task of the runtime (see [Databases::unblocking](super::Databases::unblocking)).
+//!
+//! The mailbox of an actor is bounded, the connections wait for room once it is full.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SendError, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+};
+
+use log::error;
+use tokio::sync::{oneshot, Notify};
+
+use super::keyspace::Shard;
+
+/// How the commands are run against the keyspaces
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Engine {
+    /// Every command locks the shards of its keys, from the task of its connection
+    #[default]
+    Locks,
+    /// The commands on a single shard are run by the actor owning the shard, a thread
+    Actors,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "locks" => Ok(Engine::Locks),
+            "actors" => Ok(Engine::Actors),
+            _ => Err(format!("{} is not an engine, use locks or actors", s)),
+        }
+    }
+}
+
+impl Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Engine::Locks => "locks",
+            Engine::Actors => "actors",
+        })
+    }
+}
+
+/// The messages an actor queues, beyond that the senders wait
+const MAILBOX: usize = 1024;
+
+/// The reply to a command whose actor stopped, or panicked while running it
+const ACTOR_STOPPED: &str = "ERR the actor of the shard stopped";
+
+/// The id of the next shard adopted by an actor
+static NEXT_SHARD: AtomicU64 = AtomicU64::new(0);
+
+/// A shard lent by its actor, with its id
+pub(crate) type Lent = (u64, Box<Shard>);
+
+/// A command to run on a shard, it sends its reply itself
+type Job = Box<dyn FnOnce(&mut Shard) + Send>;
+
+/// What an actor is asked to do
+enum Message {
+    /// Own the shard, under the id
+    Adopt(u64, Box<Shard>),
+    /// Drop the shard, its keyspace is gone
+    Release(u64),
+    /// Run the job on the shard
+    Run(u64, Job),
+    /// Send the shards to the lender, and wait for all of them to be given back
+    Lend {
+        ids: Vec<u64>,
+        lend: SyncSender<Vec<Lent>>,
+        back: Receiver<Vec<Lent>>,
+    },
+}
+
+/// The mailbox of an actor
+#[derive(Debug)]
+struct Actor {
+    sender: SyncSender<Message>,
+    /// Notified every time the actor takes a message, for the connections waiting for room
+    room: Arc<Notify>,
+}
+
+/// A shard owned by an actor of [ShardActors]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ShardId {
+    actor: usize,
+    id: u64,
+}
+
+impl ShardId {
+    /// The actor owning the shard, the shards are lent in the order of their actors
+    pub(crate) fn actor(&self) -> usize {
+        self.actor
+    }
+
+    /// The id of the shard for its actor
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// The shards lent by an actor, see [ShardActors::lend]. They can be given back in
+/// several parts, by the clones of the loan.
+#[derive(Debug, Clone)]
+pub(crate) struct Loan {
+    back: mpsc::Sender<Vec<Lent>>,
+}
+
+impl Loan {
+    /// Gives some of the shards back to their actor, with their ids
+    pub(crate) fn give_back(self, shards: Vec<Lent>) {
+        // The actor is gone if it fails, and the shards with it
+        let _ = self.back.send(shards);
+    }
+}
+
+/// The actors owning the shards of the keyspaces. An actor stops once every sender is
+/// dropped, that is once the keyspaces and the [ShardActors] are gone.
+#[derive(Debug, Clone)]
+pub(crate) struct ShardActors {
+    actors: Arc<Vec<Actor>>,
+}
+
+impl ShardActors {
+    /// Spawns `actors` threads (at least one)
+    pub(crate) fn spawn(actors: usize) -> Self {
+        let actors = (0..actors.max(1))
+            .map(|index| {
+                let (sender, receiver) = mpsc::sync_channel(MAILBOX);
+                let room = Arc::new(Notify::new());
+                let notified = room.clone();
+                thread::Builder::new()
+                    .name(format!("shard-actor-{}", index))
+                    .spawn(move || act(receiver, notified))
+                    .expect("Cannot spawn an actor");
+                Actor { sender, room }
+            })
+            .collect();
+        ShardActors {
+            actors: Arc::new(actors),
+        }
+    }
+
+    /// Hands the shard at `index` of a keyspace to its actor
+    pub(crate) fn adopt(&self, index: usize, shard: Shard) -> ShardId {
+        let shard_id = ShardId {
+            actor: index % self.actors.len(),
+            id: NEXT_SHARD.fetch_add(1, Ordering::Relaxed),
+        };
+        self.send(shard_id.actor, Message::Adopt(shard_id.id, Box::new(shard)));
+        shard_id
+    }
+
+    /// Tells the actor of the shard to drop it
+    pub(crate) fn release(&self, shard: ShardId) {
+        self.send(shard.actor, Message::Release(shard.id));
+    }
+
+    /// Borrows the shards of an actor, with their ids, it waits for all of them to be given
+    /// back with [Loan::give_back]. None if the actor stopped.
+    /// This blocks until the actor gets to the message, it must not run on a task of the
+    /// runtime.
+    pub(crate) fn lend(&self, actor: usize, ids: Vec<u64>) -> Option<(Vec<Lent>, Loan)> {
+        let (lend, lent) = mpsc::sync_channel(1);
+        let (back, returned) = mpsc::channel();
+        let message = Message::Lend {
+            ids,
+            lend,
+            back: returned,
+        };
+        if !self.send(actor, message) {
+            return None;
+        }
+        let shards = lent.recv().ok()?;
+        Some((shards, Loan { back }))
+    }
+
+    /// Runs `f` on the actor of the shard, and waits for its result. Fails if the actor
+    /// stopped, or if `f` panicked.
+    pub(crate) async fn run<F, R>(&self, shard: ShardId, f: F) -> Result<R, String>
+    where
+        F: FnOnce(&mut Shard) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move |shard| {
+            // The connection may be gone, the command ran anyway
+            let _ = sender.send(f(shard));
+        });
+        let actor = &self.actors[shard.actor];
+        let mut message = Message::Run(shard.id, job);
+        loop {
+            match actor.sender.try_send(message) {
+                Ok(()) => break,
+                Err(TrySendError::Full(full)) => {
+                    message = full;
+                    actor.room.notified().await;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(ACTOR_STOPPED.into()),
+            }
+        }
+        receiver.await.map_err(|_| ACTOR_STOPPED.into())
+    }
+
+    /// Sends the message to the actor, waits for room if its mailbox is full.
+    /// Returns false if the actor stopped.
+    fn send(&self, actor: usize, message: Message) -> bool {
+        self.actors[actor].sender.send(message).is_ok()
+    }
+}
+
+/// Runs `f` on a blocking thread and waits for it, for the commands that wait for the
+/// shards lent by the actors (see [ShardActors::lend]): the tasks of the runtime go on
+/// meanwhile. A panic of `f` is resumed.
+pub(crate) async fn unblocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// The loop of an actor, it owns its shards and handles the messages one after the other
+fn act(receiver: Receiver<Message>, room: Arc<Notify>) {
+    let mut shards: HashMap<u64, Box<Shard>> = HashMap::new();
+    while let Ok(message) = receiver.recv() {
+        room.notify_one();
+        match message {
+            Message::Adopt(id, shard) => {
+                shards.insert(id, shard);
+            }
+            Message::Release(id) => {
+                shards.remove(&id);
+            }
+            Message::Run(id, job) => {
+                let shard = match shards.get_mut(&id) {
+                    Some(shard) => shard,
+                    None => continue,
+                };
+                // The reply is dropped with the job, the connection gets an error
+                if panic::catch_unwind(AssertUnwindSafe(|| job(shard))).is_err() {
+                    error!("A command panicked on the shard {}", id);
+                }
+            }
+            Message::Lend { ids, lend, back } => {
+                let lent: Vec<_> = ids
+                    .iter()
+                    .filter_map(|id| Some((*id, shards.remove(id)?)))
+                    .collect();
+                let mut out = lent.len();
+                match lend.send(lent) {
+                    Ok(()) => {
+                        while out > 0 {
+                            match back.recv() {
+                                Ok(returned) => {
+                                    out = out.saturating_sub(returned.len());
+                                    shards.extend(returned);
+                                }
+                                Err(_) => {
+                                    error!("The shards {:?} were not all given back", ids);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    // The lender is gone
+                    Err(SendError(lent)) => shards.extend(lent),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Engine, ShardActors, ACTOR_STOPPED};
+    use crate::{
+        commands::{
+            get::Get,
+            keys::{DbSize, Keys, Rename},
+            set::Set,
+            watch::{Watch, WatchResult},
+            Command,
+        },
+        database::{keyspace::Shard, Database, Operation, Watcher},
+        resp::Type,
+    };
+
+    fn set(key: &str, value: String) -> Command {
+        Command::Set(Set {
+            key: key.into(),
+            value: value.into_bytes(),
+        })
+    }
+
+    #[test]
+    fn engines_are_parsed_and_shown() {
+        for engine in [Engine::Locks, Engine::Actors] {
+            assert_eq!(engine.to_string().parse::<Engine>(), Ok(engine));
+        }
+        assert_eq!("ACTORS".parse::<Engine>(), Ok(Engine::Actors));
+        assert!("threads".parse::<Engine>().is_err());
+    }
+
+    #[tokio::test]
+    async fn commands_run_on_the_actors_of_their_shards_in_order() {
+        let mut db = Database::new().with_actors(&ShardActors::spawn(2));
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(100);
+        db.watch(
+            Watch {
+                key: "key".into(),
+                operations: vec![Operation::All].into_iter().collect(),
+                pattern: false,
+                from: None,
+                snapshot: false,
+            },
+            Watcher::new(sender),
+        );
+        for i in 0..10 {
+            db.run(set("key", i.to_string()), None).await;
+        }
+        // The actors lend their shards if the keys are in different shards
+        let renamed = db
+            .run(
+                Command::Rename(Rename {
+                    key: "key".into(),
+                    new_key: "other".into(),
+                    only_if_new: false,
+                }),
+                None,
+            )
+            .await;
+        assert_eq!(renamed, Type::SimpleString("OK".into()));
+        let get = Command::Get(Get {
+            key: "other".into(),
+        });
+        assert_eq!(db.run(get, None).await, Type::BulkString("9".into()));
+        drop(db);
+        let mut values = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            let result: Result<WatchResult, _> = t.into();
+            values.push(result.unwrap().after);
+        }
+        let mut expected: Vec<_> = (0..10)
+            .map(|i| Type::BulkString(i.to_string().into()))
+            .collect();
+        expected.push(Type::Null);
+        assert_eq!(values, expected);
+    }
+
+    #[tokio::test]
+    async fn actors_lend_their_shards_to_the_commands_on_several_shards() {
+        let mut db = Database::new().with_actors(&ShardActors::spawn(2));
+        let mut other = db.clone();
+        let (done, finished) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for i in 0..200 {
+                other.apply(set(&format!("other:{}", i), i.to_string()));
+                other.apply(Command::Keys(Keys {
+                    pattern: "*".into(),
+                }));
+            }
+            done.send(()).unwrap();
+        });
+        for i in 0..200 {
+            let reply = db
+                .run(set(&format!("own:{}", i), i.to_string()), None)
+                .await;
+            assert_eq!(reply, Type::SimpleString("Ok".into()));
+        }
+        finished.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(db.apply(Command::DbSize(DbSize)), Type::Integer(400));
+    }
+
+    #[tokio::test]
+    async fn a_command_that_panics_gets_an_error() {
+        let actors = ShardActors::spawn(1);
+        let shard = actors.adopt(0, Shard::default());
+        let panicked = actors.run(shard, |_| panic!("on purpose")).await;
+        assert_eq!(panicked, Err::<(), _>(ACTOR_STOPPED.into()));
+        // The actor still runs the next ones
+        let keys = actors.run(shard, |shard| shard.map.len()).await;
+        assert_eq!(keys, Ok(0));
+        actors.release(shard);
+        assert_eq!(
+            actors.run(shard, |shard| shard.map.len()).await,
+            Err(ACTOR_STOPPED.into())
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Bit level operations on string values.
+//!
+//! Bits are addressed from the most significant bit of the first byte, i.e. bit `0` is the
+//! `0x80` bit of byte `0`. Strings are extended with zero bytes when a bit beyond the end is written.
+
+use crate::commands::bitmap::{BitOperation, BitRange, IntegerType, Overflow, RangeUnit};
+
+/// Returns the bit at the offset, bits beyond the end of the string are `0`
+pub(crate) fn get_bit(bytes: &[u8], offset: u64) -> u8 {
+    let byte = (offset >> 3) as usize;
+    let bit = 7 - (offset & 7) as u8;
+    bytes.get(byte).map_or(0, |b| (b >> bit) & 1)
+}
+
+/// Sets the bit at the offset (growing the string if needed) and returns the previous value
+pub(crate) fn set_bit(bytes: &mut Vec<u8>, offset: u64, value: u8) -> u8 {
+    let byte = (offset >> 3) as usize;
+    let bit = 7 - (offset & 7) as u8;
+    if bytes.len() <= byte {
+        bytes.resize(byte + 1, 0);
+    }
+    let previous = (bytes[byte] >> bit) & 1;
+    if value == 1 {
+        bytes[byte] |= 1 << bit;
+    } else {
+        bytes[byte] &= !(1 << bit);
+    }
+    previous
+}
+
+/// Converts the (inclusive, possibly negative) range into a range of bits.
+/// Returns `None` if the range is empty.
+fn bit_range(len: usize, start: i64, end: i64, unit: &RangeUnit) -> Option<(u64, u64)> {
+    let total = match unit {
+        RangeUnit::Byte => len as i64,
+        RangeUnit::Bit => len as i64 * 8,
+    };
+    let normalize = |i: i64| if i < 0 { (i + total).max(0) } else { i };
+    let start = normalize(start);
+    let end = normalize(end).min(total - 1);
+    if total == 0 || start > end {
+        return None;
+    }
+    match unit {
+        RangeUnit::Byte => Some((start as u64 * 8, end as u64 * 8 + 7)),
+        RangeUnit::Bit => Some((start as u64, end as u64)),
+    }
+}
+
+/// Counts the set bits, optionally restricted to a range
+pub(crate) fn bit_count(bytes: &[u8], range: Option<&BitRange>) -> u64 {
+    let (start, end) = match range {
+        Some(r) => match bit_range(bytes.len(), r.start, r.end, &r.unit) {
+            Some(range) => range,
+            None => return 0,
+        },
+        None => return bytes.iter().map(|b| b.count_ones() as u64).sum(),
+    };
+    let (first, last) = ((start >> 3) as usize, (end >> 3) as usize);
+    // Masks for the partial first and last bytes
+    let first_mask = 0xffu8 >> (start & 7);
+    let last_mask = 0xffu8 << (7 - (end & 7));
+    if first == last {
+        return (bytes[first] & first_mask & last_mask).count_ones() as u64;
+    }
+    let middle: u64 = bytes[first + 1..last]
+        .iter()
+        .map(|b| b.count_ones() as u64)
+        .sum();
+    (bytes[first] & first_mask).count_ones() as u64
+        + middle
+        + (bytes[last] & last_mask).count_ones() as u64
+}
+
+/// Returns the position of the first bit set to `bit` or `-1`.
+///
+/// When looking for a clear bit without an explicit end, the string is considered to be padded
+/// with zeroes, so the first bit after the string is returned if all the bits are set.
+pub(crate) fn bit_pos(
+    bytes: &[u8],
+    bit: u8,
+    start: Option<i64>,
+    end: Option<i64>,
+    unit: &RangeUnit,
+) -> i64 {
+    if bytes.is_empty() {
+        return if bit == 1 { -1 } else { 0 };
+    }
+    let (start_bit, end_bit) =
+        match bit_range(bytes.len(), start.unwrap_or(0), end.unwrap_or(-1), unit) {
+            Some(range) => range,
+            None => return -1,
+        };
+    // Whole bytes that cannot contain the bit are skipped
+    let skip = if bit == 1 { 0x00 } else { 0xff };
+    let mut position = start_bit;
+    while position <= end_bit {
+        if position & 7 == 0 && position + 7 <= end_bit && bytes[(position >> 3) as usize] == skip {
+            position += 8;
+            continue;
+        }
+        if get_bit(bytes, position) == bit {
+            return position as i64;
+        }
+        position += 1;
+    }
+    if bit == 0 && end.is_none() {
+        (end_bit + 1) as i64
+    } else {
+        -1
+    }
+}
+
+/// Applies the bitwise operation on the sources. Shorter sources are padded with zeroes.
+pub(crate) fn bit_op(operation: &BitOperation, sources: &[Vec<u8>]) -> Vec<u8> {
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    let byte = |s: &Vec<u8>, i: usize| s.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| {
+            let mut values = sources.iter().map(|s| byte(s, i));
+            let first = values.next().unwrap_or(0);
+            match operation {
+                BitOperation::And => values.fold(first, |acc, b| acc & b),
+                BitOperation::Or => values.fold(first, |acc, b| acc | b),
+                BitOperation::Xor => values.fold(first, |acc, b| acc ^ b),
+                BitOperation::Not => !first,
+            }
+        })
+        .collect()
+}
+
+fn min(integer_type: &IntegerType) -> i128 {
+    if integer_type.signed {
+        -(1i128 << (integer_type.bits - 1))
+    } else {
+        0
+    }
+}
+
+fn max(integer_type: &IntegerType) -> i128 {
+    if integer_type.signed {
+        (1i128 << (integer_type.bits - 1)) - 1
+    } else {
+        (1i128 << integer_type.bits) - 1
+    }
+}
+
+/// Applies the overflow policy, returns `None` if the value overflows with [Overflow::Fail]
+pub(crate) fn fit(integer_type: &IntegerType, value: i128, overflow: &Overflow) -> Option<i64> {
+    let (min, max) = (min(integer_type), max(integer_type));
+    if value >= min && value <= max {
+        return Some(value as i64);
+    }
+    match overflow {
+        Overflow::Wrap => {
+            let range = 1i128 << integer_type.bits;
+            Some(((value - min).rem_euclid(range) + min) as i64)
+        }
+        Overflow::Sat if value > max => Some(max as i64),
+        Overflow::Sat => Some(min as i64),
+        Overflow::Fail => None,
+    }
+}
+
+/// Reads the integer stored at the bit offset
+pub(crate) fn get_integer(bytes: &[u8], offset: u64, integer_type: &IntegerType) -> i64 {
+    let bits = integer_type.bits as u64;
+    let value = (0..bits).fold(0u64, |acc, i| {
+        (acc << 1) | get_bit(bytes, offset + i) as u64
+    });
+    if integer_type.signed && bits < 64 && value & (1 << (bits - 1)) != 0 {
+        // Sign extension
+        (value | !((1u64 << bits) - 1)) as i64
+    } else {
+        value as i64
+    }
+}
+
+/// Writes the integer at the bit offset, the value must fit in the type
+pub(crate) fn set_integer(
+    bytes: &mut Vec<u8>,
+    offset: u64,
+    integer_type: &IntegerType,
+    value: i64,
+) {
+    let bits = integer_type.bits as u64;
+    let value = value as u64;
+    (0..bits).for_each(|i| {
+        let bit = (value >> (bits - 1 - i)) & 1;
+        set_bit(bytes, offset + i, bit as u8);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(start: i64, end: i64, unit: RangeUnit) -> BitRange {
+        BitRange { start, end, unit }
+    }
+
+    #[test]
+    fn get_and_set_bit_works() {
+        let mut bytes = vec![];
+        assert_eq!(set_bit(&mut bytes, 7, 1), 0);
+        assert_eq!(bytes, vec![0x01]);
+        assert_eq!(set_bit(&mut bytes, 7, 1), 1);
+        // Auto extends with zero bytes
+        assert_eq!(set_bit(&mut bytes, 24, 1), 0);
+        assert_eq!(bytes, vec![0x01, 0x00, 0x00, 0x80]);
+        assert_eq!(get_bit(&bytes, 24), 1);
+        assert_eq!(get_bit(&bytes, 25), 0);
+        assert_eq!(get_bit(&bytes, 1000), 0);
+        assert_eq!(set_bit(&mut bytes, 24, 0), 1);
+        assert_eq!(bytes, vec![0x01, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn bit_count_works() {
+        let bytes = b"foobar";
+        assert_eq!(bit_count(bytes, None), 26);
+        assert_eq!(bit_count(bytes, Some(&range(0, 0, RangeUnit::Byte))), 4);
+        assert_eq!(bit_count(bytes, Some(&range(1, 1, RangeUnit::Byte))), 6);
+        assert_eq!(bit_count(bytes, Some(&range(1, 1, RangeUnit::Byte))), 6);
+        assert_eq!(bit_count(bytes, Some(&range(-2, -1, RangeUnit::Byte))), 7);
+        assert_eq!(bit_count(bytes, Some(&range(5, 30, RangeUnit::Bit))), 17);
+        assert_eq!(bit_count(bytes, Some(&range(2, 1, RangeUnit::Byte))), 0);
+        assert_eq!(bit_count(bytes, Some(&range(0, 100, RangeUnit::Byte))), 26);
+        assert_eq!(bit_count(b"", Some(&range(0, -1, RangeUnit::Byte))), 0);
+    }
+
+    #[test]
+    fn bit_pos_works() {
+        let bytes = [0xff, 0xf0, 0x00];
+        assert_eq!(bit_pos(&bytes, 0, None, None, &RangeUnit::Byte), 12);
+        assert_eq!(
+            bit_pos(&[0x00, 0xff, 0xf0], 1, Some(0), None, &RangeUnit::Byte),
+            8
+        );
+        assert_eq!(
+            bit_pos(&[0x00, 0xff, 0xf0], 1, Some(2), None, &RangeUnit::Byte),
+            16
+        );
+        assert_eq!(
+            bit_pos(&[0x00, 0xff, 0xf0], 1, Some(2), Some(-1), &RangeUnit::Byte),
+            16
+        );
+        assert_eq!(
+            bit_pos(&[0x00, 0xff, 0xf0], 1, Some(7), Some(15), &RangeUnit::Bit),
+            8
+        );
+        assert_eq!(
+            bit_pos(&[0x00, 0x00, 0x00], 1, None, None, &RangeUnit::Byte),
+            -1
+        );
+        // All bits set, no end so the string is padded with zeroes
+        assert_eq!(bit_pos(&[0xff, 0xff], 0, None, None, &RangeUnit::Byte), 16);
+        // All bits set, with an explicit end
+        assert_eq!(
+            bit_pos(&[0xff, 0xff], 0, Some(0), Some(-1), &RangeUnit::Byte),
+            -1
+        );
+        assert_eq!(bit_pos(&[], 0, None, None, &RangeUnit::Byte), 0);
+        assert_eq!(bit_pos(&[], 1, None, None, &RangeUnit::Byte), -1);
+    }
+
+    #[test]
+    fn bit_op_works() {
+        let a = b"foobar".to_vec();
+        let b = b"abcdef".to_vec();
+        let and = bit_op(&BitOperation::And, &[a.clone(), b.clone()]);
+        assert_eq!(and, b"`bc`ab".to_vec());
+        let or = bit_op(&BitOperation::Or, &[a.clone(), b.clone()]);
+        assert_eq!(or, b"goofev".to_vec());
+        let xor = bit_op(&BitOperation::Xor, &[a.clone(), b]);
+        assert_eq!(xor, vec![0x07, 0x0d, 0x0c, 0x06, 0x04, 0x14]);
+        let not = bit_op(&BitOperation::Not, &[vec![0x0f, 0xff]]);
+        assert_eq!(not, vec![0xf0, 0x00]);
+        // Shorter strings are padded with zeroes
+        let and = bit_op(&BitOperation::And, &[a, vec![0xff]]);
+        assert_eq!(and, vec![b'f', 0, 0, 0, 0, 0]);
+        assert_eq!(bit_op(&BitOperation::Or, &[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn integers_work() {
+        let u8_type = IntegerType {
+            signed: false,
+            bits: 8,
+        };
+        let i5_type = IntegerType {
+            signed: true,
+            bits: 5,
+        };
+        let mut bytes = vec![];
+        set_integer(&mut bytes, 4, &u8_type, 255);
+        assert_eq!(bytes, vec![0x0f, 0xf0]);
+        assert_eq!(get_integer(&bytes, 4, &u8_type), 255);
+        set_integer(&mut bytes, 0, &i5_type, -3);
+        assert_eq!(get_integer(&bytes, 0, &i5_type), -3);
+        let i64_type = IntegerType {
+            signed: true,
+            bits: 64,
+        };
+        set_integer(&mut bytes, 3, &i64_type, i64::MIN);
+        assert_eq!(get_integer(&bytes, 3, &i64_type), i64::MIN);
+    }
+
+    #[test]
+    fn overflow_works() {
+        let u2 = IntegerType {
+            signed: false,
+            bits: 2,
+        };
+        assert_eq!(fit(&u2, 3, &Overflow::Fail), Some(3));
+        assert_eq!(fit(&u2, 4, &Overflow::Wrap), Some(0));
+        assert_eq!(fit(&u2, 5, &Overflow::Wrap), Some(1));
+        assert_eq!(fit(&u2, -1, &Overflow::Wrap), Some(3));
+        assert_eq!(fit(&u2, 102, &Overflow::Sat), Some(3));
+        assert_eq!(fit(&u2, -1, &Overflow::Sat), Some(0));
+        assert_eq!(fit(&u2, 4, &Overflow::Fail), None);
+        let i8_type = IntegerType {
+            signed: true,
+            bits: 8,
+        };
+        assert_eq!(fit(&i8_type, 128, &Overflow::Wrap), Some(-128));
+        assert_eq!(fit(&i8_type, -129, &Overflow::Wrap), Some(127));
+        assert_eq!(fit(&i8_type, 200, &Overflow::Sat), Some(127));
+        assert_eq!(fit(&i8_type, -200, &Overflow::Sat), Some(-128));
+        assert_eq!(fit(&i8_type, -129, &Overflow::Fail), None);
+        let i64_type = IntegerType {
+            signed: true,
+            bits: 64,
+        };
+        let overflowing = i64::MAX as i128 + 1;
+        assert_eq!(fit(&i64_type, overflowing, &Overflow::Wrap), Some(i64::MIN));
+        assert_eq!(fit(&i64_type, overflowing, &Overflow::Sat), Some(i64::MAX));
+    }
+}
@@ -0,0 +1,827 @@
+//! The numbered databases, each [Database] has its own keyspace and its own watches.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, MutexGuard},
+};
+
+use crate::{
+    commands::{
+        databases::{Move, SwapDb},
+        keys::Flush,
+    },
+    resp::Type,
+    Result,
+};
+
+use super::{
+    actors::{self, ShardActors},
+    keyspace::{Change, Shards},
+    snapshot::Data,
+    Database, Engine, KeyspaceNotifier, Memory, RedisString, Store, Subscriptions, Tracker, Value,
+    Watcher,
+};
+
+const OUT_OF_RANGE: &str = "ERR DB index is out of range";
+
+type Keyspace = HashMap<RedisString, Value>;
+
+/// All the databases of a server, shared by every connection
+#[derive(Clone)]
+pub(crate) struct Databases {
+    databases: Arc<Vec<Database>>,
+    /// The memory used by all of them
+    memory: Memory,
+    engine: Engine,
+}
+
+impl Databases {
+    /// Databases that keep the default number of changes
+    #[cfg(test)]
+    pub(crate) fn new(count: usize) -> Self {
+        Databases::with_change_log(
+            count,
+            super::DEFAULT_SHARDS,
+            super::DEFAULT_CHANGE_LOG,
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    /// Every database has `shards` shards and keeps its last `change_log` changes, see
+    /// [Database::with_change_log], publishes its keyspace notifications with `events`
+    /// and accounts the size of its keys in `memory`
+    pub(crate) fn with_change_log(
+        count: usize,
+        shards: usize,
+        change_log: usize,
+        events: KeyspaceNotifier,
+        memory: Memory,
+    ) -> Self {
+        Databases {
+            databases: Arc::new(
+                (0..count)
+                    .map(|index| {
+                        let events = events.for_db(index);
+                        Database::with_change_log(shards, change_log, events, memory.clone())
+                    })
+                    .collect(),
+            ),
+            memory,
+            engine: Engine::Locks,
+        }
+    }
+
+    /// The same databases, running their commands with the engine (see [Engine]). With the
+    /// actors engine, there are as many actors as cores (but not more than shards), shared
+    /// by all the databases. They cannot be shared yet.
+    pub(crate) fn with_engine(self, engine: Engine) -> Self {
+        match engine {
+            Engine::Locks => self,
+            Engine::Actors => {
+                let databases = Arc::try_unwrap(self.databases)
+                    .ok()
+                    .expect("The databases are already shared");
+                let shards = databases.first().map_or(1, |database| database.inner.len());
+                let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+                let actors = ShardActors::spawn(cores.min(shards));
+                Databases {
+                    databases: Arc::new(
+                        databases
+                            .into_iter()
+                            .map(|database| database.with_actors(&actors))
+                            .collect(),
+                    ),
+                    memory: self.memory,
+                    engine,
+                }
+            }
+        }
+    }
+
+    /// Runs `f`, which can lock shards of several databases (see [Database::lock_all]).
+    /// With the actors engine, it runs on a blocking thread: it waits for the shards lent by
+    /// the actors, the tasks of the runtime do not.
+    pub(crate) async fn unblocking<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        match self.engine {
+            Engine::Locks => f(),
+            Engine::Actors => actors::unblocking(f).await,
+        }
+    }
+
+    /// Returns the database at the index, if it is in range
+    pub(crate) fn get(&self, index: i64) -> Option<Database> {
+        self.index(index).map(|i| self.databases[i].clone())
+    }
+
+    /// The reply for a SELECT of an index that is not in range
+    pub(crate) fn out_of_range() -> Type {
+        Type::Error(OUT_OF_RANGE.into())
+    }
+
+    fn index(&self, index: i64) -> Option<usize> {
+        if index >= 0 && (index as usize) < self.databases.len() {
+            Some(index as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Evicts keys from the databases, one after the other, until the memory used is under
+    /// the `maxmemory` (see [super::memory]). Returns false if it is still over it: nothing
+    /// could be evicted, the commands that use more memory are refused.
+    pub(crate) fn make_room(&self) -> bool {
+        while self.memory.over_limit() {
+            let policy = self.memory.policy();
+            let mut evicted = false;
+            for database in self.databases.iter() {
+                if !self.memory.over_limit() {
+                    break;
+                }
+                evicted |= database.clone().execute(|keyspace| keyspace.evict(policy));
+            }
+            if !evicted {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// [Databases::make_room] from a task, it only runs on a blocking thread if something
+    /// has to be evicted (see [Databases::unblocking])
+    pub(crate) async fn make_room_unblocking(&self) -> bool {
+        if !self.memory.over_limit() {
+            return true;
+        }
+        let databases = self.clone();
+        self.unblocking(move || databases.make_room()).await
+    }
+
+    /// Moves a key from the database at `from` to another one, if it does not exist there.
+    /// Watchers of the source see a removal, watchers of the target see an addition.
+    pub(crate) fn move_key(&self, from: usize, m: Move) -> Type {
+        let to = match self.index(m.db) {
+            Some(to) => to,
+            None => return Databases::out_of_range(),
+        };
+        if from == to {
+            return Type::Error("ERR source and destination objects are the same".into());
+        }
+        let keys = [m.key];
+        let source = self.databases[from].clone();
+        let target = self.databases[to].clone();
+        let (mut source_inner, mut target_inner) =
+            lock_both(&source, from, &target, to, Some(&keys[..]));
+        let [key] = keys;
+        let key: RedisString = key.into();
+        if target_inner.contains_key(&key) {
+            return Type::Integer(0);
+        }
+        let value = match source_inner.remove(&key) {
+            Some(value) => value,
+            None => return Type::Integer(0),
+        };
+        target_inner.insert(key.clone(), value.clone());
+        source_inner.touch(&key);
+        target_inner.touch(&key);
+        source.inner.wrote(1);
+        target.inner.wrote(1);
+        let mut removal = Change::new(key.clone(), "move_from", Some(value.clone()), None);
+        let mut addition = Change::new(key, "move_to", None, Some(value));
+        source_inner.log(&mut removal);
+        target_inner.log(&mut addition);
+        // The shards are delivered before the keyspaces are released, to keep the order
+        let _delivering = in_order(
+            from,
+            to,
+            || source_inner.deliver(Some(std::slice::from_ref(&removal))),
+            || target_inner.deliver(Some(std::slice::from_ref(&addition))),
+        );
+        drop(source_inner);
+        drop(target_inner);
+        let (mut source_subscriptions, mut target_subscriptions) =
+            lock_both_subscriptions(&source, from, &target, to);
+        source_subscriptions.notify(removal);
+        target_subscriptions.notify(addition);
+        Type::Integer(1)
+    }
+
+    /// Swaps the keyspaces of two databases, atomically (both are locked for the swap).
+    /// The watches stay with their database, watchers are notified of the keys that changed.
+    pub(crate) fn swap(&self, s: SwapDb) -> Type {
+        let (first, second) = match (self.index(s.index1), self.index(s.index2)) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return Databases::out_of_range(),
+        };
+        if first != second {
+            let first_db = self.databases[first].clone();
+            let second_db = self.databases[second].clone();
+            let (mut first_inner, mut second_inner) =
+                lock_both(&first_db, first, &second_db, second, None);
+            let _delivering = in_order(
+                first,
+                second,
+                || first_inner.deliver(None),
+                || second_inner.deliver(None),
+            );
+            let (mut first_subscriptions, mut second_subscriptions) =
+                lock_both_subscriptions(&first_db, first, &second_db, second);
+            let (mut first_changes, mut second_changes) = (Vec::new(), Vec::new());
+            // Shard by shard, a key is in the shard of the same index in every database.
+            // The versions are swapped with the values, the change logs stay.
+            for (first_shard, second_shard) in
+                first_inner.locked().into_iter().zip(second_inner.locked())
+            {
+                std::mem::swap(first_shard, second_shard);
+                std::mem::swap(&mut first_shard.log, &mut second_shard.log);
+                first_changes.extend(watched_changes(
+                    &first_subscriptions,
+                    &second_shard.map,
+                    &first_shard.map,
+                ));
+                second_changes.extend(watched_changes(
+                    &second_subscriptions,
+                    &first_shard.map,
+                    &second_shard.map,
+                ));
+            }
+            first_changes
+                .iter_mut()
+                .for_each(|change| first_inner.log(change));
+            second_changes
+                .iter_mut()
+                .for_each(|change| second_inner.log(change));
+            drop(first_inner);
+            drop(second_inner);
+            first_db.inner.wrote(1);
+            for change in first_changes {
+                first_subscriptions.notify(change);
+            }
+            for change in second_changes {
+                second_subscriptions.notify(change);
+            }
+        }
+        Type::SimpleString("OK".into())
+    }
+
+    /// FLUSHALL, flushes every database
+    pub(crate) fn flush_all(&self, f: Flush) -> Type {
+        for database in self.databases.iter() {
+            database.clone().execute(|keyspace| {
+                keyspace.flush(Flush {
+                    all: false,
+                    asynchronous: f.asynchronous,
+                })
+            });
+        }
+        Type::SimpleString("OK".into())
+    }
+
+    /// The number of changes to the keys of all the databases so far
+    pub(crate) fn writes(&self) -> u64 {
+        self.databases
+            .iter()
+            .map(|database| database.inner.writes())
+            .sum()
+    }
+
+    /// A copy of the keys of every database, with the [Databases::writes] before it.
+    /// The shards are copied one after the other, each one is only locked for its own copy.
+    /// The copy is not a single point in time: a command on several shards that runs
+    /// meanwhile can be in it for some of its keys only. Its writes are not counted as
+    /// saved though, the next snapshot has all of them.
+    pub(crate) fn snapshot(&self) -> (Data, u64) {
+        let writes = self.writes();
+        let data = self
+            .databases
+            .iter()
+            .enumerate()
+            .map(|(index, database)| {
+                let keys: Vec<_> = (0..database.inner.len())
+                    .flat_map(|shard| {
+                        let store = database.inner.lock_shard(shard);
+                        store
+                            .iter()
+                            .map(|(key, value)| (key.clone(), value.clone()))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                (index, keys)
+            })
+            .filter(|(_, keys)| !keys.is_empty())
+            .collect();
+        (data, writes)
+    }
+
+    /// Adds the keys of a snapshot to the databases, as they are loaded at startup nobody
+    /// is notified of them. Fails if a database is out of range.
+    pub(crate) fn restore(&self, data: Data) -> Result<()> {
+        if let Some((index, _)) = data
+            .iter()
+            .find(|(index, _)| *index >= self.databases.len())
+        {
+            return Err(format!(
+                "the snapshot has keys in the database {}, there are only {}",
+                index,
+                self.databases.len()
+            )
+            .into());
+        }
+        for (index, keys) in data {
+            let mut store = self.databases[index].inner.lock(None);
+            for (key, value) in keys {
+                store.insert(key.clone(), value);
+                store.touch(&key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the subscriptions of a watcher to keys, or to patterns, in every database.
+    /// Returns how many there were.
+    pub(crate) fn unwatch(&self, watcher: &Watcher, pattern: bool) -> usize {
+        self.databases
+            .iter()
+            .map(|database| database.unwatch(watcher, None, pattern))
+            .sum()
+    }
+
+    /// Tells the tracker about the changes of the keys with the prefixes, in every database
+    /// (of every key without a prefix)
+    pub(crate) fn broadcast(&self, prefixes: &[String], tracker: &Tracker) {
+        for database in self.databases.iter() {
+            if prefixes.is_empty() {
+                database.broadcast(Vec::new(), tracker);
+            }
+            for prefix in prefixes {
+                database.broadcast(prefix.as_bytes().to_vec(), tracker);
+            }
+        }
+    }
+
+    /// Forgets everything tracked by the tracker, in every database
+    pub(crate) fn untrack(&self, tracker: &Tracker) {
+        for database in self.databases.iter() {
+            database.untrack(tracker);
+        }
+    }
+
+    /// Removes all the subscriptions of a watcher, in every database
+    pub(crate) fn unwatch_all(&self, watcher: &Watcher) {
+        self.unwatch(watcher, false);
+        self.unwatch(watcher, true);
+    }
+}
+
+/// Locks the shards of the keys (or all of them) of two (different) databases together,
+/// always in the order of their index (see [Store::lock_all](super::Store::lock_all))
+fn lock_both<'a>(
+    a: &'a Database,
+    a_index: usize,
+    b: &'a Database,
+    b_index: usize,
+    keys: Option<&[String]>,
+) -> (Shards<'a>, Shards<'a>) {
+    let (first, second) = if a_index < b_index { (a, b) } else { (b, a) };
+    let mut locked = Store::lock_all(&[(&first.inner, keys), (&second.inner, keys)]);
+    let second = locked.pop().expect("Both are locked");
+    let first = locked.pop().expect("Both are locked");
+    if a_index < b_index {
+        (first, second)
+    } else {
+        (second, first)
+    }
+}
+
+/// Locks the subscriptions of two (different) databases, always in the order of their index
+/// like [lock_both]
+fn lock_both_subscriptions<'a>(
+    a: &'a Database,
+    a_index: usize,
+    b: &'a Database,
+    b_index: usize,
+) -> (MutexGuard<'a, Subscriptions>, MutexGuard<'a, Subscriptions>) {
+    if a_index < b_index {
+        let a = a.lock_and_access_subscriptions();
+        (a, b.lock_and_access_subscriptions())
+    } else {
+        let b = b.lock_and_access_subscriptions();
+        (a.lock_and_access_subscriptions(), b)
+    }
+}
+
+/// Runs `a` and `b`, in the order of their (different) index like [lock_both]
+fn in_order<A, B, T, U>(a_index: usize, b_index: usize, a: A, b: B) -> (T, U)
+where
+    A: FnOnce() -> T,
+    B: FnOnce() -> U,
+{
+    if a_index < b_index {
+        let a = a();
+        (a, b())
+    } else {
+        let b = b();
+        (a(), b)
+    }
+}
+
+/// The changes of the watched keys of a swapped database
+fn watched_changes(
+    subscriptions: &Subscriptions,
+    before: &Keyspace,
+    after: &Keyspace,
+) -> Vec<Change> {
+    let watched: BTreeSet<RedisString> = subscriptions
+        .watched_in(before)
+        .into_iter()
+        .chain(subscriptions.watched_in(after))
+        .collect();
+    watched
+        .into_iter()
+        .map(|key| (before.get(&key), after.get(&key), key))
+        .filter(|(before, after, _)| before != after)
+        .map(|(before, after, key)| Change::new(key, "swapdb", before.cloned(), after.cloned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use super::Databases;
+    use crate::{
+        commands::{
+            databases::{Move, SwapDb},
+            get::Get,
+            keys::{DbSize, Flush},
+            set::Set,
+            watch::{Watch, WatchResult},
+            Command,
+        },
+        database::Engine,
+        database::{
+            keyspace::{shard_of, Change},
+            memory,
+            sorted_set::SortedSet,
+            watchers::CHANGE_LOG_BYTES,
+            Database, MaxMemoryPolicy, Operation, RedisString, Value, Watcher, DEFAULT_SHARDS,
+        },
+        resp::Type,
+    };
+
+    fn set(db: &mut Database, key: &str, value: &str) {
+        db.apply(Command::Set(Set {
+            key: key.into(),
+            value: value.into(),
+        }));
+    }
+
+    fn get(db: &mut Database, key: &str) -> Type {
+        db.apply(Command::Get(Get { key: key.into() }))
+    }
+
+    fn watch(db: &mut Database, key: &str) -> Receiver<Type> {
+        let (sender, receiver) = channel(10);
+        db.watch(
+            Watch {
+                key: key.into(),
+                operations: vec![Operation::All].into_iter().collect(),
+                pattern: false,
+                from: None,
+                snapshot: false,
+            },
+            Watcher::new(sender),
+        );
+        receiver
+    }
+
+    async fn next(receiver: &mut Receiver<Type>) -> (Operation, Type) {
+        let result: Result<WatchResult, _> = receiver.recv().await.unwrap().into();
+        let result = result.unwrap();
+        (result.operation, result.after)
+    }
+
+    #[test]
+    fn databases_are_isolated() {
+        let databases = Databases::new(2);
+        assert!(databases.get(-1).is_none());
+        assert!(databases.get(2).is_none());
+        let (mut first, mut second) = (databases.get(0).unwrap(), databases.get(1).unwrap());
+        set(&mut first, "key", "first");
+        assert_eq!(get(&mut second, "key"), Type::Null);
+        assert_eq!(
+            databases.swap(SwapDb {
+                index1: 0,
+                index2: 2
+            }),
+            Databases::out_of_range()
+        );
+        databases.flush_all(Flush {
+            all: true,
+            asynchronous: false,
+        });
+        assert_eq!(get(&mut first, "key"), Type::Null);
+    }
+
+    #[test]
+    fn overwritten_values_are_only_kept_within_the_change_logs() {
+        let databases = Databases::new(1);
+        let memory = databases.memory.clone();
+        let mut db = databases.get(0).unwrap();
+        let size = |value: &str| memory::size(&"key".into(), &Value::String(value.into()));
+        // Its changes are bigger than a change log, they are not kept
+        let big = "x".repeat(CHANGE_LOG_BYTES);
+        for _ in 0..10 {
+            set(&mut db, "key", &big);
+        }
+        assert_eq!(memory.used(), size(&big));
+        // Those are, up to the bytes of the log
+        let small = "y".repeat(CHANGE_LOG_BYTES / 10);
+        for _ in 0..100 {
+            set(&mut db, "key", &small);
+        }
+        assert!(memory.used() > size(&small) + CHANGE_LOG_BYTES / 2);
+        assert!(memory.used() <= size(&small) + CHANGE_LOG_BYTES);
+    }
+
+    #[test]
+    fn geo_members_are_added_to_the_set_in_place() {
+        use crate::commands::geo::{GeoAdd, GeoMember};
+        let databases = Databases::new(1);
+        let memory = databases.memory.clone();
+        let mut db = databases.get(0).unwrap();
+        let member = |i: usize| format!("member:{:03}", i);
+        let mut set = SortedSet::new();
+        for i in 0..100 {
+            let reply = db.apply(Command::GeoAdd(GeoAdd {
+                key: "key".into(),
+                condition: None,
+                changed: false,
+                members: std::iter::once(GeoMember {
+                    longitude: 13.0,
+                    latitude: 38.0 + i as f64 / 100.0,
+                    member: member(i),
+                })
+                .collect(),
+            }));
+            assert_eq!(reply, Type::Integer(1));
+            // The scores do not count in the size
+            set.insert(member(i).into(), 0.0);
+        }
+        // Every change is logged with the member it added only
+        let mut added = SortedSet::new();
+        added.insert(member(0).into(), 0.0);
+        let logged = memory::logged_size(&Change::new(
+            "key".into(),
+            "zadd",
+            None,
+            Some(Value::SortedSet(added)),
+        ));
+        let size = memory::size(&"key".into(), &Value::SortedSet(set));
+        assert_eq!(memory.used(), size + 100 * logged);
+    }
+
+    #[test]
+    fn evicted_values_are_not_kept_by_the_change_logs() {
+        let databases = Databases::new(1);
+        let memory = databases.memory.clone();
+        let mut db = databases.get(0).unwrap();
+        let value = "x".repeat(CHANGE_LOG_BYTES / 4);
+        set(&mut db, "key", &value);
+        let used = memory.used();
+        memory.set_policy(MaxMemoryPolicy::AllKeysLru);
+        memory.set_maxmemory(used - 1);
+        assert!(databases.make_room());
+        // The SET is still logged, the eviction only with its key
+        let size = memory::size(&"key".into(), &Value::String(value.as_str().into()));
+        let evicted = Change::new("key".into(), "evicted", None, None);
+        assert_eq!(memory.used(), used - size + memory::logged_size(&evicted));
+    }
+
+    #[tokio::test]
+    async fn keys_are_evicted_over_the_maxmemory() {
+        let databases = Databases::new(2);
+        let memory = databases.memory.clone();
+        let (mut first, mut second) = (databases.get(0).unwrap(), databases.get(1).unwrap());
+        let mut evicted = watch(&mut first, "key");
+        set(&mut first, "key", "v");
+        for i in 0..10 {
+            set(&mut second, &format!("key:{}", i), "v");
+        }
+        assert!(databases.make_room());
+        let used = memory.used();
+        assert!(used > 0);
+        // The changes logged are about half of it, they are dropped but it is not enough
+        memory.set_maxmemory(used / 4);
+        assert!(!databases.make_room());
+        assert!(memory.used() < used);
+        // No key has a time to live
+        memory.set_policy(MaxMemoryPolicy::VolatileLru);
+        assert!(!databases.make_room());
+        memory.set_policy(MaxMemoryPolicy::AllKeysLru);
+        assert!(databases.make_room());
+        assert!(memory.used() <= used / 4);
+        // Every key, in every database
+        memory.set_maxmemory(1);
+        assert!(databases.make_room());
+        assert_eq!(memory.used(), 0);
+        assert_eq!(get(&mut first, "key"), Type::Null);
+        evicted.recv().await.unwrap();
+        let result: Result<WatchResult, _> = evicted.recv().await.unwrap().into();
+        let result = result.unwrap();
+        assert_eq!(
+            (result.operation, result.event),
+            (Operation::Removal, "evicted".to_string())
+        );
+    }
+
+    #[test]
+    fn snapshots_are_restored_in_their_databases() {
+        let databases = Databases::new(3);
+        let (mut first, mut third) = (databases.get(0).unwrap(), databases.get(2).unwrap());
+        set(&mut first, "key", "first");
+        set(&mut third, "key", "third");
+        set(&mut third, "other", "v");
+        third.apply(Command::Flush(Flush {
+            all: false,
+            asynchronous: false,
+        }));
+        set(&mut third, "key", "again");
+        // 3 sets, 2 keys flushed, then a set
+        let (data, writes) = databases.snapshot();
+        assert_eq!(writes, 6);
+        let restored = Databases::new(3);
+        restored.restore(data.clone()).unwrap();
+        assert_eq!(restored.writes(), 0);
+        let (mut first, mut third) = (restored.get(0).unwrap(), restored.get(2).unwrap());
+        assert_eq!(get(&mut first, "key"), Type::BulkString("first".into()));
+        assert_eq!(get(&mut third, "key"), Type::BulkString("again".into()));
+        assert_eq!(get(&mut third, "other"), Type::Null);
+        assert!(Databases::new(2).restore(data).is_err());
+    }
+
+    #[test]
+    fn snapshots_lock_one_shard_at_a_time() {
+        let databases = Databases::new(1);
+        let mut db = databases.get(0).unwrap();
+        let last = DEFAULT_SHARDS - 1;
+        let first_key = (0..)
+            .map(|i| format!("key:{}", i))
+            .find(|key| shard_of(key.as_bytes(), DEFAULT_SHARDS) == 0)
+            .unwrap();
+        set(&mut db, &first_key, "before");
+        let locked = db.inner.lock_shard(last);
+        let (done, finished) = std::sync::mpsc::channel();
+        let snapshotting = databases.clone();
+        std::thread::spawn(move || done.send(snapshotting.snapshot()).unwrap());
+        // The snapshot waits for the last shard, the first one is not locked meanwhile
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let (written, writing) = std::sync::mpsc::channel();
+        let (mut writer, key) = (db.clone(), first_key.clone());
+        std::thread::spawn(move || {
+            set(&mut writer, &key, "during");
+            written.send(()).unwrap();
+        });
+        writing
+            .recv_timeout(std::time::Duration::from_secs(30))
+            .unwrap();
+        drop(locked);
+        let (data, _) = finished
+            .recv_timeout(std::time::Duration::from_secs(30))
+            .unwrap();
+        let value = Value::String("before".into());
+        assert_eq!(data, vec![(0, vec![(RedisString::from(first_key), value)])]);
+    }
+
+    #[test]
+    fn moves_and_swaps_in_both_directions_do_not_deadlock() {
+        for engine in [Engine::Locks, Engine::Actors] {
+            moves_and_swaps_in_both_directions_do_not_deadlock_with(engine);
+        }
+    }
+
+    fn moves_and_swaps_in_both_directions_do_not_deadlock_with(engine: Engine) {
+        let databases = Databases::new(2).with_engine(engine);
+        for (index, key) in [(0, "a"), (1, "b")] {
+            set(&mut databases.get(index).unwrap(), key, "v");
+        }
+        let (done, finished) = std::sync::mpsc::channel();
+        for thread in 0..8_i64 {
+            let databases = databases.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                for _ in 0..500 {
+                    for (from, to, key) in [(0, 1, "a"), (1, 0, "a"), (1, 0, "b"), (0, 1, "b")] {
+                        // Half of the threads go the other way
+                        let (from, to) = if thread % 2 == 0 {
+                            (from, to)
+                        } else {
+                            (to, from)
+                        };
+                        databases.move_key(
+                            from,
+                            Move {
+                                key: key.into(),
+                                db: to as i64,
+                            },
+                        );
+                    }
+                    databases.swap(SwapDb {
+                        index1: thread % 2,
+                        index2: 1 - thread % 2,
+                    });
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..8 {
+            finished
+                .recv_timeout(std::time::Duration::from_secs(30))
+                .expect("Deadlocked");
+        }
+        let count = |index| match databases.get(index).unwrap().apply(Command::DbSize(DbSize)) {
+            Type::Integer(count) => count,
+            other => panic!("Unexpected {:?}", other),
+        };
+        assert_eq!(count(0) + count(1), 2);
+    }
+
+    #[tokio::test]
+    async fn move_works() {
+        for engine in [Engine::Locks, Engine::Actors] {
+            move_works_with(engine).await;
+        }
+    }
+
+    async fn move_works_with(engine: Engine) {
+        let databases = Databases::new(2).with_engine(engine);
+        let (mut first, mut second) = (databases.get(0).unwrap(), databases.get(1).unwrap());
+        let mut first_watch = watch(&mut first, "key");
+        let mut second_watch = watch(&mut second, "key");
+        set(&mut first, "key", "v");
+        assert_eq!(next(&mut first_watch).await.0, Operation::Addition);
+        let move_to = |db| Move {
+            key: "key".into(),
+            db,
+        };
+        assert_eq!(databases.move_key(0, move_to(1)), Type::Integer(1));
+        assert_eq!(get(&mut first, "key"), Type::Null);
+        assert_eq!(get(&mut second, "key"), Type::BulkString("v".into()));
+        assert_eq!(next(&mut first_watch).await.0, Operation::Removal);
+        assert_eq!(
+            next(&mut second_watch).await,
+            (Operation::Addition, Type::BulkString("v".into()))
+        );
+        // Nothing to move, or the key exists in the target
+        assert_eq!(databases.move_key(0, move_to(1)), Type::Integer(0));
+        set(&mut first, "key", "w");
+        assert_eq!(databases.move_key(0, move_to(1)), Type::Integer(0));
+        assert_eq!(
+            databases.move_key(0, move_to(0)),
+            Type::Error("ERR source and destination objects are the same".into())
+        );
+        assert_eq!(databases.move_key(0, move_to(5)), Databases::out_of_range());
+    }
+
+    #[tokio::test]
+    async fn swap_keeps_watches_in_their_database() {
+        for engine in [Engine::Locks, Engine::Actors] {
+            swap_keeps_watches_in_their_database_with(engine).await;
+        }
+    }
+
+    async fn swap_keeps_watches_in_their_database_with(engine: Engine) {
+        let databases = Databases::new(2).with_engine(engine);
+        let (mut first, mut second) = (databases.get(0).unwrap(), databases.get(1).unwrap());
+        set(&mut first, "key", "first");
+        set(&mut first, "only_first", "v");
+        let mut first_watch = watch(&mut first, "key");
+        let mut only_first_watch = watch(&mut first, "only_first");
+        assert_eq!(
+            databases.swap(SwapDb {
+                index1: 1,
+                index2: 0
+            }),
+            Type::SimpleString("OK".into())
+        );
+        assert_eq!(get(&mut second, "key"), Type::BulkString("first".into()));
+        assert_eq!(next(&mut first_watch).await.0, Operation::Removal);
+        assert_eq!(next(&mut only_first_watch).await.0, Operation::Removal);
+        set(&mut first, "key", "new");
+        assert_eq!(
+            next(&mut first_watch).await,
+            (Operation::Addition, Type::BulkString("new".into()))
+        );
+        // Changes to the other database are not seen
+        set(&mut second, "key", "second");
+        set(&mut first, "key", "newer");
+        assert_eq!(
+            next(&mut first_watch).await,
+            (Operation::Update, Type::BulkString("newer".into()))
+        );
+    }
+}
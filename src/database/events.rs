@@ -0,0 +1,282 @@
+//! Keyspace notifications, the changes of the keys published over pub/sub like Redis does.
+//!
+//! Every change that goes through [Subscriptions::notify](super::Subscriptions::notify) is
+//! published, if its class is enabled by the [KeyspaceEvents], as:
+//! * the event on `__keyspace@<db>__:<key>`, with `K`
+//! * the key on `__keyevent@<db>__:<event>`, with `E`
+//!
+//! Clients that do not speak WATCH can subscribe to those channels (e.g. with PSUBSCRIBE).
+
+use std::{
+    fmt::{self, Debug, Display},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+
+use super::{keyspace::Change, Operation};
+use crate::{commands::pubsub::Publish, pubsub::PubSub};
+
+const KEYSPACE: u16 = 1 << 0;
+const KEYEVENT: u16 = 1 << 1;
+const GENERIC: u16 = 1 << 2;
+const STRING: u16 = 1 << 3;
+const LIST: u16 = 1 << 4;
+const SET: u16 = 1 << 5;
+const HASH: u16 = 1 << 6;
+const ZSET: u16 = 1 << 7;
+const EXPIRED: u16 = 1 << 8;
+const EVICTED: u16 = 1 << 9;
+const STREAM: u16 = 1 << 10;
+const KEY_MISS: u16 = 1 << 11;
+const MODULE: u16 = 1 << 12;
+const NEW: u16 = 1 << 13;
+/// `A`, every class but the key misses and the new keys
+const ALL: u16 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+/// The flags, in the order Redis shows them
+const FLAGS: [(char, u16); 14] = [
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('d', MODULE),
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+    ('m', KEY_MISS),
+    ('n', NEW),
+];
+
+/// The keyspace notifications published, a flag string like `notify-keyspace-events` in
+/// Redis (e.g. `KEA`). `K` and/or `E` choose the channels, the other flags the classes of
+/// events: `g` generic (del, rename, copy, move...), `$` string, `l` list, `z` sorted set,
+/// `x` expired, `e` evicted, `n` new key, and `A` for `g$lshzxetd`. None by default.
+/// The classes of data types this server does not have are accepted, and never published.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    fn contains(self, flags: u16) -> bool {
+        self.0 & flags == flags
+    }
+}
+
+impl FromStr for KeyspaceEvents {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events = 0;
+        for c in s.chars() {
+            events |= match c {
+                'A' => ALL,
+                c => match FLAGS.iter().find(|(flag, _)| *flag == c) {
+                    Some((_, class)) => *class,
+                    None => return Err(format!("{} is not a keyspace events flag", c)),
+                },
+            };
+        }
+        Ok(KeyspaceEvents(events))
+    }
+}
+
+impl Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut events = self.0;
+        if self.contains(ALL) {
+            f.write_str("A")?;
+            events &= !ALL;
+        }
+        for (flag, class) in FLAGS.iter() {
+            if events & class != 0 {
+                write!(f, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Publishes the keyspace notifications of a database. The [KeyspaceEvents] are shared by
+/// all the databases of a server, they can be changed while it runs (CONFIG SET).
+#[derive(Clone, Default)]
+pub(crate) struct KeyspaceNotifier {
+    pubsub: PubSub,
+    events: Arc<AtomicU16>,
+    /// The index of the database
+    db: usize,
+}
+
+impl Debug for KeyspaceNotifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyspaceNotifier")
+            .field("events", &self.events())
+            .field("db", &self.db)
+            .finish()
+    }
+}
+
+impl KeyspaceNotifier {
+    /// Publishes over `pubsub`, for the database 0
+    pub(crate) fn new(pubsub: PubSub, events: KeyspaceEvents) -> Self {
+        KeyspaceNotifier {
+            pubsub,
+            events: Arc::new(AtomicU16::new(events.0)),
+            db: 0,
+        }
+    }
+
+    /// The notifier of another database, with the same events
+    pub(crate) fn for_db(&self, db: usize) -> Self {
+        KeyspaceNotifier { db, ..self.clone() }
+    }
+
+    /// The events published, as the value of `notify-keyspace-events`
+    pub(crate) fn events(&self) -> KeyspaceEvents {
+        KeyspaceEvents(self.events.load(Ordering::Relaxed))
+    }
+
+    /// Changes the events published, by every database
+    pub(crate) fn set_events(&self, events: KeyspaceEvents) {
+        self.events.store(events.0, Ordering::Relaxed);
+    }
+
+    /// Returns true if the notifications are published, on a channel at least
+    pub(crate) fn enabled(&self) -> bool {
+        self.events().0 & (KEYSPACE | KEYEVENT) != 0
+    }
+
+    /// Publishes the notifications of a change, a `new` event comes first for a key added
+    pub(crate) fn notify(&self, change: &Change) {
+        if !self.enabled() {
+            return;
+        }
+        let events = self.events();
+        if change.operation == Operation::Addition {
+            self.publish(events, NEW, "new", change);
+        }
+        self.publish(events, class(change), change.event, change);
+    }
+
+    fn publish(&self, events: KeyspaceEvents, class: u16, event: &str, change: &Change) {
+        if !events.contains(class) {
+            return;
+        }
+        let key = change.key.as_bytes();
+        if events.contains(KEYSPACE) {
+            self.pubsub.publish(Publish {
+                channel: format!("__keyspace@{}__:{}", self.db, String::from_utf8_lossy(key)),
+                message: event.as_bytes().to_vec(),
+                sharded: false,
+            });
+        }
+        if events.contains(KEYEVENT) {
+            self.pubsub.publish(Publish {
+                channel: format!("__keyevent@{}__:{}", self.db, event),
+                message: key.to_vec(),
+                sharded: false,
+            });
+        }
+    }
+}
+
+/// The class of the event of a change, after the type of the value for the commands
+/// that are not generic
+fn class(change: &Change) -> u16 {
+    match change.event {
+        "del" | "rename_from" | "rename_to" | "copy_to" | "move_from" | "move_to" | "flushdb"
+        | "swapdb" => GENERIC,
+        "expired" => EXPIRED,
+        "evicted" => EVICTED,
+        _ => match change.after.as_ref().or(change.before.as_ref()) {
+            Some(value) => match value.type_name() {
+                "list" => LIST,
+                "zset" => ZSET,
+                _ => STRING,
+            },
+            None => GENERIC,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyspaceEvents, KeyspaceNotifier};
+    use crate::{
+        commands::{
+            keys::Rename,
+            pubsub::{Message, Subscribe, SubscriptionKind},
+            set::Set,
+            Command,
+        },
+        database::{Database, DEFAULT_CHANGE_LOG, DEFAULT_SHARDS},
+        pubsub::PubSub,
+        resp::TypeConsumer,
+    };
+
+    #[test]
+    fn flags_are_parsed_and_shown_like_redis() {
+        for (flags, shown) in [("", ""), ("KEA", "AKE"), ("E$gK", "g$KE"), ("Ag$n", "An")].iter() {
+            let events: KeyspaceEvents = flags.parse().unwrap();
+            assert_eq!(events.to_string(), *shown);
+        }
+        assert!("KEy".parse::<KeyspaceEvents>().is_err());
+    }
+
+    #[tokio::test]
+    async fn changes_are_published_on_the_keyspace_and_keyevent_channels() {
+        let pubsub = PubSub::default();
+        let mut subscriber = pubsub.subscriber();
+        subscriber.subscribe(Subscribe {
+            channels: vec!["__key*@1__:*".to_string()].into_iter().collect(),
+            kind: SubscriptionKind::Pattern,
+        });
+        let notifier = KeyspaceNotifier::new(pubsub, "K$".parse().unwrap());
+        let mut db = Database::with_change_log(
+            DEFAULT_SHARDS,
+            DEFAULT_CHANGE_LOG,
+            notifier.for_db(1),
+            Default::default(),
+        );
+        let set = |key: &str| {
+            Command::Set(Set {
+                key: key.into(),
+                value: "v".into(),
+            })
+        };
+        db.apply(set("a"));
+        // The events are shared with the other databases, and can be changed
+        notifier.set_events("KEgn".parse().unwrap());
+        db.apply(set("a"));
+        db.apply(Command::Rename(Rename {
+            key: "a".into(),
+            new_key: "b".into(),
+            only_if_new: false,
+        }));
+        let mut messages = Vec::new();
+        for _ in 0..6 {
+            let t = subscriber.next_message().await.unwrap();
+            let message = Message::from(&mut TypeConsumer::new(t)).unwrap().unwrap();
+            let payload = String::from_utf8(message.payload).unwrap();
+            messages.push((message.channel, payload));
+        }
+        let message = |channel: &str, payload: &str| (channel.to_string(), payload.to_string());
+        // The string update is not published anymore, the rename is
+        assert_eq!(
+            messages,
+            vec![
+                message("__keyspace@1__:a", "set"),
+                message("__keyspace@1__:a", "rename_from"),
+                message("__keyevent@1__:rename_from", "a"),
+                message("__keyspace@1__:b", "new"),
+                message("__keyevent@1__:new", "b"),
+                message("__keyspace@1__:b", "rename_to"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Geohash encoding and distance calculations, compatible with Redis.
+//!
+//! A position is encoded as a 52 bit geohash (26 bits for each coordinate, interleaved) which
+//! is used as the score of a member of a [SortedSet]. Members close to each other have close
+//! scores, so an area can be searched with a few score range queries on the sorted set.
+
+use std::collections::HashSet;
+
+use super::sorted_set::SortedSet;
+use super::RedisString;
+
+/// The number of bits for each coordinate
+pub(crate) const STEP: u8 = 26;
+const LONGITUDE_MIN: f64 = -180.0;
+const LONGITUDE_MAX: f64 = 180.0;
+/// The limits of EPSG:900913 / EPSG:3785 / OSGEO:41001
+const LATITUDE_MIN: f64 = -85.051_128_78;
+const LATITUDE_MAX: f64 = 85.051_128_78;
+/// Earth's quadratic mean radius for WGS-84
+const EARTH_RADIUS_IN_METERS: f64 = 6_372_797.560_856;
+const MERCATOR_MAX: f64 = 20_037_726.37;
+
+/// A point, `(longitude, latitude)` in degrees
+pub(crate) type Point = (f64, f64);
+
+/// Returns true if the coordinates can be indexed
+pub(crate) fn is_valid(longitude: f64, latitude: f64) -> bool {
+    (LONGITUDE_MIN..=LONGITUDE_MAX).contains(&longitude)
+        && (LATITUDE_MIN..=LATITUDE_MAX).contains(&latitude)
+}
+
+/// Spreads the lower 32 bits of `v` to the even bits of the result
+fn spread(v: u32) -> u64 {
+    let mut x = v as u64;
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Inverse of [spread], collects the even bits
+fn squash(v: u64) -> u32 {
+    let mut x = v & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+    (x | (x >> 16)) as u32
+}
+
+/// Encodes the point with `step` bits per coordinate. Latitude bits are the even bits.
+pub(crate) fn encode(longitude: f64, latitude: f64, step: u8) -> u64 {
+    let cells = (1u64 << step) as f64;
+    let latitude_offset = (latitude - LATITUDE_MIN) / (LATITUDE_MAX - LATITUDE_MIN) * cells;
+    let longitude_offset = (longitude - LONGITUDE_MIN) / (LONGITUDE_MAX - LONGITUDE_MIN) * cells;
+    // The maximum values fall in the last cell
+    let max = (1u64 << step) - 1;
+    let latitude_offset = (latitude_offset as u64).min(max) as u32;
+    let longitude_offset = (longitude_offset as u64).min(max) as u32;
+    spread(latitude_offset) | (spread(longitude_offset) << 1)
+}
+
+/// Returns the area `(min, max)` of the cell of the hash
+fn cell(hash: u64, step: u8) -> (Point, Point) {
+    let latitude_offset = squash(hash) as f64;
+    let longitude_offset = squash(hash >> 1) as f64;
+    let cells = (1u64 << step) as f64;
+    let latitude_scale = LATITUDE_MAX - LATITUDE_MIN;
+    let longitude_scale = LONGITUDE_MAX - LONGITUDE_MIN;
+    (
+        (
+            LONGITUDE_MIN + (longitude_offset / cells) * longitude_scale,
+            LATITUDE_MIN + (latitude_offset / cells) * latitude_scale,
+        ),
+        (
+            LONGITUDE_MIN + ((longitude_offset + 1.0) / cells) * longitude_scale,
+            LATITUDE_MIN + ((latitude_offset + 1.0) / cells) * latitude_scale,
+        ),
+    )
+}
+
+/// Decodes the hash into the center of its cell
+pub(crate) fn decode(hash: u64, step: u8) -> Point {
+    let ((longitude_min, latitude_min), (longitude_max, latitude_max)) = cell(hash, step);
+    (
+        ((longitude_min + longitude_max) / 2.0).clamp(LONGITUDE_MIN, LONGITUDE_MAX),
+        ((latitude_min + latitude_max) / 2.0).clamp(LATITUDE_MIN, LATITUDE_MAX),
+    )
+}
+
+/// Decodes the score of a member
+pub(crate) fn decode_score(score: f64) -> Point {
+    decode(score as u64, STEP)
+}
+
+/// The great circle distance (haversine) in meters
+pub(crate) fn distance(from: Point, to: Point) -> f64 {
+    let (longitude1, latitude1) = (from.0.to_radians(), from.1.to_radians());
+    let (longitude2, latitude2) = (to.0.to_radians(), to.1.to_radians());
+    let u = ((latitude2 - latitude1) / 2.0).sin();
+    let v = ((longitude2 - longitude1) / 2.0).sin();
+    2.0 * EARTH_RADIUS_IN_METERS
+        * (u * u + latitude1.cos() * latitude2.cos() * v * v)
+            .sqrt()
+            .asin()
+}
+
+/// The area being searched, sizes are in meters
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl Shape {
+    /// Returns the distance from the center if the point is within the shape
+    fn distance_if_within(&self, center: Point, point: Point) -> Option<f64> {
+        match *self {
+            Shape::Radius(radius) => {
+                let d = distance(center, point);
+                if d <= radius {
+                    Some(d)
+                } else {
+                    None
+                }
+            }
+            Shape::Box { width, height } => {
+                // The latitude distance is cheaper, check it first
+                let latitude_distance =
+                    EARTH_RADIUS_IN_METERS * (point.1.to_radians() - center.1.to_radians()).abs();
+                if latitude_distance > height / 2.0 {
+                    return None;
+                }
+                if distance((point.0, point.1), (center.0, point.1)) > width / 2.0 {
+                    return None;
+                }
+                Some(distance(center, point))
+            }
+        }
+    }
+
+    /// The radius of the circle containing the shape
+    fn radius(&self) -> f64 {
+        match *self {
+            Shape::Radius(radius) => radius,
+            Shape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        }
+    }
+}
+
+/// The precision (bits per coordinate) for which the 3x3 cells around the center cover the radius
+fn steps_for_radius(radius: f64, latitude: f64) -> u8 {
+    if radius == 0.0 {
+        return STEP;
+    }
+    let mut range = radius;
+    let mut step: i32 = 1;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // Make sure the range is included in most of the base cases
+    step -= 2;
+    // Cells are narrower towards the poles
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP as i32) as u8
+}
+
+/// Returns the hashes of the cell containing the center and of its (up to) 8 neighbours
+fn cells_around(center: Point, step: u8) -> HashSet<u64> {
+    let ((longitude_min, latitude_min), (longitude_max, latitude_max)) =
+        cell(encode(center.0, center.1, step), step);
+    let (width, height) = (longitude_max - longitude_min, latitude_max - latitude_min);
+    let (longitude, latitude) = (
+        (longitude_min + longitude_max) / 2.0,
+        (latitude_min + latitude_max) / 2.0,
+    );
+    let mut hashes = HashSet::new();
+    for dx in &[-1.0, 0.0, 1.0] {
+        for dy in &[-1.0, 0.0, 1.0] {
+            let mut neighbour_longitude = longitude + dx * width;
+            // Longitudes wrap around
+            if neighbour_longitude < LONGITUDE_MIN {
+                neighbour_longitude += 360.0;
+            } else if neighbour_longitude > LONGITUDE_MAX {
+                neighbour_longitude -= 360.0;
+            }
+            let neighbour_latitude = latitude + dy * height;
+            if is_valid(neighbour_longitude, neighbour_latitude) {
+                hashes.insert(encode(neighbour_longitude, neighbour_latitude, step));
+            }
+        }
+    }
+    hashes
+}
+
+/// A member found by [search]
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Found {
+    pub(crate) member: RedisString,
+    pub(crate) distance: f64,
+    pub(crate) score: f64,
+    pub(crate) position: Point,
+}
+
+/// Returns the members within the shape around the center (in no particular order).
+/// Stops after `limit` members if there is a limit.
+pub(crate) fn search(
+    set: &SortedSet,
+    center: Point,
+    shape: &Shape,
+    limit: Option<usize>,
+) -> Vec<Found> {
+    let step = steps_for_radius(shape.radius(), center.1);
+    let mut found = Vec::new();
+    for hash in cells_around(center, step) {
+        // All the 52 bit hashes with this prefix
+        let shift = 2 * (STEP - step) as u32;
+        let (min, max) = ((hash << shift) as f64, ((hash + 1) << shift) as f64);
+        for (member, score) in set.range_by_score(min, max) {
+            let position = decode_score(score);
+            if let Some(distance) = shape.distance_if_within(center, position) {
+                found.push(Found {
+                    member: member.clone(),
+                    distance,
+                    score,
+                    position,
+                });
+                if limit.is_some_and(|l| found.len() >= l) {
+                    return found;
+                }
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PALERMO: Point = (13.361389, 38.115556);
+    const CATANIA: Point = (15.087269, 37.502669);
+
+    fn sicily() -> SortedSet {
+        let mut set = SortedSet::new();
+        set.insert("Palermo".into(), encode(PALERMO.0, PALERMO.1, STEP) as f64);
+        set.insert("Catania".into(), encode(CATANIA.0, CATANIA.1, STEP) as f64);
+        set
+    }
+
+    fn decoded_distance(from: Point, to: Point) -> f64 {
+        distance(
+            decode(encode(from.0, from.1, STEP), STEP),
+            decode(encode(to.0, to.1, STEP), STEP),
+        )
+    }
+
+    #[test]
+    fn encode_works() {
+        // The scores Redis stores for these members
+        assert_eq!(encode(PALERMO.0, PALERMO.1, STEP), 3_479_099_956_230_698);
+        assert_eq!(encode(CATANIA.0, CATANIA.1, STEP), 3_479_447_370_796_909);
+    }
+
+    #[test]
+    fn decode_works() {
+        let (longitude, latitude) = decode(encode(PALERMO.0, PALERMO.1, STEP), STEP);
+        assert!((longitude - 13.361_389_338_970_184).abs() < 1e-12);
+        assert!((latitude - 38.115_556_395_496_3).abs() < 1e-12);
+        let (longitude, latitude) = decode(encode(180.0, 85.051_128_78, STEP), STEP);
+        assert!((longitude - 180.0).abs() < 1e-5);
+        assert!((latitude - 85.051_128_78).abs() < 1e-5);
+    }
+
+    #[test]
+    fn distance_works() {
+        // Known city pairs, compared with the distances Redis returns
+        let d = decoded_distance(PALERMO, CATANIA);
+        assert_eq!(format!("{:.4}", d), "166274.1516");
+        // London - Paris, ~343.5 km
+        let d = decoded_distance((-0.1278, 51.5074), (2.3522, 48.8566)) / 1000.0;
+        assert!((d - 343.5).abs() < 1.0, "{}", d);
+        // New York - Los Angeles, ~3936 km
+        let d = decoded_distance((-74.0060, 40.7128), (-118.2437, 34.0522)) / 1000.0;
+        assert!((d - 3936.0).abs() < 5.0, "{}", d);
+        // Sydney - Auckland, across the 180th meridian is not needed, ~2156 km
+        let d = decoded_distance((151.2093, -33.8688), (174.7633, -36.8485)) / 1000.0;
+        assert!((d - 2156.0).abs() < 5.0, "{}", d);
+        assert_eq!(distance(PALERMO, PALERMO), 0.0);
+    }
+
+    #[test]
+    fn search_by_radius_works() {
+        let set = sicily();
+        let found = search(&set, (15.0, 37.0), &Shape::Radius(100_000.0), None);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].member, "Catania".into());
+        assert_eq!(format!("{:.4}", found[0].distance / 1000.0), "56.4413");
+        let mut found = search(&set, (15.0, 37.0), &Shape::Radius(200_000.0), None);
+        found.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        let members: Vec<String> = found.iter().map(|f| f.member.clone().into()).collect();
+        assert_eq!(members, vec!["Catania", "Palermo"]);
+        assert_eq!(format!("{:.4}", found[1].distance / 1000.0), "190.4424");
+        assert_eq!(
+            search(&set, (15.0, 37.0), &Shape::Radius(200_000.0), Some(1)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn search_by_box_works() {
+        let set = sicily();
+        let shape = Shape::Box {
+            width: 400_000.0,
+            height: 400_000.0,
+        };
+        assert_eq!(search(&set, (15.0, 37.0), &shape, None).len(), 2);
+        // Palermo is ~150km west of 15, 38 and Catania is ~55km south
+        let shape = Shape::Box {
+            width: 200_000.0,
+            height: 200_000.0,
+        };
+        let found = search(&set, (15.0, 38.0), &shape, None);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].member, "Catania".into());
+    }
+
+    #[test]
+    fn search_across_the_antimeridian_works() {
+        let mut set = SortedSet::new();
+        set.insert("Fiji".into(), encode(179.9, -17.0, STEP) as f64);
+        let found = search(&set, (-179.9, -17.0), &Shape::Radius(50_000.0), None);
+        assert_eq!(found.len(), 1);
+    }
+}
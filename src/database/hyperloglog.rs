@@ -0,0 +1,493 @@
+//! A HyperLogLog implementation that uses the same encoding as Redis.
+//!
+//! The structure is stored as a plain string value so that it can be read with `GET` and
+//! written back with `SET`. The layout is a 16 byte header followed by the registers:
+//!
+//! ```text
+//! +------+---+-----+----------+
+//! | HYLL | E | N/U | Cardin.  |
+//! +------+---+-----+----------+
+//! ```
+//!
+//! * `HYLL` is the magic string
+//! * `E` is the encoding, either [Encoding::Dense] (`0`) or [Encoding::Sparse] (`1`)
+//! * `N/U` are three unused bytes
+//! * `Cardin.` is the cached cardinality (little endian). If the most significant bit is set, the cache is invalid.
+//!
+//! The dense representation stores 16384 registers of 6 bits each. The sparse representation
+//! run length encodes the registers with the following opcodes:
+//! * `ZERO` - `00xxxxxx`, a run of `xxxxxx + 1` (1 to 64) zero registers
+//! * `XZERO` - `01xxxxxx yyyyyyyy`, a run of `xxxxxxyyyyyyyy + 1` (1 to 16384) zero registers
+//! * `VAL` - `1vvvvvxx`, a run of `xx + 1` (1 to 4) registers set to `vvvvv + 1` (1 to 32)
+//!
+//! A sparse HyperLogLog is promoted to the dense one once a register exceeds 32 or the
+//! encoding grows beyond [SPARSE_MAX_BYTES].
+
+use std::convert::TryInto;
+
+/// The number of bits of the hash used to select the register
+const P: u32 = 14;
+/// The number of bits of the hash used to count the leading zeroes
+const Q: u32 = 64 - P;
+/// The number of registers
+const REGISTERS: usize = 1 << P;
+const P_MASK: u64 = REGISTERS as u64 - 1;
+/// The number of bits per register (dense)
+const BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << BITS) - 1;
+const HEADER_SIZE: usize = 16;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * BITS).div_ceil(8);
+const MAGIC: &[u8] = b"HYLL";
+/// Above this size the sparse representation is promoted to dense (`hll-sparse-max-bytes`)
+pub(crate) const SPARSE_MAX_BYTES: usize = 3000;
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+/// `1 / (2 * ln(2))`
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const HASH_SEED: u64 = 0xadc8_3b19;
+
+/// The different encodings of a [HyperLogLog]
+#[repr(u8)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Encoding {
+    Dense = 0,
+    Sparse = 1,
+}
+
+/// A HyperLogLog with 16384 registers (standard error of 0.81%)
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct HyperLogLog {
+    encoding: Encoding,
+    /// One byte per register, regardless of the encoding
+    registers: Vec<u8>,
+    /// The cached cardinality, `None` when it has to be recomputed
+    cardinality: Option<u64>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    /// Creates an empty (sparse) HyperLogLog
+    pub(crate) fn new() -> Self {
+        HyperLogLog {
+            encoding: Encoding::Sparse,
+            registers: vec![0; REGISTERS],
+            cardinality: Some(0),
+        }
+    }
+
+    /// Decodes the HyperLogLog from its string representation.
+    /// Returns `None` if the bytes are not a valid HyperLogLog.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return None;
+        }
+        let (encoding, registers) = match bytes[4] {
+            0 if bytes.len() == DENSE_SIZE => {
+                (Encoding::Dense, decode_dense(&bytes[HEADER_SIZE..]))
+            }
+            1 => (Encoding::Sparse, decode_sparse(&bytes[HEADER_SIZE..])?),
+            _ => return None,
+        };
+        Some(HyperLogLog {
+            encoding,
+            registers,
+            cardinality: cached_cardinality(bytes),
+        })
+    }
+
+    /// Encodes this HyperLogLog, using the sparse representation if it is still possible.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let sparse = match self.encoding {
+            Encoding::Sparse => {
+                encode_sparse(&self.registers).filter(|s| s.len() <= SPARSE_MAX_BYTES)
+            }
+            Encoding::Dense => None,
+        };
+        let (encoding, body) = match sparse {
+            Some(body) => (Encoding::Sparse, body),
+            None => (Encoding::Dense, encode_dense(&self.registers)),
+        };
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(encoding as u8);
+        bytes.extend_from_slice(&[0; 3]);
+        match self.cardinality {
+            Some(c) => bytes.extend_from_slice(&c.to_le_bytes()),
+            None => bytes.extend_from_slice(&invalid_cache()),
+        }
+        bytes.extend(body);
+        bytes
+    }
+
+    /// The encoding that will be used by [HyperLogLog::to_bytes]
+    #[cfg(test)]
+    pub(crate) fn encoding(&self) -> Encoding {
+        let sparse = encode_sparse(&self.registers).filter(|s| s.len() <= SPARSE_MAX_BYTES);
+        match (self.encoding, sparse) {
+            (Encoding::Sparse, Some(_)) => Encoding::Sparse,
+            _ => Encoding::Dense,
+        }
+    }
+
+    /// Adds an element, returns true if any register was modified
+    pub(crate) fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = index_and_count(element);
+        if self.registers[index] < count {
+            self.registers[index] = count;
+            if count > SPARSE_VAL_MAX_VALUE {
+                self.encoding = Encoding::Dense;
+            }
+            self.cardinality = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Merges the registers from `other` into this HyperLogLog. The result is always dense.
+    pub(crate) fn merge(&mut self, other: &HyperLogLog) {
+        self.registers
+            .iter_mut()
+            .zip(other.registers.iter())
+            .for_each(|(r, &o)| *r = (*r).max(o));
+        self.encoding = Encoding::Dense;
+        self.cardinality = None;
+    }
+
+    /// Returns the approximated cardinality, using the cached value when possible.
+    pub(crate) fn count(&mut self) -> u64 {
+        match self.cardinality {
+            Some(c) => c,
+            None => {
+                let c = estimate(&self.registers);
+                self.cardinality = Some(c);
+                c
+            }
+        }
+    }
+}
+
+/// Returns the cached cardinality in the header if it is valid
+fn cached_cardinality(bytes: &[u8]) -> Option<u64> {
+    let mut card = [0; 8];
+    card.copy_from_slice(&bytes[8..16]);
+    if card[7] & (1 << 7) == 0 {
+        Some(u64::from_le_bytes(card))
+    } else {
+        None
+    }
+}
+
+fn invalid_cache() -> [u8; 8] {
+    let mut card = [0; 8];
+    card[7] = 1 << 7;
+    card
+}
+
+/// Returns the register index and the number of leading zeroes (+1) for the element
+fn index_and_count(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash64a(element, HASH_SEED);
+    let index = (hash & P_MASK) as usize;
+    // Setting the Q-th bit bounds the count to Q + 1
+    let hash = (hash >> P) | (1 << Q);
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+/// MurmurHash2, 64 bit version (the one used by Redis)
+fn murmur_hash64a(data: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().expect("8 bytes"));
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        tail.iter()
+            .enumerate()
+            .for_each(|(i, &b)| h ^= (b as u64) << (8 * i));
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Estimates the cardinality from the registers.
+/// Uses the improved estimator from Otmar Ertl ("New cardinality estimation algorithms for HyperLogLog sketches").
+fn estimate(registers: &[u8]) -> u64 {
+    let mut histogram = [0u32; 64];
+    registers.iter().for_each(|&r| histogram[r as usize] += 1);
+    let m = REGISTERS as f64;
+    let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+    for j in (1..=Q as usize).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn decode_dense(body: &[u8]) -> Vec<u8> {
+    (0..REGISTERS)
+        .map(|i| {
+            let byte = i * BITS / 8;
+            let shift = (i * BITS) & 7;
+            let b0 = body[byte] as u16;
+            let b1 = body.get(byte + 1).copied().unwrap_or(0) as u16;
+            (((b0 >> shift) | (b1 << (8 - shift))) as u8) & REGISTER_MAX
+        })
+        .collect()
+}
+
+fn encode_dense(registers: &[u8]) -> Vec<u8> {
+    let mut body = vec![0u8; DENSE_SIZE - HEADER_SIZE];
+    registers.iter().enumerate().for_each(|(i, &r)| {
+        let byte = i * BITS / 8;
+        let shift = (i * BITS) & 7;
+        let v = (r as u16) << shift;
+        body[byte] |= v as u8;
+        if let Some(next) = body.get_mut(byte + 1) {
+            *next |= (v >> 8) as u8;
+        }
+    });
+    body
+}
+
+fn decode_sparse(body: &[u8]) -> Option<Vec<u8>> {
+    let mut registers = Vec::with_capacity(REGISTERS);
+    let mut i = 0;
+    while i < body.len() {
+        let op = body[i];
+        let (value, len) = if op & 0xc0 == 0 {
+            // ZERO
+            i += 1;
+            (0, (op & 0x3f) as usize + 1)
+        } else if op & 0xc0 == 0x40 {
+            // XZERO
+            let next = *body.get(i + 1)? as usize;
+            i += 2;
+            (0, ((((op & 0x3f) as usize) << 8) | next) + 1)
+        } else {
+            // VAL
+            i += 1;
+            (((op >> 2) & 0x1f) + 1, (op & 0x03) as usize + 1)
+        };
+        if registers.len() + len > REGISTERS {
+            return None;
+        }
+        registers.extend(std::iter::repeat_n(value, len));
+    }
+    if registers.len() == REGISTERS {
+        Some(registers)
+    } else {
+        None
+    }
+}
+
+/// Returns `None` if the registers cannot be represented with the sparse encoding
+fn encode_sparse(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut i = 0;
+    while i < registers.len() {
+        let value = registers[i];
+        let run = registers[i..].iter().take_while(|&&r| r == value).count();
+        i += run;
+        if value == 0 {
+            let mut remaining = run;
+            while remaining > 0 {
+                if remaining > SPARSE_ZERO_MAX_LEN {
+                    let len = remaining.min(SPARSE_XZERO_MAX_LEN) - 1;
+                    body.push(0x40 | (len >> 8) as u8);
+                    body.push((len & 0xff) as u8);
+                    remaining -= len + 1;
+                } else {
+                    body.push((remaining - 1) as u8);
+                    remaining = 0;
+                }
+            }
+        } else if value > SPARSE_VAL_MAX_VALUE {
+            return None;
+        } else {
+            let mut remaining = run;
+            while remaining > 0 {
+                let len = remaining.min(SPARSE_VAL_MAX_LEN);
+                body.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                remaining -= len;
+            }
+        }
+    }
+    Some(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hll_with(n: u64) -> HyperLogLog {
+        let mut hll = HyperLogLog::new();
+        (0..n).for_each(|i| {
+            hll.add(format!("element:{}", i).as_bytes());
+        });
+        hll
+    }
+
+    #[test]
+    fn empty_hyperloglog_works() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.count(), 0);
+        let bytes = hll.to_bytes();
+        // Header + a single XZERO opcode
+        assert_eq!(bytes.len(), HEADER_SIZE + 2);
+        assert_eq!(&bytes[0..5], b"HYLL\x01");
+        assert_eq!(&bytes[HEADER_SIZE..], &[0x7f, 0xff]);
+        assert_eq!(HyperLogLog::from_bytes(&bytes), Some(hll));
+    }
+
+    #[test]
+    fn add_works() {
+        let mut hll = HyperLogLog::new();
+        assert!(hll.add(b"a"));
+        assert!(!hll.add(b"a"));
+        assert!(hll.add(b"b"));
+        assert_eq!(hll.count(), 2);
+    }
+
+    #[test]
+    fn sparse_round_trip_works() {
+        let mut hll = hll_with(100);
+        assert_eq!(hll.encoding(), Encoding::Sparse);
+        let bytes = hll.to_bytes();
+        assert!(bytes.len() <= HEADER_SIZE + SPARSE_MAX_BYTES);
+        let mut decoded = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.registers, hll.registers);
+        assert_eq!(decoded.count(), hll.count());
+    }
+
+    #[test]
+    fn promotion_to_dense_works() {
+        let mut hll = hll_with(5000);
+        assert_eq!(hll.encoding(), Encoding::Dense);
+        let bytes = hll.to_bytes();
+        assert_eq!(bytes.len(), DENSE_SIZE);
+        assert_eq!(bytes[4], Encoding::Dense as u8);
+        let mut decoded = HyperLogLog::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.registers, hll.registers);
+        assert_eq!(decoded.count(), hll.count());
+    }
+
+    #[test]
+    fn dense_register_encoding_works() {
+        let registers: Vec<u8> = (0..REGISTERS).map(|i| (i % 64) as u8).collect();
+        assert_eq!(encode_sparse(&registers), None);
+        assert_eq!(decode_dense(&encode_dense(&registers)), registers);
+    }
+
+    #[test]
+    fn cache_works() {
+        let mut hll = hll_with(10);
+        let count = hll.count();
+        let bytes = hll.to_bytes();
+        assert_eq!(cached_cardinality(&bytes), Some(count));
+        hll.add(b"something new");
+        assert_eq!(cached_cardinality(&hll.to_bytes()), None);
+    }
+
+    #[test]
+    fn invalid_bytes_are_rejected() {
+        assert_eq!(HyperLogLog::from_bytes(b"Hello"), None);
+        assert_eq!(
+            HyperLogLog::from_bytes(b"HYLL\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"),
+            None
+        );
+        // A sparse representation that does not cover all the registers
+        let mut bytes = HyperLogLog::new().to_bytes();
+        bytes.truncate(HEADER_SIZE);
+        bytes.push(0x00);
+        assert_eq!(HyperLogLog::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn merge_works() {
+        let mut a = HyperLogLog::new();
+        let mut b = HyperLogLog::new();
+        (0..1000).for_each(|i| {
+            a.add(format!("a:{}", i).as_bytes());
+            b.add(format!("b:{}", i).as_bytes());
+        });
+        a.merge(&b);
+        assert_eq!(a.encoding(), Encoding::Dense);
+        let count = a.count() as f64;
+        assert!(
+            (count - 2000.0).abs() / 2000.0 < 0.05,
+            "count was {}",
+            count
+        );
+    }
+
+    #[test]
+    fn error_is_bounded() {
+        // The standard error is 1.04 / sqrt(16384) = 0.81%, allow 3 standard deviations
+        let bound = 3.0 * 1.04 / (REGISTERS as f64).sqrt();
+        for &n in &[10u64, 100, 1_000, 10_000, 100_000, 500_000] {
+            let mut hll = hll_with(n);
+            let count = hll.count() as f64;
+            let error = (count - n as f64).abs() / n as f64;
+            assert!(
+                error < bound,
+                "n: {}, count: {}, error: {}",
+                n,
+                count,
+                error
+            );
+        }
+    }
+}
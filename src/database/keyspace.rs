@@ -0,0 +1,1520 @@
+//! The keyspace of a [Database](super::Database), the commands are implemented here.
+
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        BTreeMap, BTreeSet, HashMap, LinkedList,
+    },
+    hash::{BuildHasher, Hash, Hasher},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use log::debug;
+
+use super::{
+    actors::{Loan, ShardActors, ShardId},
+    bitmap, geo,
+    geo::Shape,
+    hyperloglog::HyperLogLog,
+    memory::{self, Access, MaxMemoryPolicy, Memory, SAMPLES},
+    sorted_set::SortedSet,
+    watchers::{ChangeLog, CHANGE_LOG_BYTES},
+    Operation, RedisString, Subscriptions, Tracker, Value, NOT_A_HYPERLOGLOG, WRONG_TYPE,
+};
+use crate::{
+    commands::{
+        bitmap::{BitCount, BitField, BitFieldOperation, BitOp, BitPos, GetBit, Overflow, SetBit},
+        cas::{Cas, Version},
+        geo::{GeoAdd, GeoAddCondition, GeoBy, GeoDist, GeoFrom, GeoPos, GeoSearch, SortOrder},
+        get::Get,
+        hyperloglog::{PfAdd, PfCount, PfMerge},
+        keys::{Copy, DbSize, Flush, KeyType, Keys, RandomKey, Rename, Scan},
+        list::Push,
+        set::Set,
+        Command,
+    },
+    glob,
+    resp::Type,
+};
+
+/// The reply for the commands that cannot be run against a single keyspace, e.g. in a transaction
+pub(crate) const NOT_ALLOWED: &str = "ERR Command not allowed inside a transaction";
+
+/// The event of the keys evicted, see [Keyspace::evict]
+const EVICTED: &str = "evicted";
+
+/// The last version given to a change, versions are unique across all the databases
+static VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn next_version() -> u64 {
+    VERSION.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// The number of removed keys whose version is kept by each shard, see [Shard::version]
+const TOMBSTONES: usize = 1024;
+
+/// The number of shards of a keyspace, unless configured otherwise
+pub(crate) const DEFAULT_SHARDS: usize = 16;
+
+/// The data of a [Database](super::Database), split in shards by the hash of the keys.
+/// A command only locks the shards of its keys, so commands on keys of different shards
+/// run in parallel. The commands on the whole keyspace (e.g. KEYS or FLUSHDB) lock every
+/// shard. Shards are always locked in the order of their index, a command never waits for
+/// a shard while holding one that comes after it.
+/// With the [Engine::Actors](super::Engine::Actors) engine, the shards are owned by actors
+/// instead of locks, see [ShardActors].
+///
+/// The changes are logged by their shard. Each shard also has a delivery lock, taken before
+/// the shard is released by a command whose changes are delivered to the watchers: the
+/// changes of a shard are delivered in order, without holding the shard meanwhile.
+pub(crate) struct Store {
+    shards: Owners,
+    deliveries: Vec<Mutex<()>>,
+    /// The memory used by all the stores of the server
+    memory: Memory,
+    /// The number of changes to the keys so far, for the snapshots
+    writes: AtomicU64,
+    /// The offset of the last change logged, the first change is at 1
+    offset: AtomicU64,
+}
+
+/// Where the shards of a [Store] are
+enum Owners {
+    /// Each shard is behind its own lock
+    Locks(Vec<Mutex<Shard>>),
+    /// Each shard is owned by an actor
+    Actors(ShardActors, Vec<ShardId>),
+}
+
+impl Store {
+    /// A store with `shards` shards, a single one behaves like a map behind one lock.
+    /// Each shard keeps its last `change_log` changes, the size of its keys is accounted
+    /// in `memory`.
+    pub(crate) fn new(shards: usize, change_log: usize, memory: Memory) -> Self {
+        let shards = shards.max(1);
+        Store {
+            shards: Owners::Locks(
+                (0..shards)
+                    .map(|_| {
+                        Mutex::new(Shard {
+                            log: ChangeLog::new(change_log, CHANGE_LOG_BYTES),
+                            ..Shard::default()
+                        })
+                    })
+                    .collect(),
+            ),
+            deliveries: (0..shards).map(|_| Mutex::default()).collect(),
+            memory,
+            writes: AtomicU64::new(0),
+            offset: AtomicU64::new(0),
+        }
+    }
+
+    /// Hands the shards over to the actors, they are not behind locks anymore
+    pub(crate) fn hand_to(&mut self, actors: &ShardActors) {
+        if let Owners::Locks(shards) = &mut self.shards {
+            let ids = std::mem::take(shards)
+                .into_iter()
+                .enumerate()
+                .map(|(index, shard)| actors.adopt(index, shard.into_inner().expect("Lock failed")))
+                .collect();
+            self.shards = Owners::Actors(actors.clone(), ids);
+        }
+    }
+
+    /// The actors of the shard, and its id for them, with the actors engine
+    pub(crate) fn actor(&self, shard: usize) -> Option<(&ShardActors, ShardId)> {
+        match &self.shards {
+            Owners::Locks(_) => None,
+            Owners::Actors(actors, ids) => Some((actors, ids[shard])),
+        }
+    }
+
+    /// The number of shards
+    pub(crate) fn len(&self) -> usize {
+        match &self.shards {
+            Owners::Locks(shards) => shards.len(),
+            Owners::Actors(_, ids) => ids.len(),
+        }
+    }
+
+    /// The shard of the keys, if they are all in the same one
+    pub(crate) fn shard(&self, keys: &[String]) -> Option<usize> {
+        let mut shards = keys.iter().map(|key| shard_of(key.as_bytes(), self.len()));
+        let shard = shards.next()?;
+        shards.all(|other| other == shard).then_some(shard)
+    }
+
+    /// The number of changes to the keys so far, see [Store::wrote]
+    pub(crate) fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Records that `count` keys changed
+    pub(crate) fn wrote(&self, count: usize) {
+        self.writes.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Locks the shards of the keys, or every shard for None.
+    /// With the actors engine, the actors lend them instead, in the order of the actors.
+    /// They are given back when the [Shards] are dropped.
+    pub(crate) fn lock(&self, keys: Option<&[String]>) -> Shards<'_> {
+        let locked = self.to_lock(keys);
+        Store::lock_shards(vec![(self, locked)])
+            .pop()
+            .expect("One store is locked")
+    }
+
+    /// Locks the shard at `index` only, see [Store::lock]
+    pub(crate) fn lock_shard(&self, index: usize) -> Shards<'_> {
+        let mut locked = vec![false; self.len()];
+        locked[index] = true;
+        Store::lock_shards(vec![(self, locked)])
+            .pop()
+            .expect("One store is locked")
+    }
+
+    /// Locks the shards of the keys of several stores (every shard for None), the stores
+    /// one after the other in the order given, see [Store::lock].
+    /// With the actors engine, that the stores share, each actor lends the shards it owns
+    /// for all of them at once: it runs nothing else until the first loan is given back,
+    /// asking it a second time would wait forever.
+    pub(crate) fn lock_all<'a>(stores: &[(&'a Store, Option<&[String]>)]) -> Vec<Shards<'a>> {
+        Store::lock_shards(
+            stores
+                .iter()
+                .map(|(store, keys)| (*store, store.to_lock(*keys)))
+                .collect(),
+        )
+    }
+
+    /// The shards of the keys, or every shard for None
+    fn to_lock(&self, keys: Option<&[String]>) -> Vec<bool> {
+        let mut locked = vec![false; self.len()];
+        match keys {
+            Some(keys) => {
+                for key in keys {
+                    locked[shard_of(key.as_bytes(), self.len())] = true;
+                }
+            }
+            None => locked.iter_mut().for_each(|l| *l = true),
+        }
+        locked
+    }
+
+    /// Locks the shards for which `locked` is true in each store, or borrows them from their
+    /// actors, see [Store::lock_all]
+    fn lock_shards(stores: Vec<(&Store, Vec<bool>)>) -> Vec<Shards<'_>> {
+        let mut all: Vec<_> = stores
+            .iter()
+            .map(|(store, _)| Shards {
+                store,
+                shards: (0..store.len()).map(|_| None).collect(),
+                loans: Vec::new(),
+            })
+            .collect();
+        let mut lender = None;
+        // The shards to borrow from each actor, with their store and their index
+        let mut by_actor: BTreeMap<usize, HashMap<u64, (usize, usize)>> = BTreeMap::new();
+        for (position, (store, locked)) in stores.into_iter().enumerate() {
+            let indexes = locked
+                .into_iter()
+                .enumerate()
+                .filter(|(_, locked)| *locked)
+                .map(|(index, _)| index);
+            match &store.shards {
+                Owners::Locks(locks) => {
+                    for index in indexes {
+                        let shard = locks[index].lock().expect("Lock failed");
+                        all[position].shards[index] = Some(Held::Locked(shard));
+                    }
+                }
+                Owners::Actors(actors, ids) => {
+                    lender = Some(actors);
+                    for index in indexes {
+                        by_actor
+                            .entry(ids[index].actor())
+                            .or_default()
+                            .insert(ids[index].id(), (position, index));
+                    }
+                }
+            }
+        }
+        if let Some(actors) = lender {
+            for (actor, wanted) in by_actor {
+                let (lent, loan) = actors
+                    .lend(actor, wanted.keys().copied().collect())
+                    .expect("The actor of the shard stopped");
+                let mut loans: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+                for (id, shard) in lent {
+                    let (position, index) = wanted[&id];
+                    all[position].shards[index] = Some(Held::Lent(shard));
+                    loans.entry(position).or_default().push(index);
+                }
+                for (position, indexes) in loans {
+                    all[position].loans.push((loan.clone(), indexes));
+                }
+            }
+        }
+        all
+    }
+
+    /// The shard at `index`, owned by the actor running a command on it
+    pub(crate) fn own<'a>(&'a self, index: usize, shard: &'a mut Shard) -> Shards<'a> {
+        let mut shards: Vec<_> = (0..self.len()).map(|_| None).collect();
+        shards[index] = Some(Held::Owned(shard));
+        Shards {
+            store: self,
+            shards,
+            loans: Vec::new(),
+        }
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Owners::Actors(actors, ids) = &self.shards {
+            for id in ids {
+                actors.release(*id);
+            }
+        }
+    }
+}
+
+/// The shard of a key, out of `count`
+pub(super) fn shard_of(key: &[u8], count: usize) -> usize {
+    // The default hasher uses fixed keys, a key stays in the same shard
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % count as u64) as usize
+}
+
+/// A shard of a [Store], the values of its keys and what is known of them
+#[derive(Default)]
+pub(crate) struct Shard {
+    pub(crate) map: HashMap<RedisString, Value>,
+    keys: HashMap<RedisString, KeyInfo>,
+    /// The keys in the order of their [scan_position], a SCAN reads them from its cursor
+    positions: BTreeSet<(u64, RedisString)>,
+    /// The version of the removal of the keys removed recently, which is their version
+    /// while they are missing. This way a key that is removed and created again never gets
+    /// an older version back, and removing a key does not change the version of the others.
+    tombstones: HashMap<RedisString, u64>,
+    /// The keys of the tombstones by version, the oldest are forgotten beyond [TOMBSTONES]
+    removals: BTreeMap<u64, RedisString>,
+    /// The version of the last tombstone forgotten (or of the last flush), which is the
+    /// version of the missing keys without one
+    forgotten: u64,
+    /// The last changes of the keys, for resuming watches
+    pub(crate) log: ChangeLog,
+}
+
+/// What is known of a key, besides its value
+struct KeyInfo {
+    /// Bumped by every change to the key
+    version: u64,
+    /// The estimated size of the key and its value, see [memory::size]
+    size: usize,
+    /// For the eviction, see [MaxMemoryPolicy]
+    access: Access,
+}
+
+impl Shard {
+    fn version(&self, key: &RedisString) -> u64 {
+        match self.keys.get(key) {
+            Some(info) => info.version,
+            None => self.tombstones.get(key).copied().unwrap_or(self.forgotten),
+        }
+    }
+
+    /// Keeps the version of the removal of a key, see [Shard::version]
+    fn bury(&mut self, key: &RedisString) {
+        let version = next_version();
+        if let Some(previous) = self.tombstones.insert(key.clone(), version) {
+            self.removals.remove(&previous);
+        }
+        self.removals.insert(version, key.clone());
+        if self.removals.len() > TOMBSTONES {
+            if let Some((version, key)) = self.removals.pop_first() {
+                self.tombstones.remove(&key);
+                self.forgotten = version;
+            }
+        }
+    }
+
+    /// Bumps the version of a key that changed, its new size is accounted in `memory`
+    fn touch(&mut self, key: &RedisString, memory: &Memory) {
+        let info = self.keys.remove(key);
+        let before = info.as_ref().map_or(0, |info| info.size);
+        match self.map.get(key) {
+            Some(value) => {
+                let size = memory::size(key, value);
+                memory.resize(before, size);
+                if info.is_none() {
+                    self.positions.insert((scan_position(key), key.clone()));
+                    if let Some(version) = self.tombstones.remove(key) {
+                        self.removals.remove(&version);
+                    }
+                }
+                let info = KeyInfo {
+                    version: next_version(),
+                    size,
+                    access: info.map_or_else(Access::new, |info| info.access),
+                };
+                self.keys.insert(key.clone(), info);
+            }
+            None => {
+                if info.is_some() {
+                    self.positions.remove(&(scan_position(key), key.clone()));
+                }
+                memory.resize(before, 0);
+                self.bury(key);
+            }
+        }
+    }
+
+    /// Bumps the version of a key whose value grew in place by `by` bytes, its size is not
+    /// estimated again
+    fn grow(&mut self, key: &RedisString, by: usize, memory: &Memory) {
+        if let Some(info) = self.keys.get_mut(key) {
+            memory.resize(info.size, info.size + by);
+            info.size += by;
+            info.version = next_version();
+        }
+    }
+
+    /// Takes the values out, all the keys are removed
+    fn take(&mut self, memory: &Memory) -> HashMap<RedisString, Value> {
+        let map = std::mem::take(&mut self.map);
+        if !map.is_empty() {
+            let size = self.keys.drain().map(|(_, info)| info.size).sum();
+            memory.resize(size, 0);
+            self.positions.clear();
+            self.tombstones.clear();
+            self.removals.clear();
+            self.forgotten = next_version();
+        }
+        map
+    }
+}
+
+/// A shard of [Shards]
+enum Held<'a> {
+    Locked(MutexGuard<'a, Shard>),
+    /// Lent by its actor, given back when the [Shards] are dropped
+    Lent(Box<Shard>),
+    /// Owned by the actor running the command
+    Owned(&'a mut Shard),
+}
+
+impl Deref for Held<'_> {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        match self {
+            Held::Locked(shard) => shard,
+            Held::Lent(shard) => shard,
+            Held::Owned(shard) => shard,
+        }
+    }
+}
+
+impl DerefMut for Held<'_> {
+    fn deref_mut(&mut self) -> &mut Shard {
+        match self {
+            Held::Locked(shard) => shard,
+            Held::Lent(shard) => shard,
+            Held::Owned(shard) => shard,
+        }
+    }
+}
+
+/// The locked shards of a [Store], used like a single map. Using a key of a shard that
+/// is not locked is a bug: the keys of a command are known before it runs.
+pub(crate) struct Shards<'a> {
+    /// Every shard of the store, None for those not locked
+    shards: Vec<Option<Held<'a>>>,
+    store: &'a Store,
+    /// The loans of the lent shards, with their indexes
+    loans: Vec<(Loan, Vec<usize>)>,
+}
+
+impl Drop for Shards<'_> {
+    fn drop(&mut self) {
+        for (loan, indexes) in std::mem::take(&mut self.loans) {
+            let shards = indexes
+                .iter()
+                .filter_map(|index| match self.shards[*index].take() {
+                    Some(Held::Lent(shard)) => {
+                        let (_, id) = self.store.actor(*index)?;
+                        Some((id.id(), shard))
+                    }
+                    _ => None,
+                })
+                .collect();
+            loan.give_back(shards);
+        }
+    }
+}
+
+impl<'a> Shards<'a> {
+    fn shard(&self, key: &RedisString) -> &Shard {
+        self.shards[shard_of(key.as_bytes(), self.shards.len())]
+            .as_ref()
+            .expect("The shard of the key is not locked")
+    }
+
+    fn shard_mut(&mut self, key: &RedisString) -> &mut Shard {
+        let index = shard_of(key.as_bytes(), self.shards.len());
+        self.shards[index]
+            .as_mut()
+            .expect("The shard of the key is not locked")
+    }
+
+    /// The locked shards, in the order of their index
+    pub(crate) fn locked(&mut self) -> Vec<&mut Shard> {
+        self.shards
+            .iter_mut()
+            .flatten()
+            .map(|shard| &mut **shard)
+            .collect()
+    }
+
+    pub(crate) fn get(&self, key: &RedisString) -> Option<&Value> {
+        self.shard(key).map.get(key)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &RedisString) -> Option<&mut Value> {
+        self.shard_mut(key).map.get_mut(key)
+    }
+
+    pub(crate) fn contains_key(&self, key: &RedisString) -> bool {
+        self.shard(key).map.contains_key(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: RedisString, value: Value) -> Option<Value> {
+        self.shard_mut(&key).map.insert(key, value)
+    }
+
+    pub(crate) fn remove(&mut self, key: &RedisString) -> Option<Value> {
+        self.shard_mut(key).map.remove(key)
+    }
+
+    /// The keys of the locked shards
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &RedisString> {
+        self.shards
+            .iter()
+            .flatten()
+            .flat_map(|shard| shard.map.keys())
+    }
+
+    /// The keys of the locked shards, with their values
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&RedisString, &Value)> {
+        self.shards
+            .iter()
+            .flatten()
+            .flat_map(|shard| shard.map.iter())
+    }
+
+    /// The number of keys in the locked shards
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .flatten()
+            .map(|shard| shard.map.len())
+            .sum()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The version of a key, every change to a key bumps it
+    pub(crate) fn version(&self, key: &RedisString) -> u64 {
+        self.shard(key).version(key)
+    }
+
+    /// Bumps the version of a key that changed
+    pub(crate) fn touch(&mut self, key: &RedisString) {
+        let store = self.store;
+        self.shard_mut(key).touch(key, &store.memory)
+    }
+
+    /// Bumps the version of a key whose value grew in place by `by` bytes
+    pub(crate) fn grow(&mut self, key: &RedisString, by: usize) {
+        let store = self.store;
+        self.shard_mut(key).grow(key, by, &store.memory)
+    }
+
+    /// Takes the values of the locked shards out, shard by shard
+    pub(crate) fn take(&mut self) -> Vec<HashMap<RedisString, Value>> {
+        let store = self.store;
+        self.locked()
+            .into_iter()
+            .map(|shard| shard.take(&store.memory))
+            .collect()
+    }
+
+    /// The memory used by all the stores of the server
+    pub(crate) fn memory(&self) -> &Memory {
+        &self.store.memory
+    }
+
+    /// Gives the change the next offset and keeps it in the log of the shard of its key
+    pub(crate) fn log(&mut self, change: &mut Change) {
+        change.offset = self.store.offset.fetch_add(1, Ordering::SeqCst) + 1;
+        let store = self.store;
+        self.shard_mut(&change.key).log.push(change, &store.memory);
+    }
+
+    /// Drops the changes logged by the locked shards, returns true if there were some
+    pub(crate) fn forget_changes(&mut self) -> bool {
+        let store = self.store;
+        let mut forgot = false;
+        for shard in self.shards.iter_mut().flatten() {
+            forgot |= shard.log.forget(&store.memory);
+        }
+        forgot
+    }
+
+    /// The offset of the last change logged. It does not move while every shard is locked,
+    /// nor do the changes of the keys of the locked shards.
+    pub(crate) fn offset(&self) -> u64 {
+        self.store.offset.load(Ordering::SeqCst)
+    }
+
+    /// The logged changes of the locked shards after `offset`, in order. None if some of
+    /// them are not kept anymore.
+    pub(crate) fn changes_after(&self, offset: u64) -> Option<Vec<Change>> {
+        let mut changes = Vec::new();
+        for shard in self.shards.iter().flatten() {
+            changes.extend(shard.log.after(offset)?.cloned());
+        }
+        changes.sort_by_key(|change| change.offset);
+        Some(changes)
+    }
+
+    /// Takes the delivery locks of the shards of the changes (of every locked shard for
+    /// None), in the order of their index. They are held until the changes are delivered.
+    pub(crate) fn deliver(&self, changes: Option<&[Change]>) -> Vec<MutexGuard<'a, ()>> {
+        let count = self.shards.len();
+        let mut delivered = vec![false; count];
+        match changes {
+            Some(changes) => changes
+                .iter()
+                .for_each(|change| delivered[shard_of(change.key.as_bytes(), count)] = true),
+            None => self
+                .shards
+                .iter()
+                .zip(delivered.iter_mut())
+                .for_each(|(shard, delivered)| *delivered = shard.is_some()),
+        }
+        self.store
+            .deliveries
+            .iter()
+            .zip(delivered)
+            .filter(|(_, delivered)| *delivered)
+            .map(|(delivery, _)| delivery.lock().expect("Lock failed"))
+            .collect()
+    }
+
+    /// The first `count` keys from the `cursor`, in the order of their [scan_position], and
+    /// the cursor of the next ones (0 at the end). The keys sharing the position of the last
+    /// one are returned with it, as a cursor cannot split them. Every locked shard gives at
+    /// most `count` keys (and those ties), which are enough to find the first `count` overall.
+    pub(crate) fn scan(&self, cursor: u64, count: usize) -> (Vec<&RedisString>, u64) {
+        let from = (cursor, RedisString::from(""));
+        let mut found: Vec<&(u64, RedisString)> = Vec::new();
+        for shard in self.shards.iter().flatten() {
+            let mut last = None;
+            for (taken, entry) in shard.positions.range(&from..).enumerate() {
+                if taken >= count && last != Some(entry.0) {
+                    break;
+                }
+                found.push(entry);
+                last = Some(entry.0);
+            }
+        }
+        found.sort_unstable();
+        let mut next = 0;
+        if found.len() > count {
+            let last = found[count - 1].0;
+            found.retain(|(position, _)| *position <= last);
+            next = last.checked_add(1).unwrap_or(0);
+        }
+        (found.into_iter().map(|(_, key)| key).collect(), next)
+    }
+
+    /// Records an access to a key, if it exists, for the eviction
+    fn access(&mut self, key: &RedisString) {
+        if let Some(info) = self.shard_mut(key).keys.get_mut(key) {
+            info.access.touch(random());
+        }
+    }
+
+    /// Up to `count` keys of a random locked shard, next to each other from a random
+    /// position, with their accesses (like the sampling of Redis)
+    fn sample(&self, count: usize) -> Vec<(&RedisString, &Access)> {
+        let shards: Vec<&Shard> = self
+            .shards
+            .iter()
+            .flatten()
+            .map(|shard| &**shard)
+            .filter(|shard| !shard.keys.is_empty())
+            .collect();
+        if shards.is_empty() {
+            return Vec::new();
+        }
+        let shard = shards[(random() % shards.len() as u64) as usize];
+        let start = (random() % shard.keys.len() as u64) as usize;
+        shard
+            .keys
+            .iter()
+            .skip(start)
+            .chain(shard.keys.iter())
+            .take(count.min(shard.keys.len()))
+            .map(|(key, info)| (key, &info.access))
+            .collect()
+    }
+}
+
+/// A change made to a key, every write is recorded as one.
+/// The watchers are notified of the changes once the lock is released.
+#[derive(Debug, Clone)]
+pub(crate) struct Change {
+    pub(crate) key: RedisString,
+    /// The name of the event, after the command that made the change (e.g. `set` or `rpush`)
+    pub(crate) event: &'static str,
+    pub(crate) operation: Operation,
+    /// The value before, None for an addition and for the values appended to a list
+    pub(crate) before: Option<Value>,
+    /// The value after, None for a removal. Only the appended values for a list.
+    pub(crate) after: Option<Value>,
+    /// The offset in the change log of the database, given when it is logged
+    pub(crate) offset: u64,
+    /// The tracker of the connection that made the change, if it has one (for NOLOOP)
+    pub(crate) writer: Option<u64>,
+}
+
+impl Change {
+    /// A key going from a value to another, at least one of them is some
+    pub(crate) fn new(
+        key: RedisString,
+        event: &'static str,
+        before: Option<Value>,
+        after: Option<Value>,
+    ) -> Self {
+        let operation = match (&before, &after) {
+            (Some(_), Some(_)) => Operation::Update,
+            (Some(_), None) => Operation::Removal,
+            (None, _) => Operation::Addition,
+        };
+        Change {
+            key,
+            event,
+            operation,
+            before,
+            after,
+            offset: 0,
+            writer: None,
+        }
+    }
+
+    /// The change as it is kept by a [ChangeLog]: the value of an evicted key is not, the
+    /// eviction would not free its memory otherwise
+    pub(crate) fn logged(&self) -> Change {
+        Change {
+            key: self.key.clone(),
+            event: self.event,
+            operation: self.operation.clone(),
+            before: match self.event {
+                EVICTED => None,
+                _ => self.before.clone(),
+            },
+            after: self.after.clone(),
+            offset: self.offset,
+            writer: self.writer,
+        }
+    }
+
+    /// The current value of a key for a `WATCH ... SNAPSHOT`, not a change but sent
+    /// like one: an addition of the value, or a removal if the key does not exist
+    pub(crate) fn snapshot(key: RedisString, value: Option<Value>) -> Self {
+        Change {
+            key,
+            event: "snapshot",
+            operation: match value {
+                Some(_) => Operation::Addition,
+                None => Operation::Removal,
+            },
+            before: None,
+            after: value,
+            offset: 0,
+            writer: None,
+        }
+    }
+
+    /// Values appended to a list, an addition if the list was `created`
+    fn appended(key: RedisString, values: LinkedList<Value>, created: bool) -> Self {
+        Change {
+            key,
+            event: "rpush",
+            operation: match created {
+                true => Operation::Addition,
+                false => Operation::Update,
+            },
+            before: None,
+            after: Some(Value::List(values)),
+            offset: 0,
+            writer: None,
+        }
+    }
+
+    /// Members added to or moved in an existing sorted set
+    fn scored(key: RedisString, members: SortedSet) -> Self {
+        Change {
+            key,
+            event: "zadd",
+            operation: Operation::Update,
+            before: None,
+            after: Some(Value::SortedSet(members)),
+            offset: 0,
+            writer: None,
+        }
+    }
+}
+
+/// The keyspace while the lock of its [Database](super::Database) is held.
+/// Commands are run against it and the changes they make are collected.
+pub(crate) struct Keyspace<'a> {
+    pub(crate) store: Shards<'a>,
+    pub(crate) subscriptions: &'a Mutex<Subscriptions>,
+    pub(crate) changes: Vec<Change>,
+    /// The flushed maps (and if they are to be dropped on a background task)
+    pub(crate) flushed: Vec<(HashMap<RedisString, Value>, bool)>,
+    /// The tracker of the connection running the commands, the writer of the changes
+    pub(crate) writer: Option<u64>,
+}
+
+impl Keyspace<'_> {
+    /// Runs a command against the keyspace. The commands that need more than the keyspace
+    /// (e.g. WATCH or SELECT) are run by the server.
+    pub(crate) fn apply(&mut self, command: Command) -> Type {
+        for key in command.keys().into_iter().flatten() {
+            self.store.access(&key.into());
+        }
+        match command {
+            Command::Get(g) => self.get(g),
+            Command::Set(s) => self.set(s),
+            Command::Push(p) => self.push(p),
+            Command::PfAdd(p) => self.pfadd(p),
+            Command::PfCount(p) => self.pfcount(p),
+            Command::PfMerge(p) => self.pfmerge(p),
+            Command::SetBit(s) => self.setbit(s),
+            Command::GetBit(g) => self.getbit(g),
+            Command::BitCount(b) => self.bitcount(b),
+            Command::BitPos(b) => self.bitpos(b),
+            Command::BitOp(b) => self.bitop(b),
+            Command::BitField(b) => self.bitfield(b),
+            Command::GeoAdd(g) => self.geoadd(g),
+            Command::GeoDist(g) => self.geodist(g),
+            Command::GeoPos(g) => self.geopos(g),
+            Command::GeoSearch(g) => self.geosearch(g),
+            Command::Keys(k) => self.keys(k),
+            Command::Scan(s) => self.scan(s),
+            Command::KeyType(k) => self.key_type(k),
+            Command::Rename(r) => self.rename(r),
+            Command::Copy(c) => self.copy(c),
+            Command::RandomKey(r) => self.random_key(r),
+            Command::DbSize(d) => self.db_size(d),
+            Command::Flush(f) if !f.all => self.flush(f),
+            Command::Version(v) => self.version(v),
+            Command::Cas(c) => self.cas(c),
+            Command::Ping(p) => match p.message {
+                Some(message) => Type::BulkString(message.into_bytes()),
+                None => Type::SimpleString("PONG".into()),
+            },
+            _ => Type::Error(NOT_ALLOWED.into()),
+        }
+    }
+
+    /// Tracks the keys for the tracker, it is told when they change (see CLIENT TRACKING).
+    /// They are tracked before the lock of the keyspace is released, no change is missed.
+    pub(crate) fn track(&self, keys: Vec<String>, tracker: &Tracker) {
+        let mut subscriptions = self.subscriptions.lock().expect("Lock failed");
+        for key in keys {
+            subscriptions.track(key.into(), tracker);
+        }
+    }
+
+    /// Evicts a key, among a few sampled ones, as the policy says (see [memory]). If none
+    /// can be, the changes logged by the shards are dropped instead. Returns false if there
+    /// were none either.
+    pub(crate) fn evict(&mut self, policy: MaxMemoryPolicy) -> bool {
+        let key = match policy.pick(&self.store.sample(SAMPLES)) {
+            Some(key) => key.clone(),
+            None => return self.store.forget_changes(),
+        };
+        self.remove(&key, EVICTED);
+        self.store.memory().evicted();
+        true
+    }
+
+    /// The version of a key, see [Shards::version]
+    pub(crate) fn version_of(&self, key: &str) -> u64 {
+        self.store.version(&key.into())
+    }
+
+    /// Inserts a value and records the change as `event`
+    fn insert(&mut self, key: RedisString, value: Value, event: &'static str) -> Option<Value> {
+        let before = self.store.insert(key.clone(), value.clone());
+        self.store.touch(&key);
+        self.changes
+            .push(Change::new(key, event, before.clone(), Some(value)));
+        before
+    }
+
+    /// Removes a value and records the change as `event` (if there was a value)
+    fn remove(&mut self, key: &RedisString, event: &'static str) -> Option<Value> {
+        let before = self.store.remove(key);
+        if before.is_some() {
+            self.store.touch(key);
+            self.changes
+                .push(Change::new(key.clone(), event, before.clone(), None));
+        }
+        before
+    }
+
+    pub(crate) fn get(&mut self, get: Get) -> Type {
+        let key: RedisString = get.key.into();
+        let db = &self.store;
+        match db.get(&key).cloned() {
+            Some(v) => v.into(),
+            None => Type::Null,
+        }
+    }
+
+    pub(crate) fn set(&mut self, set: Set) -> Type {
+        let key: RedisString = set.key.into();
+        let value: RedisString = set.value.into();
+        self.insert(key, Value::String(value), "set");
+        Type::SimpleString("Ok".into())
+    }
+
+    /// The list is changed in place, the change only holds the appended values
+    pub(crate) fn push(&mut self, p: Push) -> Type {
+        let r_key: RedisString = p.list_name.clone().into();
+        let len = p.values.len();
+        let appended = match Value::from(p.values) {
+            Value::List(appended) => appended,
+            _ => unreachable!("A list of strings is a list"),
+        };
+        let db = &mut self.store;
+        let (reply, created) = match db.get_mut(&r_key) {
+            // If there is a value and it is a list already we are good
+            // If it is not a list, return an error
+            Some(v) => match v {
+                // Not a a list return error
+                // A list add these elements to it
+                Value::List(list) => {
+                    list.extend(appended.iter().cloned());
+                    let reply = log_and_return(
+                        format!("Found list `{}`, and pushed {} elments", p.list_name, len),
+                        Type::Integer(list.len() as i64),
+                    );
+                    (reply, false)
+                }
+                _ => {
+                    return log_and_return(
+                        format!("key `{}` is not a list", p.list_name),
+                        Type::Error(format!(
+                            "key `{}` exists and it is not a list",
+                            &p.list_name
+                        )),
+                    )
+                }
+            },
+            // There is no value, we will create one
+            None => {
+                db.insert(r_key.clone(), Value::List(appended.clone()));
+                let reply = log_and_return(
+                    format!(
+                        "Created a new list {} and pushed {} elements",
+                        p.list_name, len
+                    ),
+                    Type::Integer(len as i64),
+                );
+                (reply, true)
+            }
+        };
+        self.store.touch(&r_key);
+        self.changes
+            .push(Change::appended(r_key, appended, created));
+        reply
+    }
+
+    pub(crate) fn pfadd(&mut self, p: PfAdd) -> Type {
+        let key: RedisString = p.key.into();
+        let db = &mut self.store;
+        let (mut hll, mut modified) = match db.get(&key) {
+            Some(v) => match as_hyperloglog(v) {
+                Ok(hll) => (hll, false),
+                Err(e) => return e,
+            },
+            // A new HyperLogLog is created even if there are no elements
+            None => (HyperLogLog::new(), true),
+        };
+        p.elements.iter().for_each(|e| modified |= hll.add(e));
+        if modified {
+            let after = Value::String(hll.to_bytes().into());
+            self.insert(key, after, "pfadd");
+        }
+        Type::Integer(modified as i64)
+    }
+
+    pub(crate) fn pfcount(&mut self, p: PfCount) -> Type {
+        let db = &mut self.store;
+        if p.keys.len() == 1 {
+            let key: RedisString = p.keys.into_iter().next().expect("Cannot be empty").into();
+            return match db.get_mut(&key) {
+                Some(v) => match as_hyperloglog(v) {
+                    Ok(mut hll) => {
+                        let count = hll.count();
+                        // Cache the cardinality, this is not a change that watchers are interested in
+                        *v = Value::String(hll.to_bytes().into());
+                        Type::Integer(count as i64)
+                    }
+                    Err(e) => e,
+                },
+                None => Type::Integer(0),
+            };
+        }
+        // The cardinality of the union is computed on a temporary HyperLogLog
+        let mut union = HyperLogLog::new();
+        for key in p.keys {
+            let key: RedisString = key.into();
+            if let Some(v) = db.get(&key) {
+                match as_hyperloglog(v) {
+                    Ok(hll) => union.merge(&hll),
+                    Err(e) => return e,
+                }
+            }
+        }
+        Type::Integer(union.count() as i64)
+    }
+
+    pub(crate) fn pfmerge(&mut self, p: PfMerge) -> Type {
+        let key: RedisString = p.destination.into();
+        let db = &mut self.store;
+        let mut merged = HyperLogLog::new();
+        for k in std::iter::once(key.clone()).chain(p.sources.into_iter().map(|s| s.into())) {
+            if let Some(v) = db.get(&k) {
+                match as_hyperloglog(v) {
+                    Ok(hll) => merged.merge(&hll),
+                    Err(e) => return e,
+                }
+            }
+        }
+        let after = Value::String(merged.to_bytes().into());
+        self.insert(key, after, "pfadd");
+        Type::SimpleString("Ok".into())
+    }
+
+    pub(crate) fn setbit(&mut self, s: SetBit) -> Type {
+        let key: RedisString = s.key.into();
+        let db = &mut self.store;
+        let mut bytes = match string_bytes(db.get(&key)) {
+            Ok(bytes) => bytes,
+            Err(e) => return e,
+        };
+        let previous = bitmap::set_bit(&mut bytes, s.offset, s.value);
+        let after = Value::String(bytes.into());
+        self.insert(key, after, "setbit");
+        Type::Integer(previous as i64)
+    }
+
+    pub(crate) fn getbit(&mut self, g: GetBit) -> Type {
+        let GetBit { key, offset } = g;
+        let key: RedisString = key.into();
+        let db = &self.store;
+        read_bytes(db.get(&key), |bytes| {
+            Type::Integer(bitmap::get_bit(bytes, offset) as i64)
+        })
+    }
+
+    pub(crate) fn bitcount(&mut self, b: BitCount) -> Type {
+        let BitCount { key, range } = b;
+        let key: RedisString = key.into();
+        let db = &self.store;
+        read_bytes(db.get(&key), |bytes| {
+            Type::Integer(bitmap::bit_count(bytes, range.as_ref()) as i64)
+        })
+    }
+
+    pub(crate) fn bitpos(&mut self, b: BitPos) -> Type {
+        let BitPos {
+            key,
+            bit,
+            start,
+            end,
+            unit,
+        } = b;
+        let key: RedisString = key.into();
+        let db = &self.store;
+        read_bytes(db.get(&key), |bytes| {
+            Type::Integer(bitmap::bit_pos(bytes, bit, start, end, &unit))
+        })
+    }
+
+    pub(crate) fn bitop(&mut self, b: BitOp) -> Type {
+        let key: RedisString = b.destination.into();
+        let db = &mut self.store;
+        let mut sources = Vec::with_capacity(b.keys.len());
+        for k in b.keys {
+            let k: RedisString = k.into();
+            match string_bytes(db.get(&k)) {
+                Ok(bytes) => sources.push(bytes),
+                Err(e) => return e,
+            }
+        }
+        let result = bitmap::bit_op(&b.operation, &sources);
+        let len = result.len();
+        // An empty result deletes the destination
+        if result.is_empty() {
+            self.remove(&key, "del");
+        } else {
+            self.insert(key, Value::String(result.into()), "set");
+        }
+        Type::Integer(len as i64)
+    }
+
+    pub(crate) fn bitfield(&mut self, b: BitField) -> Type {
+        let key: RedisString = b.key.into();
+        let db = &mut self.store;
+        let mut bytes = match string_bytes(db.get(&key)) {
+            Ok(bytes) => bytes,
+            Err(e) => return e,
+        };
+        let mut overflow = Overflow::Wrap;
+        let mut modified = false;
+        let mut replies = LinkedList::new();
+        for operation in b.operations {
+            let reply = match operation {
+                BitFieldOperation::Get(t, offset) => {
+                    Type::Integer(bitmap::get_integer(&bytes, offset, &t))
+                }
+                BitFieldOperation::Set(t, offset, value) => {
+                    let previous = bitmap::get_integer(&bytes, offset, &t);
+                    match bitmap::fit(&t, value as i128, &overflow) {
+                        Some(value) => {
+                            bitmap::set_integer(&mut bytes, offset, &t, value);
+                            modified = true;
+                            Type::Integer(previous)
+                        }
+                        None => Type::Null,
+                    }
+                }
+                BitFieldOperation::IncrBy(t, offset, increment) => {
+                    let previous = bitmap::get_integer(&bytes, offset, &t);
+                    match bitmap::fit(&t, previous as i128 + increment as i128, &overflow) {
+                        Some(value) => {
+                            bitmap::set_integer(&mut bytes, offset, &t, value);
+                            modified = true;
+                            Type::Integer(value)
+                        }
+                        None => Type::Null,
+                    }
+                }
+                BitFieldOperation::Overflow(o) => {
+                    overflow = o;
+                    continue;
+                }
+            };
+            replies.push_back(reply);
+        }
+        if modified {
+            let after = Value::String(bytes.into());
+            self.insert(key, after, "setbit");
+        }
+        Type::Array(replies)
+    }
+
+    pub(crate) fn geoadd(&mut self, g: GeoAdd) -> Type {
+        if let Some(m) = g
+            .members
+            .iter()
+            .find(|m| !geo::is_valid(m.longitude, m.latitude))
+        {
+            return Type::Error(format!(
+                "ERR invalid longitude,latitude pair {:.6},{:.6}",
+                m.longitude, m.latitude
+            ));
+        }
+        let key: RedisString = g.key.into();
+        let db = &mut self.store;
+        let set = match db.get_mut(&key) {
+            Some(Value::SortedSet(set)) => Some(set),
+            Some(_) => return Type::Error(WRONG_TYPE.into()),
+            None => None,
+        };
+        let created = set.is_none();
+        let mut new_set = SortedSet::new();
+        let set = set.unwrap_or(&mut new_set);
+        // The members written, the change sent for an existing set
+        let mut written = SortedSet::new();
+        let (mut added, mut updated, mut grown) = (0, 0, 0);
+        for m in g.members {
+            let member: RedisString = m.member.into();
+            let score = geo::encode(m.longitude, m.latitude, geo::STEP) as f64;
+            match (set.score(&member), g.condition) {
+                (Some(_), Some(GeoAddCondition::Nx)) | (None, Some(GeoAddCondition::Xx)) => {}
+                (Some(previous), _) if previous == score => {}
+                (previous, _) => {
+                    match previous {
+                        Some(_) => updated += 1,
+                        None => {
+                            added += 1;
+                            grown += memory::member_size(&member)
+                        }
+                    }
+                    written.insert(member.clone(), score);
+                    set.insert(member, score);
+                }
+            }
+        }
+        let reply = Type::Integer(if g.changed { added + updated } else { added });
+        if added + updated == 0 {
+            reply
+        } else if created {
+            self.insert(key, Value::SortedSet(new_set), "zadd");
+            reply
+        } else {
+            self.store.grow(&key, grown);
+            self.changes.push(Change::scored(key, written));
+            reply
+        }
+    }
+
+    pub(crate) fn geodist(&mut self, g: GeoDist) -> Type {
+        let key: RedisString = g.key.into();
+        let db = &self.store;
+        let set = match sorted_set(db.get(&key)) {
+            Ok(Some(set)) => set,
+            Ok(None) => return Type::Null,
+            Err(e) => return e,
+        };
+        match (set.score(&g.member1.into()), set.score(&g.member2.into())) {
+            (Some(score1), Some(score2)) => {
+                let distance = geo::distance(geo::decode_score(score1), geo::decode_score(score2));
+                format_distance(distance, g.unit.in_meters())
+            }
+            _ => Type::Null,
+        }
+    }
+
+    pub(crate) fn geopos(&mut self, g: GeoPos) -> Type {
+        let key: RedisString = g.key.into();
+        let db = &self.store;
+        let set = match sorted_set(db.get(&key)) {
+            Ok(set) => set,
+            Err(e) => return e,
+        };
+        Type::Array(
+            g.members
+                .into_iter()
+                .map(|m| match set.and_then(|s| s.score(&m.into())) {
+                    Some(score) => format_position(geo::decode_score(score)),
+                    None => Type::Null,
+                })
+                .collect(),
+        )
+    }
+
+    pub(crate) fn geosearch(&mut self, g: GeoSearch) -> Type {
+        let GeoSearch {
+            key,
+            from,
+            by,
+            order,
+            count,
+            with_coord,
+            with_dist,
+            with_hash,
+        } = g;
+        let key: RedisString = key.into();
+        let db = &self.store;
+        let set = match sorted_set(db.get(&key)) {
+            Ok(Some(set)) => set,
+            Ok(None) => return Type::Array(LinkedList::new()),
+            Err(e) => return e,
+        };
+        let center = match from {
+            GeoFrom::Member(m) => match set.score(&m.into()) {
+                Some(score) => geo::decode_score(score),
+                None => return Type::Error("ERR could not decode requested zset member".into()),
+            },
+            GeoFrom::LonLat(longitude, latitude) if geo::is_valid(longitude, latitude) => {
+                (longitude, latitude)
+            }
+            GeoFrom::LonLat(longitude, latitude) => {
+                return Type::Error(format!(
+                    "ERR invalid longitude,latitude pair {:.6},{:.6}",
+                    longitude, latitude
+                ))
+            }
+        };
+        let unit = by.unit().in_meters();
+        let shape = match by {
+            GeoBy::Radius(radius, _) => Shape::Radius(radius * unit),
+            GeoBy::Box(width, height, _) => Shape::Box {
+                width: width * unit,
+                height: height * unit,
+            },
+        };
+        // With ANY the search stops as soon as enough members are found
+        let limit = count.filter(|(_, any)| *any).map(|(count, _)| count);
+        let mut found = geo::search(set, center, &shape, limit);
+        // Sorting is implied by COUNT (without ANY)
+        let order = match (order, count) {
+            (None, Some((_, false))) => Some(SortOrder::Asc),
+            (order, _) => order,
+        };
+        match order {
+            Some(SortOrder::Asc) => found.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            Some(SortOrder::Desc) => found.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            None => {}
+        }
+        if let Some((count, _)) = count {
+            found.truncate(count);
+        }
+        Type::Array(
+            found
+                .into_iter()
+                .map(|f| {
+                    let member = Type::BulkString(f.member.as_bytes().to_vec());
+                    if !(with_dist || with_hash || with_coord) {
+                        return member;
+                    }
+                    let mut reply = LinkedList::new();
+                    reply.push_back(member);
+                    if with_dist {
+                        reply.push_back(format_distance(f.distance, unit));
+                    }
+                    if with_hash {
+                        reply.push_back(Type::Integer(f.score as i64));
+                    }
+                    if with_coord {
+                        reply.push_back(format_position(f.position));
+                    }
+                    Type::Array(reply)
+                })
+                .collect(),
+        )
+    }
+
+    pub(crate) fn keys(&mut self, k: Keys) -> Type {
+        let db = &self.store;
+        Type::Array(
+            db.keys()
+                .filter(|key| glob::matches(k.pattern.as_bytes(), key.as_bytes()))
+                .map(|key| Type::BulkString(key.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    /// Keys are visited in the order of a (per process) stable hash, the cursor being the next
+    /// hash to visit. Changes to the map never move a key behind the cursor, so every key present
+    /// for the whole iteration is returned (exactly once).
+    /// Every call only reads the next COUNT keys of the shards, see [Shards::scan].
+    pub(crate) fn scan(&mut self, s: Scan) -> Type {
+        let db = &self.store;
+        let (keys, cursor) = db.scan(s.cursor, s.count);
+        let keys = keys
+            .into_iter()
+            .filter(|key| {
+                s.pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob::matches(pattern.as_bytes(), key.as_bytes()))
+            })
+            .filter(|key| {
+                s.value_type.as_ref().is_none_or(|value_type| {
+                    db.get(key)
+                        .is_some_and(|v| v.type_name().eq_ignore_ascii_case(value_type))
+                })
+            })
+            .map(|key| Type::BulkString(key.as_bytes().to_vec()))
+            .collect();
+        let mut reply = LinkedList::new();
+        reply.push_back(Type::BulkString(cursor.to_string().into_bytes()));
+        reply.push_back(Type::Array(keys));
+        Type::Array(reply)
+    }
+
+    pub(crate) fn key_type(&mut self, k: KeyType) -> Type {
+        let key: RedisString = k.key.into();
+        let db = &self.store;
+        Type::SimpleString(db.get(&key).map_or("none", |v| v.type_name()).into())
+    }
+
+    /// Notifies a removal for the old key and an addition (or an update) for the new one
+    pub(crate) fn rename(&mut self, r: Rename) -> Type {
+        let success = |only_if_new, renamed: bool| match only_if_new {
+            true => Type::Integer(renamed as i64),
+            false => Type::SimpleString("OK".into()),
+        };
+        let key: RedisString = r.key.into();
+        let new_key: RedisString = r.new_key.into();
+        let db = &mut self.store;
+        if !db.contains_key(&key) {
+            return Type::Error("ERR no such key".into());
+        }
+        if key == new_key {
+            return success(r.only_if_new, false);
+        }
+        if r.only_if_new && db.contains_key(&new_key) {
+            return Type::Integer(0);
+        }
+        let value = self.remove(&key, "rename_from").expect("Checked above");
+        self.insert(new_key, value, "rename_to");
+        success(r.only_if_new, true)
+    }
+
+    pub(crate) fn copy(&mut self, c: Copy) -> Type {
+        let source: RedisString = c.source.into();
+        let destination: RedisString = c.destination.into();
+        if source == destination {
+            return Type::Error("ERR source and destination objects are the same".into());
+        }
+        let db = &mut self.store;
+        let value = match db.get(&source) {
+            Some(v) if c.replace || !db.contains_key(&destination) => v.clone(),
+            _ => return Type::Integer(0),
+        };
+        self.insert(destination, value, "copy_to");
+        Type::Integer(1)
+    }
+
+    pub(crate) fn random_key(&mut self, _: RandomKey) -> Type {
+        let db = &self.store;
+        if db.is_empty() {
+            return Type::Null;
+        }
+        let index = (random() % db.len() as u64) as usize;
+        match db.keys().nth(index) {
+            Some(key) => Type::BulkString(key.as_bytes().to_vec()),
+            None => Type::Null,
+        }
+    }
+
+    pub(crate) fn db_size(&mut self, _: DbSize) -> Type {
+        Type::Integer(self.store.len() as i64)
+    }
+
+    pub(crate) fn version(&mut self, v: Version) -> Type {
+        Type::Integer(self.version_of(&v.key) as i64)
+    }
+
+    /// Sets the value if the key is at the expected version, replies with 1 if it was set
+    pub(crate) fn cas(&mut self, c: Cas) -> Type {
+        let key: RedisString = c.key.into();
+        if self.store.version(&key) != c.version {
+            return Type::Integer(0);
+        }
+        self.insert(key, Value::String(c.value.into()), "cas");
+        Type::Integer(1)
+    }
+
+    /// The maps of the shards are swapped out, watchers are notified of the removals and the rest is dropped
+    /// once the lock is released (on a background task for ASYNC).
+    /// FLUSHALL is done by [super::Databases::flush_all].
+    pub(crate) fn flush(&mut self, f: Flush) -> Type {
+        let subscriptions = self.subscriptions.lock().expect("Lock failed");
+        for mut flushed in self.store.take() {
+            for key in subscriptions.watched_in(&flushed) {
+                if let Some(value) = flushed.remove(&key) {
+                    self.changes
+                        .push(Change::new(key, "flushdb", Some(value), None));
+                }
+            }
+            self.flushed.push((flushed, f.asynchronous));
+        }
+        Type::SimpleString("OK".into())
+    }
+}
+
+/// Returns a copy of the bytes of a string value, a missing value is an empty string
+fn string_bytes(v: Option<&Value>) -> std::result::Result<Vec<u8>, Type> {
+    match v {
+        Some(Value::String(s)) => Ok(s.as_bytes().to_vec()),
+        Some(_) => Err(Type::Error(WRONG_TYPE.into())),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Applies `f` on the bytes of a string value, a missing value is an empty string
+fn read_bytes<F>(v: Option<&Value>, f: F) -> Type
+where
+    F: FnOnce(&[u8]) -> Type,
+{
+    match v {
+        Some(Value::String(s)) => f(s.as_bytes()),
+        Some(_) => Type::Error(WRONG_TYPE.into()),
+        None => f(&[]),
+    }
+}
+
+/// A random number, every [RandomState] is seeded with different keys
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// The position of a key in a [Keyspace::scan]
+fn scan_position(key: &RedisString) -> u64 {
+    // The default hasher uses fixed keys, the positions are stable for the life of the process
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the sorted set, if there is a value
+fn sorted_set(v: Option<&Value>) -> std::result::Result<Option<&SortedSet>, Type> {
+    match v {
+        Some(Value::SortedSet(set)) => Ok(Some(set)),
+        Some(_) => Err(Type::Error(WRONG_TYPE.into())),
+        None => Ok(None),
+    }
+}
+
+/// Distances are replied with 4 decimals, in the given unit
+fn format_distance(meters: f64, unit_in_meters: f64) -> Type {
+    Type::BulkString(format!("{:.4}", meters / unit_in_meters).into_bytes())
+}
+
+fn format_position((longitude, latitude): geo::Point) -> Type {
+    Type::Array(
+        vec![
+            Type::BulkString(longitude.to_string().into_bytes()),
+            Type::BulkString(latitude.to_string().into_bytes()),
+        ]
+        .into_iter()
+        .collect(),
+    )
+}
+
+/// Decodes the value as a [HyperLogLog] or returns the error to reply with
+fn as_hyperloglog(v: &Value) -> std::result::Result<HyperLogLog, Type> {
+    match v {
+        Value::String(s) => HyperLogLog::from_bytes(s.as_bytes())
+            .ok_or_else(|| Type::Error(NOT_A_HYPERLOGLOG.into())),
+        _ => Err(Type::Error(WRONG_TYPE.into())),
+    }
+}
+
+fn log_and_return(message: String, result: Type) -> Type {
+    debug!("{}", message);
+    result
+}
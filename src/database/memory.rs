@@ -0,0 +1,372 @@
+//! Maxmemory, the memory used by the keyspaces is estimated and keys are evicted to stay
+//! under the limit.
+//!
+//! The size of every key is estimated when it changes (see [size]), the sum over all the
+//! databases is the memory used. Once it is over the `maxmemory`, the commands that can use
+//! more memory first evict keys as the [MaxMemoryPolicy] says, like Redis does: a few keys
+//! are sampled (see [SAMPLES]) and the best candidate among them is evicted, until the
+//! memory used is under the limit. If nothing can be evicted, they are refused with an
+//! OOM error. The keys evicted are removals for the watchers, with the `evicted` event. The
+//! change logs keep those without the value, the eviction frees it.
+//!
+//! The changes kept for resuming the watches count too (see
+//! [ChangeLog](super::watchers::ChangeLog)). When no key can be evicted, they are dropped
+//! before the commands are refused: the watches resumed from before need a RESYNC.
+
+use std::{
+    fmt::{self, Debug, Display},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use super::{keyspace::Change, RedisString, Value};
+
+/// The reply to the commands refused when the memory used is over the limit
+pub(crate) const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// The number of keys sampled for every eviction, `maxmemory-samples` in Redis
+pub(crate) const SAMPLES: usize = 5;
+
+/// The estimated overhead of a key in its map, with its metadata
+const KEY_OVERHEAD: usize = 64;
+/// The estimated overhead of a value, of an element of a list or of a sorted set
+const VALUE_OVERHEAD: usize = 16;
+
+/// The frequency of a new key, so that it is not evicted at once (`LFU_INIT_VAL`)
+const LFU_INIT: u8 = 5;
+/// The higher, the slower the frequency counters grow (`lfu-log-factor`)
+const LFU_LOG_FACTOR: u64 = 10;
+/// The frequency counters are decremented every this many minutes without an access
+/// (`lfu-decay-time`)
+const LFU_DECAY_MINUTES: u64 = 1;
+
+/// Which keys are evicted when the memory used is over the `maxmemory`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum MaxMemoryPolicy {
+    /// Nothing is evicted, the commands that use more memory are refused
+    #[default]
+    NoEviction,
+    /// The least recently used keys
+    AllKeysLru,
+    /// The least frequently used keys
+    AllKeysLfu,
+    /// The least recently used keys with a time to live
+    VolatileLru,
+    /// The keys closest to their expiry
+    VolatileTtl,
+    /// Any key
+    AllKeysRandom,
+}
+
+/// The policies, with their names (the values of `maxmemory-policy`)
+const POLICIES: [(MaxMemoryPolicy, &str); 6] = [
+    (MaxMemoryPolicy::NoEviction, "noeviction"),
+    (MaxMemoryPolicy::AllKeysLru, "allkeys-lru"),
+    (MaxMemoryPolicy::AllKeysLfu, "allkeys-lfu"),
+    (MaxMemoryPolicy::VolatileLru, "volatile-lru"),
+    (MaxMemoryPolicy::VolatileTtl, "volatile-ttl"),
+    (MaxMemoryPolicy::AllKeysRandom, "allkeys-random"),
+];
+
+impl MaxMemoryPolicy {
+    /// Only the keys with a time to live can be evicted. There are none, keys do not
+    /// expire on this server: nothing is evicted, like Redis does when no key has one.
+    fn volatile(self) -> bool {
+        matches!(
+            self,
+            MaxMemoryPolicy::VolatileLru | MaxMemoryPolicy::VolatileTtl
+        )
+    }
+
+    /// The best key to evict among the sampled ones, if any can be
+    pub(crate) fn pick<'a>(
+        self,
+        sampled: &[(&'a RedisString, &Access)],
+    ) -> Option<&'a RedisString> {
+        if self == MaxMemoryPolicy::NoEviction || self.volatile() {
+            return None;
+        }
+        let now = now();
+        let best = match self {
+            MaxMemoryPolicy::AllKeysLfu => sampled
+                .iter()
+                .min_by_key(|(_, access)| access.frequency(now)),
+            MaxMemoryPolicy::AllKeysRandom => sampled.first(),
+            _ => sampled.iter().min_by_key(|(_, access)| access.last),
+        };
+        best.map(|(key, _)| *key)
+    }
+}
+
+impl FromStr for MaxMemoryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        POLICIES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|(policy, _)| *policy)
+            .ok_or_else(|| format!("{} is not a maxmemory policy", s))
+    }
+}
+
+impl Display for MaxMemoryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name) = POLICIES
+            .iter()
+            .find(|(policy, _)| policy == self)
+            .expect("Every policy has a name");
+        f.pad(name)
+    }
+}
+
+/// Parses a number of bytes, with an optional unit like Redis: `kb`, `mb` and `gb`
+/// (multiples of 1024), or `k`, `m` and `g` (of 1000)
+pub fn parse_bytes(s: &str) -> Result<usize, String> {
+    let lower = s.to_lowercase();
+    let units = [
+        ("kb", 1 << 10),
+        ("mb", 1 << 20),
+        ("gb", 1 << 30),
+        ("k", 1000),
+        ("m", 1000 * 1000),
+        ("g", 1000 * 1000 * 1000),
+        ("b", 1),
+    ];
+    let (digits, unit) = units
+        .iter()
+        .find(|(suffix, _)| lower.ends_with(suffix))
+        .map(|(suffix, unit)| (&lower[..lower.len() - suffix.len()], *unit))
+        .unwrap_or((&lower, 1));
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("{} is not a memory size", s))
+}
+
+/// The memory used by all the databases, its limit and the policy. Shared by all the
+/// databases of a server, the limit and the policy can be changed while it runs.
+#[derive(Clone, Default)]
+pub(crate) struct Memory {
+    inner: Arc<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    used: AtomicUsize,
+    /// 0 for no limit
+    maxmemory: AtomicUsize,
+    policy: AtomicU8,
+    /// The number of keys evicted
+    evicted: AtomicU64,
+}
+
+impl Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Memory")
+            .field("used", &self.used())
+            .field("maxmemory", &self.maxmemory())
+            .field("policy", &self.policy())
+            .finish()
+    }
+}
+
+impl Memory {
+    /// At most `maxmemory` bytes (0 for no limit), evicted with the `policy`
+    pub(crate) fn new(maxmemory: usize, policy: MaxMemoryPolicy) -> Self {
+        let memory = Memory::default();
+        memory.set_maxmemory(maxmemory);
+        memory.set_policy(policy);
+        memory
+    }
+
+    /// The estimated number of bytes used by the keys and the values
+    pub(crate) fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn maxmemory(&self) -> usize {
+        self.inner.maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_maxmemory(&self, maxmemory: usize) {
+        self.inner.maxmemory.store(maxmemory, Ordering::Relaxed);
+    }
+
+    pub(crate) fn policy(&self) -> MaxMemoryPolicy {
+        let policy = self.inner.policy.load(Ordering::Relaxed);
+        POLICIES[policy as usize].0
+    }
+
+    pub(crate) fn set_policy(&self, policy: MaxMemoryPolicy) {
+        self.inner.policy.store(policy as u8, Ordering::Relaxed);
+    }
+
+    /// Returns true if more memory is used than allowed
+    pub(crate) fn over_limit(&self) -> bool {
+        let maxmemory = self.maxmemory();
+        maxmemory > 0 && self.used() > maxmemory
+    }
+
+    /// Records that a key went from `before` to `after` bytes
+    pub(crate) fn resize(&self, before: usize, after: usize) {
+        if after > before {
+            self.inner.used.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.inner.used.fetch_sub(before - after, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn evicted(&self) {
+        self.inner.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The `memory` section of INFO
+    pub(crate) fn info(&self) -> String {
+        format!(
+            "# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\nevicted_keys:{}\r\n",
+            self.used(),
+            self.maxmemory(),
+            self.policy(),
+            self.inner.evicted.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// The estimated number of bytes used by a key and its value
+pub(crate) fn size(key: &RedisString, value: &Value) -> usize {
+    KEY_OVERHEAD + key.as_bytes().len() + value_size(value)
+}
+
+/// The estimated number of bytes used by a change kept in a
+/// [ChangeLog](super::watchers::ChangeLog), with its values
+pub(crate) fn logged_size(change: &Change) -> usize {
+    KEY_OVERHEAD
+        + change.key.as_bytes().len()
+        + change.before.as_ref().map_or(0, value_size)
+        + change.after.as_ref().map_or(0, value_size)
+}
+
+fn value_size(value: &Value) -> usize {
+    VALUE_OVERHEAD
+        + match value {
+            Value::String(s) => s.as_bytes().len(),
+            Value::List(list) => list.iter().map(value_size).sum(),
+            Value::SortedSet(set) => set.iter().map(|(member, _)| member_size(member)).sum(),
+        }
+}
+
+/// The estimated number of bytes used by a member of a sorted set, with its score
+pub(crate) fn member_size(member: &RedisString) -> usize {
+    VALUE_OVERHEAD + member.as_bytes().len() + 8
+}
+
+/// The milliseconds since the first call, the clock of the accesses
+fn now() -> u64 {
+    static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// When a key was last used, and how often, for the LRU and LFU policies
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Access {
+    /// The time of the last access, see [now]
+    last: u64,
+    /// A logarithmic counter of the accesses, decayed with time (as in Redis)
+    counter: u8,
+}
+
+impl Access {
+    /// The access of a new key
+    pub(crate) fn new() -> Self {
+        Access {
+            last: now(),
+            counter: LFU_INIT,
+        }
+    }
+
+    /// The key was used again
+    pub(crate) fn touch(&mut self, random: u64) {
+        let now = now();
+        let counter = self.frequency(now);
+        // The counter grows slower as it gets higher
+        let base = (counter.saturating_sub(LFU_INIT) as u64) * LFU_LOG_FACTOR + 1;
+        let increment = counter < u8::MAX && random.is_multiple_of(base);
+        self.counter = counter + increment as u8;
+        self.last = now;
+    }
+
+    /// The counter, decremented for every period without access
+    fn frequency(&self, now: u64) -> u8 {
+        let periods = now.saturating_sub(self.last) / (LFU_DECAY_MINUTES * 60 * 1000);
+        self.counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_bytes, Access, MaxMemoryPolicy};
+    use crate::database::RedisString;
+
+    #[test]
+    fn policies_and_sizes_are_parsed() {
+        for name in [
+            "noeviction",
+            "allkeys-lru",
+            "allkeys-lfu",
+            "volatile-lru",
+            "volatile-ttl",
+            "allkeys-random",
+        ] {
+            let policy: MaxMemoryPolicy = name.parse().unwrap();
+            assert_eq!(policy.to_string(), name);
+        }
+        assert!("allkeys-fifo".parse::<MaxMemoryPolicy>().is_err());
+        assert_eq!(parse_bytes("100"), Ok(100));
+        assert_eq!(parse_bytes("1kb"), Ok(1024));
+        assert_eq!(parse_bytes("2MB"), Ok(2 << 20));
+        assert_eq!(parse_bytes("1g"), Ok(1_000_000_000));
+        assert!(parse_bytes("many").is_err());
+    }
+
+    #[test]
+    fn the_sampled_key_to_evict_depends_on_the_policy() {
+        let (old, hot, new) = (
+            RedisString::from("old"),
+            RedisString::from("hot"),
+            RedisString::from("new"),
+        );
+        let mut old_access = Access::new();
+        old_access.last = 0;
+        let mut hot_access = Access::new();
+        for _ in 0..100 {
+            // 0 always increments the counter
+            hot_access.touch(0);
+        }
+        hot_access.last = 10;
+        let mut new_access = Access::new();
+        new_access.last = 20;
+        let sampled = [
+            (&hot, &hot_access),
+            (&old, &old_access),
+            (&new, &new_access),
+        ];
+        assert_eq!(MaxMemoryPolicy::AllKeysLru.pick(&sampled), Some(&old));
+        assert_eq!(MaxMemoryPolicy::AllKeysLfu.pick(&sampled), Some(&old));
+        assert_eq!(MaxMemoryPolicy::AllKeysRandom.pick(&sampled), Some(&hot));
+        for policy in [
+            MaxMemoryPolicy::NoEviction,
+            MaxMemoryPolicy::VolatileLru,
+            MaxMemoryPolicy::VolatileTtl,
+        ] {
+            assert_eq!(policy.pick(&sampled), None);
+        }
+    }
+}
@@ -0,0 +1,1562 @@
+use std::{
+    collections::LinkedList,
+    fmt::Debug,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use self::{
+    actors::ShardActors,
+    keyspace::Change,
+    sorted_set::SortedSet,
+    watchers::{Listeners, Subscriptions},
+};
+use crate::{
+    commands::{watch::Watch, Command},
+    glob,
+    resp::Type,
+};
+
+mod actors;
+mod bitmap;
+mod databases;
+mod events;
+mod geo;
+mod hyperloglog;
+mod keyspace;
+mod memory;
+mod snapshot;
+mod sorted_set;
+mod tracking;
+mod watchers;
+
+pub use self::{
+    actors::Engine,
+    events::KeyspaceEvents,
+    memory::{parse_bytes, MaxMemoryPolicy},
+    snapshot::{SaveRule, SaveRules},
+    watchers::{SlowWatchers, WatcherLimits},
+};
+pub(crate) use self::{
+    databases::Databases,
+    events::KeyspaceNotifier,
+    keyspace::{Keyspace, Shards, Store, DEFAULT_SHARDS, NOT_ALLOWED},
+    memory::{Memory, OOM},
+    snapshot::Persistence,
+    tracking::Tracker,
+    watchers::{Notifications, Watcher, WatcherStats, DEFAULT_CHANGE_LOG},
+};
+
+const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+const NOT_A_HYPERLOGLOG: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+
+/// The type of changes
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum Operation {
+    /// Notification for an addition
+    Addition = 1,
+    /// Notification for an update
+    Update = 2,
+    /// Notification for a removal
+    Removal = 3,
+    /// Notification for any operation
+    All = 4,
+}
+
+impl From<u8> for Operation {
+    fn from(id: u8) -> Self {
+        match id {
+            1 => Operation::Addition,
+            2 => Operation::Update,
+            3 => Operation::Removal,
+            _ => Operation::All,
+        }
+    }
+}
+
+/// RedisString is how the data is stored in the data base
+#[derive(Debug, PartialEq, Hash, Eq, Clone, PartialOrd, Ord)]
+pub(crate) struct RedisString {
+    bytes: Arc<Vec<u8>>,
+}
+
+impl From<String> for RedisString {
+    fn from(s: String) -> Self {
+        Self {
+            bytes: Arc::new(s.into_bytes()),
+        }
+    }
+}
+
+impl From<RedisString> for String {
+    fn from(s: RedisString) -> Self {
+        String::from_utf8(s.bytes.as_ref().clone()).expect("Not a string")
+    }
+}
+
+impl From<Vec<u8>> for RedisString {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes: Arc::new(bytes),
+        }
+    }
+}
+
+impl RedisString {
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<&str> for RedisString {
+    fn from(s: &str) -> Self {
+        Self {
+            bytes: Arc::new(s.into()),
+        }
+    }
+}
+
+/// The types of Redis data structures
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Value {
+    String(RedisString),
+    List(LinkedList<Value>),
+    SortedSet(SortedSet),
+}
+
+impl Value {
+    /// The name of the type, as replied by `TYPE` and matched by `SCAN ... TYPE`
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<LinkedList<String>> for Value {
+    fn from(l: LinkedList<String>) -> Self {
+        let mut ll: LinkedList<Value> = LinkedList::new();
+        l.into_iter().for_each(|v| ll.push_back(v.into()));
+        Value::List(ll)
+    }
+}
+
+impl From<Value> for Type {
+    fn from(v: Value) -> Self {
+        match v {
+            // Strings are binary safe
+            Value::String(s) => Type::BulkString(s.bytes.as_ref().clone()),
+            Value::List(l) => Type::Array(
+                l.into_iter()
+                    .filter_map(|s| match s {
+                        // We look for strings
+                        Value::String(s) => Some(s),
+                        // No nested lists
+                        _ => None,
+                    })
+                    .map(|s| Type::BulkString(s.bytes.as_ref().clone()))
+                    .collect(),
+            ),
+            // Members followed by their scores
+            Value::SortedSet(set) => Type::Array(
+                set.iter()
+                    .flat_map(|(member, score)| {
+                        vec![
+                            Type::BulkString(member.bytes.as_ref().clone()),
+                            Type::BulkString(score.to_string().into_bytes()),
+                        ]
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// The Redis Data base
+pub(crate) struct Database {
+    inner: Arc<Store>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    listeners: Listeners,
+}
+
+impl Database {
+    /// A database with the default number of shards, that keeps the default number of changes
+    #[cfg(test)]
+    pub(crate) fn new() -> Self {
+        Database::with_change_log(
+            DEFAULT_SHARDS,
+            DEFAULT_CHANGE_LOG,
+            KeyspaceNotifier::default(),
+            Memory::default(),
+        )
+    }
+
+    /// A database with its keyspace split in `shards` (see [Store]), each shard keeps its
+    /// last `change_log` changes, for resuming watches.
+    /// Its changes are published as keyspace notifications by `events`, the size of its
+    /// keys is accounted in `memory`.
+    pub(crate) fn with_change_log(
+        shards: usize,
+        change_log: usize,
+        events: KeyspaceNotifier,
+        memory: Memory,
+    ) -> Self {
+        let subscriptions = Subscriptions::new(events);
+        Database {
+            inner: Arc::new(Store::new(shards, change_log, memory)),
+            listeners: subscriptions.listeners(),
+            subscriptions: Arc::new(Mutex::new(subscriptions)),
+        }
+    }
+
+    /// The same database, with its shards owned by the actors (see [Engine::Actors]).
+    /// It cannot be shared yet.
+    pub(crate) fn with_actors(mut self, actors: &ShardActors) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("The database is already shared")
+            .hand_to(actors);
+        self
+    }
+
+    fn lock_and_access_subscriptions(&self) -> MutexGuard<'_, Subscriptions> {
+        self.subscriptions.lock().expect("Lock failed")
+    }
+
+    /// Locks the shards of the keys (all of them for None) of several databases together,
+    /// the databases in the order given, that of their index (see [Store::lock_all]).
+    /// The commands are run with [Database::execute_with].
+    pub(crate) fn lock_all<'a>(databases: &[(&'a Database, Option<&[String]>)]) -> Vec<Shards<'a>> {
+        let stores: Vec<_> = databases
+            .iter()
+            .map(|(database, keys)| (&*database.inner, *keys))
+            .collect();
+        Store::lock_all(&stores)
+    }
+
+    /// Runs `f` on the whole locked keyspace, see [Database::execute_on]
+    pub(crate) fn execute<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Keyspace<'_>) -> R,
+    {
+        self.execute_on(None, f)
+    }
+
+    /// Runs `f` on the keyspace with the shards of the keys locked (all of them for None),
+    /// the changes it made are logged by their shards and the watchers are notified of them
+    /// once the lock is released. The delivery locks of those shards are taken before that,
+    /// so the notifications of the changes of a key are queued in the order they were made.
+    /// The subscriptions are not locked if nothing changed, or if nobody listens.
+    pub(crate) fn execute_on<F, R>(&mut self, keys: Option<&[String]>, f: F) -> R
+    where
+        F: FnOnce(&mut Keyspace<'_>) -> R,
+    {
+        let inner = self.inner.clone();
+        self.execute_with(inner.lock(keys), f)
+    }
+
+    /// Runs `f` on the keyspace with the shards already locked (or owned by the actor
+    /// running it), see [Database::execute_on]
+    pub(crate) fn execute_with<F, R>(&self, store: Shards<'_>, f: F) -> R
+    where
+        F: FnOnce(&mut Keyspace<'_>) -> R,
+    {
+        let inner = &self.inner;
+        let mut keyspace = Keyspace {
+            store,
+            subscriptions: &self.subscriptions,
+            changes: Vec::new(),
+            flushed: Vec::new(),
+            writer: None,
+        };
+        let result = f(&mut keyspace);
+        let Keyspace {
+            mut store,
+            mut changes,
+            flushed,
+            writer,
+            ..
+        } = keyspace;
+        // Every change is recorded, but for the keys flushed that are not watched
+        inner.wrote(changes.len() + flushed.iter().map(|(f, _)| f.len()).sum::<usize>());
+        for change in changes.iter_mut() {
+            change.writer = writer;
+            store.log(change);
+        }
+        if changes.is_empty() || !self.listeners.any() {
+            drop(store);
+        } else {
+            let _delivering = store.deliver(Some(&changes));
+            drop(store);
+            let mut subscriptions = self.lock_and_access_subscriptions();
+            for change in changes {
+                subscriptions.notify(change);
+            }
+        }
+        for (flushed, asynchronous) in flushed {
+            if asynchronous {
+                tokio::task::spawn_blocking(move || drop(flushed));
+            }
+        }
+        result
+    }
+
+    /// Runs a single command, see [Keyspace::apply]
+    pub(crate) fn apply(&mut self, command: Command) -> Type {
+        let keys = command.keys();
+        let inner = self.inner.clone();
+        self.apply_with(inner.lock(keys.as_deref()), command, None)
+    }
+
+    /// Runs a single command for a connection with CLIENT TRACKING, the changes are its own.
+    /// The keys it reads are tracked if `track`.
+    pub(crate) fn apply_tracked(
+        &mut self,
+        command: Command,
+        tracker: &Tracker,
+        track: bool,
+    ) -> Type {
+        let keys = command.keys();
+        let inner = self.inner.clone();
+        self.apply_with(inner.lock(keys.as_deref()), command, Some((tracker, track)))
+    }
+
+    /// Runs a single command on the shards of its keys, for a connection with its tracker
+    /// if it has CLIENT TRACKING (see [Database::apply_tracked])
+    fn apply_with(
+        &self,
+        store: Shards<'_>,
+        command: Command,
+        tracking: Option<(&Tracker, bool)>,
+    ) -> Type {
+        let tracker = match tracking {
+            Some((tracker, true)) => Some((tracker, command.read_keys())),
+            Some((tracker, false)) => Some((tracker, Vec::new())),
+            None => None,
+        };
+        self.execute_with(store, |keyspace| match tracker {
+            Some((tracker, keys)) => {
+                keyspace.writer = Some(tracker.id());
+                let reply = keyspace.apply(command);
+                keyspace.track(keys, tracker);
+                reply
+            }
+            None => keyspace.apply(command),
+        })
+    }
+
+    /// Runs a single command for a connection, with its tracker if it has CLIENT TRACKING
+    /// (see [Database::apply_tracked]). With the [Engine::Actors] engine, the command is run
+    /// by the actor of its shard if all its keys are in the same one, the connection waits
+    /// for it without blocking its task. The other commands wait for the shards lent by the
+    /// actors on a blocking thread.
+    pub(crate) async fn run(
+        &mut self,
+        command: Command,
+        tracking: Option<(Tracker, bool)>,
+    ) -> Type {
+        let shard = command.keys().and_then(|keys| self.inner.shard(&keys));
+        let actor = shard.and_then(|shard| {
+            let (actors, id) = self.inner.actor(shard)?;
+            Some((shard, actors.clone(), id))
+        });
+        match (actor, tracking) {
+            (Some((shard, actors, id)), tracking) => {
+                let db = self.clone();
+                actors
+                    .run(id, move |owned| {
+                        let store = db.inner.own(shard, owned);
+                        let tracking = tracking.as_ref().map(|(tracker, track)| (tracker, *track));
+                        db.apply_with(store, command, tracking)
+                    })
+                    .await
+                    .unwrap_or_else(Type::Error)
+            }
+            (None, tracking) if self.inner.actor(0).is_some() => {
+                let mut db = self.clone();
+                actors::unblocking(move || match tracking {
+                    Some((tracker, track)) => db.apply_tracked(command, &tracker, track),
+                    None => db.apply(command),
+                })
+                .await
+            }
+            (None, Some((tracker, track))) => self.apply_tracked(command, &tracker, track),
+            (None, None) => self.apply(command),
+        }
+    }
+
+    /// Subscribes the watcher to the changes of a key, or of the keys matching a pattern.
+    /// The shard of the key (every shard for a pattern) is locked, the watch starts after the
+    /// last change logged and the lock is only released once the subscriptions are locked,
+    /// so every later change follows.
+    /// With `FROM`, the logged changes are replayed first (see [Subscriptions::add]).
+    /// With `SNAPSHOT`, the current values are read under that lock.
+    pub(crate) fn watch(&mut self, watch: Watch, watcher: Watcher) -> Type {
+        self.listeners.subscribing();
+        let key = [watch.key.clone()];
+        let store = self
+            .inner
+            .lock(if watch.pattern { None } else { Some(&key[..]) });
+        let since = store.offset();
+        let replay = match watch.from {
+            Some(from) => store.changes_after(from),
+            None => Some(Vec::new()),
+        };
+        let snapshot = if !watch.snapshot {
+            Vec::new()
+        } else if watch.pattern {
+            let pattern = watch.key.as_bytes();
+            let mut keys: Vec<_> = store
+                .keys()
+                .filter(|key| glob::matches(pattern, key.as_bytes()))
+                .collect();
+            keys.sort();
+            keys.into_iter()
+                .map(|key| Change::snapshot(key.clone(), store.get(key).cloned()))
+                .collect()
+        } else {
+            let key = RedisString::from(watch.key.clone());
+            let value = store.get(&key).cloned();
+            vec![Change::snapshot(key, value)]
+        };
+        let mut subscriptions = self.lock_and_access_subscriptions();
+        drop(store);
+        subscriptions.add(watch, watcher, snapshot, replay, since)
+    }
+
+    /// Tells the tracker about the changes of the keys with the prefix (BCAST)
+    pub(crate) fn broadcast(&self, prefix: Vec<u8>, tracker: &Tracker) {
+        self.lock_and_access_subscriptions()
+            .broadcast(prefix, tracker);
+    }
+
+    /// Forgets everything tracked by the tracker
+    pub(crate) fn untrack(&self, tracker: &Tracker) {
+        self.lock_and_access_subscriptions().untrack(tracker);
+    }
+
+    /// Removes the subscriptions of the watcher to a key (or pattern), or to all of them.
+    /// Returns the number of subscriptions removed.
+    pub(crate) fn unwatch(&self, watcher: &Watcher, key: Option<String>, pattern: bool) -> usize {
+        self.lock_and_access_subscriptions()
+            .remove(watcher, key, pattern)
+    }
+}
+
+impl Clone for Database {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            subscriptions: self.subscriptions.clone(),
+            listeners: self.listeners.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap};
+
+    use super::*;
+    use crate::commands::watch::WatchResult;
+    use crate::commands::{
+        bitmap::{BitField, BitFieldOperation, BitOp, Overflow},
+        cas::{Cas, Version},
+        geo::{GeoAdd, GeoAddCondition, GeoBy, GeoDist, GeoFrom, GeoPos, GeoSearch, SortOrder},
+        get::Get,
+        hyperloglog::{PfAdd, PfCount, PfMerge},
+        keys::{Copy, DbSize, Flush, KeyType, Keys, RandomKey, Rename, Scan},
+        list::Push,
+        set::Set,
+    };
+
+    #[test]
+    fn hyperloglog_round_trips_through_get_and_set() {
+        let mut db = Database::new();
+        let elements = (0..1000).map(|i| format!("visitor:{}", i).into_bytes());
+        let pfadd = PfAdd {
+            key: "visitors".into(),
+            elements: elements.collect(),
+        };
+        assert_eq!(db.apply(Command::PfAdd(pfadd)), Type::Integer(1));
+        let count = db.apply(Command::PfCount(PfCount {
+            keys: vec!["visitors".into()].into_iter().collect(),
+        }));
+        let value = match db.apply(Command::Get(Get {
+            key: "visitors".into(),
+        })) {
+            Type::BulkString(b) => b,
+            t => panic!("Unexpected {:?}", t),
+        };
+        db.apply(Command::Set(Set {
+            key: "copy".into(),
+            value,
+        }));
+        let copy_count = db.apply(Command::PfCount(PfCount {
+            keys: vec!["copy".into()].into_iter().collect(),
+        }));
+        assert_eq!(count, copy_count);
+    }
+
+    #[test]
+    fn hyperloglog_wrong_type_works() {
+        let mut db = Database::new();
+        db.apply(Command::Set(Set {
+            key: "string".into(),
+            value: "Hello".into(),
+        }));
+        assert_eq!(
+            db.apply(Command::PfCount(PfCount {
+                keys: vec!["string".into()].into_iter().collect(),
+            })),
+            Type::Error(NOT_A_HYPERLOGLOG.into())
+        );
+        assert_eq!(
+            db.apply(Command::PfMerge(PfMerge {
+                destination: "string".into(),
+                sources: LinkedList::new(),
+            })),
+            Type::Error(NOT_A_HYPERLOGLOG.into())
+        );
+    }
+
+    #[test]
+    fn bitfield_works() {
+        use crate::commands::bitmap::IntegerType;
+        let mut db = Database::new();
+        let u2 = IntegerType {
+            signed: false,
+            bits: 2,
+        };
+        // Only reads, the key is not created
+        let get = BitField {
+            key: "bits".into(),
+            operations: vec![BitFieldOperation::Get(u2, 0)].into_iter().collect(),
+        };
+        assert_eq!(
+            db.apply(Command::BitField(get)),
+            Type::Array(vec![Type::Integer(0)].into_iter().collect())
+        );
+        assert_eq!(
+            db.apply(Command::Get(Get { key: "bits".into() })),
+            Type::Null
+        );
+        let incr = |overflow| BitField {
+            key: "bits".into(),
+            operations: vec![
+                BitFieldOperation::Overflow(overflow),
+                BitFieldOperation::IncrBy(u2, 102, 3),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let reply = |t| Type::Array(vec![t].into_iter().collect());
+        assert_eq!(
+            db.apply(Command::BitField(incr(Overflow::Wrap))),
+            reply(Type::Integer(3))
+        );
+        assert_eq!(
+            db.apply(Command::BitField(incr(Overflow::Wrap))),
+            reply(Type::Integer(2))
+        );
+        assert_eq!(
+            db.apply(Command::BitField(incr(Overflow::Sat))),
+            reply(Type::Integer(3))
+        );
+        assert_eq!(
+            db.apply(Command::BitField(incr(Overflow::Fail))),
+            reply(Type::Null)
+        );
+        // The string was extended with zero bytes
+        assert_eq!(
+            db.apply(Command::Get(Get { key: "bits".into() })),
+            Type::BulkString(vec![0; 12].into_iter().chain(vec![0x03]).collect())
+        );
+    }
+
+    #[test]
+    fn bitop_works() {
+        let mut db = Database::new();
+        db.apply(Command::Set(Set {
+            key: "a".into(),
+            value: vec![0xf0],
+        }));
+        db.apply(Command::Set(Set {
+            key: "b".into(),
+            value: vec![0x3c, 0xff],
+        }));
+        let bitop = |operation, keys: Vec<&str>| BitOp {
+            operation,
+            destination: "dest".into(),
+            keys: keys.into_iter().map(|k| k.into()).collect(),
+        };
+        use crate::commands::bitmap::BitOperation;
+        assert_eq!(
+            db.apply(Command::BitOp(bitop(BitOperation::And, vec!["a", "b"]))),
+            Type::Integer(2)
+        );
+        assert_eq!(
+            db.apply(Command::Get(Get { key: "dest".into() })),
+            Type::BulkString(vec![0x30, 0x00])
+        );
+        // An empty result removes the destination
+        assert_eq!(
+            db.apply(Command::BitOp(bitop(BitOperation::Or, vec!["missing"]))),
+            Type::Integer(0)
+        );
+        assert_eq!(
+            db.apply(Command::Get(Get { key: "dest".into() })),
+            Type::Null
+        );
+        db.apply(Command::Push(Push {
+            list_name: "list".into(),
+            values: LinkedList::new(),
+        }));
+        assert_eq!(
+            db.apply(Command::BitOp(bitop(BitOperation::Not, vec!["list"]))),
+            Type::Error(WRONG_TYPE.into())
+        );
+    }
+
+    #[test]
+    fn geo_works() {
+        use crate::commands::geo::{DistanceUnit, GeoMember};
+        let mut db = Database::new();
+        let sicily = |condition, changed, members: Vec<(f64, f64, &str)>| GeoAdd {
+            key: "Sicily".into(),
+            condition,
+            changed,
+            members: members
+                .into_iter()
+                .map(|(longitude, latitude, member)| GeoMember {
+                    longitude,
+                    latitude,
+                    member: member.into(),
+                })
+                .collect(),
+        };
+        assert_eq!(
+            db.apply(Command::GeoAdd(sicily(
+                None,
+                false,
+                vec![
+                    (13.361389, 38.115556, "Palermo"),
+                    (15.087269, 37.502669, "Catania")
+                ]
+            ))),
+            Type::Integer(2)
+        );
+        assert_eq!(
+            db.apply(Command::GeoAdd(sicily(
+                None,
+                false,
+                vec![(200.0, 38.0, "Nowhere")]
+            ))),
+            Type::Error("ERR invalid longitude,latitude pair 200.000000,38.000000".into())
+        );
+        // NX never updates, CH counts updates
+        assert_eq!(
+            db.apply(Command::GeoAdd(sicily(
+                Some(GeoAddCondition::Nx),
+                true,
+                vec![(13.0, 38.0, "Palermo")]
+            ))),
+            Type::Integer(0)
+        );
+        assert_eq!(
+            db.apply(Command::GeoAdd(sicily(
+                Some(GeoAddCondition::Xx),
+                true,
+                vec![(13.0, 38.0, "Agrigento")]
+            ))),
+            Type::Integer(0)
+        );
+        let dist = |unit| GeoDist {
+            key: "Sicily".into(),
+            member1: "Palermo".into(),
+            member2: "Catania".into(),
+            unit,
+        };
+        assert_eq!(
+            db.apply(Command::GeoDist(dist(DistanceUnit::M))),
+            Type::BulkString("166274.1516".into())
+        );
+        assert_eq!(
+            db.apply(Command::GeoDist(dist(DistanceUnit::Km))),
+            Type::BulkString("166.2742".into())
+        );
+        let search = |from, by, order, with_dist| GeoSearch {
+            key: "Sicily".into(),
+            from,
+            by,
+            order,
+            count: None,
+            with_coord: false,
+            with_dist,
+            with_hash: false,
+        };
+        let names = |names: Vec<&str>| {
+            Type::Array(
+                names
+                    .into_iter()
+                    .map(|n| Type::BulkString(n.into()))
+                    .collect(),
+            )
+        };
+        assert_eq!(
+            db.apply(Command::GeoSearch(search(
+                GeoFrom::LonLat(15.0, 37.0),
+                GeoBy::Radius(200.0, DistanceUnit::Km),
+                Some(SortOrder::Asc),
+                false
+            ))),
+            names(vec!["Catania", "Palermo"])
+        );
+        assert_eq!(
+            db.apply(Command::GeoSearch(search(
+                GeoFrom::LonLat(15.0, 37.0),
+                GeoBy::Radius(100.0, DistanceUnit::Km),
+                None,
+                false
+            ))),
+            names(vec!["Catania"])
+        );
+        assert_eq!(
+            db.apply(Command::GeoSearch(search(
+                GeoFrom::Member("Palermo".into()),
+                GeoBy::Box(400.0, 400.0, DistanceUnit::Km),
+                Some(SortOrder::Desc),
+                true
+            ))),
+            Type::Array(
+                vec![
+                    Type::Array(
+                        vec![
+                            Type::BulkString("Catania".into()),
+                            Type::BulkString("166.2742".into())
+                        ]
+                        .into_iter()
+                        .collect()
+                    ),
+                    Type::Array(
+                        vec![
+                            Type::BulkString("Palermo".into()),
+                            Type::BulkString("0.0000".into())
+                        ]
+                        .into_iter()
+                        .collect()
+                    ),
+                ]
+                .into_iter()
+                .collect()
+            )
+        );
+        let pos = db.apply(Command::GeoPos(GeoPos {
+            key: "Sicily".into(),
+            members: vec!["Palermo".to_string(), "Rome".to_string()]
+                .into_iter()
+                .collect(),
+        }));
+        match pos {
+            Type::Array(positions) => {
+                let positions: Vec<Type> = positions.into_iter().collect();
+                assert_eq!(positions[1], Type::Null);
+                assert!(matches!(&positions[0], Type::Array(p) if p.len() == 2));
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    fn strings(reply: Type) -> Vec<String> {
+        match reply {
+            Type::Array(values) => values
+                .into_iter()
+                .map(|v| match v {
+                    Type::BulkString(b) => String::from_utf8(b).unwrap(),
+                    other => panic!("Unexpected {:?}", other),
+                })
+                .collect(),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keys_works() {
+        let mut db = Database::new();
+        for key in &["hello", "hallo", "hxllo", "user:1", "user:2"] {
+            db.apply(Command::Set(Set {
+                key: key.to_string(),
+                value: b"v".to_vec(),
+            }));
+        }
+        let mut keys = |pattern: &str| {
+            let mut keys = strings(db.apply(Command::Keys(Keys {
+                pattern: pattern.into(),
+            })));
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys("h[ae]llo"), vec!["hallo", "hello"]);
+        assert_eq!(keys("h[^e]llo"), vec!["hallo", "hxllo"]);
+        assert_eq!(keys("user:?"), vec!["user:1", "user:2"]);
+        assert_eq!(keys("*").len(), 5);
+        assert!(keys("nothing*").is_empty());
+    }
+
+    #[test]
+    fn scan_is_stable_under_modification() {
+        let mut db = Database::new();
+        let set = |db: &mut Database, key: String| {
+            db.apply(Command::Set(Set {
+                key,
+                value: b"v".to_vec(),
+            }))
+        };
+        for i in 0..1000 {
+            set(&mut db, format!("stable:{}", i));
+            set(&mut db, format!("removed:{}", i));
+        }
+        db.apply(Command::Push(Push {
+            list_name: "stable:list".into(),
+            values: vec!["a".to_string()].into_iter().collect(),
+        }));
+        let scan = |cursor, pattern: Option<&str>, value_type: Option<&str>| Scan {
+            cursor,
+            pattern: pattern.map(|p| p.into()),
+            count: 37,
+            value_type: value_type.map(|t| t.into()),
+        };
+        let mut seen = HashMap::new();
+        let (mut cursor, mut calls) = (0, 0);
+        loop {
+            let mut reply =
+                strings_and_cursor(db.apply(Command::Scan(scan(cursor, Some("stable:*"), None))));
+            // Only COUNT keys are read for every call
+            assert!(reply.1.len() <= 37);
+            reply
+                .1
+                .drain(..)
+                .for_each(|k| *seen.entry(k).or_insert(0) += 1);
+            // Modify the keyspace in between the calls
+            for i in 0..10 {
+                db.apply(Command::BitOp(BitOp {
+                    operation: crate::commands::bitmap::BitOperation::Or,
+                    destination: format!("removed:{}", calls * 10 + i),
+                    keys: vec!["missing".to_string()].into_iter().collect(),
+                }));
+                set(&mut db, format!("added:{}:{}", calls, i));
+            }
+            calls += 1;
+            cursor = reply.0;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 1001);
+        assert!(seen.values().all(|count| *count == 1));
+        // The type filter
+        let (cursor, keys) = strings_and_cursor(db.apply(Command::Scan(Scan {
+            count: 100_000,
+            ..scan(0, None, Some("LIST"))
+        })));
+        assert_eq!(cursor, 0);
+        assert_eq!(keys, vec!["stable:list"]);
+        // Flushed keys are not scanned anymore
+        db.apply(Command::Flush(Flush {
+            all: false,
+            asynchronous: false,
+        }));
+        set(&mut db, "after".into());
+        let (cursor, keys) = strings_and_cursor(db.apply(Command::Scan(scan(0, None, None))));
+        assert_eq!((cursor, keys), (0, vec!["after".to_string()]));
+    }
+
+    fn strings_and_cursor(reply: Type) -> (u64, Vec<String>) {
+        match reply {
+            Type::Array(values) => {
+                let mut values = values.into_iter();
+                let cursor = match values.next() {
+                    Some(Type::BulkString(c)) => String::from_utf8(c).unwrap().parse().unwrap(),
+                    other => panic!("Unexpected {:?}", other),
+                };
+                (cursor, strings(values.next().unwrap()))
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    fn set(db: &mut Database, key: &str, value: &str) {
+        db.apply(Command::Set(Set {
+            key: key.into(),
+            value: value.into(),
+        }));
+    }
+
+    #[test]
+    fn generic_key_commands_work() {
+        let mut db = Database::new();
+        assert_eq!(db.apply(Command::RandomKey(RandomKey)), Type::Null);
+        set(&mut db, "a", "1");
+        set(&mut db, "b", "2");
+        assert_eq!(
+            db.apply(Command::KeyType(KeyType { key: "a".into() })),
+            Type::SimpleString("string".into())
+        );
+        assert_eq!(
+            db.apply(Command::KeyType(KeyType { key: "x".into() })),
+            Type::SimpleString("none".into())
+        );
+        let rename = |key: &str, new_key: &str, only_if_new| Rename {
+            key: key.into(),
+            new_key: new_key.into(),
+            only_if_new,
+        };
+        assert_eq!(
+            db.apply(Command::Rename(rename("a", "b", true))),
+            Type::Integer(0)
+        );
+        assert_eq!(
+            db.apply(Command::Rename(rename("x", "y", false))),
+            Type::Error("ERR no such key".into())
+        );
+        assert_eq!(
+            db.apply(Command::Rename(rename("a", "c", false))),
+            Type::SimpleString("OK".into())
+        );
+        assert_eq!(db.apply(Command::Get(Get { key: "a".into() })), Type::Null);
+        assert_eq!(
+            db.apply(Command::Get(Get { key: "c".into() })),
+            Type::BulkString("1".into())
+        );
+        let copy = |replace| Copy {
+            source: "c".into(),
+            destination: "b".into(),
+            replace,
+        };
+        assert_eq!(db.apply(Command::Copy(copy(false))), Type::Integer(0));
+        assert_eq!(db.apply(Command::Copy(copy(true))), Type::Integer(1));
+        assert_eq!(
+            db.apply(Command::Get(Get { key: "b".into() })),
+            Type::BulkString("1".into())
+        );
+        assert_eq!(db.apply(Command::DbSize(DbSize)), Type::Integer(2));
+        match db.apply(Command::RandomKey(RandomKey)) {
+            Type::BulkString(key) => assert!(key == b"b" || key == b"c"),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(
+            db.apply(Command::Flush(Flush {
+                all: false,
+                asynchronous: false
+            })),
+            Type::SimpleString("OK".into())
+        );
+        assert_eq!(db.apply(Command::DbSize(DbSize)), Type::Integer(0));
+    }
+
+    async fn next(receiver: &mut tokio::sync::mpsc::Receiver<Type>) -> (String, Operation) {
+        let result: std::result::Result<WatchResult, _> = receiver.recv().await.unwrap().into();
+        let result = result.unwrap();
+        (result.key, result.operation)
+    }
+
+    #[tokio::test]
+    async fn rename_and_flush_notify_watchers() {
+        let mut db = Database::new();
+        set(&mut db, "old", "v");
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        for key in &["old", "new"] {
+            db.watch(
+                Watch {
+                    key: key.to_string(),
+                    operations: vec![Operation::All].into_iter().collect(),
+                    pattern: false,
+                    from: None,
+                    snapshot: false,
+                },
+                Watcher::new(sender.clone()),
+            );
+        }
+        db.apply(Command::Rename(Rename {
+            key: "old".into(),
+            new_key: "new".into(),
+            only_if_new: false,
+        }));
+        let mut notifications = vec![next(&mut receiver).await, next(&mut receiver).await];
+        notifications.sort_by_key(|(key, _)| key.clone());
+        assert_eq!(
+            notifications,
+            vec![
+                ("new".to_string(), Operation::Addition),
+                ("old".to_string(), Operation::Removal)
+            ]
+        );
+        db.apply(Command::Flush(Flush {
+            all: false,
+            asynchronous: true,
+        }));
+        assert_eq!(
+            next(&mut receiver).await,
+            ("new".to_string(), Operation::Removal)
+        );
+        assert_eq!(db.apply(Command::DbSize(DbSize)), Type::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn pattern_watches_report_the_changed_keys() {
+        let mut db = Database::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        db.watch(
+            Watch {
+                key: "config:*".into(),
+                operations: vec![Operation::All].into_iter().collect(),
+                pattern: true,
+                from: None,
+                snapshot: false,
+            },
+            Watcher::new(sender),
+        );
+        set(&mut db, "config:a", "1");
+        set(&mut db, "user:a", "1");
+        set(&mut db, "config:b", "1");
+        db.apply(Command::Flush(Flush {
+            all: false,
+            asynchronous: false,
+        }));
+        drop(db);
+        let mut notifications = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            let result: std::result::Result<WatchResult, _> = t.into();
+            let result = result.unwrap();
+            notifications.push((result.key, result.operation));
+        }
+        // The flushed keys are removed in no particular order
+        notifications[2..].sort_by_key(|(key, _)| key.clone());
+        assert_eq!(
+            notifications,
+            vec![
+                ("config:a".to_string(), Operation::Addition),
+                ("config:b".to_string(), Operation::Addition),
+                ("config:a".to_string(), Operation::Removal),
+                ("config:b".to_string(), Operation::Removal),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_key_commands_are_atomic_across_shards() {
+        let mut db = Database::new();
+        // Two keys of different shards
+        let shard = |key: &str| keyspace::shard_of(key.as_bytes(), DEFAULT_SHARDS);
+        let other = (0..)
+            .map(|i| format!("key:{}", i))
+            .find(|key| shard(key) != shard("key"))
+            .unwrap();
+        set(&mut db, "key", "1");
+        let renames: Vec<_> = (0..4)
+            .map(|_| {
+                let mut db = db.clone();
+                let other = other.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        for (key, new_key) in [("key", other.as_str()), (&other, "key")] {
+                            db.apply(Command::Rename(Rename {
+                                key: key.into(),
+                                new_key: new_key.into(),
+                                only_if_new: true,
+                            }));
+                        }
+                    }
+                })
+            })
+            .collect();
+        // The key is always in exactly one of the shards
+        while !renames.iter().all(|rename| rename.is_finished()) {
+            assert_eq!(db.apply(Command::DbSize(DbSize)), Type::Integer(1));
+            let keys = db.apply(Command::Keys(Keys {
+                pattern: "key*".into(),
+            }));
+            assert_eq!(strings(keys).len(), 1);
+        }
+        for rename in renames {
+            rename.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn versions_change_with_every_write() {
+        let mut db = Database::new();
+        let version = |db: &mut Database, key: &str| match db
+            .apply(Command::Version(Version { key: key.into() }))
+        {
+            Type::Integer(v) => v as u64,
+            other => panic!("Unexpected {:?}", other),
+        };
+        let cas = |db: &mut Database, version, value: &str| {
+            db.apply(Command::Cas(Cas {
+                key: "key".into(),
+                version,
+                value: value.into(),
+            }))
+        };
+        let missing = version(&mut db, "key");
+        set(&mut db, "key", "1");
+        let first = version(&mut db, "key");
+        assert_ne!(first, missing);
+        // Reads do not change the version
+        db.apply(Command::Get(Get { key: "key".into() }));
+        assert_eq!(version(&mut db, "key"), first);
+        assert_eq!(cas(&mut db, missing, "stale"), Type::Integer(0));
+        assert_eq!(cas(&mut db, first, "2"), Type::Integer(1));
+        let second = version(&mut db, "key");
+        assert!(second > first);
+        assert_eq!(cas(&mut db, first, "stale"), Type::Integer(0));
+        assert_eq!(
+            db.apply(Command::Get(Get { key: "key".into() })),
+            Type::BulkString("2".into())
+        );
+        // A key that was created and removed again is not at its old version
+        db.apply(Command::Push(Push {
+            list_name: "list".into(),
+            values: vec!["a".to_string()].into_iter().collect(),
+        }));
+        let pushed = version(&mut db, "list");
+        db.apply(Command::Push(Push {
+            list_name: "list".into(),
+            values: vec!["b".to_string()].into_iter().collect(),
+        }));
+        assert_ne!(version(&mut db, "list"), pushed);
+        let before = version(&mut db, "other");
+        set(&mut db, "other", "v");
+        db.apply(Command::Rename(Rename {
+            key: "other".into(),
+            new_key: "moved".into(),
+            only_if_new: false,
+        }));
+        assert_ne!(version(&mut db, "other"), before);
+    }
+
+    #[tokio::test]
+    async fn watches_resume_from_an_offset() {
+        // A single shard keeps the last changes of the whole database
+        let mut db =
+            Database::with_change_log(1, 4, KeyspaceNotifier::default(), Memory::default());
+        let watch = |from| Watch {
+            key: "key".into(),
+            operations: vec![Operation::All].into_iter().collect(),
+            pattern: false,
+            from: Some(from),
+            snapshot: false,
+        };
+        for (key, value) in [("key", "1"), ("other", "1"), ("key", "2"), ("key", "3")].iter() {
+            set(&mut db, key, value);
+        }
+        // The first change is not kept anymore after five
+        set(&mut db, "other", "2");
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        assert_eq!(
+            db.watch(watch(0), Watcher::new(sender.clone())),
+            Type::Error(format!("{} 0", watchers::RESYNC))
+        );
+        // The changes of the key after the second one are replayed, then the live ones follow
+        assert_eq!(db.watch(watch(2), Watcher::new(sender)), Type::Integer(1));
+        set(&mut db, "key", "4");
+        drop(db);
+        let mut notifications = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            let result: std::result::Result<WatchResult, _> = t.into();
+            let result = result.unwrap();
+            notifications.push((result.offset, result.sequence, result.after));
+        }
+        let value = |v: &str| Type::BulkString(v.into());
+        assert_eq!(
+            notifications,
+            vec![(3, 1, value("2")), (4, 2, value("3")), (6, 3, value("4"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn watches_resume_from_the_logs_of_their_shards() {
+        let mut db = Database::with_change_log(
+            DEFAULT_SHARDS,
+            2,
+            KeyspaceNotifier::default(),
+            Memory::default(),
+        );
+        let shard = |key: &str| keyspace::shard_of(key.as_bytes(), DEFAULT_SHARDS);
+        let other = (0..)
+            .map(|i| format!("other:{}", i))
+            .find(|other| shard(other) != shard("key"))
+            .unwrap();
+        let watch = |key: &str, pattern, from| Watch {
+            key: key.into(),
+            operations: vec![Operation::All].into_iter().collect(),
+            pattern,
+            from: Some(from),
+            snapshot: false,
+        };
+        set(&mut db, "key", "1");
+        for value in ["1", "2", "3"].iter() {
+            set(&mut db, &other, value);
+        }
+        // The shard of the other key does not keep its first change anymore, the shard of the
+        // key keeps all of its changes
+        let (sender, mut key_receiver) = tokio::sync::mpsc::channel(10);
+        assert_eq!(
+            db.watch(watch("key", false, 0), Watcher::new(sender)),
+            Type::Integer(1)
+        );
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        assert_eq!(
+            db.watch(watch("*", true, 0), Watcher::new(sender.clone())),
+            Type::Error(format!("{} 0", watchers::RESYNC))
+        );
+        assert_eq!(
+            db.watch(watch("*", true, 2), Watcher::new(sender)),
+            Type::Integer(1)
+        );
+        set(&mut db, "key", "2");
+        drop(db);
+        let offsets = |result: Type| {
+            let result: std::result::Result<WatchResult, _> = result.into();
+            let result = result.unwrap();
+            (result.offset, result.key)
+        };
+        let mut notifications = Vec::new();
+        while let Some(t) = key_receiver.recv().await {
+            notifications.push(offsets(t));
+        }
+        assert_eq!(notifications, vec![(1, "key".into()), (5, "key".into())]);
+        let mut notifications = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            notifications.push(offsets(t));
+        }
+        assert_eq!(
+            notifications,
+            vec![(3, other.clone()), (4, other), (5, "key".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_do_not_wait_for_the_subscriptions_when_nobody_listens() {
+        let mut db = Database::new();
+        let subscriptions = db.subscriptions.clone();
+        let locked = subscriptions.lock().unwrap();
+        let (done, finished) = std::sync::mpsc::channel();
+        let mut writer = db.clone();
+        std::thread::spawn(move || {
+            set(&mut writer, "key", "1");
+            done.send(()).unwrap();
+        });
+        finished
+            .recv_timeout(std::time::Duration::from_secs(30))
+            .unwrap();
+        drop(locked);
+        // The change is still logged for the watches that resume from an offset
+        let (sender, _receiver) = tokio::sync::mpsc::channel(10);
+        let watch = Watch {
+            key: "key".into(),
+            operations: vec![Operation::All].into_iter().collect(),
+            pattern: false,
+            from: Some(0),
+            snapshot: false,
+        };
+        assert_eq!(db.watch(watch, Watcher::new(sender)), Type::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn watch_snapshots_come_before_the_later_changes() {
+        let mut db = Database::new();
+        for (key, value) in [("user:2", "1"), ("user:1", "1"), ("other", "1")].iter() {
+            set(&mut db, key, value);
+        }
+        let watch = |key: &str, pattern| Watch {
+            key: key.into(),
+            operations: vec![Operation::Update].into_iter().collect(),
+            pattern,
+            from: None,
+            snapshot: true,
+        };
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let watcher = Watcher::new(sender);
+        db.watch(watch("user:*", true), watcher.clone());
+        db.watch(watch("missing", false), watcher.clone());
+        drop(watcher);
+        // Only the update is notified, the snapshot is sent whatever the operations
+        set(&mut db, "user:3", "1");
+        set(&mut db, "user:1", "2");
+        drop(db);
+        let mut notifications = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            let result: std::result::Result<WatchResult, _> = t.into();
+            let result = result.unwrap();
+            notifications.push((result.key, result.event, result.offset, result.after));
+        }
+        let value = |v: &str| Type::BulkString(v.into());
+        let snapshot = |key: &str, after| (key.to_string(), "snapshot".to_string(), 3, after);
+        assert_eq!(
+            notifications,
+            vec![
+                snapshot("user:1", value("1")),
+                snapshot("user:2", value("1")),
+                snapshot("missing", Type::Null),
+                ("user:1".into(), "set".into(), 5, value("2")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pushes_notify_the_appended_values() {
+        let mut db = Database::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        db.watch(
+            Watch {
+                key: "queue".into(),
+                operations: vec![Operation::All].into_iter().collect(),
+                pattern: false,
+                from: None,
+                snapshot: false,
+            },
+            Watcher::new(sender),
+        );
+        let push = |db: &mut Database, values: &[&str]| {
+            db.apply(Command::Push(Push {
+                list_name: "queue".into(),
+                values: values.iter().map(|v| v.to_string()).collect(),
+            }))
+        };
+        let list = |values: &[&str]| {
+            Type::Array(
+                values
+                    .iter()
+                    .map(|v| Type::BulkString(v.as_bytes().to_vec()))
+                    .collect(),
+            )
+        };
+        push(&mut db, &["a", "b"]);
+        push(&mut db, &["c"]);
+        set(&mut db, "queue", "replaced");
+        drop(db);
+        let mut notifications = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            let result: std::result::Result<WatchResult, _> = t.into();
+            let result = result.unwrap();
+            notifications.push((result.event, result.operation, result.before, result.after));
+        }
+        assert_eq!(
+            notifications,
+            vec![
+                (
+                    "rpush".into(),
+                    Operation::Addition,
+                    Some(Type::Null),
+                    list(&["a", "b"])
+                ),
+                (
+                    "rpush".into(),
+                    Operation::Update,
+                    Some(Type::Null),
+                    list(&["c"])
+                ),
+                (
+                    "set".into(),
+                    Operation::Update,
+                    Some(list(&["a", "b", "c"])),
+                    Type::BulkString("replaced".into())
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn watch_only_notifies_the_watched_operations() {
+        let every = [
+            Operation::Addition,
+            Operation::Update,
+            Operation::Removal,
+            Operation::All,
+        ];
+        // Every non empty combination of the four operations
+        for combination in 1..16 {
+            let operations: BTreeSet<Operation> = every
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| combination & (1 << bit) != 0)
+                .map(|(_, operation)| operation.clone())
+                .collect();
+            let mut db = Database::new();
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+            db.watch(
+                Watch {
+                    key: "key".into(),
+                    operations: operations.clone(),
+                    pattern: false,
+                    from: None,
+                    snapshot: false,
+                },
+                Watcher::new(sender),
+            );
+            set(&mut db, "key", "added");
+            set(&mut db, "key", "updated");
+            db.apply(Command::Flush(Flush {
+                all: false,
+                asynchronous: false,
+            }));
+            // The subscriptions are dropped with the database, which ends the notifications
+            drop(db);
+            let mut notified = Vec::new();
+            while let Some(t) = receiver.recv().await {
+                let result: std::result::Result<WatchResult, _> = t.into();
+                notified.push(result.unwrap().operation);
+            }
+            notified.sort();
+            let expected: Vec<Operation> = every[..3]
+                .iter()
+                .filter(|o| operations.contains(o) || operations.contains(&Operation::All))
+                .cloned()
+                .collect();
+            assert_eq!(notified, expected, "watching {:?}", operations);
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_notifications_arrive_in_order() {
+        let mut db = Database::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        db.watch(
+            Watch {
+                key: "key".into(),
+                operations: vec![Operation::All].into_iter().collect(),
+                pattern: false,
+                from: None,
+                snapshot: false,
+            },
+            Watcher::new(sender),
+        );
+        // The writes happen on other threads, faster than the notifications are read
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let mut db = db.clone();
+                std::thread::spawn(move || {
+                    for i in 0..250 {
+                        set(&mut db, "key", &format!("{}:{}", writer, i));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        drop(db);
+        let mut previous = Some(Type::Null);
+        let mut sequence = 0;
+        while let Some(t) = receiver.recv().await {
+            let result: std::result::Result<WatchResult, _> = t.into();
+            let result = result.unwrap();
+            sequence += 1;
+            assert_eq!(result.sequence, sequence);
+            // Every notification starts from the value the previous one ended with
+            assert_eq!(result.before, previous);
+            previous = Some(result.after);
+        }
+        assert_eq!(sequence, 1000);
+    }
+
+    fn watch_all(db: &mut Database, key: &str, watcher: &Watcher) {
+        db.watch(
+            Watch {
+                key: key.into(),
+                operations: vec![Operation::All].into_iter().collect(),
+                pattern: false,
+                from: None,
+                snapshot: false,
+            },
+            watcher.clone(),
+        );
+    }
+
+    #[tokio::test]
+    async fn unwatch_only_removes_the_subscriptions_of_the_watcher() {
+        let mut db = Database::new();
+        let (sender, _receiver) = tokio::sync::mpsc::channel(10);
+        let (first, second) = (Watcher::new(sender.clone()), Watcher::new(sender));
+        watch_all(&mut db, "a", &first);
+        watch_all(&mut db, "b", &first);
+        watch_all(&mut db, "a", &second);
+        assert_eq!(db.unwatch(&first, Some("a".into()), false), 1);
+        assert_eq!(db.unwatch(&first, Some("a".into()), false), 0);
+        assert_eq!(db.unwatch(&first, None, false), 1);
+        assert!(!db.lock_and_access_subscriptions().is_empty());
+        assert_eq!(db.unwatch(&second, None, false), 1);
+        assert!(db.lock_and_access_subscriptions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn watches_of_a_connection_are_told_apart() {
+        let mut db = Database::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let watcher = Watcher::new(sender);
+        let watch = |key: &str| Watch {
+            key: key.into(),
+            operations: vec![Operation::All].into_iter().collect(),
+            pattern: false,
+            from: None,
+            snapshot: false,
+        };
+        assert_eq!(db.watch(watch("a"), watcher.clone()), Type::Integer(1));
+        assert_eq!(db.watch(watch("b"), watcher.clone()), Type::Integer(2));
+        set(&mut db, "b", "1");
+        set(&mut db, "a", "1");
+        // The removal of a watch is pushed after its last notification
+        assert_eq!(db.unwatch(&watcher, Some("a".into()), false), 1);
+        set(&mut db, "a", "2");
+        drop(watcher);
+        drop(db);
+        let mut pushes = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            pushes.push(t);
+        }
+        let unwatch = Type::Push(
+            vec![Type::SimpleString("unwatch".into()), Type::Integer(1)]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(pushes.len(), 3);
+        assert_eq!(pushes[2], unwatch);
+        let watches: Vec<_> = pushes
+            .into_iter()
+            .take(2)
+            .map(|t| {
+                let result: std::result::Result<WatchResult, _> = t.into();
+                let result = result.unwrap();
+                (result.watch, result.key)
+            })
+            .collect();
+        assert_eq!(watches, vec![(2, "b".to_string()), (1, "a".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn subscriptions_of_dead_watchers_are_removed() {
+        let mut db = Database::new();
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        watch_all(&mut db, "key", &Watcher::new(sender));
+        drop(receiver);
+        // The watcher finds out on its first notification after the receiver is gone,
+        // the writes after that remove its subscription
+        for i in 0..100 {
+            if db.lock_and_access_subscriptions().is_empty() {
+                return;
+            }
+            set(&mut db, "key", &i.to_string());
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        panic!("The subscription of the dead watcher was not removed");
+    }
+}
@@ -0,0 +1,636 @@
+//! Snapshots, the keys of every database saved to a file and loaded back when the server
+//! starts (like the RDB files of Redis).
+//!
+//! SAVE writes a snapshot while its connection waits, BGSAVE writes it from a blocking task
+//! and replies at once. Either way the keys are copied one shard at a time, each shard is
+//! only locked for its own copy (see [Databases::snapshot](super::Databases::snapshot)).
+//! The copy is cheap (the strings are shared, see [RedisString]): the encoding and the
+//! writes to the file happen once the locks are released. The [SaveRules] start a BGSAVE once enough keys changed since the
+//! last snapshot. A snapshot is written to a temporary file that is then renamed over the
+//! previous one, which is never left half written.
+//!
+//! # Format
+//!
+//! The numbers are little endian, a string is its length (u64) followed by its bytes.
+//!
+//! - The magic `MINIREDIS` and the version of the format (u16), see [VERSION]
+//! - For every database with keys, [DB] and its index (u32), then its keys:
+//!   - optionally [EXPIRY] and the unix time the key expires at, in milliseconds (u64)
+//!   - the type of the value ([STRING], [LIST] or [SORTED_SET]), the key, then the value:
+//!     a string, the number of elements of a list followed by every element with its type,
+//!     or the number of members of a sorted set followed by every member and its score (f64)
+//! - [EOF] and the CRC-64 of everything before it (u64), see [crc64]
+//!
+//! Keys do not expire on this server, so no expiry is written. A key loaded with an expiry
+//! in the past is skipped, the others are loaded without their expiry.
+
+use std::{
+    collections::LinkedList,
+    convert::TryInto,
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+
+use super::{sorted_set::SortedSet, Databases, RedisString, Value};
+use crate::{resp::Type, Result};
+
+const MAGIC: &[u8] = b"MINIREDIS";
+/// The version of the format, snapshots of a later version are not loaded
+const VERSION: u16 = 1;
+
+/// Followed by the index of a database, the keys after it are in that database
+const DB: u8 = 0xFE;
+/// Followed by the unix time a key expires at, before the key
+const EXPIRY: u8 = 0xFC;
+/// The end of the keys, followed by the checksum
+const EOF: u8 = 0xFF;
+const STRING: u8 = 0;
+const LIST: u8 = 1;
+const SORTED_SET: u8 = 2;
+
+/// The reply to SAVE and BGSAVE while a snapshot is written
+const IN_PROGRESS: &str = "ERR Background save already in progress";
+
+/// A failed automatic snapshot is tried again after this many seconds
+const RETRY_SECONDS: u64 = 5;
+
+/// The keys of the databases with keys, with their index
+pub(crate) type Data = Vec<(usize, Vec<(RedisString, Value)>)>;
+
+/// Encodes the keys in the snapshot format
+pub(crate) fn encode(data: &Data) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    for (index, keys) in data.iter().filter(|(_, keys)| !keys.is_empty()) {
+        bytes.push(DB);
+        bytes.extend_from_slice(&(*index as u32).to_le_bytes());
+        for (key, value) in keys {
+            bytes.push(type_of(value));
+            encode_string(&mut bytes, key.as_bytes());
+            encode_value(&mut bytes, value);
+        }
+    }
+    bytes.push(EOF);
+    let checksum = crc64(&bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+fn type_of(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => STRING,
+        Value::List(_) => LIST,
+        Value::SortedSet(_) => SORTED_SET,
+    }
+}
+
+fn encode_string(bytes: &mut Vec<u8>, s: &[u8]) {
+    bytes.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(s);
+}
+
+/// Encodes a value, without its type
+fn encode_value(bytes: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(s) => encode_string(bytes, s.as_bytes()),
+        Value::List(list) => {
+            bytes.extend_from_slice(&(list.len() as u64).to_le_bytes());
+            for element in list {
+                bytes.push(type_of(element));
+                encode_value(bytes, element);
+            }
+        }
+        Value::SortedSet(set) => {
+            bytes.extend_from_slice(&(set.len() as u64).to_le_bytes());
+            for (member, score) in set.iter() {
+                encode_string(bytes, member.as_bytes());
+                bytes.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Decodes a snapshot, checking its version and its checksum
+pub(crate) fn decode(bytes: &[u8]) -> Result<Data> {
+    if bytes.len() < MAGIC.len() + 2 + 1 + 8 || !bytes.starts_with(MAGIC) {
+        return Err("not a snapshot".into());
+    }
+    let (content, checksum) = bytes.split_at(bytes.len() - 8);
+    let checksum = u64::from_le_bytes(checksum.try_into().expect("8 bytes"));
+    if crc64(content) != checksum {
+        return Err("the checksum of the snapshot is wrong".into());
+    }
+    let mut reader = Reader {
+        bytes: content,
+        position: MAGIC.len(),
+    };
+    let version = u16::from_le_bytes(reader.array()?);
+    if version > VERSION {
+        return Err(format!("the version {} of the snapshot is not supported", version).into());
+    }
+    let now = unix_time().as_millis() as u64;
+    let mut data: Data = Vec::new();
+    loop {
+        let expiry = match reader.u8()? {
+            EOF => break,
+            DB => {
+                let index = u32::from_le_bytes(reader.array()?) as usize;
+                data.push((index, Vec::new()));
+                continue;
+            }
+            EXPIRY => Some(u64::from_le_bytes(reader.array()?)),
+            _ => {
+                // Not an opcode, it is the type of a key without an expiry
+                reader.position -= 1;
+                None
+            }
+        };
+        let kind = reader.u8()?;
+        let key: RedisString = reader.string()?.into();
+        let value = reader.value(kind)?;
+        let keys = match data.last_mut() {
+            Some((_, keys)) => keys,
+            None => return Err("a key is not in a database".into()),
+        };
+        match expiry {
+            Some(expiry) if expiry <= now => {}
+            Some(_) => {
+                warn!("The key {:?} is loaded without its expiry", key);
+                keys.push((key, value));
+            }
+            None => keys.push((key, value)),
+        }
+    }
+    if reader.position != content.len() {
+        return Err("there are bytes after the end of the snapshot".into());
+    }
+    Ok(data)
+}
+
+/// Reads the content of a snapshot, every read fails past its end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8]> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("the snapshot is truncated")?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(u64::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// A value of the type `kind`
+    fn value(&mut self, kind: u8) -> Result<Value> {
+        let value = match kind {
+            STRING => Value::String(self.string()?.into()),
+            LIST => {
+                let mut list = LinkedList::new();
+                for _ in 0..self.len()? {
+                    let kind = self.u8()?;
+                    list.push_back(self.value(kind)?);
+                }
+                Value::List(list)
+            }
+            SORTED_SET => {
+                let mut set = SortedSet::new();
+                for _ in 0..self.len()? {
+                    let member = self.string()?.into();
+                    let score = f64::from_le_bytes(self.array()?);
+                    if score.is_nan() {
+                        return Err("a score of a sorted set is not a number".into());
+                    }
+                    set.insert(member, score);
+                }
+                Value::SortedSet(set)
+            }
+            _ => return Err(format!("{} is not a type of value", kind).into()),
+        };
+        Ok(value)
+    }
+}
+
+/// The CRC-64 used by Redis (Jones polynomial, reflected)
+pub(crate) fn crc64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |crc, byte| {
+        CRC64_TABLE[((crc ^ *byte as u64) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+const fn crc64_table() -> [u64; 256] {
+    const POLYNOMIAL: u64 = 0x95AC_9329_AC4B_C9B5;
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Writes the snapshot to a temporary file next to `path`, then renames it to `path`
+fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    let temporary = PathBuf::from(temporary);
+    let written = File::create(&temporary).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    match written.and_then(|_| fs::rename(&temporary, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temporary);
+            Err(e)
+        }
+    }
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// A snapshot is saved once `changes` keys changed, if `seconds` passed since the last one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    /// The seconds since the last snapshot
+    pub seconds: u64,
+    /// The number of changes since the last snapshot
+    pub changes: u64,
+}
+
+/// The rules of the automatic snapshots, one is saved as soon as any of them says so.
+/// Written like the `save` parameter of Redis: pairs of seconds and changes, e.g. `60 1000`.
+/// By default the same as Redis, `3600 1 300 100 60 10000`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveRules(pub Vec<SaveRule>);
+
+impl Default for SaveRules {
+    fn default() -> Self {
+        SaveRules(
+            [(3600, 1), (300, 100), (60, 10000)]
+                .iter()
+                .map(|&(seconds, changes)| SaveRule { seconds, changes })
+                .collect(),
+        )
+    }
+}
+
+impl FromStr for SaveRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let numbers = s
+            .split_whitespace()
+            .map(|n| n.parse::<u64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| format!("{} are not save rules", s))?;
+        if numbers.len() % 2 != 0 {
+            return Err(format!("{} are not pairs of seconds and changes", s));
+        }
+        Ok(SaveRules(
+            numbers
+                .chunks(2)
+                .map(|pair| SaveRule {
+                    seconds: pair[0],
+                    changes: pair[1],
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Display for SaveRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<_> = self
+            .0
+            .iter()
+            .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+            .collect();
+        f.pad(&rules.join(" "))
+    }
+}
+
+/// The snapshots of the databases of a server, saved to a file. Without a file, SAVE and
+/// BGSAVE fail and nothing is loaded. The save rules can be changed while it runs.
+#[derive(Clone)]
+pub(crate) struct Persistence {
+    inner: Arc<PersistenceState>,
+}
+
+struct PersistenceState {
+    databases: Databases,
+    path: Option<PathBuf>,
+    rules: Mutex<SaveRules>,
+    /// A snapshot is being written, by SAVE or BGSAVE
+    saving: AtomicBool,
+    /// The writes to the databases (see [Databases::writes]) in the last snapshot saved
+    saved_writes: AtomicU64,
+    /// The unix time of the last snapshot saved (or loaded), in seconds
+    last_save: AtomicU64,
+    /// The unix time of the last snapshot started, in seconds
+    last_attempt: AtomicU64,
+    /// Whether the last snapshot was saved
+    last_status: AtomicBool,
+}
+
+impl Persistence {
+    /// The snapshots of the `databases`, saved to `path`
+    pub(crate) fn new(databases: Databases, path: Option<PathBuf>, rules: SaveRules) -> Self {
+        let now = unix_time().as_secs();
+        Persistence {
+            inner: Arc::new(PersistenceState {
+                saved_writes: AtomicU64::new(databases.writes()),
+                databases,
+                path,
+                rules: Mutex::new(rules),
+                saving: AtomicBool::new(false),
+                last_save: AtomicU64::new(now),
+                last_attempt: AtomicU64::new(now),
+                last_status: AtomicBool::new(true),
+            }),
+        }
+    }
+
+    /// Loads the snapshot into the databases, if there is one. Returns the number of keys.
+    pub(crate) fn load(&self) -> Result<usize> {
+        let path = match &self.inner.path {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let data = decode(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let keys = data.iter().map(|(_, keys)| keys.len()).sum();
+        self.inner.databases.restore(data)?;
+        info!("Loaded {} keys from {}", keys, path.display());
+        Ok(keys)
+    }
+
+    /// SAVE, writes a snapshot before replying
+    pub(crate) fn save(&self) -> Type {
+        let path = match self.start() {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+        let (data, writes) = self.inner.databases.snapshot();
+        let saved = write(&path, &encode(&data));
+        self.finish(writes, &saved);
+        match saved {
+            Ok(()) => Type::SimpleString("OK".into()),
+            Err(e) => Type::Error(format!("ERR {}", e)),
+        }
+    }
+
+    /// BGSAVE, replies once the keys are copied, the snapshot is written by a blocking task
+    pub(crate) fn bgsave(&self) -> Type {
+        let path = match self.start() {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+        let (data, writes) = self.inner.databases.snapshot();
+        let persistence = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let saved = write(&path, &encode(&data));
+            persistence.finish(writes, &saved);
+        });
+        Type::SimpleString("Background saving started".into())
+    }
+
+    /// Marks a snapshot as in progress, returns where to write it
+    fn start(&self) -> std::result::Result<PathBuf, Type> {
+        let path = self
+            .inner
+            .path
+            .clone()
+            .ok_or_else(|| Type::Error("ERR no file to save the snapshots to".into()))?;
+        self.inner
+            .saving
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| Type::Error(IN_PROGRESS.into()))?;
+        self.inner
+            .last_attempt
+            .store(unix_time().as_secs(), Ordering::Relaxed);
+        Ok(path)
+    }
+
+    /// Records the end of a snapshot, of the databases after `writes` writes
+    fn finish(&self, writes: u64, saved: &io::Result<()>) {
+        match saved {
+            Ok(()) => {
+                self.inner.saved_writes.store(writes, Ordering::Relaxed);
+                self.inner
+                    .last_save
+                    .store(unix_time().as_secs(), Ordering::Relaxed);
+                info!("Snapshot saved");
+            }
+            Err(e) => error!("The snapshot was not saved: {}", e),
+        }
+        self.inner
+            .last_status
+            .store(saved.is_ok(), Ordering::Relaxed);
+        self.inner.saving.store(false, Ordering::Release);
+    }
+
+    /// The number of changes since the last snapshot
+    fn changes(&self) -> u64 {
+        self.inner.databases.writes() - self.inner.saved_writes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn rules(&self) -> SaveRules {
+        self.inner.rules.lock().expect("Lock failed").clone()
+    }
+
+    pub(crate) fn set_rules(&self, rules: SaveRules) {
+        *self.inner.rules.lock().expect("Lock failed") = rules;
+    }
+
+    /// Starts a BGSAVE if a save rule says so. After a failure, it waits a few seconds.
+    fn check_rules(&self) {
+        let now = unix_time().as_secs();
+        let since = |time: &AtomicU64| now.saturating_sub(time.load(Ordering::Relaxed));
+        let changes = self.changes();
+        let due = self.rules().0.iter().any(|rule| {
+            changes >= rule.changes.max(1) && since(&self.inner.last_save) >= rule.seconds
+        });
+        let retry = self.inner.last_status.load(Ordering::Relaxed)
+            || since(&self.inner.last_attempt) >= RETRY_SECONDS;
+        if due && retry && !self.inner.saving.load(Ordering::Acquire) {
+            info!("{} changes, saving a snapshot", changes);
+            self.bgsave();
+        }
+    }
+
+    /// Spawns the task checking the save rules every second, if there is a file to save to.
+    /// A BGSAVE copies the keys first, with the actors engine the check runs on a blocking
+    /// thread (see [Databases::unblocking](super::Databases::unblocking)).
+    pub(crate) fn spawn_save_rules(&self) {
+        if self.inner.path.is_none() {
+            return;
+        }
+        let persistence = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let checking = persistence.clone();
+                let databases = &persistence.inner.databases;
+                databases.unblocking(move || checking.check_rules()).await;
+            }
+        });
+    }
+
+    /// The `persistence` section of INFO
+    pub(crate) fn info(&self) -> String {
+        format!(
+            "# Persistence\r\nrdb_changes_since_last_save:{}\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\n",
+            self.changes(),
+            self.inner.saving.load(Ordering::Relaxed) as u8,
+            self.inner.last_save.load(Ordering::Relaxed),
+            if self.inner.last_status.load(Ordering::Relaxed) {
+                "ok"
+            } else {
+                "err"
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::LinkedList;
+
+    use super::{crc64, decode, encode, Data, SaveRules, EOF, EXPIRY, MAGIC, VERSION};
+    use crate::database::{sorted_set::SortedSet, Value};
+
+    fn data() -> Data {
+        let mut set = SortedSet::new();
+        set.insert("member".into(), 1.5);
+        set.insert("other".into(), -3.0);
+        let list: LinkedList<String> = vec!["a".to_string(), "b".to_string()].into_iter().collect();
+        vec![
+            (
+                0,
+                vec![
+                    ("string".into(), Value::String(vec![0, 255, 10].into())),
+                    ("list".into(), Value::from(list)),
+                    ("empty".into(), "".to_string().into()),
+                ],
+            ),
+            (3, vec![("zset".into(), Value::SortedSet(set))]),
+        ]
+    }
+
+    #[test]
+    fn the_crc64_is_the_one_of_redis() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn snapshots_are_decoded_back() {
+        let bytes = encode(&data());
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(decode(&bytes).unwrap(), data());
+        assert_eq!(decode(&encode(&Vec::new())).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn corrupted_snapshots_are_not_loaded() {
+        let bytes = encode(&data());
+        // A flipped bit
+        let mut corrupted = bytes.clone();
+        corrupted[20] ^= 1;
+        assert!(decode(&corrupted).is_err());
+        // Truncated
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        // From a later version, with a valid checksum
+        let mut later = bytes[..bytes.len() - 8].to_vec();
+        later[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let checksum = crc64(&later);
+        later.extend_from_slice(&checksum.to_le_bytes());
+        assert!(decode(&later).is_err());
+    }
+
+    #[test]
+    fn expired_keys_are_skipped() {
+        let mut bytes = encode(&vec![(0, vec![("kept".into(), "v".to_string().into())])]);
+        bytes.truncate(bytes.len() - 9);
+        for (key, expiry) in [("expired", 1), ("later", u64::MAX)] {
+            bytes.push(EXPIRY);
+            bytes.extend_from_slice(&expiry.to_le_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.extend_from_slice(&1u64.to_le_bytes());
+            bytes.push(b'v');
+        }
+        bytes.push(EOF);
+        let checksum = crc64(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        let keys: Vec<String> = decode(&bytes).unwrap()[0]
+            .1
+            .iter()
+            .map(|(key, _)| key.clone().into())
+            .collect();
+        assert_eq!(keys, vec!["kept", "later"]);
+    }
+
+    #[test]
+    fn save_rules_are_parsed_and_shown() {
+        let rules: SaveRules = "3600 1 300 100 60 10000".parse().unwrap();
+        assert_eq!(rules, SaveRules::default());
+        assert_eq!(rules.to_string(), "3600 1 300 100 60 10000");
+        assert_eq!("".parse::<SaveRules>().unwrap().0, Vec::new());
+        assert!("60".parse::<SaveRules>().is_err());
+        assert!("60 x".parse::<SaveRules>().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! A sorted set, members are unique and ordered by their score (and then lexicographically).
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use super::RedisString;
+
+/// A score that can be ordered (scores are never NaN)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Score(pub(crate) f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// The sorted set, members can be looked up by name and ranges can be queried by score
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct SortedSet {
+    scores: HashMap<RedisString, Score>,
+    ordered: BTreeSet<(Score, RedisString)>,
+}
+
+impl SortedSet {
+    pub(crate) fn new() -> Self {
+        SortedSet::default()
+    }
+
+    /// Adds or updates a member, returns the previous score
+    pub(crate) fn insert(&mut self, member: RedisString, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), Score(score));
+        if let Some(previous) = previous {
+            self.ordered.remove(&(previous, member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous.map(|s| s.0)
+    }
+
+    pub(crate) fn score(&self, member: &RedisString) -> Option<f64> {
+        self.scores.get(member).map(|s| s.0)
+    }
+
+    /// Returns the members with `min <= score < max`, in order
+    pub(crate) fn range_by_score(
+        &self,
+        min: f64,
+        max: f64,
+    ) -> impl Iterator<Item = (&RedisString, f64)> {
+        // The empty string is the smallest member for a given score
+        let smallest = || RedisString::from("");
+        self.ordered
+            .range((
+                Bound::Included((Score(min), smallest())),
+                Bound::Excluded((Score(max), smallest())),
+            ))
+            .map(|(score, member)| (member, score.0))
+    }
+
+    /// The number of members
+    pub(crate) fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Returns all the members, in order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&RedisString, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SortedSet;
+
+    #[test]
+    fn sorted_set_works() {
+        let mut set = SortedSet::new();
+        assert_eq!(set.insert("b".into(), 2.0), None);
+        assert_eq!(set.insert("a".into(), 2.0), None);
+        assert_eq!(set.insert("c".into(), 1.0), None);
+        assert_eq!(set.insert("c".into(), 3.0), Some(1.0));
+        assert_eq!(set.iter().count(), 3);
+        assert_eq!(set.score(&"c".into()), Some(3.0));
+        assert_eq!(set.score(&"d".into()), None);
+        let members: Vec<String> = set.iter().map(|(m, _)| m.clone().into()).collect();
+        assert_eq!(members, vec!["a", "b", "c"]);
+        let members: Vec<String> = set
+            .range_by_score(2.0, 3.0)
+            .map(|(m, _)| m.clone().into())
+            .collect();
+        assert_eq!(members, vec!["a", "b"]);
+        assert_eq!(set.range_by_score(3.5, 10.0).count(), 0);
+    }
+}
@@ -0,0 +1,196 @@
+//! CLIENT TRACKING, the connections caching keys are told when they change.
+//!
+//! A connection with tracking on has a [Tracker], its invalidations are queued with its
+//! watch notifications (they go through its [Watcher]). Every [Database](super::Database)
+//! has a [TrackingTable] with:
+//! * the keys read by the trackers, a key is forgotten once it is invalidated: the tracker
+//!   has to read it again to be told about its next change
+//! * the prefixes of the trackers in BCAST mode, told about every change of the keys with
+//!   one of their prefixes (of every key for an empty prefix)
+
+use std::collections::{HashMap, HashSet};
+
+use super::{keyspace::Change, RedisString, Watcher};
+
+/// The tracking of a connection
+#[derive(Debug, Clone)]
+pub(crate) struct Tracker {
+    watcher: Watcher,
+    /// Not told about its own changes
+    noloop: bool,
+}
+
+impl Tracker {
+    pub(crate) fn new(watcher: Watcher, noloop: bool) -> Self {
+        Tracker { watcher, noloop }
+    }
+
+    /// Identifies the tracker, and the changes it made (see [Change::writer])
+    pub(crate) fn id(&self) -> u64 {
+        self.watcher.id()
+    }
+
+    /// Tells the connection the key changed, unless it made the change itself with NOLOOP.
+    /// Returns false if the connection is gone.
+    fn invalidate(&self, change: &Change) -> bool {
+        if self.noloop && change.writer == Some(self.id()) {
+            return true;
+        }
+        self.watcher.invalidate(change.key.clone())
+    }
+}
+
+/// The keys and the prefixes tracked in a database
+#[derive(Debug, Default)]
+pub(super) struct TrackingTable {
+    keys: HashMap<RedisString, HashMap<u64, Tracker>>,
+    prefixes: Vec<(Vec<u8>, Tracker)>,
+}
+
+impl TrackingTable {
+    /// Remembers that the tracker read the key
+    pub(super) fn track(&mut self, key: RedisString, tracker: &Tracker) {
+        self.keys
+            .entry(key)
+            .or_default()
+            .insert(tracker.id(), tracker.clone());
+    }
+
+    /// Tells the tracker about the changes of the keys with the prefix (BCAST)
+    pub(super) fn broadcast(&mut self, prefix: Vec<u8>, tracker: &Tracker) {
+        self.prefixes.push((prefix, tracker.clone()));
+    }
+
+    /// The keys read by the trackers
+    pub(super) fn keys(&self) -> impl Iterator<Item = &RedisString> {
+        self.keys.keys()
+    }
+
+    /// Returns true if a tracker is told about the changes of the key
+    pub(super) fn tracks(&self, key: &RedisString) -> bool {
+        self.keys.contains_key(key)
+            || self
+                .prefixes
+                .iter()
+                .any(|(prefix, _)| key.as_bytes().starts_with(prefix))
+    }
+
+    /// Returns true if there are trackers in BCAST mode
+    pub(super) fn has_prefixes(&self) -> bool {
+        !self.prefixes.is_empty()
+    }
+
+    /// Returns true if nothing is tracked
+    pub(super) fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.prefixes.is_empty()
+    }
+
+    /// Forgets everything tracked by the tracker
+    pub(super) fn untrack(&mut self, id: u64) {
+        self.keys.retain(|_, trackers| {
+            trackers.remove(&id);
+            !trackers.is_empty()
+        });
+        self.prefixes.retain(|(_, tracker)| tracker.id() != id);
+    }
+
+    /// Tells the trackers of the key that it changed, once each
+    pub(super) fn invalidate(&mut self, change: &Change) {
+        let mut told = HashSet::new();
+        if let Some(trackers) = self.keys.remove(&change.key) {
+            for (id, tracker) in trackers {
+                tracker.invalidate(change);
+                told.insert(id);
+            }
+        }
+        let key = change.key.as_bytes();
+        // The trackers of the connections that are gone are removed
+        self.prefixes.retain(|(prefix, tracker)| {
+            !key.starts_with(prefix) || !told.insert(tracker.id()) || tracker.invalidate(change)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Tracker;
+    use crate::{
+        commands::{get::Get, set::Set, Command},
+        database::{Database, Watcher},
+        resp::Type,
+    };
+
+    fn set(key: &str) -> Command {
+        Command::Set(Set {
+            key: key.into(),
+            value: "v".into(),
+        })
+    }
+
+    fn get(key: &str) -> Command {
+        Command::Get(Get { key: key.into() })
+    }
+
+    fn invalidated(t: Type) -> String {
+        let invalidate = |key: &str| {
+            let keys = Type::Array(vec![Type::BulkString(key.into())].into_iter().collect());
+            Type::Push(
+                vec![Type::SimpleString("invalidate".into()), keys]
+                    .into_iter()
+                    .collect(),
+            )
+        };
+        for key in ["a", "b", "user:1"].iter() {
+            if t == invalidate(key) {
+                return key.to_string();
+            }
+        }
+        panic!("Unexpected {:?}", t)
+    }
+
+    #[tokio::test]
+    async fn keys_read_are_invalidated_once() {
+        let mut db = Database::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let tracker = Tracker::new(Watcher::new(sender.clone()), false);
+        let noloop = Tracker::new(Watcher::new(sender), true);
+        db.apply_tracked(get("a"), &tracker, true);
+        db.apply_tracked(get("b"), &tracker, false);
+        db.apply_tracked(get("b"), &noloop, true);
+        db.apply(set("a"));
+        // Forgotten once invalidated, until read again
+        db.apply(set("a"));
+        db.apply(set("b"));
+        // Not told about its own changes
+        db.apply_tracked(get("b"), &noloop, true);
+        db.apply_tracked(set("b"), &noloop, false);
+        db.apply_tracked(get("a"), &tracker, true);
+        db.untrack(&tracker);
+        db.apply(set("a"));
+        drop((db, tracker, noloop));
+        let mut keys = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            keys.push(invalidated(t));
+        }
+        assert_eq!(keys, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_are_told_about_the_keys_with_their_prefixes() {
+        let mut db = Database::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let tracker = Tracker::new(Watcher::new(sender), false);
+        db.broadcast(b"user:".to_vec(), &tracker);
+        db.broadcast(b"us".to_vec(), &tracker);
+        db.apply(set("a"));
+        db.apply(set("user:1"));
+        db.apply(set("user:1"));
+        drop((db, tracker));
+        let mut keys = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            keys.push(invalidated(t));
+        }
+        // Once per change, whatever the number of prefixes
+        assert_eq!(keys, vec!["user:1", "user:1"]);
+    }
+}
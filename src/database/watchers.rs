@@ -0,0 +1,1096 @@
+//! The subscriptions of WATCH and PWATCH.
+//!
+//! The notifications of a connection all go through its [Watcher], in order. Its queue is
+//! bounded by [WatcherLimits]: a connection that does not keep up is disconnected, or its
+//! notifications are coalesced (see [SlowWatchers]).
+//! The patterns are indexed by their literal prefix (the bytes before the first special byte),
+//! so a write only matches its key against the patterns whose prefix the key starts with.
+//!
+//! Every change is numbered and the recent ones are kept in the [ChangeLog] of their shard,
+//! a watcher that reconnects can resume from the last change it saw with
+//! `WATCH ... FROM <offset>`. The changes are logged under the lock of their shard, the
+//! subscriptions are only locked to deliver them when somebody listens (see [Listeners]).
+//! The values kept by the logs count in the memory used, a log keeps at most
+//! [CHANGE_LOG_BYTES] of them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, LinkedList, VecDeque},
+    hash::Hash,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::info;
+use tokio::sync::Notify;
+
+use super::{
+    keyspace::Change,
+    memory::{self, Memory},
+    tracking::{Tracker, TrackingTable},
+    KeyspaceNotifier, Operation, RedisString, Value,
+};
+use crate::{
+    commands::watch::{Watch, WatchResult},
+    glob,
+    resp::Type,
+};
+
+/// The number of changes kept by each shard of a database, unless configured otherwise
+pub(crate) const DEFAULT_CHANGE_LOG: usize = 1024;
+
+/// The estimated bytes of the changes kept by each shard of a database (see
+/// [memory::logged_size]), the oldest ones are dropped beyond
+pub(crate) const CHANGE_LOG_BYTES: usize = 1 << 20;
+
+/// The reply to a `WATCH ... FROM` whose changes are not all kept anymore
+pub(super) const RESYNC: &str = "RESYNC required, the change log does not go back to";
+
+/// The id of the next [Watcher]
+static NEXT_WATCHER: AtomicU64 = AtomicU64::new(0);
+
+/// What happens to a watcher whose connection does not read its notifications fast enough
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowWatchers {
+    /// The connection is closed and its subscriptions are removed
+    Disconnect,
+    /// A notification replaces the queued one of the same key, which is dropped (the
+    /// sequence numbers show the gap). The connection is still closed at the hard limit
+    /// when there is nothing to replace.
+    Coalesce,
+}
+
+impl FromStr for SlowWatchers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "disconnect" => Ok(SlowWatchers::Disconnect),
+            "coalesce" => Ok(SlowWatchers::Coalesce),
+            _ => Err(format!("{} is neither disconnect nor coalesce", s)),
+        }
+    }
+}
+
+/// The output buffer limits of a watcher, in notifications queued for its connection,
+/// like `client-output-buffer-limit` in Redis. A limit of 0 is no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatcherLimits {
+    /// The watcher is too slow as soon as this many notifications are queued
+    pub hard: usize,
+    /// The watcher is too slow once this many notifications stay queued for `soft_seconds`
+    pub soft: usize,
+    /// How long the soft limit can be exceeded
+    pub soft_seconds: u64,
+    /// What happens to a watcher that is too slow
+    pub policy: SlowWatchers,
+}
+
+impl Default for WatcherLimits {
+    fn default() -> Self {
+        WatcherLimits {
+            hard: 8192,
+            soft: 1024,
+            soft_seconds: 60,
+            policy: SlowWatchers::Disconnect,
+        }
+    }
+}
+
+/// The counters of the watchers of a server, shown by INFO
+#[derive(Debug, Default)]
+pub(crate) struct WatcherStats {
+    /// The connections with a watcher
+    watchers: AtomicU64,
+    /// The notifications queued, not sent yet
+    pending: AtomicU64,
+    /// The notifications queued since the start
+    notifications: AtomicU64,
+    /// The notifications dropped for a later one of the same key
+    coalesced: AtomicU64,
+    /// The watchers disconnected for being too slow
+    disconnected: AtomicU64,
+}
+
+impl WatcherStats {
+    /// The `watchers` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let counters = [
+            ("watchers", &self.watchers),
+            ("watch_pending_notifications", &self.pending),
+            ("watch_notifications", &self.notifications),
+            ("watch_coalesced_notifications", &self.coalesced),
+            ("watch_disconnected_watchers", &self.disconnected),
+        ];
+        let mut info = String::from("# Watchers\r\n");
+        for (name, counter) in counters.iter() {
+            info.push_str(&format!("{}:{}\r\n", name, counter.load(Ordering::Relaxed)));
+        }
+        info
+    }
+}
+
+/// The watches of a connection, the notifications of all its subscriptions go through it.
+/// They are queued in order, each with the next sequence number, and the connection takes
+/// them from its [Notifications]. The queue is bounded by the [WatcherLimits].
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    /// Identifies the subscriptions of the connection, for UNWATCH
+    id: u64,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<WatcherQueue>,
+    /// Wakes the connection up when a notification is queued or the watcher is closed
+    ready: Notify,
+    /// Makes the connection close its socket, once too slow
+    disconnect: Arc<Notify>,
+    limits: WatcherLimits,
+    stats: Arc<WatcherStats>,
+}
+
+#[derive(Debug)]
+struct WatcherQueue {
+    /// The sequence number of the last notification
+    sequence: u64,
+    /// The id of the last watch, a connection tells its watches apart with it
+    watches: u64,
+    pending: VecDeque<Push>,
+    /// Since when the soft limit is exceeded
+    soft_since: Option<Instant>,
+    /// The number of [Watcher] handles, the notifications end when there is none left
+    handles: usize,
+    /// Once the connection is gone, or disconnected for being too slow
+    closed: bool,
+}
+
+impl WatcherQueue {
+    /// Drops the queued notifications, when nobody is going to receive them
+    fn close(&mut self, stats: &WatcherStats) {
+        self.closed = true;
+        stats
+            .pending
+            .fetch_sub(self.pending.len() as u64, Ordering::Relaxed);
+        self.pending.clear();
+    }
+}
+
+/// What is queued for a connection, sent as a [Type::Push]
+#[derive(Debug)]
+enum Push {
+    /// `watch`, the notification of a change
+    Notification(WatchResult),
+    /// `unwatch`, the watch with this id was removed: nothing else is pushed for it
+    Unwatched(u64),
+    /// `invalidate`, a key tracked by the connection changed (see CLIENT TRACKING)
+    Invalidate(RedisString),
+}
+
+impl Push {
+    /// Returns true if this push makes the other one useless, when coalescing
+    fn replaces(&self, other: &Push) -> bool {
+        match (self, other) {
+            (Push::Notification(r), Push::Notification(o)) => r.key == o.key && r.watch == o.watch,
+            (Push::Invalidate(key), Push::Invalidate(other)) => key == other,
+            _ => false,
+        }
+    }
+}
+
+impl From<Push> for Type {
+    fn from(push: Push) -> Self {
+        match push {
+            Push::Notification(result) => result.into(),
+            Push::Invalidate(key) => {
+                let kind = Type::SimpleString("invalidate".into());
+                let keys = Type::Array(vec![Value::String(key).into()].into_iter().collect());
+                Type::Push(vec![kind, keys].into_iter().collect())
+            }
+            Push::Unwatched(watch) => {
+                let kind = Type::SimpleString("unwatch".into());
+                Type::Push(
+                    vec![kind, Type::Integer(watch as i64)]
+                        .into_iter()
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+/// The notifications of a [Watcher], taken by its connection
+#[derive(Debug)]
+pub(crate) struct Notifications {
+    shared: Arc<Shared>,
+}
+
+impl Watcher {
+    /// Creates a watcher, its connection takes the notifications from the other half.
+    /// `disconnect` is notified if the connection is too slow, it has to be closed even
+    /// while it is waiting to write to its socket.
+    pub(crate) fn with_limits(
+        limits: WatcherLimits,
+        stats: Arc<WatcherStats>,
+        disconnect: Arc<Notify>,
+    ) -> (Self, Notifications) {
+        stats.watchers.fetch_add(1, Ordering::Relaxed);
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(WatcherQueue {
+                sequence: 0,
+                watches: 0,
+                pending: VecDeque::new(),
+                soft_since: None,
+                handles: 1,
+                closed: false,
+            }),
+            ready: Notify::new(),
+            disconnect,
+            limits,
+            stats,
+        });
+        let watcher = Watcher {
+            id: NEXT_WATCHER.fetch_add(1, Ordering::Relaxed),
+            shared: shared.clone(),
+        };
+        (watcher, Notifications { shared })
+    }
+
+    /// Creates a watcher that forwards its notifications to `sink`, from a task of its own
+    #[cfg(test)]
+    pub(crate) fn new(sink: tokio::sync::mpsc::Sender<Type>) -> Self {
+        let (watcher, mut notifications) = Watcher::with_limits(
+            WatcherLimits::default(),
+            Default::default(),
+            Default::default(),
+        );
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.next().await {
+                if sink.send(notification).await.is_err() {
+                    break;
+                }
+            }
+        });
+        watcher
+    }
+
+    /// Queues a notification, after the ones queued before it.
+    /// Returns false if the connection is gone, or too slow (see [SlowWatchers]),
+    /// its subscriptions can then be removed.
+    fn notify(&self, result: WatchResult) -> bool {
+        self.queue(Push::Notification(result))
+    }
+
+    /// Tells the connection that a key it tracks changed, like [Watcher::notify]
+    pub(super) fn invalidate(&self, key: RedisString) -> bool {
+        self.queue(Push::Invalidate(key))
+    }
+
+    /// Queues a push within the limits, the notifications get their sequence number here
+    fn queue(&self, mut push: Push) -> bool {
+        let Shared {
+            queue,
+            ready,
+            disconnect,
+            limits,
+            stats,
+        } = self.shared.as_ref();
+        let mut queue = queue.lock().expect("Lock failed");
+        if queue.closed {
+            return false;
+        }
+        if let Push::Notification(result) = &mut push {
+            queue.sequence += 1;
+            result.sequence = queue.sequence;
+        }
+        let queued = queue.pending.len();
+        let hard = limits.hard > 0 && queued >= limits.hard;
+        let soft = limits.soft > 0 && queued >= limits.soft && {
+            let since = *queue.soft_since.get_or_insert_with(Instant::now);
+            since.elapsed() >= Duration::from_secs(limits.soft_seconds)
+        };
+        if hard || soft {
+            let same_key = match limits.policy {
+                SlowWatchers::Coalesce => queue.pending.iter().position(|p| push.replaces(p)),
+                SlowWatchers::Disconnect => None,
+            };
+            match same_key {
+                Some(index) => {
+                    queue.pending.remove(index);
+                    stats.pending.fetch_sub(1, Ordering::Relaxed);
+                    stats.coalesced.fetch_add(1, Ordering::Relaxed);
+                }
+                // Coalescing, below the hard limit
+                None if !hard && limits.policy == SlowWatchers::Coalesce => {}
+                None => {
+                    info!("Watcher {} is too slow, disconnecting it", self.id);
+                    queue.close(stats);
+                    stats.disconnected.fetch_add(1, Ordering::Relaxed);
+                    ready.notify_one();
+                    disconnect.notify_one();
+                    return false;
+                }
+            }
+        }
+        queue.pending.push_back(push);
+        stats.pending.fetch_add(1, Ordering::Relaxed);
+        stats.notifications.fetch_add(1, Ordering::Relaxed);
+        ready.notify_one();
+        true
+    }
+
+    /// Identifies the connection
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The id of a new watch of the connection
+    fn next_watch(&self) -> u64 {
+        let mut queue = self.shared.queue.lock().expect("Lock failed");
+        queue.watches += 1;
+        queue.watches
+    }
+
+    /// Tells the connection that a watch was removed, after its last notification
+    fn unwatched(&self, watch: u64) {
+        let mut queue = self.shared.queue.lock().expect("Lock failed");
+        if !queue.closed {
+            queue.pending.push_back(Push::Unwatched(watch));
+            self.shared.stats.pending.fetch_add(1, Ordering::Relaxed);
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+impl Clone for Watcher {
+    fn clone(&self) -> Self {
+        self.shared.queue.lock().expect("Lock failed").handles += 1;
+        Watcher {
+            id: self.id,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.shared.queue.lock().expect("Lock failed").handles -= 1;
+        self.shared.ready.notify_one();
+    }
+}
+
+impl Notifications {
+    /// The next notification, None once the watcher is disconnected for being too slow
+    /// (or once every [Watcher] handle is gone)
+    pub(crate) async fn next(&mut self) -> Option<Type> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().expect("Lock failed");
+                if let Some(push) = queue.pending.pop_front() {
+                    self.shared.stats.pending.fetch_sub(1, Ordering::Relaxed);
+                    if queue.pending.len() < self.shared.limits.soft {
+                        queue.soft_since = None;
+                    }
+                    return Some(push.into());
+                }
+                if queue.closed || queue.handles == 0 {
+                    return None;
+                }
+            }
+            // Notify keeps a permit when nobody is waiting, a wake up is never missed
+            self.shared.ready.notified().await;
+        }
+    }
+}
+
+impl Drop for Notifications {
+    fn drop(&mut self) {
+        let stats = &self.shared.stats;
+        self.shared.queue.lock().expect("Lock failed").close(stats);
+        stats.watchers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The subscription for the changes of some types of operation
+#[derive(Debug)]
+pub struct OperationSubscription {
+    operations: BTreeSet<Operation>,
+    watcher: Watcher,
+    /// The id of the watch, in the notifications
+    watch: u64,
+    /// The offset of the last change made before the watch, those are not delivered to it
+    since: u64,
+}
+
+impl OperationSubscription {
+    fn new(operations: BTreeSet<Operation>, watcher: Watcher, watch: u64, since: u64) -> Self {
+        OperationSubscription {
+            operations,
+            watcher,
+            watch,
+            since,
+        }
+    }
+
+    /// Returns true if the subscriber wants to be notified of the change, made after it
+    /// subscribed with a type of operation it watches
+    fn wants(&self, change: &Change) -> bool {
+        change.offset > self.since
+            && (self.operations.contains(&change.operation)
+                || self.operations.contains(&Operation::All))
+    }
+}
+
+/// The most recent changes of a shard, a bounded ring buffer. The offsets are given by its
+/// [Store](super::Store), for the whole database: the changes of a shard are not contiguous.
+/// It keeps at most `capacity` changes and `max_bytes` of them, those bytes are accounted
+/// in the [Memory] of the store.
+#[derive(Debug, Default)]
+pub(crate) struct ChangeLog {
+    /// The changes, with their size
+    changes: VecDeque<(Change, usize)>,
+    capacity: usize,
+    max_bytes: usize,
+    /// The size of the changes kept
+    bytes: usize,
+    /// The offset of the last change that is not kept anymore, 0 if there is none
+    dropped: u64,
+}
+
+impl ChangeLog {
+    pub(super) fn new(capacity: usize, max_bytes: usize) -> Self {
+        ChangeLog {
+            changes: VecDeque::with_capacity(capacity.min(DEFAULT_CHANGE_LOG)),
+            capacity,
+            max_bytes,
+            bytes: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Keeps the change, which has the next offset, instead of the oldest ones when full
+    /// (see [Change::logged]). A change bigger than the log is not kept, nor are the ones
+    /// before it.
+    pub(super) fn push(&mut self, change: &Change, memory: &Memory) {
+        let change = change.logged();
+        let size = memory::logged_size(&change);
+        if self.capacity == 0 || size > self.max_bytes {
+            self.forget(memory);
+            self.dropped = change.offset;
+            return;
+        }
+        while self.changes.len() == self.capacity || self.bytes + size > self.max_bytes {
+            match self.changes.pop_front() {
+                Some((oldest, oldest_size)) => {
+                    self.dropped = oldest.offset;
+                    self.bytes -= oldest_size;
+                    memory.resize(oldest_size, 0);
+                }
+                None => break,
+            }
+        }
+        self.bytes += size;
+        memory.resize(0, size);
+        self.changes.push_back((change, size));
+    }
+
+    /// Drops every change kept, returns true if there were some
+    pub(super) fn forget(&mut self, memory: &Memory) -> bool {
+        let forgotten = match self.changes.back() {
+            Some((last, _)) => {
+                self.dropped = last.offset;
+                true
+            }
+            None => false,
+        };
+        self.changes.clear();
+        memory.resize(self.bytes, 0);
+        self.bytes = 0;
+        forgotten
+    }
+
+    /// The changes after `offset`, None if some of them are not kept anymore
+    pub(super) fn after(&self, offset: u64) -> Option<impl Iterator<Item = &Change>> {
+        if offset < self.dropped {
+            return None;
+        }
+        Some(
+            self.changes
+                .iter()
+                .map(|(change, _)| change)
+                .skip_while(move |c| c.offset <= offset),
+        )
+    }
+}
+
+/// Whether the changes of a database are delivered to its [Subscriptions], known without
+/// locking them: they are only locked if something is watched or tracked, or if keyspace
+/// notifications are published.
+#[derive(Debug, Clone)]
+pub(super) struct Listeners {
+    subscribed: Arc<AtomicBool>,
+    events: KeyspaceNotifier,
+}
+
+impl Listeners {
+    /// Returns true if the changes have to be delivered
+    pub(super) fn any(&self) -> bool {
+        self.subscribed.load(Ordering::SeqCst) || self.events.enabled()
+    }
+
+    /// Deliveries start before a subscription is added, the changes made after it locked
+    /// its shards are never missed. The [Subscriptions] set it back once they know better.
+    pub(super) fn subscribing(&self) {
+        self.subscribed.store(true, Ordering::SeqCst);
+    }
+}
+
+type Watches = LinkedList<OperationSubscription>;
+
+/// The subscriptions of a [Database](super::Database), to keys and to patterns
+#[derive(Debug)]
+pub(crate) struct Subscriptions {
+    keys: HashMap<RedisString, Watches>,
+    /// The patterns by their literal prefix
+    patterns: HashMap<Vec<u8>, HashMap<Vec<u8>, Watches>>,
+    /// The number of prefixes of each length, a key is only looked up with those lengths
+    prefix_lengths: BTreeMap<usize, usize>,
+    /// Publishes the changes as keyspace notifications
+    events: KeyspaceNotifier,
+    /// The keys tracked by the connections caching them
+    tracking: TrackingTable,
+    listeners: Listeners,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions::new(KeyspaceNotifier::default())
+    }
+}
+
+impl Subscriptions {
+    /// No subscriptions
+    pub(super) fn new(events: KeyspaceNotifier) -> Self {
+        Subscriptions {
+            keys: HashMap::new(),
+            patterns: HashMap::new(),
+            prefix_lengths: BTreeMap::new(),
+            listeners: Listeners {
+                subscribed: Arc::new(AtomicBool::new(false)),
+                events: events.clone(),
+            },
+            events,
+            tracking: TrackingTable::default(),
+        }
+    }
+
+    /// Tells whether the changes have to be delivered, without locking the subscriptions
+    pub(super) fn listeners(&self) -> Listeners {
+        self.listeners.clone()
+    }
+
+    /// Subscribes the watcher after the change at offset `since`, the `replay` of the logged
+    /// changes after the offset of `FROM` is sent first. It is None if some of them are not
+    /// kept anymore, the watcher has to resync then.
+    /// The `snapshot` of the current values is sent before anything else, at the offset
+    /// `since`, whatever the operations watched.
+    /// Replies with the id of the watch, which its notifications carry.
+    pub(super) fn add(
+        &mut self,
+        watch: Watch,
+        watcher: Watcher,
+        snapshot: Vec<Change>,
+        replay: Option<Vec<Change>>,
+        since: u64,
+    ) -> Type {
+        let replay = match (watch.from, replay) {
+            (Some(from), None) => {
+                self.listened();
+                return Type::Error(format!("{} {}", RESYNC, from));
+            }
+            (_, replay) => replay.unwrap_or_default(),
+        };
+        let id = watcher.next_watch();
+        for mut change in snapshot {
+            change.offset = since;
+            watcher.notify(watch_result(&change, id));
+        }
+        let key = watch.key.as_bytes();
+        for change in replay.iter() {
+            let matches = match watch.pattern {
+                true => glob::matches(key, change.key.as_bytes()),
+                false => key == change.key.as_bytes(),
+            };
+            let wants = watch.operations.contains(&change.operation)
+                || watch.operations.contains(&Operation::All);
+            if matches && wants {
+                watcher.notify(watch_result(change, id));
+            }
+        }
+        let subscription = OperationSubscription::new(watch.operations, watcher, id, since);
+        if watch.pattern {
+            let pattern = watch.key.into_bytes();
+            let prefix = glob::literal_prefix(&pattern).to_vec();
+            if !self.patterns.contains_key(&prefix) {
+                *self.prefix_lengths.entry(prefix.len()).or_default() += 1;
+            }
+            let patterns = self.patterns.entry(prefix).or_default();
+            patterns.entry(pattern).or_default().push_back(subscription);
+        } else {
+            let watches = self.keys.entry(watch.key.into()).or_default();
+            watches.push_back(subscription);
+        }
+        self.listened();
+        Type::Integer(id as i64)
+    }
+
+    /// Removes the subscriptions of the watcher to a key (or pattern), or to all of them.
+    /// The connection is told about every watch removed. Returns how many were removed.
+    pub(super) fn remove(
+        &mut self,
+        watcher: &Watcher,
+        key: Option<String>,
+        pattern: bool,
+    ) -> usize {
+        let keep = |s: &OperationSubscription| {
+            let removed = s.watcher.id == watcher.id;
+            if removed {
+                watcher.unwatched(s.watch);
+            }
+            !removed
+        };
+        let removed = match (key, pattern) {
+            (Some(key), false) => retain_key(&mut self.keys, &key.into(), keep),
+            (None, false) => {
+                let removed = self
+                    .keys
+                    .values_mut()
+                    .map(|watches| retain(watches, keep))
+                    .sum();
+                self.keys.retain(|_, watches| !watches.is_empty());
+                removed
+            }
+            (Some(pattern), true) => {
+                let pattern = pattern.into_bytes();
+                let prefix = glob::literal_prefix(&pattern).to_vec();
+                self.retain_patterns(&prefix, |p| p == pattern.as_slice(), keep)
+            }
+            (None, true) => {
+                let prefixes: Vec<Vec<u8>> = self.patterns.keys().cloned().collect();
+                prefixes
+                    .iter()
+                    .map(|prefix| self.retain_patterns(prefix, |_| true, keep))
+                    .sum()
+            }
+        };
+        self.listened();
+        removed
+    }
+
+    /// Returns true if nothing is watched
+    #[cfg(test)]
+    pub(super) fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Remembers that the tracker read the key, see [TrackingTable]
+    pub(super) fn track(&mut self, key: RedisString, tracker: &Tracker) {
+        self.tracking.track(key, tracker);
+        self.listened();
+    }
+
+    /// Tells the tracker about the changes of the keys with the prefix (BCAST)
+    pub(super) fn broadcast(&mut self, prefix: Vec<u8>, tracker: &Tracker) {
+        self.tracking.broadcast(prefix, tracker);
+        self.listened();
+    }
+
+    /// Forgets everything tracked by the tracker
+    pub(super) fn untrack(&mut self, tracker: &Tracker) {
+        self.tracking.untrack(tracker.id());
+        self.listened();
+    }
+
+    /// Updates the [Listeners] after the subscriptions changed
+    fn listened(&self) {
+        let subscribed =
+            !self.keys.is_empty() || !self.patterns.is_empty() || !self.tracking.is_empty();
+        self.listeners
+            .subscribed
+            .store(subscribed, Ordering::SeqCst);
+    }
+
+    /// Returns true if the key, or a pattern matching it, is watched (or tracked)
+    fn is_watched(&self, key: &RedisString) -> bool {
+        let bytes = key.as_bytes();
+        self.keys.contains_key(key)
+            || self.tracking.tracks(key)
+            || self.prefixes(bytes).any(|prefix| {
+                self.patterns[prefix]
+                    .keys()
+                    .any(|pattern| glob::matches(pattern, bytes))
+            })
+    }
+
+    /// The watched (or tracked) keys of a keyspace
+    pub(super) fn watched_in(&self, keyspace: &HashMap<RedisString, Value>) -> Vec<RedisString> {
+        if self.patterns.is_empty() && !self.tracking.has_prefixes() {
+            self.keys
+                .keys()
+                .chain(self.tracking.keys())
+                .filter(|key| keyspace.contains_key(*key))
+                .cloned()
+                .collect()
+        } else {
+            keyspace
+                .keys()
+                .filter(|key| self.is_watched(key))
+                .cloned()
+                .collect()
+        }
+    }
+
+    /// Queues the notifications of a change for the subscriptions of the key, and of the
+    /// patterns matching it, that want it. The subscriptions of the connections that are gone
+    /// are removed. Every change made to a database goes through here while there are
+    /// [Listeners], and is published as keyspace notifications. The trackers of the key
+    /// are told too.
+    /// The changes of a key come in the order of their offsets, those of different shards
+    /// can come in any order: their offsets order them.
+    pub(super) fn notify(&mut self, change: Change) {
+        info!("Invoking subscribers for {:?}", change);
+        self.events.notify(&change);
+        self.tracking.invalidate(&change);
+        let mut notify = |s: &OperationSubscription| {
+            !s.wants(&change) || s.watcher.notify(watch_result(&change, s.watch))
+        };
+        retain_key(&mut self.keys, &change.key, &mut notify);
+        let bytes = change.key.as_bytes();
+        let prefixes: Vec<Vec<u8>> = self.prefixes(bytes).map(|p| p.to_vec()).collect();
+        for prefix in prefixes {
+            self.retain_patterns(&prefix, |p| glob::matches(p, bytes), &mut notify);
+        }
+        self.listened();
+    }
+
+    /// The indexed prefixes the key starts with
+    fn prefixes<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.prefix_lengths
+            .range(..=key.len())
+            .map(move |(&length, _)| &key[..length])
+            .filter(move |prefix| self.patterns.contains_key(*prefix))
+    }
+
+    /// Only keeps the subscriptions for which `keep` is true, of the selected patterns with
+    /// the prefix. Returns the number of subscriptions removed.
+    fn retain_patterns<S, F>(&mut self, prefix: &[u8], select: S, mut keep: F) -> usize
+    where
+        S: Fn(&[u8]) -> bool,
+        F: FnMut(&OperationSubscription) -> bool,
+    {
+        let patterns = match self.patterns.get_mut(prefix) {
+            Some(patterns) => patterns,
+            None => return 0,
+        };
+        let removed = patterns
+            .iter_mut()
+            .filter(|(pattern, _)| select(pattern))
+            .map(|(_, watches)| retain(watches, &mut keep))
+            .sum();
+        patterns.retain(|_, watches| !watches.is_empty());
+        if patterns.is_empty() {
+            self.patterns.remove(prefix);
+            if let Some(count) = self.prefix_lengths.get_mut(&prefix.len()) {
+                *count -= 1;
+                if *count == 0 {
+                    self.prefix_lengths.remove(&prefix.len());
+                }
+            }
+        }
+        removed
+    }
+}
+
+/// The notification of a change for a watch, the sequence number is given by the [Watcher]
+fn watch_result(change: &Change, watch: u64) -> WatchResult {
+    WatchResult {
+        watch,
+        key: change.key.clone().into(),
+        operation: change.operation.clone(),
+        before: change.before.clone().map(|v| v.into()),
+        after: change.after.clone().map(|v| v.into()).unwrap_or(Type::Null),
+        sequence: 0,
+        event: change.event.into(),
+        offset: change.offset,
+    }
+}
+
+/// Only keeps the subscriptions of the key for which `keep` is true, the key is forgotten
+/// once it has none. Returns the number of subscriptions removed.
+fn retain_key<K, F>(watched: &mut HashMap<K, Watches>, key: &K, keep: F) -> usize
+where
+    K: Hash + Eq,
+    F: FnMut(&OperationSubscription) -> bool,
+{
+    let watches = match watched.get_mut(key) {
+        Some(watches) => watches,
+        None => return 0,
+    };
+    let removed = retain(watches, keep);
+    if watches.is_empty() {
+        watched.remove(key);
+    }
+    removed
+}
+
+/// Only keeps the subscriptions for which `keep` is true, returns how many were removed
+fn retain<F>(watches: &mut Watches, keep: F) -> usize
+where
+    F: FnMut(&OperationSubscription) -> bool,
+{
+    let count = watches.len();
+    *watches = std::mem::take(watches).into_iter().filter(keep).collect();
+    count - watches.len()
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    use std::sync::Arc;
+
+    use super::{
+        memory, Change, ChangeLog, Memory, Notifications, SlowWatchers, Subscriptions, Watcher,
+        WatcherLimits, WatcherStats, CHANGE_LOG_BYTES,
+    };
+    use crate::{
+        commands::watch::{Watch, WatchResult},
+        database::{Operation, Value},
+        resp::Type,
+    };
+
+    fn pwatch(subscriptions: &mut Subscriptions, pattern: &str) -> (Watcher, Receiver<Type>) {
+        let (sender, receiver) = channel(10);
+        let watcher = Watcher::new(sender);
+        let watch = Watch {
+            key: pattern.into(),
+            operations: vec![Operation::All].into_iter().collect(),
+            pattern: true,
+            from: None,
+            snapshot: false,
+        };
+        subscriptions.add(watch, watcher.clone(), Vec::new(), None, 0);
+        (watcher, receiver)
+    }
+
+    fn set(subscriptions: &mut Subscriptions, key: &str) {
+        let value = Some(Value::String(key.into()));
+        let mut change = Change::new(key.into(), "set", None, value);
+        change.offset = 1;
+        subscriptions.notify(change);
+    }
+
+    async fn keys(receiver: &mut Receiver<Type>) -> Vec<String> {
+        let mut keys = Vec::new();
+        while let Some(t) = receiver.recv().await {
+            let result: Result<WatchResult, _> = t.into();
+            keys.push(result.unwrap().key);
+        }
+        keys
+    }
+
+    #[tokio::test]
+    async fn patterns_are_notified_of_the_matching_keys() {
+        let mut subscriptions = Subscriptions::default();
+        let (_, mut config) = pwatch(&mut subscriptions, "config:*");
+        let (_, mut databases) = pwatch(&mut subscriptions, "config:db:?");
+        let (_, mut everything) = pwatch(&mut subscriptions, "*");
+        for key in ["config:a", "config:db:1", "config:db:10", "other", "conf"].iter() {
+            set(&mut subscriptions, key);
+        }
+        // The receivers end with the subscriptions
+        drop(subscriptions);
+        assert_eq!(
+            keys(&mut config).await,
+            vec!["config:a", "config:db:1", "config:db:10"]
+        );
+        assert_eq!(keys(&mut databases).await, vec!["config:db:1"]);
+        assert_eq!(keys(&mut everything).await.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn patterns_are_removed_from_the_index() {
+        let mut subscriptions = Subscriptions::default();
+        let (first, _first) = pwatch(&mut subscriptions, "config:*");
+        let (second, _second) = pwatch(&mut subscriptions, "config:?");
+        // Its receiver is dropped right away
+        pwatch(&mut subscriptions, "user:*");
+        assert_eq!(subscriptions.prefix_lengths.get(&7), Some(&1));
+        assert_eq!(
+            subscriptions.remove(&first, Some("config:?".into()), true),
+            0
+        );
+        assert_eq!(
+            subscriptions.remove(&first, Some("config:*".into()), true),
+            1
+        );
+        // The prefix stays while one of its patterns is watched
+        assert!(subscriptions.patterns.contains_key(b"config:".as_ref()));
+        assert_eq!(subscriptions.remove(&second, None, true), 1);
+        assert!(!subscriptions.patterns.contains_key(b"config:".as_ref()));
+        assert_eq!(subscriptions.prefix_lengths.get(&7), None);
+        assert!(!subscriptions.is_empty());
+        // The watcher of "user:*" is gone, its subscription goes with the next notification
+        set(&mut subscriptions, "user:1");
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        set(&mut subscriptions, "user:2");
+        assert!(subscriptions.is_empty());
+    }
+
+    #[test]
+    fn the_change_log_keeps_the_last_changes() {
+        let memory = Memory::default();
+        let mut log = ChangeLog::new(3, CHANGE_LOG_BYTES);
+        let offsets = |log: &ChangeLog, from| {
+            log.after(from)
+                .map(|changes| changes.map(|c| c.offset).collect::<Vec<_>>())
+        };
+        let change = |key: &str, offset| {
+            let mut change = Change::new(key.into(), "set", None, None);
+            change.offset = offset;
+            change
+        };
+        assert_eq!(offsets(&log, 0), Some(vec![]));
+        for (offset, key) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            log.push(&change(key, offset as u64 + 1), &memory);
+        }
+        assert_eq!(offsets(&log, 1), None);
+        assert_eq!(offsets(&log, 2), Some(vec![3, 4, 5]));
+        assert_eq!(offsets(&log, 4), Some(vec![5]));
+        assert_eq!(offsets(&log, 5), Some(vec![]));
+        // An offset that was not given yet has nothing to replay
+        assert_eq!(offsets(&log, 9), Some(vec![]));
+        // The other shards take the offsets in between
+        log.push(&change("f", 8), &memory);
+        assert_eq!(offsets(&log, 6), Some(vec![8]));
+        assert_eq!(offsets(&log, 3), Some(vec![4, 5, 8]));
+        assert_eq!(offsets(&log, 2), None);
+        // Nothing is kept without a capacity
+        let mut log = ChangeLog::new(0, CHANGE_LOG_BYTES);
+        log.push(&change("a", 1), &memory);
+        assert_eq!(offsets(&log, 0), None);
+        assert_eq!(offsets(&log, 1), Some(vec![]));
+    }
+
+    #[test]
+    fn the_change_log_keeps_its_bytes_in_the_memory_used() {
+        let memory = Memory::default();
+        let value = |size| Some(Value::String(vec![b'x'; size].into()));
+        let change = |offset, size| {
+            let mut change = Change::new("a".into(), "set", value(size), value(size));
+            change.offset = offset;
+            change
+        };
+        let size = memory::logged_size(&change(1, 100));
+        let mut log = ChangeLog::new(10, size * 2);
+        for offset in 1..=3 {
+            log.push(&change(offset, 100), &memory);
+        }
+        // The oldest change is dropped for the third one
+        assert_eq!(memory.used(), size * 2);
+        assert_eq!(log.after(0).map(|changes| changes.count()), None);
+        assert_eq!(log.after(1).map(|changes| changes.count()), Some(2));
+        // A change too big is not kept, nor the ones before it
+        log.push(&change(4, 1000), &memory);
+        assert_eq!(memory.used(), 0);
+        assert_eq!(log.after(3).map(|changes| changes.count()), None);
+        assert_eq!(log.after(4).map(|changes| changes.count()), Some(0));
+        log.push(&change(5, 100), &memory);
+        assert!(log.forget(&memory));
+        assert_eq!(memory.used(), 0);
+        assert_eq!(log.after(4).map(|changes| changes.count()), None);
+    }
+
+    fn slow_watcher(
+        hard: usize,
+        soft: usize,
+        policy: SlowWatchers,
+    ) -> (Watcher, Notifications, Arc<WatcherStats>) {
+        let limits = WatcherLimits {
+            hard,
+            soft,
+            soft_seconds: 0,
+            policy,
+        };
+        let stats = Arc::new(WatcherStats::default());
+        let (watcher, notifications) =
+            Watcher::with_limits(limits, stats.clone(), Default::default());
+        (watcher, notifications, stats)
+    }
+
+    fn notify(watcher: &Watcher, key: &str) -> bool {
+        watcher.notify(WatchResult {
+            watch: 1,
+            key: key.into(),
+            operation: Operation::Addition,
+            before: None,
+            after: Type::Null,
+            sequence: 0,
+            event: "set".into(),
+            offset: 0,
+        })
+    }
+
+    async fn next(notifications: &mut Notifications) -> Option<(String, u64)> {
+        let t = notifications.next().await?;
+        let result: Result<WatchResult, _> = t.into();
+        let result = result.unwrap();
+        Some((result.key, result.sequence))
+    }
+
+    #[tokio::test]
+    async fn slow_watchers_are_disconnected() {
+        // At the hard limit, or over the soft limit for too long (no time at all here)
+        for (hard, soft) in [(2, 0), (0, 2)].iter().cloned() {
+            let (watcher, mut notifications, stats) =
+                slow_watcher(hard, soft, SlowWatchers::Disconnect);
+            assert!(notify(&watcher, "a"));
+            assert!(notify(&watcher, "b"));
+            assert!(stats.info().contains("watch_pending_notifications:2\r\n"));
+            assert!(!notify(&watcher, "c"));
+            // The queued notifications are dropped, the connection is closed
+            assert_eq!(next(&mut notifications).await, None);
+            assert!(!notify(&watcher, "d"));
+            let info = stats.info();
+            assert!(info.contains("watch_pending_notifications:0\r\n"));
+            assert!(info.contains("watch_disconnected_watchers:1\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_watchers_are_coalesced() {
+        let (watcher, mut notifications, stats) = slow_watcher(3, 2, SlowWatchers::Coalesce);
+        for key in ["a", "b", "a", "c"].iter() {
+            assert!(notify(&watcher, key));
+        }
+        // The first notification of a was replaced by the second one
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(next(&mut notifications).await.unwrap());
+        }
+        let expected = [("b", 2), ("a", 3), ("c", 4)];
+        let expected: Vec<_> = expected.iter().map(|(k, s)| (k.to_string(), *s)).collect();
+        assert_eq!(received, expected);
+        assert!(stats.info().contains("watch_coalesced_notifications:1\r\n"));
+        // At the hard limit without anything to replace, the watcher is disconnected
+        for key in ["x", "y", "z"].iter() {
+            assert!(notify(&watcher, key));
+        }
+        assert!(!notify(&watcher, "w"));
+        assert_eq!(next(&mut notifications).await, None);
+    }
+}
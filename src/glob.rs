@@ -0,0 +1,167 @@
+//! Redis compatible glob-style pattern matching, used for `KEYS`, `SCAN MATCH` and the like.
+//!
+//! Supported patterns:
+//! * `?` matches exactly one byte
+//! * `*` matches any number of bytes (including none)
+//! * `[abc]`, `[a-z]` match one byte from the set, `[^x]` matches one byte not in the set
+//! * `\x` matches `x` literally (also inside a set)
+
+/// Returns true if the whole `input` matches the `pattern`
+pub(crate) fn matches(pattern: &[u8], input: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Where to resume when the bytes after the last `*` stop matching
+    let mut backtrack: Option<(usize, usize)> = None;
+    while i < input.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                while p < pattern.len() && pattern[p] == b'*' {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                backtrack = Some((p, i));
+                continue;
+            }
+            if let Some(next) = match_one(pattern, p, input[i]) {
+                p = next;
+                i += 1;
+                continue;
+            }
+        }
+        match backtrack.as_mut() {
+            // Let the `*` swallow one more byte and try again
+            Some((after_star, swallowed)) => {
+                *swallowed += 1;
+                p = *after_star;
+                i = *swallowed;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// The bytes every input matching the pattern starts with, up to the first special byte
+pub(crate) fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Matches a single byte against the pattern element at `p`.
+/// Returns the position of the next pattern element on a match.
+fn match_one(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then(|| p + 2),
+        b'[' => match_set(pattern, p + 1, byte),
+        literal => (literal == byte).then(|| p + 1),
+    }
+}
+
+/// Matches a byte against a `[...]` set starting at `p` (just after the `[`).
+/// An unterminated set extends to the end of the pattern.
+fn match_set(pattern: &[u8], mut p: usize, byte: u8) -> Option<usize> {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() {
+        match pattern[p] {
+            b']' => {
+                p += 1;
+                break;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                matched |= pattern[p + 1] == byte;
+                p += 2;
+            }
+            start if p + 2 < pattern.len() && pattern[p + 1] == b'-' => {
+                let end = pattern[p + 2];
+                let (low, high) = if start <= end {
+                    (start, end)
+                } else {
+                    (end, start)
+                };
+                matched |= low <= byte && byte <= high;
+                p += 3;
+            }
+            literal => {
+                matched |= literal == byte;
+                p += 1;
+            }
+        }
+    }
+    (matched != negate).then_some(p)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{literal_prefix, matches};
+
+    fn m(pattern: &str, input: &str) -> bool {
+        matches(pattern.as_bytes(), input.as_bytes())
+    }
+
+    #[test]
+    fn wildcards_work() {
+        assert!(m("*", ""));
+        assert!(m("*", "anything"));
+        assert!(m("h?llo", "hello"));
+        assert!(m("h?llo", "hallo"));
+        assert!(!m("h?llo", "hllo"));
+        assert!(m("h*llo", "hllo"));
+        assert!(m("h*llo", "heeeello"));
+        assert!(m("*o*o*", "foo:boo"));
+        assert!(!m("*o*o*", "fox"));
+        assert!(m("user:*:name", "user:42:name"));
+        assert!(!m("user:*:name", "user:42:email"));
+        assert!(m("a**b", "axxb"));
+        assert!(!m("abc", "abcd"));
+        assert!(!m("", "a"));
+        assert!(m("", ""));
+    }
+
+    #[test]
+    fn sets_work() {
+        assert!(m("h[ae]llo", "hello"));
+        assert!(m("h[ae]llo", "hallo"));
+        assert!(!m("h[ae]llo", "hillo"));
+        assert!(m("h[^e]llo", "hallo"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("h[a-b]llo", "hbllo"));
+        assert!(!m("h[a-b]llo", "hcllo"));
+        // Reversed ranges are swapped
+        assert!(m("h[b-a]llo", "hallo"));
+        assert!(m("key[0-9][0-9]", "key42"));
+        assert!(!m("key[0-9][0-9]", "key4x"));
+        // An unterminated set runs up to the end of the pattern
+        assert!(m("a[bc", "ac"));
+    }
+
+    #[test]
+    fn escapes_work() {
+        assert!(m("h\\*llo", "h*llo"));
+        assert!(!m("h\\*llo", "hello"));
+        assert!(m("what\\?", "what?"));
+        assert!(!m("what\\?", "whats"));
+        assert!(m("[\\]]", "]"));
+        assert!(m("[\\-a]", "-"));
+        // A trailing backslash matches itself
+        assert!(m("a\\", "a\\"));
+    }
+
+    #[test]
+    fn literal_prefixes_work() {
+        let prefix = |pattern: &str| literal_prefix(pattern.as_bytes()).to_vec();
+        assert_eq!(prefix("config:*"), b"config:");
+        assert_eq!(prefix("config"), b"config");
+        assert_eq!(prefix("user:?:name"), b"user:");
+        assert_eq!(prefix("[ab]*"), b"");
+        assert_eq!(prefix("a\\*b"), b"a");
+    }
+}
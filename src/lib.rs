@@ -6,7 +6,9 @@ pub mod client;
 pub mod commands;
 pub mod connection;
 pub(crate) mod database;
+pub(crate) mod glob;
 pub mod parse;
+pub(crate) mod pubsub;
 pub mod resp;
 pub mod server;
 
@@ -5,7 +5,6 @@ use atoi::atoi;
 use bytes::Buf;
 use std::{collections::LinkedList, io::Cursor};
 use std::{
-    collections::VecDeque,
     convert::TryInto,
     error::Error,
     fmt::Display,
@@ -66,17 +65,12 @@ impl From<FromUtf8Error> for ParseError {
 
 /// A utility struct that is used to create [Type] instances from a byte array ([u8])
 #[derive(Debug, Default)]
-pub struct Parse {
-    /// An iterator for type
-    parts: VecDeque<Type>,
-}
+pub struct Parse {}
 
 impl Parse {
     /// Creates a new instance of [Parse]
     pub fn new() -> Self {
-        Self {
-            parts: VecDeque::new(),
-        }
+        Self {}
     }
 
     /// Everytime this is called either a [Type] is returned or an error is returned.
@@ -118,7 +112,8 @@ impl Parse {
                 let line = get_bytes(bytes, number_of_bytes.try_into().unwrap())?;
                 parse_bulk_string(line.to_vec())
             }
-            b'*' => {
+            // A push (RESP3) is encoded like an array
+            b'*' | b'>' => {
                 let number = get_line(bytes)?;
                 let number_of_elements: i64 = atoi::atoi(number).ok_or_else(|| {
                     ParseError::InvalidEncoding(format!(
@@ -141,7 +136,10 @@ impl Parse {
                         }
                     };
                 }
-                parse_array(types_array)
+                match marker {
+                    b'>' => Ok(Type::Push(types_array)),
+                    _ => parse_array(types_array),
+                }
             }
             _ => Err(ParseError::InvalidMarker(marker)),
         }
@@ -152,7 +150,8 @@ fn get_bytes<'a>(
     bytes: &'a mut Cursor<&[u8]>,
     number_of_bytes: usize,
 ) -> Result<&'a [u8], ParseError> {
-    if bytes.remaining() < number_of_bytes {
+    // The payload is followed by CRLF
+    if bytes.remaining() < number_of_bytes + 2 {
         Err(ParseError::Incomplete)
     } else {
         // this is fine
@@ -160,13 +159,11 @@ fn get_bytes<'a>(
         let &r = bytes.get_ref();
         // get the number of bytes
         let result = &r[position..(position + number_of_bytes)];
-        if bytes.remaining() >= 2 {
-            // Seek till the end of  CRLF
-            let seek_to = (number_of_bytes + 2).try_into().unwrap();
-            bytes
-                .seek(SeekFrom::Current(seek_to))
-                .expect("Should not seek beyond limits");
-        }
+        // Seek till the end of  CRLF
+        let seek_to = (number_of_bytes + 2).try_into().unwrap();
+        bytes
+            .seek(SeekFrom::Current(seek_to))
+            .expect("Should not seek beyond limits");
         Ok(result)
     }
 }
@@ -391,6 +388,22 @@ mod test {
             let t = parse.parse_next(&mut test);
             assert_eq!(t, Err(ParseError::Incomplete));
         }
+
+        #[test]
+        fn parse_next_push_works() {
+            let mut test = Cursor::new(&b">2\r\n+watch\r\n:1\r\n"[..]);
+            let parse = Parse::new();
+            let t = parse.parse_next(&mut test);
+            let types: LinkedList<Type> =
+                vec![Type::SimpleString("watch".into()), Type::Integer(1)]
+                    .into_iter()
+                    .collect();
+            assert_eq!(t, Ok(Type::Push(types.clone())));
+            assert_eq!(
+                Type::Push(types).into_bytes(),
+                b">2\r\n+watch\r\n:1\r\n".to_vec()
+            );
+        }
         #[test]
         fn parse_invalid_encoding() {
             // Success
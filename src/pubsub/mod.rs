@@ -0,0 +1,558 @@
+//! Pub/sub, shared by all the connections of a server (channels are not tied to a database).
+//!
+//! There are two independent subsystems:
+//! * the channels and the patterns of PUBLISH and (P)SUBSCRIBE, in a single registry
+//!   (a message is matched against every pattern anyway)
+//! * the shard channels of SPUBLISH and SSUBSCRIBE, spread over [sharded::SHARDS] registries
+//!
+//! Every connection has a [Subscriber], with a bounded queue of [SUBSCRIBER_QUEUE] messages
+//! that the connection drains into its socket. Publishing never waits for a subscriber:
+//! when the queue of a subscriber is full, the subscriber is too slow to keep up and it is
+//! disconnected. Its queue is closed, which closes the connection (like the pubsub client
+//! output buffer limit of Redis), and its subscriptions are removed with it. This way a slow
+//! subscriber can neither slow down the publishers nor silently miss messages.
+
+mod sharded;
+
+use std::{
+    collections::{BTreeSet, HashMap, LinkedList},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use log::info;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use crate::{
+    commands::{
+        ping::Ping,
+        pubsub::{Introspection, Message, Publish, Subscribe, SubscriptionKind, Unsubscribe},
+    },
+    glob,
+    resp::Type,
+};
+
+use self::sharded::{ShardChannels, SHARDS};
+
+/// The number of messages queued for a subscriber before it is disconnected
+pub(crate) const SUBSCRIBER_QUEUE: usize = 1024;
+
+/// The reply to the commands that are not allowed once a connection subscribed
+pub(crate) const ONLY_PUBSUB: &str =
+    "ERR only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING are allowed in this context";
+
+type SubscriberId = u64;
+
+static NEXT_SUBSCRIBER: AtomicU64 = AtomicU64::new(0);
+
+/// The subscribers of every channel (or pattern)
+type Subscribers = HashMap<String, HashMap<SubscriberId, Queue>>;
+
+/// The queue of a subscriber, shared by all the registries it is subscribed in
+#[derive(Clone)]
+struct Queue {
+    id: SubscriberId,
+    /// None once the subscriber has been disconnected
+    sender: Arc<Mutex<Option<Sender<Type>>>>,
+}
+
+impl Queue {
+    /// Queues a message, returns true if the subscriber is going to receive it.
+    /// The queue is closed if it is full, see the slow subscriber policy above.
+    fn push(&self, message: Type) -> bool {
+        let mut sender = self.sender.lock().expect("Lock failed");
+        let result = match sender.as_ref() {
+            Some(sender) => sender.try_send(message),
+            None => return false,
+        };
+        match result {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                info!("Subscriber {} is too slow, disconnecting it", self.id);
+                *sender = None;
+                false
+            }
+            // The connection is gone
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// The channels and the patterns with their subscribers, and the shard channels
+#[derive(Clone)]
+pub(crate) struct PubSub {
+    registry: Arc<Mutex<Registry>>,
+    shards: ShardChannels,
+}
+
+#[derive(Default)]
+struct Registry {
+    channels: Subscribers,
+    patterns: Subscribers,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub {
+            registry: Default::default(),
+            shards: ShardChannels::new(SHARDS),
+        }
+    }
+}
+
+impl PubSub {
+    /// Creates the [Subscriber] of a connection
+    pub(crate) fn subscriber(&self) -> Subscriber {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_QUEUE);
+        Subscriber {
+            queue: Queue {
+                id: NEXT_SUBSCRIBER.fetch_add(1, Ordering::Relaxed),
+                sender: Arc::new(Mutex::new(Some(sender))),
+            },
+            pubsub: self.clone(),
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            shard_channels: BTreeSet::new(),
+            messages: receiver,
+        }
+    }
+
+    /// PUBLISH and SPUBLISH, replies with the number of subscribers that received the message
+    pub(crate) fn publish(&self, p: Publish) -> Type {
+        let Publish {
+            channel,
+            message,
+            sharded,
+        } = p;
+        let received = if sharded {
+            self.shards.publish(channel, message)
+        } else {
+            self.registry
+                .lock()
+                .expect("Lock failed")
+                .publish(channel, message)
+        };
+        Type::Integer(received as i64)
+    }
+
+    /// PUBSUB, the channels and patterns with subscribers
+    pub(crate) fn introspect(&self, i: Introspection) -> Type {
+        match i {
+            Introspection::Channels { pattern, sharded } => {
+                let channels = if sharded {
+                    self.shards.channels(pattern.as_deref())
+                } else {
+                    let registry = self.registry.lock().expect("Lock failed");
+                    matching(&registry.channels, pattern.as_deref())
+                };
+                Type::Array(
+                    channels
+                        .into_iter()
+                        .map(|c| Type::BulkString(c.into_bytes()))
+                        .collect(),
+                )
+            }
+            Introspection::NumSub { channels, sharded } => {
+                let mut reply = LinkedList::new();
+                for channel in channels {
+                    let count = if sharded {
+                        self.shards.count(&channel)
+                    } else {
+                        let registry = self.registry.lock().expect("Lock failed");
+                        registry.channels.get(&channel).map_or(0, HashMap::len)
+                    };
+                    reply.push_back(Type::BulkString(channel.into_bytes()));
+                    reply.push_back(Type::Integer(count as i64));
+                }
+                Type::Array(reply)
+            }
+            Introspection::NumPat => {
+                let registry = self.registry.lock().expect("Lock failed");
+                Type::Integer(registry.patterns.len() as i64)
+            }
+        }
+    }
+}
+
+impl Registry {
+    /// Publishes a message to the subscribers of the channel and of the matching patterns
+    fn publish(&self, channel: String, payload: Vec<u8>) -> usize {
+        let mut received = 0;
+        if let Some(subscribers) = self.channels.get(&channel) {
+            let message: Type = Message {
+                pattern: None,
+                channel: channel.clone(),
+                payload: payload.clone(),
+                sharded: false,
+            }
+            .into();
+            received += deliver(subscribers, &message);
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if glob::matches(pattern.as_bytes(), channel.as_bytes()) {
+                let message: Type = Message {
+                    pattern: Some(pattern.clone()),
+                    channel: channel.clone(),
+                    payload: payload.clone(),
+                    sharded: false,
+                }
+                .into();
+                received += deliver(subscribers, &message);
+            }
+        }
+        received
+    }
+
+    fn subscribers(&mut self, kind: SubscriptionKind) -> &mut Subscribers {
+        match kind {
+            SubscriptionKind::Pattern => &mut self.patterns,
+            _ => &mut self.channels,
+        }
+    }
+}
+
+/// Queues a message for the subscribers, returns the number that are going to receive it
+fn deliver(subscribers: &HashMap<SubscriberId, Queue>, message: &Type) -> usize {
+    subscribers
+        .values()
+        .filter(|queue| queue.push(message.clone()))
+        .count()
+}
+
+/// The channels matching a glob-style pattern (all of them without a pattern), sorted
+fn matching(subscribers: &Subscribers, pattern: Option<&str>) -> Vec<String> {
+    let mut channels: Vec<String> = subscribers
+        .keys()
+        .filter(|c| pattern.is_none_or(|p| glob::matches(p.as_bytes(), c.as_bytes())))
+        .cloned()
+        .collect();
+    channels.sort();
+    channels
+}
+
+/// Adds a subscriber to a channel
+fn add(subscribers: &mut Subscribers, channel: String, queue: Queue) {
+    subscribers
+        .entry(channel)
+        .or_default()
+        .insert(queue.id, queue);
+}
+
+/// Removes a subscriber from a channel, and the channel once it has no subscribers
+fn remove(subscribers: &mut Subscribers, channel: &str, id: SubscriberId) {
+    if let Some(queues) = subscribers.get_mut(channel) {
+        queues.remove(&id);
+        if queues.is_empty() {
+            subscribers.remove(channel);
+        }
+    }
+}
+
+/// The subscriptions of a connection, and the queue of the messages it received
+pub(crate) struct Subscriber {
+    queue: Queue,
+    pubsub: PubSub,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    shard_channels: BTreeSet<String>,
+    messages: Receiver<Type>,
+}
+
+impl Subscriber {
+    /// Returns true if the connection is subscribed to any channel, pattern or shard channel
+    pub(crate) fn is_subscribed(&self) -> bool {
+        !(self.channels.is_empty() && self.patterns.is_empty() && self.shard_channels.is_empty())
+    }
+
+    /// The number of subscriptions, as replied to a (un)subscription of this kind
+    fn count(&self, kind: SubscriptionKind) -> usize {
+        match kind {
+            SubscriptionKind::ShardChannel => self.shard_channels.len(),
+            _ => self.channels.len() + self.patterns.len(),
+        }
+    }
+
+    fn subscribed(&mut self, kind: SubscriptionKind) -> &mut BTreeSet<String> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+
+    /// Waits for the next message, None once the subscriber has been disconnected
+    pub(crate) async fn next_message(&mut self) -> Option<Type> {
+        self.messages.recv().await
+    }
+
+    /// (P|S)SUBSCRIBE, replies with a confirmation for every channel
+    pub(crate) fn subscribe(&mut self, s: Subscribe) -> Vec<Type> {
+        let Subscribe { channels, kind } = s;
+        let name = format!("{}subscribe", kind.prefix().to_lowercase());
+        channels
+            .into_iter()
+            .map(|channel| {
+                if self.subscribed(kind).insert(channel.clone()) {
+                    let queue = self.queue.clone();
+                    match kind {
+                        SubscriptionKind::ShardChannel => {
+                            self.pubsub.shards.subscribe(channel.clone(), queue)
+                        }
+                        _ => {
+                            let mut registry = self.pubsub.registry.lock().expect("Lock failed");
+                            add(registry.subscribers(kind), channel.clone(), queue)
+                        }
+                    }
+                }
+                confirmation(&name, Some(channel), self.count(kind))
+            })
+            .collect()
+    }
+
+    /// (P|S)UNSUBSCRIBE, replies with a confirmation for every channel
+    pub(crate) fn unsubscribe(&mut self, u: Unsubscribe) -> Vec<Type> {
+        let Unsubscribe { channels, kind } = u;
+        let name = format!("{}unsubscribe", kind.prefix().to_lowercase());
+        let channels: Vec<String> = if channels.is_empty() {
+            self.subscribed(kind).iter().cloned().collect()
+        } else {
+            channels.into_iter().collect()
+        };
+        if channels.is_empty() {
+            return vec![confirmation(&name, None, self.count(kind))];
+        }
+        channels
+            .into_iter()
+            .map(|channel| {
+                if self.subscribed(kind).remove(&channel) {
+                    self.remove(kind, &channel);
+                }
+                confirmation(&name, Some(channel), self.count(kind))
+            })
+            .collect()
+    }
+
+    /// Removes the subscription from its registry
+    fn remove(&self, kind: SubscriptionKind, channel: &str) {
+        match kind {
+            SubscriptionKind::ShardChannel => {
+                self.pubsub.shards.unsubscribe(channel, self.queue.id)
+            }
+            _ => {
+                let mut registry = self.pubsub.registry.lock().expect("Lock failed");
+                remove(registry.subscribers(kind), channel, self.queue.id)
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            self.remove(SubscriptionKind::Channel, channel);
+        }
+        for pattern in &self.patterns {
+            self.remove(SubscriptionKind::Pattern, pattern);
+        }
+        for channel in &self.shard_channels {
+            self.remove(SubscriptionKind::ShardChannel, channel);
+        }
+    }
+}
+
+/// The reply to a PING once subscribed
+pub(crate) fn pong(p: Ping) -> Type {
+    let mut reply = LinkedList::new();
+    reply.push_back(Type::BulkString(b"pong".to_vec()));
+    reply.push_back(Type::BulkString(p.message.unwrap_or_default().into_bytes()));
+    Type::Array(reply)
+}
+
+/// The confirmation of a (un)subscription, with the number of subscriptions left
+fn confirmation(name: &str, channel: Option<String>, count: usize) -> Type {
+    let mut reply = LinkedList::new();
+    reply.push_back(Type::BulkString(name.as_bytes().to_vec()));
+    reply.push_back(channel.map_or(Type::Null, |c| Type::BulkString(c.into_bytes())));
+    reply.push_back(Type::Integer(count as i64));
+    Type::Array(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PubSub, Subscriber, SUBSCRIBER_QUEUE};
+    use crate::{
+        commands::pubsub::{
+            Introspection, Message, Publish, Subscribe, SubscriptionKind, Unsubscribe,
+        },
+        resp::{Type, TypeConsumer},
+    };
+
+    pub(super) fn subscribe(
+        subscriber: &mut Subscriber,
+        channels: &[&str],
+        kind: SubscriptionKind,
+    ) -> Vec<Type> {
+        subscriber.subscribe(Subscribe {
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            kind,
+        })
+    }
+
+    pub(super) fn publish(pubsub: &PubSub, channel: &str, sharded: bool) -> Type {
+        pubsub.publish(Publish {
+            channel: channel.into(),
+            message: b"message".to_vec(),
+            sharded,
+        })
+    }
+
+    pub(super) async fn next(subscriber: &mut Subscriber) -> Message {
+        let t = subscriber.next_message().await.unwrap();
+        Message::from(&mut TypeConsumer::new(t)).unwrap().unwrap()
+    }
+
+    fn counts(replies: Vec<Type>) -> Vec<i64> {
+        replies
+            .into_iter()
+            .map(|reply| match reply {
+                Type::Array(mut values) => match values.pop_back() {
+                    Some(Type::Integer(count)) => count,
+                    other => panic!("Unexpected {:?}", other),
+                },
+                other => panic!("Unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn publish_reaches_channels_and_patterns() {
+        let pubsub = PubSub::default();
+        let mut first = pubsub.subscriber();
+        let mut second = pubsub.subscriber();
+        assert_eq!(
+            counts(subscribe(
+                &mut first,
+                &["news", "sport"],
+                SubscriptionKind::Channel
+            )),
+            vec![1, 2]
+        );
+        assert_eq!(
+            counts(subscribe(
+                &mut second,
+                &["news.*"],
+                SubscriptionKind::Pattern
+            )),
+            vec![1]
+        );
+        assert_eq!(publish(&pubsub, "news", false), Type::Integer(1));
+        assert_eq!(publish(&pubsub, "news.today", false), Type::Integer(1));
+        assert_eq!(publish(&pubsub, "weather", false), Type::Integer(0));
+        // Plain and shard channels are separate
+        assert_eq!(publish(&pubsub, "news", true), Type::Integer(0));
+        assert_eq!(
+            next(&mut first).await,
+            Message {
+                pattern: None,
+                channel: "news".into(),
+                payload: b"message".to_vec(),
+                sharded: false
+            }
+        );
+        assert_eq!(
+            next(&mut second).await,
+            Message {
+                pattern: Some("news.*".into()),
+                channel: "news.today".into(),
+                payload: b"message".to_vec(),
+                sharded: false
+            }
+        );
+        // Unsubscribing from everything
+        assert_eq!(
+            counts(first.unsubscribe(Unsubscribe {
+                channels: Default::default(),
+                kind: SubscriptionKind::Channel
+            })),
+            vec![1, 0]
+        );
+        assert!(!first.is_subscribed());
+        assert_eq!(publish(&pubsub, "news", false), Type::Integer(0));
+        drop(second);
+        assert_eq!(publish(&pubsub, "news.today", false), Type::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_are_disconnected() {
+        let pubsub = PubSub::default();
+        let mut slow = pubsub.subscriber();
+        let mut fast = pubsub.subscriber();
+        subscribe(&mut slow, &["news"], SubscriptionKind::Channel);
+        subscribe(&mut slow, &["news"], SubscriptionKind::ShardChannel);
+        subscribe(&mut fast, &["news"], SubscriptionKind::Channel);
+        for i in 0..SUBSCRIBER_QUEUE {
+            assert_eq!(publish(&pubsub, "news", false), Type::Integer(2));
+            assert_eq!(next(&mut fast).await.payload, b"message".to_vec(), "{}", i);
+        }
+        // The queue of the slow subscriber is full, it is disconnected from every channel
+        assert_eq!(publish(&pubsub, "news", false), Type::Integer(1));
+        assert_eq!(publish(&pubsub, "news", true), Type::Integer(0));
+        // It gets the messages that were queued, then it is disconnected
+        for _ in 0..SUBSCRIBER_QUEUE {
+            assert!(slow.next_message().await.is_some());
+        }
+        assert_eq!(slow.next_message().await, None);
+    }
+
+    #[test]
+    fn introspection_works() {
+        let pubsub = PubSub::default();
+        let mut first = pubsub.subscriber();
+        let mut second = pubsub.subscriber();
+        subscribe(&mut first, &["news", "sport"], SubscriptionKind::Channel);
+        subscribe(&mut second, &["news"], SubscriptionKind::Channel);
+        subscribe(&mut second, &["news.*", "*"], SubscriptionKind::Pattern);
+        subscribe(&mut second, &["orders"], SubscriptionKind::ShardChannel);
+        let strings = |values: &[&str]| {
+            Type::Array(
+                values
+                    .iter()
+                    .map(|v| Type::BulkString(v.as_bytes().to_vec()))
+                    .collect(),
+            )
+        };
+        let channels = |pattern: Option<&str>, sharded| {
+            pubsub.introspect(Introspection::Channels {
+                pattern: pattern.map(|p| p.to_string()),
+                sharded,
+            })
+        };
+        assert_eq!(channels(None, false), strings(&["news", "sport"]));
+        assert_eq!(channels(Some("n*"), false), strings(&["news"]));
+        assert_eq!(channels(None, true), strings(&["orders"]));
+        assert_eq!(
+            pubsub.introspect(Introspection::NumSub {
+                channels: vec!["news".to_string(), "nothing".to_string()]
+                    .into_iter()
+                    .collect(),
+                sharded: false
+            }),
+            Type::Array(
+                vec![
+                    Type::BulkString(b"news".to_vec()),
+                    Type::Integer(2),
+                    Type::BulkString(b"nothing".to_vec()),
+                    Type::Integer(0)
+                ]
+                .into_iter()
+                .collect()
+            )
+        );
+        assert_eq!(pubsub.introspect(Introspection::NumPat), Type::Integer(2));
+        drop(second);
+        assert_eq!(pubsub.introspect(Introspection::NumPat), Type::Integer(0));
+        assert_eq!(channels(None, true), strings(&[]));
+    }
+}
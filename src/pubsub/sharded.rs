@@ -0,0 +1,145 @@
+//! The shard channels of SPUBLISH and SSUBSCRIBE.
+//!
+//! The channels are hashed onto independent shards, each with its own lock, so a busy
+//! channel only holds up the channels of its own shard. Shard channels have no patterns,
+//! a message only goes to the subscribers of its channel.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{commands::pubsub::Message, resp::Type};
+
+use super::{add, deliver, matching, remove, Queue, SubscriberId, Subscribers};
+
+/// The number of shards of a server
+pub(super) const SHARDS: usize = 16;
+
+/// The shard channels with their subscribers
+#[derive(Clone)]
+pub(super) struct ShardChannels {
+    shards: Arc<Vec<Mutex<Subscribers>>>,
+}
+
+impl ShardChannels {
+    pub(super) fn new(count: usize) -> Self {
+        ShardChannels {
+            shards: Arc::new((0..count.max(1)).map(|_| Default::default()).collect()),
+        }
+    }
+
+    /// The index of the shard of a channel
+    fn index(&self, channel: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        channel.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, channel: &str) -> MutexGuard<'_, Subscribers> {
+        self.shards[self.index(channel)]
+            .lock()
+            .expect("Lock failed")
+    }
+
+    pub(super) fn subscribe(&self, channel: String, queue: Queue) {
+        add(&mut self.shard(&channel), channel, queue)
+    }
+
+    pub(super) fn unsubscribe(&self, channel: &str, id: SubscriberId) {
+        remove(&mut self.shard(channel), channel, id)
+    }
+
+    /// Publishes a message to the subscribers of the channel, only its shard is locked.
+    /// Returns the number of subscribers that received it.
+    pub(super) fn publish(&self, channel: String, payload: Vec<u8>) -> usize {
+        let shard = self.shard(&channel);
+        match shard.get(&channel) {
+            Some(subscribers) => {
+                let message: Type = Message {
+                    pattern: None,
+                    channel,
+                    payload,
+                    sharded: true,
+                }
+                .into();
+                deliver(subscribers, &message)
+            }
+            None => 0,
+        }
+    }
+
+    /// The number of subscribers of a channel
+    pub(super) fn count(&self, channel: &str) -> usize {
+        self.shard(channel).get(channel).map_or(0, |s| s.len())
+    }
+
+    /// The channels matching a glob-style pattern, the shards are locked one after the other
+    pub(super) fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let mut channels: Vec<String> = self
+            .shards
+            .iter()
+            .flat_map(|shard| matching(&shard.lock().expect("Lock failed"), pattern))
+            .collect();
+        channels.sort();
+        channels
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        commands::pubsub::{Message, SubscriptionKind},
+        pubsub::{
+            test::{next, publish, subscribe},
+            PubSub,
+        },
+        resp::Type,
+    };
+
+    #[tokio::test]
+    async fn shards_are_independent() {
+        let pubsub = PubSub::default();
+        let mut subscriber = pubsub.subscriber();
+        let channels: Vec<String> = (0..100).map(|i| format!("channel:{}", i)).collect();
+        let channels: Vec<&str> = channels.iter().map(|c| c.as_str()).collect();
+        subscribe(&mut subscriber, &channels, SubscriptionKind::ShardChannel);
+        // Two channels on different shards
+        let busy = channels[0];
+        let other = channels
+            .iter()
+            .find(|c| pubsub.shards.index(c) != pubsub.shards.index(busy))
+            .expect("100 channels are spread over more than one shard");
+        // Publishing to a channel does not need the lock of the other shards
+        let busy_shard = pubsub.shards.shard(busy);
+        assert_eq!(publish(&pubsub, other, true), Type::Integer(1));
+        drop(busy_shard);
+        assert_eq!(
+            next(&mut subscriber).await,
+            Message {
+                pattern: None,
+                channel: other.to_string(),
+                payload: b"message".to_vec(),
+                sharded: true
+            }
+        );
+    }
+
+    #[test]
+    fn channels_are_listed_across_shards() {
+        let pubsub = PubSub::default();
+        let mut subscriber = pubsub.subscriber();
+        subscribe(
+            &mut subscriber,
+            &["b", "a", "c", "ab"],
+            SubscriptionKind::ShardChannel,
+        );
+        assert_eq!(pubsub.shards.channels(None), vec!["a", "ab", "b", "c"]);
+        assert_eq!(pubsub.shards.channels(Some("a*")), vec!["a", "ab"]);
+        assert_eq!(pubsub.shards.count("a"), 1);
+        drop(subscriber);
+        assert_eq!(pubsub.shards.count("a"), 0);
+        assert!(pubsub.shards.channels(None).is_empty());
+    }
+}
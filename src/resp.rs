@@ -58,17 +58,24 @@ pub enum Type {
     /// An additional RESP type for every element of the Array.
     /// It can contain mixed types
     Array(LinkedList<Type>),
+    /// Pushes (from RESP3) are data sent by the server without being asked for, like the
+    /// notifications of WATCH. They are encoded like arrays with a '>' instead of the '*',
+    /// so that a client can tell them apart from the replies. The first element is the kind.
+    ///
+    /// Example: `">2\r\n+unwatch\r\n:1\r\n"`
+    Push(LinkedList<Type>),
 }
 
 impl Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Type::SimpleString(s) => f.write_str(&s),
-            Type::Error(s) => f.write_str(&s),
+            Type::SimpleString(s) => f.write_str(s),
+            Type::Error(s) => f.write_str(s),
             Type::Integer(i) => f.write_str(&i.to_string()),
             Type::Null => f.write_str("Null"),
             Type::BulkString(b) => f.write_fmt(format_args!("{:?}", b)),
             Type::Array(a) => f.write_fmt(format_args!("{:?}", a)),
+            Type::Push(p) => f.write_fmt(format_args!(">{:?}", p)),
         }
     }
 }
@@ -82,7 +89,8 @@ impl Type {
             Type::Integer(i) => Type::integer(i),
             Type::Null => Type::null(),
             Type::BulkString(b) => Type::bulk_string(b),
-            Type::Array(a) => Type::array(a),
+            Type::Array(a) => Type::array(b'*', a),
+            Type::Push(p) => Type::array(b'>', p),
         }
     }
 
@@ -95,7 +103,7 @@ impl Type {
     }
 
     fn integer(i: i64) -> Vec<u8> {
-        format!(":{}\r\n", i.to_string()).into()
+        format!(":{}\r\n", i).into()
     }
 
     fn null() -> Vec<u8> {
@@ -115,8 +123,8 @@ impl Type {
         result
     }
 
-    fn array(l: LinkedList<Type>) -> Vec<u8> {
-        let mut result: Vec<u8> = vec![b'*'];
+    fn array(marker: u8, l: LinkedList<Type>) -> Vec<u8> {
+        let mut result: Vec<u8> = vec![marker];
         // Add the number of elements
         let number_of_elements = l.len().to_string().into_bytes();
         number_of_elements.iter().for_each(|&b| result.push(b));
@@ -186,7 +194,7 @@ impl TypeConsumer {
     pub fn next_type(&mut self) -> Result<Option<Type>, TypeConsumerError> {
         match &mut self.inner {
             Some(t) => match t {
-                Type::Array(values) => Ok(values.pop_front()),
+                Type::Array(values) | Type::Push(values) => Ok(values.pop_front()),
                 _ => Ok(self.inner.take()),
             },
             None => Ok(None),
@@ -199,7 +207,9 @@ impl TypeConsumer {
     ) -> Result<Option<T>, TypeConsumerError> {
         match &mut self.inner {
             Some(t) => match t {
-                Type::Array(values) => next_token_from_values::<T>(values, extractor),
+                Type::Array(values) | Type::Push(values) => {
+                    next_token_from_values::<T>(values, extractor)
+                }
                 _ => extractor(self.inner.take().expect("Cannot be None")).map(Some),
             },
             None => Ok(None),
@@ -207,7 +217,7 @@ impl TypeConsumer {
     }
 }
 
-/** Utility methods **/
+/* Utility methods */
 
 fn next_token_from_values<T>(
     values: &mut LinkedList<Type>,
@@ -223,11 +233,11 @@ fn next_bytes(value: Type) -> Result<Vec<u8>, TypeConsumerError> {
     match value {
         Type::SimpleString(s) => Ok(s.into()),
         Type::BulkString(s) => Ok(s),
-        _ => Err(cannot_convert_err(value.to_string(), "Bytes")),
+        _ => Err(cannot_convert_err(format!("{:?}", value), "Bytes")),
     }
 }
 fn next_integer(value: Type) -> Result<i64, TypeConsumerError> {
-    let v = value.to_string();
+    let v = format!("{:?}", value);
     match value {
         Type::SimpleString(s) => {
             atoi::atoi(s.as_bytes()).ok_or_else(|| cannot_convert_err(v, "Integer"))
@@ -238,7 +248,7 @@ fn next_integer(value: Type) -> Result<i64, TypeConsumerError> {
     }
 }
 fn next_string(value: Type) -> Result<String, TypeConsumerError> {
-    let v = value.to_string();
+    let v = format!("{:?}", value);
     match value {
         Type::SimpleString(s) => Ok(s),
         Type::Integer(i) => Ok(i.to_string()),
@@ -1,48 +1,175 @@
 //! The server module. This module implements a basic Tokio based server
 
+pub use crate::database::{
+    parse_bytes, Engine, KeyspaceEvents, MaxMemoryPolicy, SaveRule, SaveRules, SlowWatchers,
+    WatcherLimits,
+};
 use crate::{
-    commands::Command,
+    commands::{
+        client::{Client, Tracking, TrackingMode},
+        config::{Config, PARAMETERS},
+        info::Info,
+        Command,
+    },
     connection,
-    database::Database,
+    database::{
+        self, Database, Databases, KeyspaceNotifier, Memory, Notifications, Persistence, Shards,
+        Tracker, Watcher, WatcherStats, NOT_ALLOWED, OOM,
+    },
+    glob,
+    pubsub::{self, PubSub, ONLY_PUBSUB},
     resp::{Type, TypeConsumer},
     Result,
 };
 use connection::Connection;
 use log::{error, info};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc::{self},
+    sync::{
+        mpsc::{self},
+        Notify,
+    },
 };
+
+/// The number of databases, unless configured otherwise
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// The number of shards of every keyspace, unless configured otherwise
+pub const DEFAULT_SHARDS: usize = database::DEFAULT_SHARDS;
+
+/// The number of recent changes kept by every shard of every database, unless configured otherwise
+pub const DEFAULT_CHANGE_LOG: usize = database::DEFAULT_CHANGE_LOG;
+
+/// The configuration of a [RedisServer]
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The number of databases, connections start with database 0
+    pub databases: usize,
+    /// The number of shards of every keyspace, a command only locks the shards of its keys.
+    /// With a single shard every command waits for the previous one.
+    pub shards: usize,
+    /// How the commands are run, by the tasks of the connections locking the shards of
+    /// their keys (the default), or by the actors owning the shards
+    pub engine: Engine,
+    /// The number of recent changes kept by every shard of every database, within 1 MiB of
+    /// values per shard that count in the memory used.
+    /// A watcher can resume with `WATCH ... FROM <offset>` as long as its changes are kept.
+    pub change_log: usize,
+    /// The output buffer limits of the watchers, and what happens to those too slow
+    pub watcher_limits: WatcherLimits,
+    /// The keyspace notifications published over pub/sub, none by default.
+    /// Can be changed with `CONFIG SET notify-keyspace-events`.
+    pub keyspace_events: KeyspaceEvents,
+    /// The estimated number of bytes the keys and values can use, 0 (the default) for no
+    /// limit. Can be changed with `CONFIG SET maxmemory`.
+    pub maxmemory: usize,
+    /// Which keys are evicted once the `maxmemory` is reached, nothing by default.
+    /// Can be changed with `CONFIG SET maxmemory-policy`.
+    pub maxmemory_policy: MaxMemoryPolicy,
+    /// The file the snapshots are saved to (with SAVE, BGSAVE and the `save` rules), and
+    /// loaded from when the server starts. None (the default) for no snapshots.
+    pub dbfilename: Option<PathBuf>,
+    /// When a snapshot is saved automatically, see [SaveRules].
+    /// Can be changed with `CONFIG SET save`.
+    pub save: SaveRules,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            databases: DEFAULT_DATABASES,
+            shards: DEFAULT_SHARDS,
+            engine: Engine::default(),
+            change_log: DEFAULT_CHANGE_LOG,
+            watcher_limits: WatcherLimits::default(),
+            keyspace_events: KeyspaceEvents::default(),
+            maxmemory: 0,
+            maxmemory_policy: MaxMemoryPolicy::default(),
+            dbfilename: None,
+            save: SaveRules::default(),
+        }
+    }
+}
+
 /// A simple Redis Server that uses Tokio
 #[derive(Debug, Default)]
-pub struct RedisServer {}
+pub struct RedisServer {
+    config: ServerConfig,
+}
 
 impl RedisServer {
-    ///
+    /// Creates a new [RedisServer] with the default [ServerConfig]
     pub fn new() -> Self {
-        RedisServer {}
+        RedisServer::default()
     }
 
-    /// Starts listening on a given address
+    /// Creates a new [RedisServer] with the given [ServerConfig]
+    pub fn with_config(config: ServerConfig) -> Self {
+        RedisServer { config }
+    }
+
+    /// Loads the snapshot, if there is one, then starts listening on a given address
     pub async fn listen(&self, addr: &str) -> Result<()> {
         info!("Starting");
-        let db = Database::new();
+        let pubsub = PubSub::default();
+        let events = KeyspaceNotifier::new(pubsub.clone(), self.config.keyspace_events);
+        let memory = Memory::new(self.config.maxmemory, self.config.maxmemory_policy);
+        let databases = Databases::with_change_log(
+            self.config.databases.max(1),
+            self.config.shards,
+            self.config.change_log,
+            events.clone(),
+            memory.clone(),
+        )
+        .with_engine(self.config.engine);
+        let persistence = Persistence::new(
+            databases.clone(),
+            self.config.dbfilename.clone(),
+            self.config.save.clone(),
+        );
+        persistence.load()?;
+        persistence.spawn_save_rules();
+        let shared = Shared {
+            stats: Arc::new(WatcherStats::default()),
+            events,
+            memory,
+            persistence,
+        };
         let listener = TcpListener::bind(addr).await?;
         info!("Listening at {}", addr);
         loop {
             let (socket, addr) = listener.accept().await?;
             info!("Received connection from {:?}", addr);
-            let db = db.clone();
+            let databases = databases.clone();
+            let pubsub = pubsub.clone();
+            let limits = self.config.watcher_limits;
+            let shared = shared.clone();
             // A new task is spawned for each inbound socket. The socket is
             // moved to the new task and processed there.
             tokio::spawn(async move {
-                process(socket, db).await;
+                process(socket, databases, pubsub, limits, shared).await;
             });
         }
     }
 }
 
-async fn process(socket: TcpStream, mut db: Database) {
+/// What the server shares with every connection, besides the databases and the pub/sub
+#[derive(Clone)]
+struct Shared {
+    stats: Arc<WatcherStats>,
+    events: KeyspaceNotifier,
+    memory: Memory,
+    persistence: Persistence,
+}
+
+async fn process(
+    socket: TcpStream,
+    databases: Databases,
+    pubsub: PubSub,
+    limits: WatcherLimits,
+    shared: Shared,
+) {
     let client_id = socket.peer_addr().expect("address cannot be empty");
     info!("Processing bytes from client: {}", client_id);
     // create a connection (read and write halves)
@@ -50,10 +177,54 @@ async fn process(socket: TcpStream, mut db: Database) {
     let (mut read, mut write) = Connection::new(socket).read_write_split();
     // response channel
     let (response_sender, mut response_receiver) = mpsc::channel::<Type>(32);
+    // Notified when a watcher of the connection is too slow, the writes stop at once
+    let disconnect = Arc::new(Notify::new());
+    let slow = disconnect.clone();
     // Tokio reads
     tokio::spawn(async move {
+        // The selected database
+        let mut index = 0;
+        let mut db = databases.get(0).expect("There is at least one database");
+        // The commands queued after MULTI
+        let mut transaction: Option<Transaction> = None;
+        // The keys watched with OWATCH, EXEC fails if any of them changed
+        let mut watched_versions: Vec<WatchedVersion> = Vec::new();
+        // The channels and patterns subscribed to
+        let mut subscriber = pubsub.subscriber();
+        // Queues the WATCH notifications of the connection in order, created on the first WATCH
+        let mut watcher: Option<Watcher> = None;
+        let mut notifications: Option<Notifications> = None;
+        // CLIENT TRACKING, the invalidations are queued by the watcher too
+        let mut tracking: Option<(Tracker, Tracking)> = None;
+        // CLIENT CACHING YES|NO, only for the next command
+        let mut caching: Option<bool> = None;
         loop {
-            match read.recv().await {
+            let frame = tokio::select! {
+                frame = read.recv() => frame,
+                message = subscriber.next_message() => match message {
+                    Some(message) => {
+                        if let Err(e) = response_sender.send(message).await {
+                            error!("Error {}", e);
+                            break;
+                        }
+                        continue;
+                    }
+                    // Disconnected, see the slow subscriber policy of the pubsub module
+                    None => break,
+                },
+                notification = next_notification(&mut notifications) => match notification {
+                    Some(notification) => {
+                        if let Err(e) = response_sender.send(notification).await {
+                            error!("Error {}", e);
+                            break;
+                        }
+                        continue;
+                    }
+                    // Disconnected, see the slow watcher policy of the watchers module
+                    None => break,
+                },
+            };
+            match frame {
                 Ok(t) => match t {
                     Some(t) => {
                         info!("Received {}", t);
@@ -62,20 +233,209 @@ async fn process(socket: TcpStream, mut db: Database) {
                         let r = match command {
                             Ok(command) => {
                                 info!("Received {:?}", command);
-                                let r = match command {
-                                    Command::Get(g) => db.get(g),
-                                    Command::Set(s) => db.set(s),
-                                    Command::Push(p) => db.push(p),
-                                    Command::Watch(w) => {
-                                        info!("Client: {} will entering watch mode", client_id);
-                                        db.watch(w, response_sender.clone())
+                                let caching_next =
+                                    matches!(command, Command::Client(Client::Caching(_)));
+                                let replies = match (command, transaction.is_some()) {
+                                    (command, false)
+                                        if subscriber.is_subscribed()
+                                            && !command.allowed_when_subscribed() =>
+                                    {
+                                        vec![Type::Error(ONLY_PUBSUB.into())]
+                                    }
+                                    (Command::Subscribe(s), false) => subscriber.subscribe(s),
+                                    (Command::Unsubscribe(u), false) => subscriber.unsubscribe(u),
+                                    (Command::Ping(p), false) if subscriber.is_subscribed() => {
+                                        vec![pubsub::pong(p)]
                                     }
+                                    (command, _) => vec![match (command, transaction.as_mut()) {
+                                        (Command::Multi(_), Some(_)) => {
+                                            Type::Error("ERR MULTI calls can not be nested".into())
+                                        }
+                                        (Command::Multi(_), None) => {
+                                            transaction = Some(Transaction::default());
+                                            Type::SimpleString("OK".into())
+                                        }
+                                        (Command::Exec(_), _) => match transaction.take() {
+                                            Some(t) => {
+                                                let watched = std::mem::take(&mut watched_versions);
+                                                let (mut db, all) = (db.clone(), databases.clone());
+                                                databases
+                                                    .unblocking(move || {
+                                                        t.exec(&mut db, index, watched, &all)
+                                                    })
+                                                    .await
+                                            }
+                                            None => Type::Error("ERR EXEC without MULTI".into()),
+                                        },
+                                        (Command::Discard(_), _) => match transaction.take() {
+                                            Some(_) => {
+                                                watched_versions.clear();
+                                                Type::SimpleString("OK".into())
+                                            }
+                                            None => Type::Error("ERR DISCARD without MULTI".into()),
+                                        },
+                                        (command, Some(t)) => t.queue(command),
+                                        (Command::Watch(w), None) => {
+                                            info!("Client: {} will entering watch mode", client_id);
+                                            let watcher = connection_watcher(
+                                                &mut watcher,
+                                                &mut notifications,
+                                                limits,
+                                                &shared.stats,
+                                                &disconnect,
+                                            );
+                                            let mut db = db.clone();
+                                            databases.unblocking(move || db.watch(w, watcher)).await
+                                        }
+                                        (Command::Client(Client::Tracking(t)), None) => {
+                                            if let Some((tracker, _)) = tracking.take() {
+                                                databases.untrack(&tracker);
+                                            }
+                                            if t.on {
+                                                let watcher = connection_watcher(
+                                                    &mut watcher,
+                                                    &mut notifications,
+                                                    limits,
+                                                    &shared.stats,
+                                                    &disconnect,
+                                                );
+                                                let tracker = Tracker::new(watcher, t.noloop);
+                                                if t.bcast {
+                                                    databases.broadcast(&t.prefixes, &tracker);
+                                                }
+                                                tracking = Some((tracker, t));
+                                            }
+                                            Type::SimpleString("OK".into())
+                                        }
+                                        (Command::Client(Client::Caching(yes)), None) => {
+                                            let mode = tracking.as_ref().map(|(_, t)| t.mode);
+                                            match (mode, yes) {
+                                                (Some(TrackingMode::OptIn), true)
+                                                | (Some(TrackingMode::OptOut), false) => {
+                                                    caching = Some(yes);
+                                                    Type::SimpleString("OK".into())
+                                                }
+                                                (Some(TrackingMode::OptIn), false) => Type::Error(
+                                                    "ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.".into(),
+                                                ),
+                                                (Some(TrackingMode::OptOut), true) => Type::Error(
+                                                    "ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.".into(),
+                                                ),
+                                                _ => Type::Error(
+                                                    "ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled".into(),
+                                                ),
+                                            }
+                                        }
+                                        (Command::Info(i), None) => info(i, &shared),
+                                        (Command::Config(c), None) => config(c, &shared),
+                                        (Command::Save(s), None) => {
+                                            let persistence = shared.persistence.clone();
+                                            databases
+                                                .unblocking(move || match s.background {
+                                                    true => persistence.bgsave(),
+                                                    false => persistence.save(),
+                                                })
+                                                .await
+                                        }
+                                        (Command::Unwatch(u), None) => {
+                                            let removed = match (&watcher, u.key) {
+                                                (None, _) => 0,
+                                                (Some(w), Some(key)) => {
+                                                    db.unwatch(w, Some(key), u.pattern)
+                                                }
+                                                (Some(w), None) => databases.unwatch(w, u.pattern),
+                                            };
+                                            Type::Integer(removed as i64)
+                                        }
+                                        (Command::OWatch(o), None) => {
+                                            let keys: Vec<_> = o.keys.into_iter().collect();
+                                            let mut db = db.clone();
+                                            let versions = databases
+                                                .unblocking(move || {
+                                                    db.execute_on(Some(&keys), |keyspace| {
+                                                        keys.iter()
+                                                            .cloned()
+                                                            .map(|key| WatchedVersion {
+                                                                index,
+                                                                version: keyspace.version_of(&key),
+                                                                key,
+                                                            })
+                                                            .collect::<Vec<_>>()
+                                                    })
+                                                })
+                                                .await;
+                                            watched_versions.extend(versions);
+                                            Type::SimpleString("OK".into())
+                                        }
+                                        (Command::OUnwatch(_), None) => {
+                                            watched_versions.clear();
+                                            Type::SimpleString("OK".into())
+                                        }
+                                        (Command::Flush(f), None) if f.all => {
+                                            let all = databases.clone();
+                                            databases.unblocking(move || all.flush_all(f)).await
+                                        }
+                                        (Command::Select(s), None) => {
+                                            match databases.get(s.index) {
+                                                Some(selected) => {
+                                                    index = s.index as usize;
+                                                    db = selected;
+                                                    Type::SimpleString("OK".into())
+                                                }
+                                                None => Databases::out_of_range(),
+                                            }
+                                        }
+                                        (Command::Move(m), None) => {
+                                            let all = databases.clone();
+                                            databases
+                                                .unblocking(move || all.move_key(index, m))
+                                                .await
+                                        }
+                                        (Command::SwapDb(s), None) => {
+                                            let all = databases.clone();
+                                            databases.unblocking(move || all.swap(s)).await
+                                        }
+                                        (Command::Publish(p), None) => pubsub.publish(p),
+                                        (Command::PubSub(p), None) => pubsub.introspect(p),
+                                        (command, None)
+                                            if command.uses_memory()
+                                                && !databases.make_room_unblocking().await =>
+                                        {
+                                            Type::Error(OOM.into())
+                                        }
+                                        (command, None) => match &tracking {
+                                            Some((tracker, t)) => {
+                                                let track = !t.bcast
+                                                    && match t.mode {
+                                                        TrackingMode::Default => true,
+                                                        TrackingMode::OptIn => {
+                                                            caching == Some(true)
+                                                        }
+                                                        TrackingMode::OptOut => {
+                                                            caching != Some(false)
+                                                        }
+                                                    };
+                                                db.run(command, Some((tracker.clone(), track)))
+                                                    .await
+                                            }
+                                            None => db.run(command, None).await,
+                                        },
+                                    }],
                                 };
-                                info!("Recieved {:?} from DB", r);
-                                response_sender.send(r).await
+                                if !caching_next {
+                                    caching = None;
+                                }
+                                info!("Recieved {:?} from DB", replies);
+                                send_all(&response_sender, replies).await
                             }
                             // Error, response sender closed
-                            Err(e) => response_sender.send(error(e)).await,
+                            Err(e) => {
+                                // An invalid command fails the transaction
+                                if let Some(t) = transaction.as_mut() {
+                                    t.aborted = true;
+                                }
+                                response_sender.send(error(e)).await
+                            }
                         };
                         if let Err(e) = r {
                             error!("Error {}", e);
@@ -92,14 +452,29 @@ async fn process(socket: TcpStream, mut db: Database) {
                 }
             }
         }
+        // The watches and the tracking of the connection are not needed anymore
+        if let Some((tracker, _)) = tracking {
+            databases.untrack(&tracker);
+        }
+        if let Some(watcher) = watcher {
+            databases.unwatch_all(&watcher);
+        }
         info!("Stopping read");
     });
 
     // Tokio writes
     tokio::spawn(async move {
-        while let Some(t) = response_receiver.recv().await {
+        while let Some(t) = tokio::select! {
+            t = response_receiver.recv() => t,
+            _ = slow.notified() => None,
+        } {
             info!("Sending {} to client", t);
-            if let Err(e) = write.send(t).await {
+            let sent = tokio::select! {
+                sent = write.send(t) => sent,
+                // The client is not reading, see the slow watcher policy of the watchers module
+                _ = slow.notified() => break,
+            };
+            if let Err(e) = sent {
                 error!("Error {:?}", e);
                 break;
             }
@@ -108,9 +483,448 @@ async fn process(socket: TcpStream, mut db: Database) {
     });
 }
 
+/// The watcher of a connection, created on first use (by WATCH or CLIENT TRACKING)
+fn connection_watcher(
+    watcher: &mut Option<Watcher>,
+    notifications: &mut Option<Notifications>,
+    limits: WatcherLimits,
+    stats: &Arc<WatcherStats>,
+    disconnect: &Arc<Notify>,
+) -> Watcher {
+    watcher
+        .get_or_insert_with(|| {
+            let (watcher, receiver) =
+                Watcher::with_limits(limits, stats.clone(), disconnect.clone());
+            *notifications = Some(receiver);
+            watcher
+        })
+        .clone()
+}
+
+/// The next notification of the watcher of a connection, never ready without one
+async fn next_notification(notifications: &mut Option<Notifications>) -> Option<Type> {
+    match notifications {
+        Some(notifications) => notifications.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// INFO, only the memory, persistence and watchers sections for now
+fn info(i: Info, shared: &Shared) -> Type {
+    // Whether the section asked for includes the section `name`
+    let includes = |name: &str| match &i.section {
+        None => true,
+        Some(section) => ["all", "default", "everything", name]
+            .iter()
+            .any(|s| section.eq_ignore_ascii_case(s)),
+    };
+    let mut info = String::new();
+    if includes("memory") {
+        info.push_str(&shared.memory.info());
+    }
+    if includes("persistence") {
+        info.push_str(&shared.persistence.info());
+    }
+    if includes("watchers") {
+        info.push_str(&shared.stats.info());
+    }
+    Type::BulkString(info.into_bytes())
+}
+
+/// CONFIG, only for the [PARAMETERS] that can be changed while the server runs
+fn config(c: Config, shared: &Shared) -> Type {
+    let Shared {
+        events,
+        memory,
+        persistence,
+        ..
+    } = shared;
+    match c {
+        Config::Get { pattern } => {
+            let pattern = pattern.to_lowercase();
+            Type::Array(
+                PARAMETERS
+                    .iter()
+                    .filter(|parameter| glob::matches(pattern.as_bytes(), parameter.as_bytes()))
+                    .flat_map(|parameter| {
+                        let value = match *parameter {
+                            "maxmemory" => memory.maxmemory().to_string(),
+                            "maxmemory-policy" => memory.policy().to_string(),
+                            "save" => persistence.rules().to_string(),
+                            _ => events.events().to_string(),
+                        };
+                        vec![
+                            Type::BulkString(parameter.as_bytes().to_vec()),
+                            Type::BulkString(value.into_bytes()),
+                        ]
+                    })
+                    .collect(),
+            )
+        }
+        Config::Set { parameter, value } => {
+            let set = match parameter.to_lowercase().as_ref() {
+                "notify-keyspace-events" => value.parse().map(|e| events.set_events(e)),
+                "maxmemory" => parse_bytes(&value).map(|m| memory.set_maxmemory(m)),
+                "maxmemory-policy" => value.parse().map(|p| memory.set_policy(p)),
+                "save" => value.parse().map(|r| persistence.set_rules(r)),
+                _ => {
+                    return Type::Error(format!("ERR Unsupported CONFIG parameter: {}", parameter))
+                }
+            };
+            match set {
+                Ok(()) => Type::SimpleString("OK".into()),
+                Err(_) => Type::Error(format!(
+                    "ERR Invalid argument '{}' for CONFIG SET '{}'",
+                    value, parameter
+                )),
+            }
+        }
+    }
+}
+
+/// Sends the replies to a command, in order
+async fn send_all(
+    response_sender: &mpsc::Sender<Type>,
+    replies: Vec<Type>,
+) -> std::result::Result<(), mpsc::error::SendError<Type>> {
+    for reply in replies {
+        response_sender.send(reply).await?;
+    }
+    Ok(())
+}
+
+/// A key watched with OWATCH, and its version at the time
+struct WatchedVersion {
+    /// The database the key was watched in
+    index: usize,
+    key: String,
+    version: u64,
+}
+
+/// The commands queued by a connection, between MULTI and EXEC
+#[derive(Default)]
+struct Transaction {
+    commands: Vec<Command>,
+    /// An error was found while queuing, EXEC will fail
+    aborted: bool,
+}
+
+impl Transaction {
+    fn queue(&mut self, command: Command) -> Type {
+        if command.allowed_in_transaction() {
+            self.commands.push(command);
+            Type::SimpleString("QUEUED".into())
+        } else {
+            self.aborted = true;
+            Type::Error(NOT_ALLOWED.into())
+        }
+    }
+
+    /// Runs all the commands while holding the locks of the shards of their keys (all the
+    /// shards of the database `db` at `index` if one of them works on the whole keyspace),
+    /// the watchers only see the changes once they are all done.
+    /// Nothing is run, and the reply is Null, if any of the `watched` keys changed: the shards
+    /// of the keys watched in other databases are locked with them (see
+    /// [Database::lock_all]), and all the versions are checked under those locks.
+    /// The reply is an OOM error if a command can use more memory and the `maxmemory` is
+    /// reached (see [Databases::make_room]).
+    fn exec(
+        self,
+        db: &mut Database,
+        index: usize,
+        watched: Vec<WatchedVersion>,
+        databases: &Databases,
+    ) -> Type {
+        if self.aborted {
+            return Type::Error(
+                "EXECABORT Transaction discarded because of previous errors.".into(),
+            );
+        }
+        if self.commands.iter().any(Command::uses_memory) && !databases.make_room() {
+            return Type::Error(OOM.into());
+        }
+        // The shards of the keys watched in every database are locked together with the
+        // shards of the commands, in the order of the databases, and the versions are checked
+        // under those locks
+        let mut watched_in: BTreeMap<usize, Vec<WatchedVersion>> = BTreeMap::new();
+        for w in watched {
+            watched_in.entry(w.index).or_default().push(w);
+        }
+        let here = watched_in.remove(&index).unwrap_or_default();
+        let mut others = Vec::new();
+        for (other, watched) in watched_in {
+            match databases.get(other as i64) {
+                Some(database) => {
+                    let keys: Vec<_> = watched.iter().map(|w| w.key.clone()).collect();
+                    others.push((other, database, keys, watched));
+                }
+                None => return Type::Null,
+            }
+        }
+        let commands = self.commands;
+        let keys = commands
+            .iter()
+            .map(Command::keys)
+            .chain(here.iter().map(|w| Some(vec![w.key.clone()])))
+            .try_fold(Vec::new(), |mut keys, command_keys| {
+                keys.extend(command_keys?);
+                Some(keys)
+            });
+        let mut to_lock: Vec<_> = others
+            .iter()
+            .map(|(_, database, keys, _)| (database, Some(keys.as_slice())))
+            .collect();
+        let position = others.iter().filter(|(other, ..)| *other < index).count();
+        to_lock.insert(position, (&*db, keys.as_deref()));
+        let mut locked = Database::lock_all(&to_lock);
+        let store = locked.remove(position);
+        if !unchanged(&store, &here)
+            || !locked
+                .iter()
+                .zip(&others)
+                .all(|(shards, (_, _, _, watched))| unchanged(shards, watched))
+        {
+            return Type::Null;
+        }
+        db.execute_with(store, |keyspace| {
+            Type::Array(
+                commands
+                    .into_iter()
+                    .map(|command| keyspace.apply(command))
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// Returns true if none of the watched keys changed, their shards being locked
+fn unchanged(shards: &Shards<'_>, watched: &[WatchedVersion]) -> bool {
+    watched
+        .iter()
+        .all(|w| shards.version(&w.key.as_str().into()) == w.version)
+}
+
 fn error<T>(e: T) -> Type
 where
     T: std::error::Error,
 {
     Type::Error(format!("Error: {}", e))
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Transaction, WatchedVersion};
+    use crate::{
+        commands::{databases::Select, get::Get, list::Push, set::Set, Command},
+        database::{Database, Databases, Engine, Shards, DEFAULT_CHANGE_LOG, NOT_ALLOWED},
+        resp::Type,
+    };
+
+    fn lock<'a>(db: &'a Database, key: &str) -> Shards<'a> {
+        let keys = [key.to_string()];
+        Database::lock_all(&[(db, Some(&keys[..]))]).pop().unwrap()
+    }
+
+    fn set(key: &str, value: &str) -> Command {
+        Command::Set(Set {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    fn get(key: &str) -> Command {
+        Command::Get(Get { key: key.into() })
+    }
+
+    #[test]
+    fn exec_replies_with_all_the_replies() {
+        let databases = Databases::new(1);
+        let mut db = databases.get(0).unwrap();
+        let mut transaction = Transaction::default();
+        let queued = Type::SimpleString("QUEUED".into());
+        assert_eq!(transaction.queue(set("a", "1")), queued);
+        assert_eq!(
+            transaction.queue(Command::Push(Push {
+                list_name: "a".into(),
+                values: vec!["x".to_string()].into_iter().collect(),
+            })),
+            queued
+        );
+        assert_eq!(transaction.queue(get("a")), queued);
+        // Errors while running do not stop the other commands
+        assert_eq!(
+            transaction.exec(&mut db, 0, vec![], &databases),
+            Type::Array(
+                vec![
+                    Type::SimpleString("Ok".into()),
+                    Type::Error("key `a` exists and it is not a list".into()),
+                    Type::BulkString("1".into()),
+                ]
+                .into_iter()
+                .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn errors_while_queuing_abort_exec() {
+        let databases = Databases::new(1);
+        let mut db = databases.get(0).unwrap();
+        let mut transaction = Transaction::default();
+        transaction.queue(set("a", "1"));
+        assert_eq!(
+            transaction.queue(Command::Select(Select { index: 1 })),
+            Type::Error(NOT_ALLOWED.into())
+        );
+        assert_eq!(
+            transaction.exec(&mut db, 0, vec![], &databases),
+            Type::Error("EXECABORT Transaction discarded because of previous errors.".into())
+        );
+        assert_eq!(db.apply(get("a")), Type::Null);
+    }
+
+    #[test]
+    fn exec_fails_if_a_watched_key_changed() {
+        for engine in [Engine::Locks, Engine::Actors] {
+            exec_fails_if_a_watched_key_changed_with(engine);
+        }
+    }
+
+    fn exec_fails_if_a_watched_key_changed_with(engine: Engine) {
+        let databases = Databases::new(2).with_engine(engine);
+        let (mut db, mut other) = (databases.get(0).unwrap(), databases.get(1).unwrap());
+        let watch = |db: &mut Database, index, key: &str| WatchedVersion {
+            index,
+            key: key.into(),
+            version: db.execute(|keyspace| keyspace.version_of(key)),
+        };
+        let transaction = || {
+            let mut transaction = Transaction::default();
+            transaction.queue(set("a", "from transaction"));
+            transaction
+        };
+        // Not changed, the shards of `a` are lent by the same actor for both databases
+        let watched = vec![
+            watch(&mut db, 0, "a"),
+            watch(&mut other, 1, "a"),
+            watch(&mut other, 1, "b"),
+        ];
+        assert_eq!(
+            transaction().exec(&mut db, 0, watched, &databases),
+            Type::Array(vec![Type::SimpleString("Ok".into())].into_iter().collect())
+        );
+        // Changed in the same database
+        let watched = vec![watch(&mut db, 0, "a")];
+        db.apply(set("a", "changed"));
+        assert_eq!(
+            transaction().exec(&mut db, 0, watched, &databases),
+            Type::Null
+        );
+        // Changed in an other database, created then removed
+        let watched = vec![watch(&mut other, 1, "b")];
+        other.apply(set("b", "created"));
+        other.apply(Command::Rename(crate::commands::keys::Rename {
+            key: "b".into(),
+            new_key: "c".into(),
+            only_if_new: false,
+        }));
+        assert_eq!(
+            transaction().exec(&mut db, 0, watched, &databases),
+            Type::Null
+        );
+        assert_eq!(db.apply(get("a")), Type::BulkString("changed".into()));
+    }
+
+    #[test]
+    fn exec_ignores_the_removal_of_other_keys_of_the_shard() {
+        // A single shard, every key is in it
+        let databases = Databases::with_change_log(
+            1,
+            1,
+            DEFAULT_CHANGE_LOG,
+            Default::default(),
+            Default::default(),
+        );
+        let mut db = databases.get(0).unwrap();
+        let watch = |db: &mut Database| WatchedVersion {
+            index: 0,
+            key: "missing".into(),
+            version: db.execute(|keyspace| keyspace.version_of("missing")),
+        };
+        let transaction = || {
+            let mut transaction = Transaction::default();
+            transaction.queue(set("a", "from transaction"));
+            transaction
+        };
+        let rename = |from: &str, to: &str| {
+            Command::Rename(crate::commands::keys::Rename {
+                key: from.into(),
+                new_key: to.into(),
+                only_if_new: false,
+            })
+        };
+        db.apply(set("other", "v"));
+        let watched = vec![watch(&mut db)];
+        db.apply(rename("other", "renamed"));
+        assert_eq!(
+            transaction().exec(&mut db, 0, watched, &databases),
+            Type::Array(vec![Type::SimpleString("Ok".into())].into_iter().collect())
+        );
+        // The watched key created then removed changed
+        let watched = vec![watch(&mut db)];
+        db.apply(set("missing", "v"));
+        db.apply(rename("missing", "found"));
+        assert_eq!(
+            transaction().exec(&mut db, 0, watched, &databases),
+            Type::Null
+        );
+    }
+
+    #[test]
+    fn exec_checks_the_keys_watched_elsewhere_under_their_locks() {
+        let databases = Databases::new(3);
+        let mut db = databases.get(1).unwrap();
+        let (mut before, mut after) = (databases.get(0).unwrap(), databases.get(2).unwrap());
+        let watched = vec![
+            WatchedVersion {
+                index: 0,
+                key: "b".into(),
+                version: before.execute(|keyspace| keyspace.version_of("b")),
+            },
+            WatchedVersion {
+                index: 2,
+                key: "c".into(),
+                version: after.execute(|keyspace| keyspace.version_of("c")),
+            },
+        ];
+        let mut transaction = Transaction::default();
+        transaction.queue(set("a", "from transaction"));
+        let same = databases.get(1).unwrap();
+        let locked = lock(&same, "a");
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let (exec, databases) = (sender.clone(), &databases);
+            scope.spawn(move || exec.send(transaction.exec(&mut db, 1, watched, databases)));
+            // EXEC locks the shard of `b` in the database 0, then waits for the one of `a`
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let write = sender;
+            let before = &before;
+            scope.spawn(move || {
+                drop(lock(before, "b"));
+                write.send(Type::Null)
+            });
+            // The lock of `b` is held until the commands ran
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(receiver.try_recv().is_err());
+            drop(locked);
+        });
+        let replies: Vec<Type> = receiver.iter().collect();
+        assert!(replies.contains(&Type::Array(
+            vec![Type::SimpleString("Ok".into())].into_iter().collect()
+        )));
+        assert_eq!(
+            databases.get(1).unwrap().apply(get("a")),
+            Type::BulkString("from transaction".into())
+        );
+    }
+}